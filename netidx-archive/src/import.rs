@@ -0,0 +1,388 @@
+use crate::logfile::{ArchiveWriter, BatchItem, Id, BATCH_POOL};
+use anyhow::{Context, Error, Result};
+use chrono::prelude::*;
+use fxhash::FxHashMap;
+use log::info;
+use netidx::{
+    chars::Chars,
+    path::Path,
+    subscriber::{Event, Value},
+};
+use serde_derive::Deserialize;
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    io::BufRead,
+    path::{Path as FilePath, PathBuf},
+    str::FromStr,
+    sync::Arc,
+};
+
+/// The format of the data to be imported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// One `timestamp,path,value` row per line. The timestamp must
+    /// be rfc3339, and the value is written in netidx value syntax,
+    /// e.g. `u32:42`, `"a string"`, or `3.1415`. The value is the
+    /// rest of the line, so it may contain commas. A path containing
+    /// a comma must be quoted with `"`, and `""` in a quoted path is
+    /// a literal `"`. Blank lines, lines starting with `#`, and a
+    /// leading `timestamp,path,value` header are ignored.
+    Csv,
+    /// One json object per line of the form `{"timestamp":
+    /// "2023-01-01T00:00:00Z", "path": "/foo", "value": 42}`. Plain
+    /// json values are mapped to the closest netidx type, objects
+    /// are interpreted as serialized netidx values, e.g. `{"type":
+    /// "U32", "value": 42}`.
+    JsonLines,
+}
+
+impl FromStr for Format {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim() {
+            "csv" => Ok(Format::Csv),
+            "json" | "jsonl" | "json-lines" => Ok(Format::JsonLines),
+            s => bail!("unknown import format {}, expected csv or json", s),
+        }
+    }
+}
+
+/// Statistics about a completed import
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ImportStats {
+    /// the number of rows read from the input
+    pub rows: usize,
+    /// the number of distinct paths in the input
+    pub paths: usize,
+    /// the number of delta batches written
+    pub delta_batches: usize,
+    /// the number of image batches written
+    pub image_batches: usize,
+    /// the timestamp of the first batch written
+    pub first: Option<DateTime<Utc>>,
+    /// the timestamp of the last batch written
+    pub last: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct JsonRow {
+    timestamp: DateTime<Utc>,
+    path: String,
+    value: serde_json::Value,
+}
+
+fn value_from_json(v: serde_json::Value) -> Result<Value> {
+    use serde_json::Value as J;
+    match v {
+        J::Null => Ok(Value::Null),
+        J::Bool(true) => Ok(Value::True),
+        J::Bool(false) => Ok(Value::False),
+        J::Number(n) => match (n.as_i64(), n.as_u64(), n.as_f64()) {
+            (Some(i), _, _) => Ok(Value::I64(i)),
+            (None, Some(u), _) => Ok(Value::U64(u)),
+            (None, None, Some(f)) => Ok(Value::F64(f)),
+            (None, None, None) => bail!("unrepresentable number {}", n),
+        },
+        J::String(s) => Ok(Value::String(Chars::from(s))),
+        J::Array(elts) => {
+            let elts =
+                elts.into_iter().map(value_from_json).collect::<Result<Vec<_>>>()?;
+            Ok(Value::Array(Arc::from(elts)))
+        }
+        v @ J::Object(_) => serde_json::from_value::<Value>(v)
+            .context("objects must be serialized netidx values"),
+    }
+}
+
+// split the path field off the front of a csv row, and return it
+// with the rest of the row after it's separating comma
+fn csv_path(s: &str) -> Result<(String, &str)> {
+    let s = s.trim_start();
+    let mut s = match s.strip_prefix('"') {
+        Some(s) => s,
+        None => match s.split_once(',') {
+            None => bail!("missing value"),
+            Some((path, rest)) => return Ok((String::from(path.trim()), rest)),
+        },
+    };
+    let mut path = String::new();
+    loop {
+        match s.find('"') {
+            None => bail!("unterminated quoted path"),
+            Some(i) => {
+                path.push_str(&s[..i]);
+                s = &s[i + 1..];
+                match s.strip_prefix('"') {
+                    None => break,
+                    Some(rest) => {
+                        path.push('"');
+                        s = rest
+                    }
+                }
+            }
+        }
+    }
+    match s.trim_start().strip_prefix(',') {
+        None => bail!("expected a comma after the quoted path"),
+        Some(rest) => Ok((path, rest)),
+    }
+}
+
+fn parse_row(format: Format, line: &str) -> Result<(DateTime<Utc>, Path, Value)> {
+    match format {
+        Format::Csv => {
+            let (ts, rest) =
+                line.split_once(',').ok_or_else(|| anyhow!("missing path"))?;
+            let ts = ts.trim().parse::<DateTime<Utc>>().context("parsing timestamp")?;
+            let (path, value) = csv_path(rest)?;
+            let value = value.trim().parse::<Value>().context("parsing value")?;
+            Ok((ts, Path::from(path), value))
+        }
+        Format::JsonLines => {
+            let row = serde_json::from_str::<JsonRow>(line).context("parsing row")?;
+            let value = value_from_json(row.value)?;
+            Ok((row.timestamp, Path::from(row.path), value))
+        }
+    }
+}
+
+fn is_header(format: Format, line: &str) -> bool {
+    match format {
+        Format::JsonLines => false,
+        Format::Csv => line.starts_with("timestamp,"),
+    }
+}
+
+struct Importer<'a> {
+    dest: &'a mut ArchiveWriter,
+    pathindex: Option<&'a mut ArchiveWriter>,
+    image_frequency: Option<usize>,
+    image: FxHashMap<Id, Event>,
+    last_image: usize,
+    pending: Vec<(Path, Value)>,
+    stats: ImportStats,
+}
+
+impl<'a> Importer<'a> {
+    fn flush_batch(&mut self, ts: DateTime<Utc>) -> Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let mut batch = BATCH_POOL.take();
+        {
+            let ids = match &mut self.pathindex {
+                Some(pathindex) => &mut **pathindex,
+                None => &mut *self.dest,
+            };
+            let known = ids.len();
+            ids.add_paths(self.pending.iter().map(|(p, _)| p)).context("adding paths")?;
+            if ids.len() > known {
+                ids.flush().context("flushing path mappings")?;
+            }
+            for (path, v) in self.pending.drain(..) {
+                let id = ids.id_for_path(&path).unwrap();
+                batch.push(BatchItem(id, Event::Update(v)));
+            }
+        }
+        if self.image_frequency.is_some() {
+            for BatchItem(id, ev) in batch.iter() {
+                self.image.insert(*id, ev.clone());
+            }
+        }
+        self.dest.add_batch(false, ts, &batch).context("adding delta batch")?;
+        self.stats.delta_batches += 1;
+        self.stats.first = self.stats.first.or(Some(ts));
+        self.stats.last = Some(ts);
+        match self.image_frequency {
+            None => (),
+            Some(freq) if self.dest.len() - self.last_image < freq => (),
+            Some(_) => {
+                let mut image = BATCH_POOL.take();
+                for (id, ev) in self.image.iter() {
+                    image.push(BatchItem(*id, ev.clone()));
+                }
+                self.dest.add_batch(true, ts, &image).context("adding image batch")?;
+                self.stats.image_batches += 1;
+                self.last_image = self.dest.len();
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Import the time series in `input` into `dest`. Rows with the same
+/// timestamp are written as a single delta batch, and rows must be
+/// sorted by timestamp. If `image_frequency` is specified, then an
+/// image batch will be written each time that many bytes of deltas
+/// have been written, just like the recorder does.
+///
+/// If `pathindex` is specified then path ids will be allocated in it
+/// rather than in `dest`. This is how the recorder lays out a shard,
+/// the data files refer to the ids stored in the shard's pathindex.
+pub fn import(
+    input: impl BufRead,
+    format: Format,
+    image_frequency: Option<usize>,
+    dest: &mut ArchiveWriter,
+    pathindex: Option<&mut ArchiveWriter>,
+) -> Result<ImportStats> {
+    let last_image = dest.len();
+    let mut t = Importer {
+        dest,
+        pathindex,
+        image_frequency,
+        image: HashMap::default(),
+        last_image,
+        pending: Vec::new(),
+        stats: ImportStats::default(),
+    };
+    let mut paths: HashSet<Path> = HashSet::new();
+    let mut current: Option<DateTime<Utc>> = None;
+    for (i, line) in input.lines().enumerate() {
+        let line = line.context("reading input")?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || (i == 0 && is_header(format, line))
+        {
+            continue;
+        }
+        let (ts, path, value) =
+            parse_row(format, line).with_context(|| format!("line {}", i + 1))?;
+        match current {
+            Some(cur) if ts < cur => {
+                bail!(
+                    "line {}: rows must be sorted by timestamp, {} < {}",
+                    i + 1,
+                    ts,
+                    cur
+                )
+            }
+            Some(cur) if ts == cur => (),
+            Some(cur) => {
+                t.flush_batch(cur)?;
+                current = Some(ts);
+            }
+            None => current = Some(ts),
+        }
+        paths.insert(path.clone());
+        t.pending.push((path, value));
+        t.stats.rows += 1;
+    }
+    if let Some(cur) = current {
+        t.flush_batch(cur)?;
+    }
+    t.dest.flush().context("flushing archive")?;
+    t.stats.paths = paths.len();
+    Ok(t.stats)
+}
+
+/// Import the time series in `input` into the recorder shard
+/// directory `shard_dir` (e.g. `${archive_directory}/${shard}`). Path
+/// ids are allocated in the shard's pathindex, and the resulting
+/// file is named with the rfc3339 timestamp of it's last batch, just
+/// like a rotated log file, so the recorder will serve it via
+/// `LogfileIndex` the next time it reads the shard directory.
+///
+/// The recorder holds an exclusive lock on the pathindex while it is
+/// recording the shard, so recording must be stopped during the
+/// import. Returns the path of the new file.
+pub fn import_to_shard(
+    input: impl BufRead,
+    format: Format,
+    image_frequency: Option<usize>,
+    shard_dir: impl AsRef<FilePath>,
+) -> Result<(PathBuf, ImportStats)> {
+    let shard_dir = shard_dir.as_ref();
+    fs::create_dir_all(shard_dir).context("creating shard directory")?;
+    let mut pathindex = ArchiveWriter::open(shard_dir.join("pathindex"))
+        .context("opening pathindex, is the recorder running?")?;
+    let tmp = shard_dir.join(format!("import-{}.tmp", std::process::id()));
+    let res = ArchiveWriter::open(&tmp).and_then(|mut dest| {
+        import(input, format, image_frequency, &mut dest, Some(&mut pathindex))
+    });
+    let stats = match res {
+        Ok(stats) => stats,
+        Err(e) => {
+            let _ = fs::remove_file(&tmp);
+            return Err(e);
+        }
+    };
+    let last = match stats.last {
+        Some(last) => last,
+        None => {
+            fs::remove_file(&tmp)?;
+            bail!("no data to import")
+        }
+    };
+    let dest = shard_dir.join(last.to_rfc3339());
+    if dest.exists() {
+        fs::remove_file(&tmp)?;
+        bail!("{} already exists", dest.display())
+    }
+    fs::rename(&tmp, &dest).context("renaming imported file")?;
+    info!("imported {} rows into {}", stats.rows, dest.display());
+    Ok((dest, stats))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::logfile::{ArchiveReader, Cursor};
+
+    #[test]
+    fn import_csv() {
+        let file = FilePath::new("test-import-data");
+        if FilePath::is_file(&file) {
+            fs::remove_file(file).unwrap();
+        }
+        let input = "timestamp,path,value\n\
+                     2023-01-01T00:00:00Z,/foo/bar,u32:1\n\
+                     2023-01-01T00:00:00Z,/foo/baz,\"hello, world\"\n\
+                     # a comment\n\
+                     2023-01-01T00:00:01Z,/foo/bar,u32:2\n";
+        let stats = {
+            let mut dest = ArchiveWriter::open(&file).unwrap();
+            import(input.as_bytes(), Format::Csv, Some(0), &mut dest, None).unwrap()
+        };
+        assert_eq!(stats.rows, 3);
+        assert_eq!(stats.paths, 2);
+        assert_eq!(stats.delta_batches, 2);
+        assert_eq!(stats.image_batches, 2);
+        let reader = ArchiveReader::open(&file).unwrap();
+        let bar = *reader.index().id_for_path(&Path::from("/foo/bar")).unwrap();
+        let mut cursor = Cursor::new();
+        let (_, batches) = reader.read_deltas(None, &mut cursor, 10).unwrap();
+        assert_eq!(batches.len(), 2);
+        assert_eq!(
+            batches[0].0,
+            "2023-01-01T00:00:00Z".parse::<DateTime<Utc>>().unwrap()
+        );
+        assert_eq!(batches[0].1.len(), 2);
+        assert_eq!(batches[1].1.len(), 1);
+        let BatchItem(id, ev) = &batches[1].1[0];
+        assert_eq!(*id, bar);
+        assert_eq!(ev, &Event::Update(Value::U32(2)));
+        drop(reader);
+        fs::remove_file(file).unwrap();
+    }
+
+    #[test]
+    fn csv_paths() {
+        let row = |rest: &str| {
+            let line = format!("2023-01-01T00:00:00Z,{}", rest);
+            parse_row(Format::Csv, &line).map(|(_, p, v)| (p, v))
+        };
+        let ok = |rest, path: &str, v: Value| {
+            assert_eq!(row(rest).unwrap(), (Path::from(String::from(path)), v))
+        };
+        ok(" /foo/bar , u32:1", "/foo/bar", Value::U32(1));
+        ok("\"/foo,bar\",u32:1", "/foo,bar", Value::U32(1));
+        ok("\"/a \"\"b\"\"\",\"c,d\"", "/a \"b\"", Value::from("c,d"));
+        ok("\"/\"\"\" , null", "/\"", Value::Null);
+        for rest in ["", "/foo", "\"/foo,u32:1", "\"/foo\"bar,u32:1"] {
+            assert!(row(rest).is_err())
+        }
+        assert!(parse_row(Format::Csv, "yesterday,/foo,u32:1").is_err())
+    }
+}
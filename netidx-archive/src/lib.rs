@@ -5,6 +5,8 @@ extern crate lazy_static;
 #[macro_use]
 extern crate anyhow;
 
+pub mod import;
 pub mod logfile;
 pub mod recorder;
 pub mod recorder_client;
+pub mod reshard;
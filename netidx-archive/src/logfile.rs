@@ -22,7 +22,7 @@ use parking_lot::{
 use std::{
     self,
     cell::RefCell,
-    cmp::max,
+    cmp::{max, min},
     collections::{BTreeMap, HashMap, HashSet, VecDeque},
     error, fmt,
     fs::{File, OpenOptions},
@@ -49,7 +49,25 @@ pub struct FileHeader {
 
 static FILE_MAGIC: &'static [u8] = b"netidx archive";
 static COMMITTED_OFFSET: usize = FILE_MAGIC.len() + mem::size_of::<u32>();
-const FILE_VERSION: u32 = 0;
+// version 0 records have no checksum
+// version 1 records end with a crc32 of the record header and body
+const FILE_VERSION: u32 = 1;
+const CHECKSUM_LEN: usize = 4;
+
+impl FileHeader {
+    /// true if every record in the file ends with a checksum
+    pub fn checksummed(&self) -> bool {
+        self.version >= 1
+    }
+
+    fn checksum_len(&self) -> usize {
+        if self.checksummed() {
+            CHECKSUM_LEN
+        } else {
+            0
+        }
+    }
+}
 
 impl Pack for FileHeader {
     fn const_encoded_len() -> Option<usize> {
@@ -65,7 +83,7 @@ impl Pack for FileHeader {
         buf.put_u32(
             ((self.compressed as u32) << 31)
                 | ((self.indexed as u32) << 30)
-                | FILE_VERSION,
+                | self.version,
         );
         buf.put_u64(self.committed);
         Ok(())
@@ -565,6 +583,17 @@ impl fmt::Display for RecordTooLarge {
 
 impl error::Error for RecordTooLarge {}
 
+// check the checksum of the record at pos with header rh
+fn checksum_ok(buf: &[u8], pos: usize, rh: &RecordHeader) -> bool {
+    let len =
+        <RecordHeader as Pack>::const_encoded_len().unwrap() + rh.record_length as usize;
+    if (rh.record_length as usize) < CHECKSUM_LEN || pos + len > buf.len() {
+        return false;
+    }
+    let csum_pos = pos + len - CHECKSUM_LEN;
+    crc32fast::hash(&buf[pos..csum_pos]) == (&buf[csum_pos..]).get_u32()
+}
+
 fn scan_records(
     path_by_id: &mut IndexMap<Id, Path, FxBuildHasher>,
     id_by_path: &mut HashMap<Path, Id>,
@@ -572,6 +601,7 @@ fn scan_records(
     mut deltamap: Option<&mut BTreeMap<DateTime<Utc>, usize>>,
     time_basis: &mut DateTime<Utc>,
     max_id: &mut u32,
+    checksum_len: usize,
     end: usize,
     start_pos: usize,
     buf: &mut impl Buf,
@@ -604,6 +634,7 @@ fn scan_records(
             }
             RecordTyp::Timestamp => {
                 *time_basis = <DateTime<Utc> as Pack>::decode(buf)?;
+                buf.advance(checksum_len);
             }
             RecordTyp::ImageBatch => {
                 if let Some(imagemap) = &mut imagemap {
@@ -629,6 +660,7 @@ fn scan_records(
                     }
                     *max_id = max(pm.1 .0, *max_id);
                 }
+                buf.advance(checksum_len);
             }
         }
     }
@@ -642,9 +674,9 @@ fn scan_header(buf: &mut impl Buf) -> Result<FileHeader> {
     let header = <FileHeader as Pack>::decode(buf)
         .map_err(Error::from)
         .context("read file header")?;
-    // this is the first version, so no upgrading can be done yet
-    if header.version != FILE_VERSION {
-        bail!("file version mismatch, expected {} got {}", header.version, FILE_VERSION)
+    // versions before FILE_VERSION can still be read and appended to
+    if header.version > FILE_VERSION {
+        bail!("unsupported file version {}, expected <= {}", header.version, FILE_VERSION)
     }
     Ok(header)
 }
@@ -659,6 +691,7 @@ pub fn read_file_header(path: impl AsRef<FilePath>) -> Result<FileHeader> {
 
 fn scan_file(
     indexed: &mut bool,
+    checksummed: &mut bool,
     compressed: &mut Option<CompressionHeader>,
     path_by_id: &mut IndexMap<Id, Path, FxBuildHasher>,
     id_by_path: &mut HashMap<Path, Id>,
@@ -675,6 +708,7 @@ fn scan_file(
             Some(CompressionHeader::decode(buf).context("read compression header")?);
     }
     *indexed = header.indexed;
+    *checksummed = header.checksummed();
     scan_records(
         path_by_id,
         id_by_path,
@@ -682,6 +716,7 @@ fn scan_file(
         deltamap,
         time_basis,
         max_id,
+        header.checksum_len(),
         header.committed as usize,
         total_bytes - buf.remaining(),
         buf,
@@ -697,8 +732,11 @@ fn scan_file(
 ///
 /// Files begin with a file header, which consists of the string
 /// "netidx archive" followed by the file format
-/// version. Currently there is 1 version, and the version number
-/// is 0.
+/// version. Currently there are 2 versions. Version 0 records have no
+/// checksum, version 1 records end with a crc32 of the record header
+/// and data item, which is included in the record length. New files
+/// are always written as version 1, existing version 0 files can
+/// still be read and appended to.
 ///
 /// Following the header are a series of records. Every record begins
 /// with a (RecordHeader)[RecordHeader], which is followed by a data
@@ -770,6 +808,7 @@ pub struct ArchiveWriter {
     block_size: usize,
     mmap: MmapMut,
     indexed: bool,
+    checksummed: bool,
     index: FxHashSet<Id>,
     index_vec: Vec<Id>,
 }
@@ -811,12 +850,14 @@ impl ArchiveWriter {
                 block_size,
                 mmap,
                 indexed: false,
+                checksummed: false,
                 index: HashSet::default(),
                 index_vec: Vec::new(),
             };
             let mut compress = None;
             let end = scan_file(
                 &mut t.indexed,
+                &mut t.checksummed,
                 &mut compress,
                 &mut t.path_by_id,
                 &mut t.id_by_path,
@@ -871,6 +912,7 @@ impl ArchiveWriter {
                 block_size,
                 mmap,
                 indexed: true,
+                checksummed: fh.checksummed(),
                 index: HashSet::default(),
                 index_vec: Vec::new(),
             })
@@ -918,6 +960,19 @@ impl ArchiveWriter {
         Ok(())
     }
 
+    /// like `flush`, but also wait for the updated end of archive
+    /// marker to reach the disk, so that every record written so far
+    /// will survive a crash. This is considerably more expensive than
+    /// `flush`.
+    pub fn sync(&mut self) -> Result<()> {
+        let end = self.end.load(Ordering::Relaxed);
+        if self.committed < end {
+            self.flush()?;
+            self.mmap.flush_range(0, COMMITTED_OFFSET + mem::size_of::<u64>())?;
+        }
+        Ok(())
+    }
+
     /// allocate path ids for any of the specified paths that don't
     /// already have one, and write a path mappings record containing
     /// the new assignments.
@@ -938,21 +993,74 @@ impl ArchiveWriter {
         self.add_raw_pathmappings(pms)
     }
 
+    /// Add path mappings using the specified ids rather than
+    /// allocating new ones. This is used when copying data from
+    /// another archive while preserving it's path ids. Paths that are
+    /// already mapped are skipped. It is an error if an id is already
+    /// mapped to a different path.
+    pub fn add_paths_with_ids<'a>(
+        &mut self,
+        paths: impl IntoIterator<Item = (&'a Id, &'a Path)>,
+    ) -> Result<()> {
+        let mut pms = PM_POOL.take();
+        for (id, path) in paths {
+            if !self.id_by_path.contains_key(path) {
+                if let Some(old) = self.path_by_id.get(id) {
+                    bail!("id {:?} is already mapped to {}", id, old)
+                }
+                self.next_id = max(self.next_id, id.0 + 1);
+                self.id_by_path.insert(path.clone(), *id);
+                self.path_by_id.insert(*id, path.clone());
+                pms.push(PathMapping(path.clone(), *id));
+            }
+        }
+        self.add_raw_pathmappings(pms)
+    }
+
     fn add_raw_pathmappings(&mut self, pms: Pooled<Vec<PathMapping>>) -> Result<()> {
         if pms.len() > 0 {
-            let record_length = <Pooled<Vec<PathMapping>> as Pack>::encoded_len(&pms);
-            let len = self.check_reserve(record_length)?;
-            let end = self.end.load(Ordering::Relaxed);
-            let mut buf = &mut self.mmap[end..];
-            let rh = RecordHeader {
-                record_type: RecordTyp::PathMappings,
-                record_length: record_length as u32,
-                timestamp: 0,
-            };
+            let body_length = <Pooled<Vec<PathMapping>> as Pack>::encoded_len(&pms);
+            self.write_record(RecordTyp::PathMappings, 0, body_length, |buf| {
+                Ok(<Pooled<Vec<PathMapping>> as Pack>::encode(&pms, buf)?)
+            })?;
+        }
+        Ok(())
+    }
+
+    fn checksum_len(&self) -> usize {
+        if self.checksummed {
+            CHECKSUM_LEN
+        } else {
+            0
+        }
+    }
+
+    // write a record with a body of body_length bytes to the end of
+    // the file, f must write exactly body_length bytes. If the file is
+    // checksummed the checksum will be appended to the body.
+    fn write_record<F: FnOnce(&mut &mut [u8]) -> Result<()>>(
+        &mut self,
+        record_type: RecordTyp,
+        timestamp: u32,
+        body_length: usize,
+        f: F,
+    ) -> Result<()> {
+        let record_length = body_length + self.checksum_len();
+        let len = self.check_reserve(record_length)?;
+        let start = self.end.load(Ordering::Relaxed);
+        let rh =
+            RecordHeader { record_type, record_length: record_length as u32, timestamp };
+        {
+            let mut buf = &mut self.mmap[start..];
             <RecordHeader as Pack>::encode(&rh, &mut buf)?;
-            <Pooled<Vec<PathMapping>> as Pack>::encode(&pms, &mut buf)?;
-            self.end.fetch_add(len, Ordering::AcqRel);
+            f(&mut buf)?;
+        }
+        if self.checksummed {
+            let csum_pos = start + len - CHECKSUM_LEN;
+            let csum = crc32fast::hash(&self.mmap[start..csum_pos]);
+            (&mut self.mmap[csum_pos..]).put_u32(csum);
         }
+        self.end.fetch_add(len, Ordering::AcqRel);
         Ok(())
     }
 
@@ -963,40 +1071,21 @@ impl ArchiveWriter {
         record_length: usize,
         f: F,
     ) -> Result<()> {
-        if record_length > MAX_RECORD_LEN as usize {
+        if record_length + self.checksum_len() > MAX_RECORD_LEN as usize {
             bail!(RecordTooLarge)
         }
         match timestamp {
             Timestamp::Offset(_, _) => (),
             Timestamp::NewBasis(basis) => {
-                let record_length = <DateTime<Utc> as Pack>::encoded_len(&basis);
-                let rh = RecordHeader {
-                    record_type: RecordTyp::Timestamp,
-                    record_length: record_length as u32,
-                    timestamp: 0,
-                };
-                let len = self.check_reserve(record_length)?;
-                let mut buf = &mut self.mmap[self.end.load(Ordering::Relaxed)..];
-                <RecordHeader as Pack>::encode(&rh, &mut buf)?;
-                <DateTime<Utc> as Pack>::encode(&basis, &mut buf)?;
-                self.end.fetch_add(len, Ordering::AcqRel);
+                let body_length = <DateTime<Utc> as Pack>::encoded_len(&basis);
+                self.write_record(RecordTyp::Timestamp, 0, body_length, |buf| {
+                    Ok(<DateTime<Utc> as Pack>::encode(&basis, buf)?)
+                })?;
             }
         }
-        let len = self.check_reserve(record_length)?;
-        let mut buf = &mut self.mmap[self.end.load(Ordering::Relaxed)..];
-        let rh = RecordHeader {
-            record_type: if image {
-                RecordTyp::ImageBatch
-            } else {
-                RecordTyp::DeltaBatch
-            },
-            record_length: record_length as u32,
-            timestamp: timestamp.offset(),
-        };
-        <RecordHeader as Pack>::encode(&rh, &mut buf)?;
-        f(&mut buf)?;
-        self.end.fetch_add(len, Ordering::AcqRel);
-        Ok(())
+        let record_type =
+            if image { RecordTyp::ImageBatch } else { RecordTyp::DeltaBatch };
+        self.write_record(record_type, timestamp.offset(), record_length, f)
     }
 
     /// Add a data batch to the archive. If `image` is true then it
@@ -1097,6 +1186,7 @@ impl ArchiveWriter {
             index: Arc::new(RwLock::new(ArchiveIndex::new())),
             compressed: None,
             indexed: self.indexed,
+            checksummed: self.checksummed,
             file: self.file.clone(),
             end: self.end.clone(),
             mmap: Arc::new(RwLock::new(unsafe { Mmap::map(&*self.file)? })),
@@ -1225,6 +1315,7 @@ pub struct ArchiveReader {
     file: Arc<File>,
     end: Arc<AtomicUsize>,
     indexed: bool,
+    checksummed: bool,
     mmap: Arc<RwLock<Mmap>>,
 }
 
@@ -1249,8 +1340,10 @@ impl ArchiveReader {
         let mut max_id = 0;
         let mut compressed = None;
         let mut indexed = false;
+        let mut checksummed = false;
         let end = scan_file(
             &mut indexed,
+            &mut checksummed,
             &mut compressed,
             &mut index.path_by_id,
             &mut index.id_by_path,
@@ -1272,6 +1365,7 @@ impl ArchiveReader {
         Ok(ArchiveReader {
             index: Arc::new(RwLock::new(index)),
             indexed,
+            checksummed,
             compressed,
             file: Arc::new(file),
             end: Arc::new(AtomicUsize::new(end)),
@@ -1287,6 +1381,18 @@ impl ArchiveReader {
         self.indexed
     }
 
+    pub fn is_checksummed(&self) -> bool {
+        self.checksummed
+    }
+
+    fn checksum_len(&self) -> usize {
+        if self.checksummed {
+            CHECKSUM_LEN
+        } else {
+            0
+        }
+    }
+
     pub(crate) fn strong_count(&self) -> usize {
         Arc::strong_count(&self.index)
     }
@@ -1334,6 +1440,7 @@ impl ArchiveReader {
                 Some(&mut r.deltamap),
                 &mut r.time_basis,
                 &mut max_id,
+                self.checksum_len(),
                 end,
                 r.end,
                 &mut &mmap[r.end..end],
@@ -1433,8 +1540,9 @@ impl ArchiveReader {
 
     fn get_batch_at(
         indexed: bool,
+        checksummed: bool,
         compressed: &Option<Arc<Mutex<Decompressor>>>,
-        mmap: &Mmap,
+        mmap: &[u8],
         pos: usize,
         end: usize,
     ) -> Result<(usize, Pooled<Vec<BatchItem>>)> {
@@ -1450,6 +1558,14 @@ impl ArchiveReader {
         if pos + rh.record_length as usize > end {
             bail!("get_batch: error truncated record at {}", pos);
         }
+        let checksum_len = if checksummed {
+            if !checksum_ok(mmap, pos, &rh) {
+                bail!("get_batch: checksum mismatch in record at {}", pos)
+            }
+            CHECKSUM_LEN
+        } else {
+            0
+        };
         let pos = pos + <RecordHeader as Pack>::const_encoded_len().unwrap();
         match compressed {
             None => {
@@ -1475,7 +1591,8 @@ impl ArchiveReader {
                     if compression_buf.len() < uncomp_len {
                         compression_buf.resize(uncomp_len, 0u8);
                     }
-                    let comp_len = rh.record_length as usize - 4 - index_len;
+                    let comp_len =
+                        rh.record_length as usize - 4 - index_len - checksum_len;
                     let len = dcm
                         .decompress_to_buffer(
                             &mmap[pos..pos + comp_len],
@@ -1521,6 +1638,7 @@ impl ArchiveReader {
                         Some((ts, pos)) => {
                             let (_, mut batch) = ArchiveReader::get_batch_at(
                                 self.indexed,
+                                self.checksummed,
                                 &self.compressed,
                                 &*mmap,
                                 *pos,
@@ -1548,6 +1666,7 @@ impl ArchiveReader {
                 for (_, pos) in matched {
                     let (_, mut batch) = ArchiveReader::get_batch_at(
                         self.indexed,
+                        self.checksummed,
                         &self.compressed,
                         &*mmap,
                         pos as usize,
@@ -1623,6 +1742,7 @@ impl ArchiveReader {
         for (ts, pos) in matched {
             let (len, batch) = ArchiveReader::get_batch_at(
                 self.indexed,
+                self.checksummed,
                 &self.compressed,
                 &*mmap,
                 pos as usize,
@@ -1664,6 +1784,7 @@ impl ArchiveReader {
             Some((ts, pos)) => {
                 let (_, batch) = ArchiveReader::get_batch_at(
                     self.indexed,
+                    self.checksummed,
                     &self.compressed,
                     &*mmap,
                     pos as usize,
@@ -1675,6 +1796,51 @@ impl ArchiveReader {
         }
     }
 
+    /// read at most `n` batches, both images and deltas, in timestamp
+    /// order from the specified cursor, and advance it by the number of
+    /// batches read. The returned flag is true for image batches.
+    pub fn read_batches(
+        &self,
+        cursor: &mut Cursor,
+        n: usize,
+    ) -> Result<Vec<(DateTime<Utc>, bool, Pooled<Vec<BatchItem>>)>> {
+        self.check_remap_rescan()?;
+        let start = match cursor.current {
+            None => cursor.start,
+            Some(dt) => Bound::Excluded(dt),
+        };
+        let mmap = self.mmap.read();
+        let index = self.index.read();
+        let mut deltas = index.deltamap.range((start, cursor.end)).peekable();
+        let mut images = index.imagemap.range((start, cursor.end)).peekable();
+        let mut res = Vec::new();
+        while res.len() < n {
+            let (image, (ts, pos)) = match (deltas.peek(), images.peek()) {
+                (None, None) => break,
+                (Some(_), None) => (false, deltas.next().unwrap()),
+                (None, Some(_)) => (true, images.next().unwrap()),
+                (Some((dts, _)), Some((its, _))) => {
+                    if its <= dts {
+                        (true, images.next().unwrap())
+                    } else {
+                        (false, deltas.next().unwrap())
+                    }
+                }
+            };
+            let (_, batch) = ArchiveReader::get_batch_at(
+                self.indexed,
+                self.checksummed,
+                &self.compressed,
+                &*mmap,
+                *pos,
+                index.end,
+            )?;
+            cursor.current = Some(*ts);
+            res.push((*ts, image, batch));
+        }
+        Ok(res)
+    }
+
     fn train(&self) -> Result<(usize, Vec<u8>)> {
         let mmap = self.mmap.read();
         let mut lengths: Vec<usize> = Vec::new();
@@ -1722,8 +1888,14 @@ impl ArchiveReader {
         output.add_raw_pathmappings(pms)?;
         let mmap = self.mmap.read();
         for (ts, (image, pos)) in unified_index.iter() {
-            let (_, batch) =
-                Self::get_batch_at(false, &self.compressed, &*mmap, *pos, index.end)?;
+            let (_, batch) = Self::get_batch_at(
+                false,
+                self.checksummed,
+                &self.compressed,
+                &*mmap,
+                *pos,
+                index.end,
+            )?;
             output.add_batch(*image, *ts, &batch)?;
         }
         Ok(())
@@ -1751,6 +1923,7 @@ impl ArchiveReader {
         }
         async fn compress_task(
             indexed: bool,
+            checksum_len: usize,
             mmap: Arc<RwLock<Mmap>>,
             mut job: CompJob,
         ) -> Result<CompJob> {
@@ -1759,8 +1932,9 @@ impl ArchiveReader {
                 let pos = job.pos;
                 let rh = RecordHeader::decode(&mut &mmap[pos..])?;
                 let pos = pos + RecordHeader::const_encoded_len().unwrap();
-                let end = pos + rh.record_length as usize;
-                (&mut job.cbuf[0..4]).put_u32(rh.record_length);
+                let record_length = rh.record_length - checksum_len as u32;
+                let end = pos + record_length as usize;
+                (&mut job.cbuf[0..4]).put_u32(record_length);
                 let index_len = if indexed {
                     let index_len = decode_varint(&mut &mmap[pos..])? as usize;
                     (&mut job.cbuf[4..4 + index_len])
@@ -1844,6 +2018,7 @@ impl ArchiveReader {
                     commitq.insert(*ts, None);
                     running_jobs.spawn(compress_task(
                         self.indexed,
+                        self.checksum_len(),
                         Arc::clone(&self.mmap),
                         job,
                     ));
@@ -1855,6 +2030,321 @@ impl ArchiveReader {
     }
 }
 
+/// The result of checking, or repairing, the records in an archive
+/// file.
+#[derive(Debug, Clone, Default)]
+pub struct IntegrityReport {
+    /// true if the file has per record checksums
+    pub checksummed: bool,
+    /// the number of intact records
+    pub records: usize,
+    /// the position of the start of each corrupted region
+    pub corrupted: Vec<usize>,
+    /// the total size of all the corrupted regions
+    pub lost_bytes: usize,
+    /// the position of the corrupted region where a timestamp record
+    /// was lost. The time of every batch after it is unknown, so
+    /// salvaging stops there.
+    pub unrecoverable: Option<usize>,
+    /// ids that have values in an intact batch, but no intact path
+    /// mapping in the file. Their values can't be read unless the
+    /// mappings are kept elsewhere, e.g. in a recorder shard's
+    /// pathindex, otherwise their path mappings were lost.
+    pub unmapped: Vec<Id>,
+}
+
+impl IntegrityReport {
+    pub fn is_ok(&self) -> bool {
+        self.corrupted.is_empty()
+    }
+}
+
+enum Salvaged {
+    PathMappings(Pooled<Vec<PathMapping>>),
+    Batch(bool, DateTime<Utc>, Pooled<Vec<BatchItem>>),
+}
+
+// Walk every committed record in the file, calling f for each intact
+// path mapping and batch record. When a corrupted record is found in a
+// checksummed file, scan forward until the next record with a valid
+// checksum. Without checksums there is no reliable way to find the
+// next record, so stop at the first corrupted record. Batch timestamps
+// are offsets from the last timestamp record, so salvaging also stops
+// when a timestamp record is lost.
+fn salvage_records<F: FnMut(Salvaged) -> Result<()>>(
+    path: &FilePath,
+    mut f: F,
+) -> Result<IntegrityReport> {
+    let file = OpenOptions::new().read(true).open(path).context("open file")?;
+    file.try_lock_shared()?;
+    let mmap = unsafe { Mmap::map(&file).context("mmap file")? };
+    let mut buf = &mmap[..];
+    let header = scan_header(&mut buf).context("scan header")?;
+    let compressed = if header.compressed {
+        let ch =
+            CompressionHeader::decode(&mut buf).context("read compression header")?;
+        let dc = Decompressor::with_dictionary(&ch.dictionary)
+            .context("create decompressor")?;
+        Some(Arc::new(Mutex::new(dc)))
+    } else {
+        None
+    };
+    let checksummed = header.checksummed();
+    let rhl = <RecordHeader as Pack>::const_encoded_len().unwrap();
+    let end = min(header.committed as usize, mmap.len());
+    let mut pos = mmap.len() - buf.remaining();
+    let mut time_basis = DateTime::<Utc>::MIN_UTC;
+    let mut last_offset: Option<u32> = None;
+    let mut mapped: FxHashSet<Id> = HashSet::default();
+    let mut unmapped: FxHashSet<Id> = HashSet::default();
+    let mut corrupted: Option<usize> = None;
+    let mut report = IntegrityReport { checksummed, ..IntegrityReport::default() };
+    while pos < end {
+        let mut read_record = || -> Result<(RecordHeader, usize, Option<Salvaged>)> {
+            if pos + rhl > end {
+                bail!("truncated record header")
+            }
+            let rh = RecordHeader::decode(&mut &mmap[pos..])?;
+            let len = rhl + rh.record_length as usize;
+            if pos + len > end {
+                bail!("truncated record")
+            }
+            if checksummed && !checksum_ok(&mmap[..end], pos, &rh) {
+                bail!("checksum mismatch")
+            }
+            let body = &mmap[pos + rhl..pos + len];
+            let rec = match rh.record_type {
+                RecordTyp::Timestamp => {
+                    time_basis = <DateTime<Utc> as Pack>::decode(&mut &*body)?;
+                    None
+                }
+                RecordTyp::PathMappings => {
+                    Some(Salvaged::PathMappings(
+                        <Pooled<Vec<PathMapping>> as Pack>::decode(&mut &*body)?,
+                    ))
+                }
+                typ @ (RecordTyp::DeltaBatch | RecordTyp::ImageBatch) => {
+                    let ts =
+                        time_basis + chrono::Duration::microseconds(rh.timestamp as i64);
+                    let (_, batch) = ArchiveReader::get_batch_at(
+                        header.indexed,
+                        checksummed,
+                        &compressed,
+                        &mmap[..end],
+                        pos,
+                        end,
+                    )?;
+                    let image = matches!(typ, RecordTyp::ImageBatch);
+                    Some(Salvaged::Batch(image, ts, batch))
+                }
+            };
+            Ok((rh, len, rec))
+        };
+        match read_record() {
+            Ok((rh, len, rec)) => {
+                match rh.record_type {
+                    RecordTyp::Timestamp => last_offset = None,
+                    RecordTyp::PathMappings => (),
+                    RecordTyp::DeltaBatch | RecordTyp::ImageBatch => {
+                        // offsets strictly increase until the next timestamp
+                        // record, which resets them, so if one goes backwards
+                        // a timestamp record was lost
+                        if last_offset.map(|o| rh.timestamp <= o).unwrap_or(false) {
+                            let start = *corrupted.get_or_insert_with(|| {
+                                report.corrupted.push(pos);
+                                pos
+                            });
+                            warn!("timestamp record lost at {}", start);
+                            report.unrecoverable = Some(start);
+                            break;
+                        }
+                        last_offset = Some(rh.timestamp);
+                    }
+                }
+                if let Some(start) = corrupted.take() {
+                    report.lost_bytes += pos - start;
+                }
+                report.records += 1;
+                match &rec {
+                    None => (),
+                    Some(Salvaged::PathMappings(pms)) => {
+                        for PathMapping(_, id) in pms.iter() {
+                            mapped.insert(*id);
+                            unmapped.remove(id);
+                        }
+                    }
+                    Some(Salvaged::Batch(_, _, batch)) => {
+                        for BatchItem(id, _) in batch.iter() {
+                            if !mapped.contains(id) {
+                                unmapped.insert(*id);
+                            }
+                        }
+                    }
+                }
+                if let Some(rec) = rec {
+                    f(rec)?
+                }
+                pos += len;
+            }
+            Err(e) => {
+                if corrupted.is_none() {
+                    warn!("corrupted record at {}, {:?}", pos, e);
+                    report.corrupted.push(pos);
+                    corrupted = Some(pos);
+                    // a corrupted record that starts at a record boundary
+                    // with an intact header can be identified
+                    let is_timestamp = pos + rhl <= end
+                        && RecordHeader::decode(&mut &mmap[pos..])
+                            .map(|rh| matches!(rh.record_type, RecordTyp::Timestamp))
+                            .unwrap_or(false);
+                    if is_timestamp {
+                        report.unrecoverable = Some(pos);
+                        break;
+                    }
+                }
+                if !checksummed {
+                    break;
+                }
+                pos += 1;
+            }
+        }
+    }
+    if let Some(start) = corrupted {
+        report.lost_bytes += end - start;
+    }
+    report.unmapped = unmapped.into_iter().collect();
+    report.unmapped.sort();
+    Ok(report)
+}
+
+/// Check the integrity of every committed record in the specified
+/// archive file. Version 0 files have no checksums, so for them this
+/// can only check that every record can be decoded, and checking will
+/// stop at the first record that can't be.
+pub fn verify_file(path: impl AsRef<FilePath>) -> Result<IntegrityReport> {
+    salvage_records(path.as_ref(), |_| Ok(()))
+}
+
+/// Write a copy of the archive file `src` to `dest` containing every
+/// intact record in `src`, skipping past corrupted records if `src`
+/// is checksummed, or stopping at the first corrupted record if it
+/// isn't. `dest` must not already exist. The copy is uncompressed,
+/// indexed, and checksummed, and path ids are preserved, so a repaired
+/// recorder shard file remains consistent with the shard's pathindex.
+pub fn repair_file(
+    src: impl AsRef<FilePath>,
+    dest: impl AsRef<FilePath>,
+) -> Result<IntegrityReport> {
+    if dest.as_ref().exists() {
+        bail!("{} already exists", dest.as_ref().display())
+    }
+    let mut output = ArchiveWriter::open_full(dest, true, None)?;
+    let report = salvage_records(src.as_ref(), |rec| match rec {
+        Salvaged::PathMappings(pms) => output.add_raw_pathmappings(pms),
+        Salvaged::Batch(image, ts, batch) => output.add_batch(image, ts, &batch),
+    })?;
+    output.flush()?;
+    Ok(report)
+}
+
+/// The result of recovering an archive file after a crash.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RecoveryReport {
+    /// the number of intact records that were kept
+    pub records: usize,
+    /// the end of the recovered data, the file is truncated here
+    pub end: usize,
+    /// the committed length recorded in the file header before recovery
+    pub committed: usize,
+    /// the number of bytes of written data, committed or not, that were
+    /// discarded because they were incomplete or corrupted
+    pub lost_bytes: usize,
+}
+
+/// Recover an archive file that may not have been closed cleanly,
+/// e.g. because the process writing it crashed. Every committed record
+/// is checked, and the file is truncated after the last intact record
+/// before the first one that is incomplete or fails it's checksum
+/// (version 0 files can only be checked structurally). Any data that
+/// was written after the committed end, but never committed, is also
+/// discarded. After recovery the file can be opened by both
+/// `ArchiveReader` and `ArchiveWriter`.
+///
+/// This requires exclusive access to the file, so it must not be
+/// called on a file that is open for writing. Compressed files are
+/// never written incrementally, and so can't be recovered.
+pub fn recover_file(path: impl AsRef<FilePath>) -> Result<RecoveryReport> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path.as_ref())
+        .context("open file")?;
+    file.try_lock_exclusive()?;
+    let mut mmap = unsafe { MmapMut::map_mut(&file).context("mmap file")? };
+    let mut buf = &mmap[..];
+    let header = scan_header(&mut buf).context("scan header")?;
+    if header.compressed {
+        bail!("compressed files can't be recovered")
+    }
+    let checksum_len = header.checksum_len();
+    let rhl = <RecordHeader as Pack>::const_encoded_len().unwrap();
+    let committed = header.committed as usize;
+    let end = min(committed, mmap.len());
+    let mut pos = mmap.len() - buf.remaining();
+    let mut report = RecoveryReport { committed, ..RecoveryReport::default() };
+    while pos < end {
+        let check_record = || -> Result<usize> {
+            if pos + rhl > end {
+                bail!("truncated record header")
+            }
+            let rh = RecordHeader::decode(&mut &mmap[pos..])?;
+            let len = rhl + rh.record_length as usize;
+            if pos + len > end {
+                bail!("truncated record")
+            }
+            if checksum_len > 0 && !checksum_ok(&mmap[..end], pos, &rh) {
+                bail!("checksum mismatch")
+            }
+            let mut body = &mmap[pos + rhl..pos + len - checksum_len];
+            match rh.record_type {
+                RecordTyp::Timestamp => {
+                    <DateTime<Utc> as Pack>::decode(&mut body)?;
+                }
+                RecordTyp::PathMappings => {
+                    <Pooled<Vec<PathMapping>> as Pack>::decode(&mut body)?;
+                }
+                RecordTyp::DeltaBatch | RecordTyp::ImageBatch => (),
+            }
+            Ok(len)
+        };
+        match check_record() {
+            Ok(len) => {
+                report.records += 1;
+                pos += len;
+            }
+            Err(e) => {
+                warn!("incomplete or corrupted record at {}, {:?}", pos, e);
+                break;
+            }
+        }
+    }
+    report.end = pos;
+    // the file is preallocated with zeros, so anything non zero past
+    // the end of the intact records was written by someone
+    let written = mmap[pos..].iter().rposition(|b| *b != 0).map(|i| pos + i + 1);
+    if let Some(written) = written {
+        report.lost_bytes = written - pos;
+        mmap[pos..written].fill(0);
+    }
+    if report.lost_bytes > 0 || committed != pos {
+        mmap.flush().context("flushing recovered data")?;
+        (&mut mmap[COMMITTED_OFFSET..]).put_u64(pos as u64);
+        mmap.flush_range(0, COMMITTED_OFFSET + mem::size_of::<u64>())
+            .context("flushing recovered header")?;
+    }
+    Ok(report)
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -1945,4 +2435,205 @@ mod test {
             fs::remove_file(file).unwrap();
         }
     }
+
+    #[test]
+    fn repair_test() {
+        let file = FilePath::new("test-data-repair");
+        let repaired = FilePath::new("test-data-repair.rp");
+        for f in [file, repaired] {
+            if FilePath::is_file(f) {
+                fs::remove_file(f).unwrap();
+            }
+        }
+        let paths = [Path::from("/foo/bar"), Path::from("/foo/baz")];
+        {
+            let mut t = ArchiveWriter::open(&file).unwrap();
+            t.add_paths(&paths).unwrap();
+            let mut batch = BATCH_POOL.take();
+            batch.extend(paths.iter().map(|p| {
+                BatchItem(t.id_for_path(p).unwrap(), Event::Update(Value::U64(42)))
+            }));
+            for _ in 0..3 {
+                t.add_batch(false, Utc::now(), &batch).unwrap();
+            }
+            t.flush().unwrap();
+        }
+        assert!(verify_file(&file).unwrap().is_ok());
+        // flip some bits in the middle batch
+        let pos = {
+            let t = ArchiveReader::open(&file).unwrap();
+            let pos = *t.index().deltamap().values().nth(1).unwrap();
+            pos
+        };
+        let mut data = fs::read(&file).unwrap();
+        data[pos + 10] ^= 0xFF;
+        fs::write(&file, &data).unwrap();
+        let report = verify_file(&file).unwrap();
+        assert_eq!(report.corrupted, vec![pos]);
+        let report = repair_file(&file, &repaired).unwrap();
+        assert_eq!(report.corrupted, vec![pos]);
+        check_contents(&ArchiveReader::open(&repaired).unwrap(), &paths, 2);
+        for f in [file, repaired] {
+            if FilePath::is_file(f) {
+                fs::remove_file(f).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn recover_test() {
+        let file = FilePath::new("test-data-recover");
+        if FilePath::is_file(&file) {
+            fs::remove_file(file).unwrap();
+        }
+        let paths = [Path::from("/foo/bar"), Path::from("/foo/baz")];
+        let mut batch = BATCH_POOL.take();
+        let end = {
+            let mut t = ArchiveWriter::open(&file).unwrap();
+            t.add_paths(&paths).unwrap();
+            batch.extend(paths.iter().map(|p| {
+                BatchItem(t.id_for_path(p).unwrap(), Event::Update(Value::U64(42)))
+            }));
+            for _ in 0..3 {
+                t.add_batch(false, Utc::now(), &batch).unwrap();
+            }
+            t.sync().unwrap();
+            t.len()
+        };
+        let pos = {
+            let t = ArchiveReader::open(&file).unwrap();
+            let pos = *t.index().deltamap().values().nth(2).unwrap();
+            pos
+        };
+        // simulate a crash part way through writing the last batch,
+        // and some uncommitted data written after it
+        let mut data = fs::read(&file).unwrap();
+        data[pos + 10..end].fill(0);
+        data[end + 5] = 0xAB;
+        fs::write(&file, &data).unwrap();
+        let report = recover_file(&file).unwrap();
+        assert_eq!(report.committed, end);
+        assert_eq!(report.end, pos);
+        assert_eq!(report.lost_bytes, end + 6 - pos);
+        check_contents(&ArchiveReader::open(&file).unwrap(), &paths, 2);
+        {
+            let mut t = ArchiveWriter::open(&file).unwrap();
+            t.add_batch(false, Utc::now(), &batch).unwrap();
+            t.flush().unwrap();
+        }
+        check_contents(&ArchiveReader::open(&file).unwrap(), &paths, 3);
+        assert_eq!(recover_file(&file).unwrap().lost_bytes, 0);
+        fs::remove_file(file).unwrap();
+    }
+
+    fn record_positions(data: &[u8]) -> Vec<(usize, RecordTyp)> {
+        let mut buf = data;
+        let header = scan_header(&mut buf).unwrap();
+        let mut pos = data.len() - buf.remaining();
+        let mut records = vec![];
+        while pos < header.committed as usize {
+            let rh = RecordHeader::decode(&mut &data[pos..]).unwrap();
+            records.push((pos, rh.record_type));
+            pos += <RecordHeader as Pack>::const_encoded_len().unwrap()
+                + rh.record_length as usize;
+        }
+        records
+    }
+
+    #[test]
+    fn salvage_test() {
+        let file = FilePath::new("test-data-salvage");
+        let damaged = FilePath::new("test-data-salvage.dm");
+        let repaired = FilePath::new("test-data-salvage.rp");
+        for f in [file, damaged, repaired] {
+            if FilePath::is_file(f) {
+                fs::remove_file(f).unwrap();
+            }
+        }
+        let (a, b) = (Path::from("/foo/bar"), Path::from("/foo/baz"));
+        let now = Utc::now();
+        let ms = |n| now + chrono::Duration::milliseconds(n);
+        let (ida, idb) = {
+            let mut t = ArchiveWriter::open(&file).unwrap();
+            t.add_paths([&a]).unwrap();
+            let ida = t.id_for_path(&a).unwrap();
+            let mut batch = BATCH_POOL.take();
+            batch.push(BatchItem(ida, Event::Update(Value::U64(42))));
+            t.add_batch(false, ms(0), &batch).unwrap();
+            t.add_batch(false, ms(1), &batch).unwrap();
+            t.add_paths([&b]).unwrap();
+            let idb = t.id_for_path(&b).unwrap();
+            batch.push(BatchItem(idb, Event::Update(Value::U64(42))));
+            t.add_batch(false, ms(2), &batch).unwrap();
+            t.add_batch(true, ms(3), &batch).unwrap();
+            // too far from the last batch for an offset, so this
+            // writes a new timestamp record
+            t.add_batch(false, ms(300_000), &batch).unwrap();
+            t.flush().unwrap();
+            (ida, idb)
+        };
+        let data = fs::read(&file).unwrap();
+        let records = record_positions(&data);
+        let typs = records.iter().map(|(_, typ)| *typ as u8).collect::<Vec<_>>();
+        // pm, ts, delta, delta, pm, delta, image, ts, delta
+        assert_eq!(typs, vec![1, 0, 2, 2, 1, 2, 3, 0, 2]);
+        let end = read_file_header(&file).unwrap().committed as usize;
+        let rhl = <RecordHeader as Pack>::const_encoded_len().unwrap();
+        // corrupt the record at records[i], flipping `mask` in the byte
+        // at `offset`, and repair the result
+        let salvage = |i: usize, offset: usize, mask: u8| {
+            let mut data = data.clone();
+            data[records[i].0 + offset] ^= mask;
+            for f in [damaged, repaired] {
+                if FilePath::is_file(f) {
+                    fs::remove_file(f).unwrap();
+                }
+            }
+            fs::write(&damaged, &data).unwrap();
+            let report = repair_file(&damaged, &repaired).unwrap();
+            assert_eq!(report.corrupted, vec![records[i].0]);
+            (report, ArchiveReader::open(&repaired).unwrap())
+        };
+        // a delta batch is skipped, and nothing else is lost
+        let (report, t) = salvage(3, rhl, 0xFF);
+        assert_eq!(report.records, 8);
+        assert_eq!(report.unrecoverable, None);
+        assert!(report.unmapped.is_empty());
+        assert_eq!((t.delta_batches(), t.image_batches()), (3, 1));
+        assert!(*t.deltamap().keys().last().unwrap() > ms(60_000));
+        // an image batch is skipped, and nothing else is lost
+        let (report, t) = salvage(6, rhl, 0xFF);
+        assert_eq!(report.records, 8);
+        assert_eq!(report.unrecoverable, None);
+        assert!(report.unmapped.is_empty());
+        assert_eq!((t.delta_batches(), t.image_batches()), (4, 0));
+        assert!(*t.deltamap().keys().last().unwrap() > ms(60_000));
+        // the values of /foo/baz are kept, but reported
+        let (report, t) = salvage(4, rhl, 0xFF);
+        assert_eq!(report.records, 8);
+        assert_eq!(report.unrecoverable, None);
+        assert_eq!(report.unmapped, vec![idb]);
+        assert_eq!((t.delta_batches(), t.image_batches()), (4, 1));
+        assert_eq!(t.index().path_for_id(&ida), Some(&a));
+        assert_eq!(t.index().path_for_id(&idb), None);
+        // the time of the last batch is unknown, so it is dropped
+        let (report, t) = salvage(7, rhl, 0xFF);
+        assert_eq!(report.records, 7);
+        assert_eq!(report.unrecoverable, Some(records[7].0));
+        assert_eq!(report.lost_bytes, end - records[7].0);
+        assert_eq!((t.delta_batches(), t.image_batches()), (3, 1));
+        assert!(t.deltamap().keys().all(|ts| *ts < ms(60_000)));
+        // the same, but with the record type damaged too, so the lost
+        // timestamp record is only found from the next batch's offset
+        let (report, t) = salvage(7, 0, 0xC0);
+        assert_eq!(report.records, 7);
+        assert_eq!(report.unrecoverable, Some(records[7].0));
+        assert_eq!(report.lost_bytes, end - records[7].0);
+        assert_eq!((t.delta_batches(), t.image_batches()), (3, 1));
+        for f in [file, damaged, repaired] {
+            if FilePath::is_file(f) {
+                fs::remove_file(f).unwrap();
+            }
+        }
+    }
 }
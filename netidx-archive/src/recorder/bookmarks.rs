@@ -0,0 +1,185 @@
+use anyhow::{Context, Result};
+use chrono::prelude::*;
+use futures::prelude::*;
+use netidx::{chars::Chars, publisher::Value};
+use parking_lot::Mutex;
+use serde_derive::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fs, path::PathBuf, sync::Arc};
+use tokio::task;
+
+/// A named playback position saved by a user.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Bookmark {
+    /// the position in the archive
+    pub pos: DateTime<Utc>,
+    /// the filter of the session, only matching paths are played back
+    pub filter: Vec<Chars>,
+    /// the playback speed, None for unlimited
+    pub speed: Option<f64>,
+    /// when the bookmark was created
+    pub created: DateTime<Utc>,
+}
+
+impl Bookmark {
+    /// the bookmark as a netidx value [name, pos, speed, filter, created]
+    pub fn to_value(&self, name: &str) -> Value {
+        Value::from(vec![
+            Value::from(String::from(name)),
+            Value::from(self.pos),
+            match self.speed {
+                Some(speed) => Value::from(speed),
+                None => Value::from("unlimited"),
+            },
+            Value::from(self.filter.clone()),
+            Value::from(self.created),
+        ])
+    }
+}
+
+/// The bookmarks of every user, persisted as json in the archive
+/// directory. Bookmarks are stored by the recorder instance that
+/// handles the request, in a cluster they are not replicated.
+#[derive(Debug)]
+pub(super) struct Bookmarks {
+    path: PathBuf,
+    by_user: BTreeMap<String, BTreeMap<String, Bookmark>>,
+    // the generation of the last change, and of the last change
+    // written to disk. Writes happen in the background and may
+    // finish out of order, older generations are not written.
+    generation: u64,
+    written: Arc<Mutex<u64>>,
+}
+
+impl Bookmarks {
+    /// load the bookmarks from `path`, if it doesn't exist then start
+    /// with no bookmarks.
+    pub(super) fn load(path: PathBuf) -> Result<Self> {
+        let by_user = if path.is_file() {
+            let s = fs::read(&path).context("reading bookmarks")?;
+            serde_json::from_slice(&s).context("parsing bookmarks")?
+        } else {
+            BTreeMap::new()
+        };
+        Ok(Self { path, by_user, generation: 0, written: Arc::new(Mutex::new(0)) })
+    }
+
+    fn write(path: &PathBuf, s: Vec<u8>) -> Result<()> {
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, s).context("writing bookmarks")?;
+        fs::rename(&tmp, path).context("renaming bookmarks")?;
+        Ok(())
+    }
+
+    /// write the bookmarks to disk on the blocking pool. The returned
+    /// future resolves when they are written.
+    fn save(&mut self) -> impl Future<Output = Result<()>> {
+        self.generation += 1;
+        let generation = self.generation;
+        let written = self.written.clone();
+        let path = self.path.clone();
+        let s = serde_json::to_vec_pretty(&self.by_user);
+        let job = task::spawn_blocking(move || {
+            let s = s?;
+            let mut written = written.lock();
+            // a newer generation already includes this change
+            if *written < generation {
+                Self::write(&path, s)?;
+                *written = generation;
+            }
+            Ok(())
+        });
+        async move { job.await? }
+    }
+
+    /// add or replace the bookmark `name` for `user`. The returned
+    /// future resolves when the change is saved.
+    pub(super) fn add(
+        &mut self,
+        user: &str,
+        name: &str,
+        bookmark: Bookmark,
+    ) -> impl Future<Output = Result<()>> {
+        self.by_user
+            .entry(String::from(user))
+            .or_insert_with(BTreeMap::new)
+            .insert(String::from(name), bookmark);
+        self.save()
+    }
+
+    /// remove the bookmark `name` of `user`. If it existed return a
+    /// future that resolves when the change is saved.
+    pub(super) fn remove(
+        &mut self,
+        user: &str,
+        name: &str,
+    ) -> Option<impl Future<Output = Result<()>>> {
+        let removed = match self.by_user.get_mut(user) {
+            None => false,
+            Some(bookmarks) => {
+                let removed = bookmarks.remove(name).is_some();
+                if bookmarks.is_empty() {
+                    self.by_user.remove(user);
+                }
+                removed
+            }
+        };
+        if removed {
+            Some(self.save())
+        } else {
+            None
+        }
+    }
+
+    pub(super) fn get(&self, user: &str, name: &str) -> Option<&Bookmark> {
+        self.by_user.get(user).and_then(|b| b.get(name))
+    }
+
+    pub(super) fn list<'a>(
+        &'a self,
+        user: &str,
+    ) -> impl Iterator<Item = (&'a String, &'a Bookmark)> + 'a {
+        self.by_user.get(user).into_iter().flat_map(|b| b.iter())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn bookmark(pos: DateTime<Utc>) -> Bookmark {
+        Bookmark {
+            pos,
+            filter: vec![Chars::from("/**")],
+            speed: Some(1.),
+            created: Utc::now(),
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn save_and_load() {
+        let path = PathBuf::from("test-bookmarks.json");
+        let _ = fs::remove_file(&path);
+        let mut bookmarks = Bookmarks::load(path.clone()).unwrap();
+        let t = Utc::now();
+        // writes may finish out of order, the last change must win
+        let mut saves = Vec::new();
+        for i in 0..10 {
+            let name = format!("b{}", i);
+            saves.push(bookmarks.add("alice", &name, bookmark(t)));
+        }
+        saves.push(bookmarks.add("bob", "b0", bookmark(t)));
+        for saved in saves.into_iter().rev() {
+            saved.await.unwrap()
+        }
+        assert!(bookmarks.remove("alice", "nope").is_none());
+        bookmarks.remove("alice", "b3").unwrap().await.unwrap();
+        bookmarks.remove("bob", "b0").unwrap().await.unwrap();
+        let loaded = Bookmarks::load(path.clone()).unwrap();
+        let names = loaded.list("alice").map(|(n, _)| n.clone()).collect::<Vec<_>>();
+        assert_eq!(names.len(), 9);
+        assert!(!names.contains(&String::from("b3")));
+        assert_eq!(loaded.get("alice", "b0").unwrap().pos, t);
+        assert!(loaded.list("bob").next().is_none());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,9 +1,9 @@
-use crate::logfile::{ArchiveReader, ArchiveWriter, BatchItem};
-use anyhow::Result;
+use crate::logfile::{self, ArchiveReader, ArchiveWriter, BatchItem};
+use anyhow::{Context, Result};
 use arcstr::ArcStr;
 use chrono::prelude::*;
 use fxhash::FxHashMap;
-use log::error;
+use log::{error, warn};
 use netidx::{
     chars::Chars,
     config::Config as NetIdxCfg,
@@ -22,6 +22,7 @@ use tokio::{sync::broadcast, task::JoinSet};
 
 use self::{file::RecordShardConfig, logfile_index::LogfileIndex};
 
+mod bookmarks;
 pub mod logfile_collection;
 pub mod logfile_index;
 mod oneshot;
@@ -117,6 +118,8 @@ pub mod file {
         pub rotate_interval: Option<RotateDirective>,
         #[serde(default = "default_slack")]
         pub slack: usize,
+        #[serde(default)]
+        pub critical: bool,
     }
 
     impl RecordShardConfig {
@@ -129,6 +132,7 @@ pub mod file {
                 flush_interval: None,
                 rotate_interval: None,
                 slack: default_slack(),
+                critical: false,
             }
         }
     }
@@ -287,6 +291,11 @@ pub struct RecordConfig {
     pub rotate_interval: RotateDirective,
     /// how much channel slack to allocate
     pub slack: usize,
+    /// sync the archive to disk after every batch, so that no
+    /// recorded data is lost if the recorder or the machine crashes.
+    /// This limits the recording rate to the rate at which the disk
+    /// can sync.
+    pub critical: bool,
 }
 
 impl RecordConfig {
@@ -301,6 +310,7 @@ impl RecordConfig {
             flush_interval: file::default_flush_interval(),
             rotate_interval: file::default_rotate_interval(),
             slack: file::default_slack(),
+            critical: false,
         }
     }
 
@@ -315,6 +325,7 @@ impl RecordConfig {
                 flush_interval,
                 rotate_interval,
                 slack,
+                critical,
             } = c;
             let res = RecordConfig {
                 spec: GlobSet::new(
@@ -327,6 +338,7 @@ impl RecordConfig {
                 flush_interval: flush_interval.or(f.flush_interval),
                 rotate_interval: rotate_interval.unwrap_or(f.rotate_interval),
                 slack,
+                critical,
             };
             shards.insert(name, res);
         }
@@ -407,6 +419,26 @@ impl Config {
 
 atomic_id!(ShardId);
 
+/// Open the archive file at `path` for writing, first recovering it if
+/// it wasn't closed cleanly, e.g. because the recorder crashed.
+fn open_recovered(path: &std::path::Path) -> Result<ArchiveWriter> {
+    if path.is_file() {
+        let report = logfile::recover_file(path)
+            .with_context(|| format!("recovering {}", path.display()))?;
+        if report.lost_bytes > 0 || report.end != report.committed {
+            warn!(
+                "recovered {} records from {}, committed {}, recovered {}, lost {} bytes",
+                report.records,
+                path.display(),
+                report.committed,
+                report.end,
+                report.lost_bytes
+            );
+        }
+    }
+    ArchiveWriter::open(path)
+}
+
 #[derive(Debug, Clone)]
 enum BCastMsg {
     LogRotated(DateTime<Utc>),
@@ -480,7 +512,7 @@ impl Shards {
             t.spec.insert(id, rcfg.spec.clone());
             let dir = config.archive_directory.join(&**name);
             fs::create_dir_all(&dir)?;
-            let writer = ArchiveWriter::open(dir.join("pathindex"))?;
+            let writer = open_recovered(&dir.join("pathindex"))?;
             let reader = writer.reader()?;
             t.pathindexes.insert(id, reader);
             writers.insert(id, writer);
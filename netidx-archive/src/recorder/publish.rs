@@ -1,5 +1,8 @@
 use super::{
-    logfile_collection::LogfileCollection, logfile_index::LogfileIndex, oneshot::FILTER,
+    bookmarks::{Bookmark, Bookmarks},
+    logfile_collection::LogfileCollection,
+    logfile_index::LogfileIndex,
+    oneshot::FILTER,
     ShardId, Shards,
 };
 use crate::{
@@ -56,6 +59,17 @@ static STATE_DOC: &'static str = "The current state of playback, {pause, play, t
 static POS_DOC: &'static str = "The current playback position. Null if the archive is empty, or the timestamp of the current record. Set to any timestamp where start <= t <= end to seek. Set to [+-][0-9]+ to seek a specific number of batches, e.g. +1 to single step forward -1 to single step back. Set to [+-][0-9]+[yMdhmsu] to step forward or back that amount of time, e.g. -1y step back 1 year. -1u to step back 1 microsecond. set to 'beginning' to seek to the beginning and 'end' to seek to the end. By default the initial position is set to 'beginning' when opening the archive.";
 static PLAY_AFTER_DOC: &'static str = "Start playing after waiting the specified timeout";
 pub(crate) static FILTER_DOC: &'static str = "Only publish paths matching the specified filter. e.g. [\"/**\"] would match everything";
+static SHARE_DOC: &'static str = "Allow other clients to follow this session by passing it's id as follow when they create a session. Default false.";
+static FOLLOW_DOC: &'static str = "The id of a shared session to follow. The new session starts at the position of the followed session, and then mirrors it's seeks, and changes to it's speed, state, start, and end. Default null.";
+static BOOKMARK_NAME_DOC: &'static str =
+    "The name of the bookmark. Bookmarks are private to the user who creates them.";
+static BOOKMARK_POS_DOC: &'static str =
+    "The timestamp to bookmark. If null, the current position of session is used.";
+static BOOKMARK_SPEED_DOC: &'static str = "The playback speed to bookmark. If null, the speed of session is used, or 1 if session is null.";
+static BOOKMARK_FILTER_DOC: &'static str = "The filter to bookmark. If null, the filter of session is used, or [\"/**\"] if session is null.";
+static BOOKMARK_SESSION_DOC: &'static str =
+    "Bookmark the current position, speed, and filter of this session. Default null.";
+static JUMP_SESSION_DOC: &'static str = "Seek this session to the bookmark, and set it's speed. If null, a new session is created at the bookmark and it's id is returned. Default null.";
 
 fn session_base(publish_base: &Path, id: Uuid) -> Path {
     use uuid::fmt::Simple;
@@ -95,6 +109,10 @@ pub(super) fn parse_bound(v: Value) -> Result<Bound<DateTime<Utc>>> {
     }
 }
 
+fn parse_session_id(s: Option<Chars>) -> Result<Option<Uuid>> {
+    Ok(s.map(|s| Uuid::parse_str(s.trim())).transpose()?)
+}
+
 fn get_bound(r: WriteRequest) -> Option<Bound<DateTime<Utc>>> {
     match parse_bound(r.value) {
         Ok(b) => Some(b),
@@ -130,6 +148,9 @@ enum SessionUpdate {
 #[derive(Debug, Clone, Copy)]
 enum SessionBCastMsg {
     Command(ClusterCmd),
+    /// a command for the shards on this member only, it isn't sent to
+    /// the rest of the cluster
+    Local(ClusterCmd),
     Update(SessionUpdate),
 }
 
@@ -224,6 +245,8 @@ struct NewSessionConfig {
     state: Option<State>,
     play_after: Option<Duration>,
     filter: Vec<Chars>,
+    share: bool,
+    follow: Option<Uuid>,
 }
 
 impl NewSessionConfig {
@@ -236,6 +259,8 @@ impl NewSessionConfig {
         state: Option<State>,
         play_after: Option<Duration>,
         filter: Vec<Chars>,
+        share: bool,
+        follow: Option<Chars>,
     ) -> Option<(NewSessionConfig, RpcReply)> {
         let start = match parse_bound(start) {
             Ok(s) => s,
@@ -252,6 +277,10 @@ impl NewSessionConfig {
         if let Err(e) = parse_filter(filter.clone()) {
             rpc_err!(req.reply, format!("could not parse filter {}", e))
         }
+        let follow = match parse_session_id(follow) {
+            Ok(f) => f,
+            Err(e) => rpc_err!(req.reply, format!("invalid follow {}", e)),
+        };
         let s = NewSessionConfig {
             client: req.client,
             start,
@@ -261,6 +290,8 @@ impl NewSessionConfig {
             state,
             play_after,
             filter,
+            share,
+            follow,
         };
         Some((s, req.reply))
     }
@@ -516,7 +547,7 @@ impl SessionShard {
             Err(broadcast::error::RecvError::Closed) => bail!("closed"),
             Err(broadcast::error::RecvError::Lagged(_)) => warn!("shard missed messages"),
             Ok(SessionBCastMsg::Update(_)) => (),
-            Ok(SessionBCastMsg::Command(c)) => match c {
+            Ok(SessionBCastMsg::Command(c) | SessionBCastMsg::Local(c)) => match c {
                 ClusterCmd::NotIdle => {
                     *idle = false;
                 }
@@ -920,6 +951,156 @@ impl Controls {
     }
 }
 
+#[derive(Debug, Clone, Copy)]
+struct SessionStatus {
+    pos: Option<DateTime<Utc>>,
+    start: Bound<DateTime<Utc>>,
+    end: Bound<DateTime<Utc>>,
+    speed: Option<f64>,
+    state: State,
+}
+
+impl SessionStatus {
+    fn update(&mut self, u: SessionUpdate) {
+        match u {
+            SessionUpdate::Pos(pos) => self.pos = pos,
+            SessionUpdate::Start(start) => self.start = start,
+            SessionUpdate::End(end) => self.end = end,
+            SessionUpdate::Speed(speed) => self.speed = speed,
+            SessionUpdate::State(state) => self.state = state,
+        }
+    }
+
+    // the commands that will bring another session to this status
+    fn sync_cmds(&self) -> Vec<ClusterCmd> {
+        let mut cmds = vec![
+            ClusterCmd::SetStart(self.start),
+            ClusterCmd::SetEnd(self.end),
+            ClusterCmd::SetSpeed(self.speed),
+        ];
+        match (self.state, self.pos) {
+            (State::Tail, _) => cmds.push(ClusterCmd::SeekTo(Seek::End)),
+            (_, Some(pos)) => cmds.push(ClusterCmd::SeekTo(Seek::Absolute(pos))),
+            (_, None) => (),
+        }
+        cmds.push(ClusterCmd::SetState(self.state));
+        cmds
+    }
+}
+
+struct RegisteredSession {
+    // the user who created the session, None if it was started by a
+    // cluster member that didn't say
+    owner: Option<ArcStr>,
+    share: bool,
+    filter: Vec<Chars>,
+    bcast: broadcast::Sender<SessionBCastMsg>,
+    status: Arc<Mutex<SessionStatus>>,
+}
+
+/// The playback sessions running on this instance. Used to follow
+/// shared sessions, and to bookmark or jump existing sessions.
+#[derive(Clone, Default)]
+struct SessionRegistry(Arc<Mutex<FxHashMap<Uuid, RegisteredSession>>>);
+
+impl SessionRegistry {
+    fn register(
+        &self,
+        id: Uuid,
+        owner: Option<ArcStr>,
+        share: bool,
+        filter: Vec<Chars>,
+        bcast: broadcast::Sender<SessionBCastMsg>,
+    ) -> Registration {
+        let status = Arc::new(Mutex::new(SessionStatus {
+            pos: None,
+            start: Bound::Unbounded,
+            end: Bound::Unbounded,
+            speed: Some(1.),
+            state: State::Pause,
+        }));
+        let reg =
+            RegisteredSession { owner, share, filter, bcast, status: status.clone() };
+        self.0.lock().insert(id, reg);
+        Registration { registry: self.clone(), id, status }
+    }
+
+    fn contains(&self, id: &Uuid) -> bool {
+        self.0.lock().contains_key(id)
+    }
+
+    fn set_owner(&self, id: &Uuid, owner: ArcStr) {
+        if let Some(s) = self.0.lock().get_mut(id) {
+            s.owner = Some(owner);
+        }
+    }
+
+    fn is_shared(&self, id: &Uuid) -> bool {
+        self.0.lock().get(id).map(|s| s.share).unwrap_or(false)
+    }
+
+    fn follow(
+        &self,
+        id: &Uuid,
+    ) -> Option<(broadcast::Receiver<SessionBCastMsg>, SessionStatus)> {
+        let inner = self.0.lock();
+        inner.get(id).filter(|s| s.share).map(|s| (s.bcast.subscribe(), *s.status.lock()))
+    }
+
+    /// get a session that is owned by `user`
+    fn get(
+        &self,
+        id: &Uuid,
+        user: &str,
+    ) -> Result<(broadcast::Sender<SessionBCastMsg>, SessionStatus, Vec<Chars>)> {
+        let inner = self.0.lock();
+        match inner.get(id) {
+            None => bail!("no such session"),
+            Some(s) => match &s.owner {
+                Some(owner) if owner == user => {
+                    Ok((s.bcast.clone(), *s.status.lock(), s.filter.clone()))
+                }
+                Some(_) => bail!("session {} is owned by another user", uuid_string(*id)),
+                None => bail!("the owner of session {} is unknown", uuid_string(*id)),
+            },
+        }
+    }
+}
+
+/// true if the two filters have the same globs, in any order
+fn same_filter(f0: &[Chars], f1: &[Chars]) -> bool {
+    let f0 = f0.iter().map(|g| g.trim()).collect::<HashSet<_>>();
+    let f1 = f1.iter().map(|g| g.trim()).collect::<HashSet<_>>();
+    f0 == f1
+}
+
+struct Registration {
+    registry: SessionRegistry,
+    id: Uuid,
+    status: Arc<Mutex<SessionStatus>>,
+}
+
+impl Registration {
+    fn update(&self, u: SessionUpdate) {
+        self.status.lock().update(u)
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.registry.0.lock().remove(&self.id);
+    }
+}
+
+async fn recv_leader(
+    leader: &mut Option<broadcast::Receiver<SessionBCastMsg>>,
+) -> std::result::Result<SessionBCastMsg, broadcast::error::RecvError> {
+    match leader {
+        None => future::pending().await,
+        Some(leader) => leader.recv().await,
+    }
+}
+
 async fn session(
     shards: Arc<Shards>,
     subscriber: Subscriber,
@@ -927,11 +1108,16 @@ async fn session(
     session_id: Uuid,
     config: Arc<Config>,
     publish_config: Arc<PublishConfig>,
-    filter: GlobSet,
+    registry: SessionRegistry,
+    registration: Registration,
+    mut session_bcast: broadcast::Sender<SessionBCastMsg>,
+    mut session_bcast_rx: broadcast::Receiver<SessionBCastMsg>,
+    filter_txt: Vec<Chars>,
+    follow: Option<Uuid>,
     cfg: Option<NewSessionConfig>,
 ) -> Result<()> {
+    let filter = parse_filter(filter_txt)?;
     let (control_tx, control_rx) = mpsc::channel(3);
-    let (mut session_bcast, mut session_bcast_rx) = broadcast::channel(1000);
     let session_base = session_base(&publish_config.base, session_id);
     debug!("new session base {}", session_base);
     let mut cluster = Cluster::new(
@@ -943,6 +1129,7 @@ async fn session(
     .await?;
     debug!("cluster established");
     let controls = Controls::new(&session_base, &publisher, &control_tx).await?;
+    let leader = follow.and_then(|id| registry.follow(&id));
     let mut joinset: JoinSet<Result<()>> = JoinSet::new();
     for (id, pathindex) in shards.pathindexes.iter() {
         if let Some(spec) = shards.spec.get(id) {
@@ -983,6 +1170,17 @@ async fn session(
             Ok::<(), anyhow::Error>(())
         });
     }
+    // every member runs the leader, and follows it independently, so
+    // the commands from the leader are not sent to the cluster
+    let mut leader = match leader {
+        None => None,
+        Some((leader, status)) => {
+            for cmd in status.sync_cmds() {
+                let _ = session_bcast.send(SessionBCastMsg::Local(cmd));
+            }
+            Some(leader)
+        }
+    };
     let mut control_rx = control_rx.fuse();
     loop {
         select_biased! {
@@ -997,13 +1195,31 @@ async fn session(
                     let _ = session_bcast.send(SessionBCastMsg::Command(cmd));
                 }
             },
+            m = recv_leader(&mut leader).fuse() => match m {
+                Ok(SessionBCastMsg::Command(c) | SessionBCastMsg::Local(c)) => match c {
+                    ClusterCmd::NotIdle | ClusterCmd::Terminate => (),
+                    c => { let _ = session_bcast.send(SessionBCastMsg::Local(c)); }
+                },
+                Ok(SessionBCastMsg::Update(_)) => (),
+                Err(broadcast::error::RecvError::Lagged(_)) => {
+                    warn!("session {} missed commands from it's leader", session_id)
+                }
+                Err(broadcast::error::RecvError::Closed) => {
+                    info!("the leader of session {} has ended", session_id);
+                    leader = None;
+                }
+            },
             mut m = session_bcast_rx.recv().fuse() => {
                 let mut batch = publisher.start_batch();
                 loop {
                     match m {
                         Ok(m) => match m {
                             SessionBCastMsg::Command(c) => cluster.send_cmd(&c),
-                            SessionBCastMsg::Update(u) => controls.process_update(&mut batch, u),
+                            SessionBCastMsg::Local(_) => (),
+                            SessionBCastMsg::Update(u) => {
+                                registration.update(u);
+                                controls.process_update(&mut batch, u)
+                            }
                         }
                         Err(e) => error!("session bcast for session {} error {}", session_id, e),
                     }
@@ -1093,11 +1309,25 @@ fn start_session(
     subscriber: &Subscriber,
     config: Arc<Config>,
     publish_config: Arc<PublishConfig>,
-    filter: GlobSet,
+    registry: SessionRegistry,
+    owner: Option<ArcStr>,
+    filter: Vec<Chars>,
+    share: bool,
+    follow: Option<Uuid>,
     cfg: Option<NewSessionConfig>,
 ) {
     let subscriber = subscriber.clone();
     let publisher_cl = publisher.clone();
+    // register before the session starts, so it can be found, and
+    // sent commands, as soon as it's id is known
+    let (session_bcast, session_bcast_rx) = broadcast::channel(1000);
+    let registration = registry.register(
+        session_id,
+        owner,
+        share,
+        filter.clone(),
+        session_bcast.clone(),
+    );
     task::spawn(async move {
         let res = session(
             shards,
@@ -1106,7 +1336,12 @@ fn start_session(
             session_id,
             config.clone(),
             publish_config.clone(),
+            registry,
+            registration,
+            session_bcast,
+            session_bcast_rx,
             filter,
+            follow,
             cfg,
         )
         .await;
@@ -1122,6 +1357,402 @@ fn start_session(
     });
 }
 
+/// A session started by a member of the cluster, which the other
+/// members also start. This is the message of the original session
+/// channel, which can't change without breaking older members.
+type LegacySessionCmd = (ClId, Uuid, Vec<Chars>);
+
+/// A session started by a member of the cluster, sent on a separate
+/// channel from `LegacySessionCmd`. Sessions that are neither shared
+/// nor following are sent on both channels, so older members start
+/// them too. Older members don't run shared or following sessions.
+#[derive(Debug, Clone, PartialEq, Pack)]
+struct SessionCmd {
+    client: ClId,
+    id: Uuid,
+    owner: ArcStr,
+    filter: Vec<Chars>,
+    share: bool,
+    follow: Option<Uuid>,
+}
+
+impl SessionCmd {
+    fn legacy(&self) -> Option<LegacySessionCmd> {
+        if self.share || self.follow.is_some() {
+            None
+        } else {
+            Some((self.client, self.id, self.filter.clone()))
+        }
+    }
+}
+
+struct SessionCluster {
+    legacy: Cluster<LegacySessionCmd>,
+    sessions: Cluster<SessionCmd>,
+}
+
+impl SessionCluster {
+    async fn new(
+        publisher: &Publisher,
+        subscriber: &Subscriber,
+        base: Path,
+        shards: usize,
+    ) -> Result<Self> {
+        let legacy =
+            Cluster::new(publisher, subscriber.clone(), base.append("publish"), shards)
+                .await?;
+        // don't wait for members here, older members never join
+        let mut sessions =
+            Cluster::new(publisher, subscriber.clone(), base.append("sessions"), 0)
+                .await?;
+        sessions.poll_members().await?;
+        Ok(Self { legacy, sessions })
+    }
+
+    async fn poll_members(&mut self) -> Result<()> {
+        self.legacy.poll_members().await?;
+        self.sessions.poll_members().await?;
+        Ok(())
+    }
+
+    fn send_cmd(&self, cmd: &SessionCmd) {
+        if let Some(legacy) = cmd.legacy() {
+            self.legacy.send_cmd(&legacy)
+        }
+        self.sessions.send_cmd(cmd)
+    }
+}
+
+struct SessionCtx<'a> {
+    shards: &'a Arc<Shards>,
+    subscriber: &'a Subscriber,
+    publisher: &'a Publisher,
+    config: &'a Arc<Config>,
+    publish_config: &'a Arc<PublishConfig>,
+    sessions: &'a SessionIds,
+    registry: &'a SessionRegistry,
+}
+
+impl<'a> SessionCtx<'a> {
+    fn new_session(
+        &self,
+        cluster: &mut SessionCluster,
+        cfg: NewSessionConfig,
+        mut reply: RpcReply,
+    ) {
+        if let Some(leader) = &cfg.follow {
+            if !self.registry.is_shared(leader) {
+                let m = format!("no shared session {} to follow", uuid_string(*leader));
+                return reply.send(Value::Error(Chars::from(m)));
+            }
+        }
+        match self.sessions.add_session(cfg.client) {
+            None => {
+                let m = format!("too many sessions, client {:?}", cfg.client);
+                reply.send(Value::Error(Chars::from(m)));
+            }
+            Some(session_token) => {
+                let filter_txt = cfg.filter.clone();
+                if let Err(e) = parse_filter(cfg.filter.clone()) {
+                    warn!("failed to parse filters {}", e);
+                    return;
+                }
+                let session_id = Uuid::new_v4();
+                let client = cfg.client;
+                let owner = self.user(&client);
+                let share = cfg.share;
+                let follow = cfg.follow;
+                info!("start session {}", session_id);
+                start_session(
+                    self.publisher.clone(),
+                    session_id,
+                    session_token,
+                    self.shards.clone(),
+                    self.subscriber,
+                    self.config.clone(),
+                    self.publish_config.clone(),
+                    self.registry.clone(),
+                    Some(owner.clone()),
+                    filter_txt.clone(),
+                    share,
+                    follow,
+                    Some(cfg),
+                );
+                cluster.send_cmd(&SessionCmd {
+                    client,
+                    id: session_id,
+                    owner,
+                    filter: filter_txt,
+                    share,
+                    follow,
+                });
+                reply.send(Value::from(uuid_string(session_id)));
+            }
+        }
+    }
+
+    /// start a session that another member of the cluster started,
+    /// unless it is already running because it was sent on both
+    /// channels.
+    fn cluster_session(
+        &self,
+        client: ClId,
+        session_id: Uuid,
+        owner: Option<ArcStr>,
+        filter: Vec<Chars>,
+        share: bool,
+        follow: Option<Uuid>,
+    ) {
+        if self.registry.contains(&session_id) {
+            if let Some(owner) = owner {
+                self.registry.set_owner(&session_id, owner)
+            }
+            return;
+        }
+        if let Err(e) = parse_filter(filter.clone()) {
+            error!("can't parse filter from cluster {}", e);
+            return;
+        }
+        match self.sessions.add_session(client) {
+            None => {
+                error!(
+                    "can't start session requested by cluster member, too many sessions"
+                )
+            }
+            Some(session_token) => start_session(
+                self.publisher.clone(),
+                session_id,
+                session_token,
+                self.shards.clone(),
+                self.subscriber,
+                self.config.clone(),
+                self.publish_config.clone(),
+                self.registry.clone(),
+                owner,
+                filter,
+                share,
+                follow,
+                None,
+            ),
+        }
+    }
+
+    fn user(&self, client: &ClId) -> ArcStr {
+        match self.publisher.user(client) {
+            Some(user) => user.name,
+            None => ArcStr::from("anonymous"),
+        }
+    }
+
+    // handle a bookmark request, if it requires a new session to be
+    // created return the config for it
+    fn bookmark(
+        &self,
+        bookmarks: &mut Bookmarks,
+        req: BookmarkReq,
+    ) -> Option<(NewSessionConfig, RpcReply)> {
+        macro_rules! or_reply {
+            ($reply:expr, $e:expr) => {
+                match $e {
+                    Ok(r) => r,
+                    Err(e) => {
+                        $reply.send(Value::Error(Chars::from(format!("{}", e))));
+                        return None;
+                    }
+                }
+            };
+        }
+        match req {
+            BookmarkReq::Create {
+                client,
+                name,
+                pos,
+                speed,
+                filter,
+                session,
+                mut reply,
+            } => {
+                let user = self.user(&client);
+                let current = match session {
+                    None => None,
+                    Some(id) => {
+                        let (_, status, filter) =
+                            or_reply!(reply, self.registry.get(&id, &user));
+                        Some((status, filter))
+                    }
+                };
+                let pos = match (pos, &current) {
+                    (Some(pos), _) => pos,
+                    (None, Some((SessionStatus { pos: Some(pos), .. }, _))) => *pos,
+                    (None, _) => rpc_err!(reply, "a position is required"),
+                };
+                let speed = match (speed, &current) {
+                    (Some(speed), _) => speed,
+                    (None, Some((status, _))) => status.speed,
+                    (None, None) => Some(1.),
+                };
+                let filter = match (filter, current) {
+                    (Some(filter), _) => filter,
+                    (None, Some((_, filter))) => filter,
+                    (None, None) => vec![Chars::from("/**")],
+                };
+                let bookmark = Bookmark { pos, filter, speed, created: Utc::now() };
+                reply_when_saved(bookmarks.add(&user, &name, bookmark), reply);
+                None
+            }
+            BookmarkReq::List { client, mut reply } => {
+                let user = self.user(&client);
+                let res = bookmarks
+                    .list(&user)
+                    .map(|(name, b)| b.to_value(name))
+                    .collect::<Vec<_>>();
+                reply.send(Value::from(res));
+                None
+            }
+            BookmarkReq::Delete { client, name, mut reply } => {
+                let user = self.user(&client);
+                match bookmarks.remove(&user, &name) {
+                    Some(saved) => reply_when_saved(saved, reply),
+                    None => reply.send(Value::Error(Chars::from("no such bookmark"))),
+                }
+                None
+            }
+            BookmarkReq::Jump { client, name, session, mut reply } => {
+                let user = self.user(&client);
+                let bookmark = match bookmarks.get(&user, &name) {
+                    Some(b) => b.clone(),
+                    None => rpc_err!(reply, "no such bookmark"),
+                };
+                match session {
+                    Some(id) => {
+                        let (bcast, _, filter) =
+                            or_reply!(reply, self.registry.get(&id, &user));
+                        // the filter of a running session can't change
+                        if !same_filter(&filter, &bookmark.filter) {
+                            rpc_err!(reply, "the session and the bookmark filters differ")
+                        }
+                        let speed = ClusterCmd::SetSpeed(bookmark.speed);
+                        let seek = ClusterCmd::SeekTo(Seek::Absolute(bookmark.pos));
+                        let _ = bcast.send(SessionBCastMsg::Command(speed));
+                        let _ = bcast.send(SessionBCastMsg::Command(seek));
+                        reply.send(Value::Ok);
+                        None
+                    }
+                    None => {
+                        let cfg = NewSessionConfig {
+                            client,
+                            start: Bound::Unbounded,
+                            end: Bound::Unbounded,
+                            speed: bookmark.speed,
+                            pos: Some(Seek::Absolute(bookmark.pos)),
+                            state: Some(State::Pause),
+                            play_after: None,
+                            filter: bookmark.filter,
+                            share: false,
+                            follow: None,
+                        };
+                        Some((cfg, reply))
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn reply_when_saved(
+    saved: impl Future<Output = Result<()>> + Send + 'static,
+    mut reply: RpcReply,
+) {
+    task::spawn(async move {
+        match saved.await {
+            Ok(()) => reply.send(Value::Ok),
+            Err(e) => reply.send(Value::Error(Chars::from(format!("{}", e)))),
+        }
+    });
+}
+
+enum BookmarkReq {
+    Create {
+        client: ClId,
+        name: Chars,
+        pos: Option<DateTime<Utc>>,
+        speed: Option<Option<f64>>,
+        filter: Option<Vec<Chars>>,
+        session: Option<Uuid>,
+        reply: RpcReply,
+    },
+    List {
+        client: ClId,
+        reply: RpcReply,
+    },
+    Delete {
+        client: ClId,
+        name: Chars,
+        reply: RpcReply,
+    },
+    Jump {
+        client: ClId,
+        name: Chars,
+        session: Option<Uuid>,
+        reply: RpcReply,
+    },
+}
+
+impl BookmarkReq {
+    fn create(
+        mut req: RpcCall,
+        name: Chars,
+        pos: Value,
+        speed: Value,
+        filter: Option<Vec<Chars>>,
+        session: Option<Chars>,
+    ) -> Option<Self> {
+        let pos = match pos {
+            Value::Null => None,
+            pos => match parse_bound(pos) {
+                Ok(Bound::Included(ts)) => Some(ts),
+                Ok(_) => rpc_err!(req.reply, "pos must be a timestamp"),
+                Err(e) => rpc_err!(req.reply, format!("invalid pos {}", e)),
+            },
+        };
+        let speed = match speed {
+            Value::Null => None,
+            speed => match parse_speed(speed) {
+                Ok(speed) => Some(speed),
+                Err(e) => rpc_err!(req.reply, format!("invalid speed {}", e)),
+            },
+        };
+        if let Some(filter) = &filter {
+            if let Err(e) = parse_filter(filter.clone()) {
+                rpc_err!(req.reply, format!("could not parse filter {}", e))
+            }
+        }
+        let session = match parse_session_id(session) {
+            Ok(s) => s,
+            Err(e) => rpc_err!(req.reply, format!("invalid session {}", e)),
+        };
+        let client = req.client;
+        let reply = req.reply;
+        Some(BookmarkReq::Create { client, name, pos, speed, filter, session, reply })
+    }
+
+    fn list(req: RpcCall) -> Option<Self> {
+        Some(BookmarkReq::List { client: req.client, reply: req.reply })
+    }
+
+    fn delete(req: RpcCall, name: Chars) -> Option<Self> {
+        Some(BookmarkReq::Delete { client: req.client, name, reply: req.reply })
+    }
+
+    fn jump(mut req: RpcCall, name: Chars, session: Option<Chars>) -> Option<Self> {
+        let session = match parse_session_id(session) {
+            Ok(s) => s,
+            Err(e) => rpc_err!(req.reply, format!("invalid session {}", e)),
+        };
+        Some(BookmarkReq::Jump { client: req.client, name, session, reply: req.reply })
+    }
+}
+
 pub(super) async fn run(
     shards: Arc<Shards>,
     subscriber: Subscriber,
@@ -1133,6 +1764,10 @@ pub(super) async fn run(
         publish_config.max_sessions,
         publish_config.max_sessions_per_client,
     );
+    let registry = SessionRegistry::default();
+    let mut bookmarks = task::block_in_place(|| {
+        Bookmarks::load(config.archive_directory.join("bookmarks.json"))
+    })?;
     let (control_tx, control_rx) = mpsc::channel(3);
     let _new_session: Result<Proc> = define_rpc!(
         &publisher,
@@ -1146,17 +1781,66 @@ pub(super) async fn run(
         pos: Option<Seek> = Value::Null; POS_DOC,
         state: Option<State> = Value::Null; STATE_DOC,
         play_after: Option<Duration> = None::<Duration>; PLAY_AFTER_DOC,
-        filter: Vec<Chars> = vec![Chars::from("/**")]; FILTER_DOC
+        filter: Vec<Chars> = vec![Chars::from("/**")]; FILTER_DOC,
+        share: bool = false; SHARE_DOC,
+        follow: Option<Chars> = Value::Null; FOLLOW_DOC
     );
     let _new_session = _new_session?;
-    let mut cluster = Cluster::<(ClId, Uuid, Vec<Chars>)>::new(
+    let (bookmark_tx, bookmark_rx) = mpsc::channel(3);
+    let _create_bookmark = define_rpc!(
         &publisher,
-        subscriber.clone(),
-        publish_config.base.append(&publish_config.cluster).append("publish"),
+        publish_config.base.append("bookmarks/create"),
+        "create or replace a named bookmark",
+        BookmarkReq::create,
+        Some(bookmark_tx.clone()),
+        name: Chars = Value::Null; BOOKMARK_NAME_DOC,
+        pos: Value = Value::Null; BOOKMARK_POS_DOC,
+        speed: Value = Value::Null; BOOKMARK_SPEED_DOC,
+        filter: Option<Vec<Chars>> = Value::Null; BOOKMARK_FILTER_DOC,
+        session: Option<Chars> = Value::Null; BOOKMARK_SESSION_DOC
+    )?;
+    let _list_bookmarks = define_rpc!(
+        &publisher,
+        publish_config.base.append("bookmarks/list"),
+        "list your bookmarks as [name, pos, speed, filter, created]",
+        BookmarkReq::list,
+        Some(bookmark_tx.clone()),
+    )?;
+    let _delete_bookmark = define_rpc!(
+        &publisher,
+        publish_config.base.append("bookmarks/delete"),
+        "delete a named bookmark",
+        BookmarkReq::delete,
+        Some(bookmark_tx.clone()),
+        name: Chars = Value::Null; BOOKMARK_NAME_DOC
+    )?;
+    let _jump_to_bookmark = define_rpc!(
+        &publisher,
+        publish_config.base.append("bookmarks/jump"),
+        "jump to a named bookmark",
+        BookmarkReq::jump,
+        Some(bookmark_tx.clone()),
+        name: Chars = Value::Null; BOOKMARK_NAME_DOC,
+        session: Option<Chars> = Value::Null; JUMP_SESSION_DOC
+    )?;
+    let mut cluster = SessionCluster::new(
+        &publisher,
+        &subscriber,
+        publish_config.base.append(&publish_config.cluster),
         publish_config.cluster_shards.unwrap_or(0),
     )
     .await?;
+    let ctx = SessionCtx {
+        shards: &shards,
+        subscriber: &subscriber,
+        publisher: &publisher,
+        config: &config,
+        publish_config: &publish_config,
+        sessions: &sessions,
+        registry: &registry,
+    };
     let mut control_rx = control_rx.fuse();
+    let mut bookmark_rx = bookmark_rx.fuse();
     let mut poll_members = time::interval(std::time::Duration::from_secs(30));
     loop {
         select_biased! {
@@ -1165,75 +1849,123 @@ pub(super) async fn run(
                     warn!("failed to poll cluster members, will retry {}", e)
                 }
             },
-            cmds = cluster.wait_cmds().fuse() => match cmds {
+            cmds = cluster.legacy.wait_cmds().fuse() => match cmds {
                 Err(e) => {
                     error!("received unparsable cluster commands {}", e)
                 }
                 Ok(cmds) => for (client, session_id, filter) in cmds {
-                    let filter = match parse_filter(filter) {
-                        Ok(filter) => filter,
-                        Err(e) => {
-                            error!("can't parse filter from cluster {}", e);
-                            continue
-                        }
-                    };
-                    match sessions.add_session(client) {
-                        None => {
-                            error!("can't start session requested by cluster member, too many sessions")
-                        },
-                        Some(session_token) => {
-                            start_session(
-                                publisher.clone(),
-                                session_id,
-                                session_token,
-                                shards.clone(),
-                                &subscriber,
-                                config.clone(),
-                                publish_config.clone(),
-                                filter,
-                                None
-                            );
-                        }
-                    }
+                    ctx.cluster_session(client, session_id, None, filter, false, None)
+                }
+            },
+            cmds = cluster.sessions.wait_cmds().fuse() => match cmds {
+                Err(e) => {
+                    error!("received unparsable cluster commands {}", e)
+                }
+                Ok(cmds) => for c in cmds {
+                    let SessionCmd { client, id, owner, filter, share, follow } = c;
+                    ctx.cluster_session(client, id, Some(owner), filter, share, follow)
                 }
             },
             m = control_rx.next() => match m {
                 None => break Ok(()),
-                Some((cfg, mut reply)) => {
-                    match sessions.add_session(cfg.client) {
-                        None => {
-                            let m = format!("too many sessions, client {:?}", cfg.client);
-                            reply.send(Value::Error(Chars::from(m)));
-                        },
-                        Some(session_token) => {
-                            let filter_txt = cfg.filter.clone();
-                            let filter = match parse_filter(cfg.filter.clone()) {
-                                Ok(filter) => filter,
-                                Err(e) => {
-                                    warn!("failed to parse filters {}", e);
-                                    continue
-                                }
-                            };
-                            let session_id = Uuid::new_v4();
-                            let client = cfg.client;
-                            info!("start session {}", session_id);
-                            start_session(
-                                publisher.clone(),
-                                session_id,
-                                session_token,
-                                shards.clone(),
-                                &subscriber,
-                                config.clone(),
-                                publish_config.clone(),
-                                filter,
-                                Some(cfg)
-                            );
-                            cluster.send_cmd(&(client, session_id, filter_txt));
-                            reply.send(Value::from(uuid_string(session_id)));
-                        }
+                Some((cfg, reply)) => ctx.new_session(&mut cluster, cfg, reply),
+            },
+            m = bookmark_rx.next() => match m {
+                None => break Ok(()),
+                Some(req) => {
+                    if let Some((cfg, reply)) = ctx.bookmark(&mut bookmarks, req) {
+                        ctx.new_session(&mut cluster, cfg, reply)
                     }
                 }
             },
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use netidx::{pack::Pack, utils};
+
+    fn cmd(share: bool, follow: Option<Uuid>) -> SessionCmd {
+        SessionCmd {
+            client: ClId::new(),
+            id: Uuid::new_v4(),
+            owner: ArcStr::from("alice"),
+            filter: vec![Chars::from("/foo/**")],
+            share,
+            follow,
+        }
+    }
+
+    #[test]
+    fn session_cmd_wire() {
+        let plain = cmd(false, None);
+        // older members can still decode plain sessions
+        let b = utils::pack(&plain.legacy().unwrap()).unwrap();
+        let (client, id, filter) = LegacySessionCmd::decode(&mut &*b).unwrap();
+        assert_eq!(client, plain.client);
+        assert_eq!(id, plain.id);
+        assert_eq!(filter, plain.filter);
+        // shared and following sessions are only on the new channel
+        assert!(cmd(true, None).legacy().is_none());
+        assert!(cmd(false, Some(Uuid::new_v4())).legacy().is_none());
+        let c = cmd(true, Some(Uuid::new_v4()));
+        let b = utils::pack(&c).unwrap();
+        assert_eq!(SessionCmd::decode(&mut &*b).unwrap(), c);
+    }
+
+    #[test]
+    fn session_owner() {
+        let registry = SessionRegistry::default();
+        let id = Uuid::new_v4();
+        let (tx, _rx) = broadcast::channel(10);
+        let filter = vec![Chars::from("/foo/**")];
+        let reg = registry.register(id, None, false, filter.clone(), tx);
+        // started by an older cluster member, the owner is unknown
+        assert!(registry.contains(&id));
+        assert!(registry.get(&id, "alice").is_err());
+        registry.set_owner(&id, ArcStr::from("alice"));
+        let (_, _, f) = registry.get(&id, "alice").unwrap();
+        assert_eq!(f, filter);
+        assert!(registry.get(&id, "bob").is_err());
+        assert!(registry.get(&Uuid::new_v4(), "alice").is_err());
+        // it isn't shared, so it can't be followed
+        assert!(!registry.is_shared(&id));
+        assert!(registry.follow(&id).is_none());
+        drop(reg);
+        assert!(!registry.contains(&id));
+    }
+
+    #[test]
+    fn follow_shared() {
+        let registry = SessionRegistry::default();
+        let id = Uuid::new_v4();
+        let (tx, _rx) = broadcast::channel(10);
+        let owner = Some(ArcStr::from("alice"));
+        let reg = registry.register(id, owner, true, vec![Chars::from("/**")], tx);
+        let pos = Utc::now();
+        reg.update(SessionUpdate::Pos(Some(pos)));
+        reg.update(SessionUpdate::Speed(Some(2.)));
+        reg.update(SessionUpdate::State(State::Play));
+        let (_, status) = registry.follow(&id).unwrap();
+        let cmds = status.sync_cmds();
+        assert_eq!(cmds.len(), 5);
+        assert!(matches!(cmds[2], ClusterCmd::SetSpeed(Some(s)) if s == 2.));
+        assert!(matches!(cmds[3], ClusterCmd::SeekTo(Seek::Absolute(p)) if p == pos));
+        assert!(matches!(cmds[4], ClusterCmd::SetState(State::Play)));
+        reg.update(SessionUpdate::State(State::Tail));
+        let (_, status) = registry.follow(&id).unwrap();
+        let cmds = status.sync_cmds();
+        assert!(matches!(cmds[3], ClusterCmd::SeekTo(Seek::End)));
+    }
+
+    #[test]
+    fn filters() {
+        let f =
+            |l: &[&'static str]| l.iter().map(|g| Chars::from(*g)).collect::<Vec<_>>();
+        assert!(same_filter(&f(&["/a/**", "/b"]), &f(&["/b", " /a/**"])));
+        assert!(!same_filter(&f(&["/a/**"]), &f(&["/a/*"])));
+        assert!(!same_filter(&f(&["/a/**", "/b"]), &f(&["/a/**"])));
+    }
+}
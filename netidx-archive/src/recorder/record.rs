@@ -1,6 +1,6 @@
 use super::{
-    ArchiveCmds, BCastMsg, Config, LogfileIndex, RecordConfig, RotateDirective, ShardId,
-    Shards,
+    open_recovered, ArchiveCmds, BCastMsg, Config, LogfileIndex, RecordConfig,
+    RotateDirective, ShardId, Shards,
 };
 use crate::logfile::{ArchiveWriter, BatchItem, Id, BATCH_POOL};
 use anyhow::{Context, Result};
@@ -187,6 +187,7 @@ fn write_pathmap(
     pathindex: &mut ArchiveWriter,
     to_add: &mut Vec<(Path, SubId)>,
     by_subid: &mut FxHashMap<SubId, Id>,
+    critical: bool,
 ) -> Result<()> {
     task::block_in_place(|| {
         let i = to_add.iter().map(|(ref p, _)| p);
@@ -199,7 +200,11 @@ fn write_pathmap(
             by_subid.insert(subid, id);
         }
     }
-    pathindex.flush().context("flushing pathindex")?;
+    if critical {
+        pathindex.sync().context("syncing pathindex")?;
+    } else {
+        pathindex.flush().context("flushing pathindex")?;
+    }
     Ok(())
 }
 
@@ -236,7 +241,7 @@ pub(super) async fn run(
     let mut subscribed: HashMap<Path, Dval> = HashMap::new();
     let bcast = shards.bcast[&shard_id].clone();
     let mut archive = task::block_in_place(|| {
-        ArchiveWriter::open(config.archive_directory.join(&*shard_name).join("current"))
+        open_recovered(&config.archive_directory.join(&*shard_name).join("current"))
     })
     .context("opening current archive for write")?;
     {
@@ -356,8 +361,12 @@ pub(super) async fn run(
                             by_subid.remove(&dv.id());
                         }
                     }
-                    write_pathmap(&mut pathindex, &mut to_add, &mut by_subid)
-			.context("writing pathmap")?
+                    write_pathmap(
+                        &mut pathindex,
+                        &mut to_add,
+                        &mut by_subid,
+                        record_config.critical
+                    ).context("writing pathmap")?
                 }
             },
             batch = rx_batch.next() => match batch {
@@ -391,6 +400,10 @@ pub(super) async fn run(
                             }
                         }
                         match flush_frequency {
+                            _ if record_config.critical => {
+                                archive.sync().context("syncing archive")?;
+                                last_flush = archive.len();
+                            }
                             None => (),
                             Some(freq) if archive.len() - last_flush < freq => (),
                             Some(_) => {
@@ -0,0 +1,298 @@
+use crate::logfile::{ArchiveReader, ArchiveWriter, BatchItem, Cursor, Id, BATCH_POOL};
+use anyhow::{Context, Result};
+use chrono::prelude::*;
+use fxhash::{FxHashMap, FxHashSet};
+use netidx::{path::Path, pool::Pooled, protocol::glob::GlobSet, subscriber::Event};
+use std::{
+    collections::{HashMap, VecDeque},
+    ops::Bound,
+    path::{Path as FilePath, PathBuf},
+};
+
+const READ_BATCHES: usize = 100;
+
+/// An archive file to be merged, split, or cut, along with the
+/// pathindex that maps it's path ids, if they aren't stored in the
+/// file itself. This is the case for files written by the recorder,
+/// where all the files in a shard share the shard's pathindex.
+#[derive(Debug, Clone)]
+pub struct Source {
+    pub archive: ArchiveReader,
+    pub pathindex: Option<ArchiveReader>,
+}
+
+impl Source {
+    /// Open the specified archive file. If the file doesn't contain
+    /// any path mappings, and there is a file named `pathindex` in
+    /// the same directory, as there is in a recorder shard directory,
+    /// then it will be used as the pathindex. The recorder holds an
+    /// exclusive lock on the pathindex of shards it is recording, so
+    /// this will fail for those shards while the recorder is running.
+    pub fn open(path: impl AsRef<FilePath>) -> Result<Self> {
+        let path = path.as_ref();
+        let archive = ArchiveReader::open(path).context("opening archive")?;
+        let has_paths = archive.index().iter_pathmap().next().is_some();
+        let pathindex = match path.parent().map(|d| d.join("pathindex")) {
+            Some(pi) if !has_paths && pi.is_file() && pi != path => {
+                Some(ArchiveReader::open(pi).context("opening pathindex")?)
+            }
+            Some(_) | None => None,
+        };
+        Ok(Self { archive, pathindex })
+    }
+
+    /// return the path mappings for the archive
+    pub fn paths(&self) -> Result<FxHashMap<Id, Path>> {
+        let archive = self.pathindex.as_ref().unwrap_or(&self.archive);
+        archive.check_remap_rescan()?;
+        let index = archive.index();
+        Ok(index.iter_pathmap().map(|(id, path)| (*id, path.clone())).collect())
+    }
+}
+
+fn create(dest: &FilePath) -> Result<ArchiveWriter> {
+    if dest.exists() {
+        bail!("{} already exists", dest.display())
+    }
+    ArchiveWriter::open(dest)
+}
+
+fn write_image(
+    output: &mut ArchiveWriter,
+    state: &FxHashMap<Id, Event>,
+    ts: DateTime<Utc>,
+) -> Result<()> {
+    let mut image = BATCH_POOL.take();
+    image.extend(state.iter().map(|(id, ev)| BatchItem(*id, ev.clone())));
+    output.add_batch(true, ts, &image).context("writing image")
+}
+
+/// Merge `sources` into a single time ordered archive file `dest`,
+/// e.g. to combine the files of several recorder shards. Each path is
+/// assigned a new id in `dest`, so paths with conflicting ids in
+/// different sources are reconciled. Whenever a source contains an
+/// image, an image of the merged state is written to `dest`, and if
+/// `image_frequency` is specified, an image will also be written each
+/// time that many bytes of deltas have been written.
+pub fn merge(
+    sources: &[Source],
+    dest: impl AsRef<FilePath>,
+    image_frequency: Option<usize>,
+) -> Result<()> {
+    let mut output = create(dest.as_ref())?;
+    let mut idmaps: Vec<FxHashMap<Id, Id>> = Vec::with_capacity(sources.len());
+    for src in sources {
+        let paths = src.paths()?;
+        output.add_paths(paths.values()).context("adding paths")?;
+        idmaps.push(
+            paths
+                .iter()
+                .map(|(id, path)| (*id, output.id_for_path(path).unwrap()))
+                .collect(),
+        );
+    }
+    let mut cursors = vec![Cursor::new(); sources.len()];
+    let mut queues: Vec<VecDeque<(DateTime<Utc>, bool, Pooled<Vec<BatchItem>>)>> =
+        sources.iter().map(|_| VecDeque::new()).collect();
+    let mut done = vec![false; sources.len()];
+    let mut state: FxHashMap<Id, Event> = HashMap::default();
+    let mut last_image = output.len();
+    loop {
+        for (i, src) in sources.iter().enumerate() {
+            if queues[i].is_empty() && !done[i] {
+                let batches = src.archive.read_batches(&mut cursors[i], READ_BATCHES)?;
+                done[i] = batches.is_empty();
+                queues[i].extend(batches);
+            }
+        }
+        let next = queues
+            .iter()
+            .enumerate()
+            .filter_map(|(i, q)| q.front().map(|(ts, _, _)| (i, *ts)))
+            .min_by_key(|(_, ts)| *ts);
+        let i = match next {
+            None => break,
+            Some((i, _)) => i,
+        };
+        let (ts, image, mut batch) = queues[i].pop_front().unwrap();
+        let mut out = BATCH_POOL.take();
+        for BatchItem(id, ev) in batch.drain(..) {
+            if let Some(id) = idmaps[i].get(&id) {
+                state.insert(*id, ev.clone());
+                out.push(BatchItem(*id, ev));
+            }
+        }
+        if image {
+            write_image(&mut output, &state, ts)?;
+            last_image = output.len();
+        } else {
+            output.add_batch(false, ts, &out).context("writing batch")?;
+            match image_frequency {
+                None => (),
+                Some(freq) if output.len() - last_image < freq => (),
+                Some(_) => {
+                    write_image(&mut output, &state, ts)?;
+                    last_image = output.len();
+                }
+            }
+        }
+    }
+    output.flush()?;
+    Ok(())
+}
+
+/// Split `source` into several archive files, one for each globset
+/// in `outputs`. Each output will contain the data for the paths in
+/// `source` that match it's globset, including the matching part of
+/// every image, so the outputs are independently seekable. Batches,
+/// including images, with nothing for an output's paths are
+/// skipped. Path ids are preserved, and every output contains the
+/// mappings for it's paths.
+pub fn split(source: &Source, outputs: &[(GlobSet, PathBuf)]) -> Result<()> {
+    let paths = source.paths()?;
+    let mut writers: Vec<(FxHashSet<Id>, ArchiveWriter)> = vec![];
+    for (spec, dest) in outputs {
+        let mut output = create(dest)?;
+        let ids: FxHashSet<Id> = paths
+            .iter()
+            .filter_map(|(id, path)| if spec.is_match(path) { Some(*id) } else { None })
+            .collect();
+        output
+            .add_paths_with_ids(paths.iter().filter(|(id, _)| ids.contains(id)))
+            .context("adding paths")?;
+        writers.push((ids, output));
+    }
+    let mut cursor = Cursor::new();
+    loop {
+        let batches = source.archive.read_batches(&mut cursor, READ_BATCHES)?;
+        if batches.is_empty() {
+            break;
+        }
+        for (ts, image, batch) in batches.iter() {
+            for (ids, output) in writers.iter_mut() {
+                let mut out = BATCH_POOL.take();
+                out.extend(batch.iter().filter(|b| ids.contains(&b.0)).cloned());
+                if !out.is_empty() {
+                    output.add_batch(*image, *ts, &out).context("writing batch")?;
+                }
+            }
+        }
+    }
+    for (_, output) in writers.iter_mut() {
+        output.flush()?;
+    }
+    Ok(())
+}
+
+/// Write the part of `source` between `start` and `end` to
+/// `dest`. If `start` is bounded, then an image of the state at
+/// `start` is written before the first batch, so `dest` is
+/// independently seekable. Images in the range are preserved, and
+/// so are path ids.
+pub fn cut(
+    source: &Source,
+    dest: impl AsRef<FilePath>,
+    start: Bound<DateTime<Utc>>,
+    end: Bound<DateTime<Utc>>,
+) -> Result<()> {
+    let paths = source.paths()?;
+    let mut output = create(dest.as_ref())?;
+    output.add_paths_with_ids(paths.iter()).context("adding paths")?;
+    let mut cursor = Cursor::create_from(start, end, None);
+    let mut first = true;
+    loop {
+        let batches = source.archive.read_batches(&mut cursor, READ_BATCHES)?;
+        if batches.is_empty() {
+            break;
+        }
+        for (ts, image, batch) in batches.iter() {
+            if first && !image && start != Bound::Unbounded {
+                let init = Cursor::create_from(start, end, None);
+                let state = source.archive.build_image(None, &init)?;
+                let ts = *ts - chrono::Duration::microseconds(1);
+                write_image(&mut output, &state, ts)?;
+            }
+            first = false;
+            output.add_batch(*image, *ts, batch).context("writing batch")?;
+        }
+    }
+    output.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use netidx::{protocol::glob::Glob, subscriber::Value};
+    use std::fs;
+
+    #[test]
+    fn split_merge() {
+        let files =
+            ["test-reshard-src", "test-reshard-a", "test-reshard-b", "test-reshard-m"];
+        for f in files.iter() {
+            if FilePath::is_file(FilePath::new(f)) {
+                fs::remove_file(f).unwrap();
+            }
+        }
+        let (a, b) = (Path::from("/a/x"), Path::from("/b/y"));
+        {
+            let mut w = ArchiveWriter::open(files[0]).unwrap();
+            w.add_paths([&a, &b]).unwrap();
+            let (ida, idb) = (w.id_for_path(&a).unwrap(), w.id_for_path(&b).unwrap());
+            for i in 0..10u32 {
+                let mut batch = BATCH_POOL.take();
+                batch.push(BatchItem(ida, Event::Update(Value::U32(i))));
+                batch.push(BatchItem(idb, Event::Update(Value::U32(i * 2))));
+                w.add_batch(false, Utc::now(), &batch).unwrap();
+            }
+            // deltas for only /a/x, and an image with only /b/y
+            for i in 10..15u32 {
+                let mut batch = BATCH_POOL.take();
+                batch.push(BatchItem(ida, Event::Update(Value::U32(i))));
+                w.add_batch(false, Utc::now(), &batch).unwrap();
+            }
+            let mut image = BATCH_POOL.take();
+            image.push(BatchItem(idb, Event::Update(Value::U32(100))));
+            w.add_batch(true, Utc::now(), &image).unwrap();
+            w.flush().unwrap();
+        }
+        let globs = |g: &str| GlobSet::new(true, [Glob::new(g.into()).unwrap()]).unwrap();
+        let src = Source::open(files[0]).unwrap();
+        split(
+            &src,
+            &[(globs("/a/*"), files[1].into()), (globs("/b/*"), files[2].into())],
+        )
+        .unwrap();
+        let sa = Source::open(files[1]).unwrap();
+        let sb = Source::open(files[2]).unwrap();
+        assert_eq!(sa.paths().unwrap().len(), 1);
+        assert_eq!(sa.archive.delta_batches(), 15);
+        assert_eq!(sb.archive.delta_batches(), 10);
+        assert_eq!(sa.archive.image_batches(), 0);
+        assert_eq!(sb.archive.image_batches(), 1);
+        merge(&[sa, sb], files[3], None).unwrap();
+        let m = Source::open(files[3]).unwrap();
+        assert_eq!(m.paths().unwrap().len(), 2);
+        assert_eq!(m.archive.delta_batches(), 25);
+        let state = m
+            .archive
+            .build_image(
+                None,
+                &Cursor::create_from(
+                    Bound::Unbounded,
+                    Bound::Unbounded,
+                    Some(Utc::now()),
+                ),
+            )
+            .unwrap();
+        let ida = *m.archive.index().id_for_path(&a).unwrap();
+        let idb = *m.archive.index().id_for_path(&b).unwrap();
+        assert_eq!(state.get(&ida), Some(&Event::Update(Value::U32(14))));
+        assert_eq!(state.get(&idb), Some(&Event::Update(Value::U32(100))));
+        drop((src, m));
+        for f in files.iter() {
+            fs::remove_file(f).unwrap();
+        }
+    }
+}
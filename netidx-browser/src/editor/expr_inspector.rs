@@ -7,7 +7,10 @@ use glib::idle_add_local_once;
 use glib::{clone, prelude::*, subclass::prelude::*, thread_guard::ThreadGuard};
 use gtk::{self, prelude::*};
 use netidx::subscriber::Value;
-use netidx_bscript::{expr, vm};
+use netidx_bscript::{
+    check::{Checker, Diagnostic, Severity},
+    expr, vm,
+};
 use parking_lot::Mutex;
 use sourceview4::{self as sv, prelude::*, traits::ViewExt};
 use std::{
@@ -224,6 +227,32 @@ impl ErrorDisplay {
         let m = glib::markup_escape_text(msg);
         self.error_body.set_markup(&format!("<span foreground='red'>{}</span>", m));
     }
+
+    fn display_diagnostics(&self, diags: &[Diagnostic]) {
+        if diags.is_empty() {
+            return self.clear();
+        }
+        let color = |d: &Diagnostic| match d.severity {
+            Severity::Error => "red",
+            Severity::Warning => "orange",
+        };
+        let lbl_color = if diags.iter().any(|d| d.severity == Severity::Error) {
+            "red"
+        } else {
+            "orange"
+        };
+        self.error_lbl
+            .set_markup(&format!("<span foreground='{}'>Errors</span>", lbl_color));
+        let body = diags
+            .iter()
+            .map(|d| {
+                let m = glib::markup_escape_text(&d.to_string());
+                format!("<span foreground='{}'>{}</span>", color(d), m)
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        self.error_body.set_markup(&body);
+    }
 }
 
 struct Tools {
@@ -247,15 +276,16 @@ impl Tools {
 
     fn display(&self, e: &expr::Expr) {
         self.data_flow.display(e);
-        self.error.clear()
+        self.check(e)
     }
 
     fn set_error(&self, msg: &str) {
         self.error.display(msg)
     }
 
-    fn clear_error(&self) {
-        self.error.clear()
+    fn check(&self, e: &expr::Expr) {
+        let diags = Checker::from_ctx(&self.data_flow.ctx.borrow()).check(e);
+        self.error.display_diagnostics(&diags)
     }
 }
 
@@ -314,9 +344,9 @@ impl ExprEditor {
                             save_button.set_sensitive(false);
                         },
                         Ok(e) => {
+                            tools.check(&e);
                             *expr.borrow_mut() = e;
                             save_button.set_sensitive(true);
-                            tools.clear_error();
                         },
                     }
                 }
@@ -0,0 +1,640 @@
+use crate::{
+    expr::{Expr, ExprId, ExprKind, Pattern},
+    testing::TestCtx,
+    vm::{Ctx, ExecCtx},
+};
+use fxhash::{FxHashMap, FxHashSet};
+use netidx::subscriber::Value;
+use std::{fmt, mem};
+
+/// What the checker knows about the type of an expression
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Any,
+    Number,
+    String,
+    Bool,
+    Array,
+    DateTime,
+    Duration,
+    Null,
+}
+
+impl Kind {
+    fn of_value(v: &Value) -> Self {
+        match v {
+            v if v.number() => Kind::Number,
+            Value::String(_) => Kind::String,
+            Value::True | Value::False => Kind::Bool,
+            Value::Array(_) => Kind::Array,
+            Value::DateTime(_) => Kind::DateTime,
+            Value::Duration(_) => Kind::Duration,
+            Value::Null => Kind::Null,
+            _ => Kind::Any,
+        }
+    }
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            Kind::Any => "any",
+            Kind::Number => "number",
+            Kind::String => "string",
+            Kind::Bool => "bool",
+            Kind::Array => "array",
+            Kind::DateTime => "datetime",
+            Kind::Duration => "duration",
+            Kind::Null => "null",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// The arguments a function accepts, and the kind of it's result
+#[derive(Debug, Clone)]
+pub struct Signature {
+    pub min: usize,
+    /// None if the function takes any number of arguments
+    pub max: Option<usize>,
+    /// the kind of each argument, the last kind also applies to any
+    /// further arguments
+    pub args: Vec<Kind>,
+    pub returns: Kind,
+    /// the position of an argument that is a function, and the
+    /// number of arguments it is called with
+    pub callback: Option<(usize, usize)>,
+}
+
+impl Signature {
+    /// a function that takes any arguments
+    pub fn any() -> Self {
+        Signature { min: 0, max: None, args: vec![], returns: Kind::Any, callback: None }
+    }
+
+    /// a function that takes `min` to `max` arguments of the kinds in
+    /// `args`, and returns a `returns`
+    pub fn new(min: usize, max: Option<usize>, args: &[Kind], returns: Kind) -> Self {
+        Signature { min, max, args: args.to_vec(), returns, callback: None }
+    }
+
+    /// argument `i` is a function that is called with `arity` arguments
+    pub fn callback(self, i: usize, arity: usize) -> Self {
+        Signature { callback: Some((i, arity)), ..self }
+    }
+
+    fn arg(&self, i: usize) -> Kind {
+        self.args.get(i).or(self.args.last()).copied().unwrap_or(Kind::Any)
+    }
+
+    fn arity(&self) -> String {
+        match self.max {
+            Some(max) if max == self.min => format!("{}", max),
+            Some(max) => format!("{} to {}", self.min, max),
+            None => format!("at least {}", self.min),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// A problem found by the checker. `expr` is the expression with the
+/// problem.
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub id: ExprId,
+    pub severity: Severity,
+    pub message: String,
+    pub expr: Expr,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        write!(f, "{}: {}, in {}", severity, self.message, self.expr)
+    }
+}
+
+// the name bound by a let or set
+fn binding(function: &str, args: &[Expr]) -> Option<String> {
+    if (function == "let" || function == "set") && args.len() == 2 && args[0].is_fn() {
+        match &args[0].kind {
+            ExprKind::Constant(Value::String(c)) => Some(String::from(&**c)),
+            _ => None,
+        }
+    } else {
+        None
+    }
+}
+
+fn params(args: &[Expr]) -> impl Iterator<Item = String> + '_ {
+    args[..args.len() - 1].iter().filter_map(|p| match &p.kind {
+        ExprKind::Constant(Value::String(c)) => Some(String::from(&**c)),
+        _ => None,
+    })
+}
+
+// a scope, and the names bound in it, with the number of arguments
+// of the ones that are functions
+#[derive(Default)]
+struct Scope {
+    parent: Option<usize>,
+    bound: FxHashMap<String, Option<usize>>,
+}
+
+// the scopes of an expression, the names bound in each of them, and
+// the uses of those names. Like the vm, the body of a lambda, and every
+// do block except the top level one, is a new scope. A let binds in
+// the scope it's in, a set binds in the closest scope that binds the
+// same name, or in the root scope if none does.
+struct Names {
+    scopes: Vec<Scope>,
+    // the scope each lambda and do block creates
+    created: FxHashMap<ExprId, usize>,
+    sets: Vec<(usize, String, Option<usize>)>,
+    lets: Vec<(usize, String, Expr)>,
+    used: FxHashSet<(usize, String)>,
+}
+
+impl Names {
+    fn new(top: &Expr) -> Self {
+        let mut t = Names {
+            scopes: vec![Scope::default()],
+            created: FxHashMap::default(),
+            sets: vec![],
+            lets: vec![],
+            used: FxHashSet::default(),
+        };
+        t.collect(top.id, 0, top);
+        for (scope, name, arity) in mem::take(&mut t.sets) {
+            let scope = t.resolve(scope, &name).unwrap_or(0);
+            let bound = t.scopes[scope].bound.entry(name).or_insert(None);
+            if arity.is_some() {
+                *bound = arity;
+            }
+        }
+        t.uses(0, top);
+        t
+    }
+
+    // the scope that binds name, visible from scope
+    fn resolve(&self, mut scope: usize, name: &str) -> Option<usize> {
+        loop {
+            if self.scopes[scope].bound.contains_key(name) {
+                break Some(scope);
+            }
+            scope = self.scopes[scope].parent?;
+        }
+    }
+
+    // the scope that binds the function name, visible from scope, and
+    // the number of arguments it takes
+    fn function(&self, mut scope: usize, name: &str) -> Option<(usize, usize)> {
+        loop {
+            if let Some(Some(arity)) = self.scopes[scope].bound.get(name) {
+                break Some((scope, *arity));
+            }
+            scope = self.scopes[scope].parent?;
+        }
+    }
+
+    fn collect(&mut self, top: ExprId, scope: usize, e: &Expr) {
+        let (args, function) = match &e.kind {
+            ExprKind::Constant(_) => return,
+            ExprKind::Apply { args, function } => (args, function),
+        };
+        let scope = if e.is_lambda() || (function == "do" && e.id != top) {
+            let new = self.scopes.len();
+            self.scopes.push(Scope { parent: Some(scope), bound: FxHashMap::default() });
+            self.created.insert(e.id, new);
+            new
+        } else {
+            scope
+        };
+        if let Some(name) = binding(function, args) {
+            let arity = match &args[1].kind {
+                ExprKind::Apply { args: l, .. } if args[1].is_lambda() => {
+                    Some(l.len() - 1)
+                }
+                _ => None,
+            };
+            if function == "let" {
+                self.scopes[scope].bound.insert(name.clone(), arity);
+                self.lets.push((scope, name, e.clone()));
+            } else {
+                self.sets.push((scope, name, arity));
+            }
+        } else if e.is_lambda() {
+            self.scopes[scope].bound.extend(params(args).map(|p| (p, None)));
+        }
+        for a in args {
+            self.collect(top, scope, a)
+        }
+    }
+
+    // a bare name may be a function, or a variable
+    fn use_name(&mut self, scope: usize, name: &str, variable: bool) {
+        let found = match self.function(scope, name) {
+            Some((scope, _)) => Some(scope),
+            None if variable => self.resolve(scope, name),
+            None => None,
+        };
+        if let Some(scope) = found {
+            self.used.insert((scope, String::from(name)));
+        }
+    }
+
+    fn uses(&mut self, scope: usize, e: &Expr) {
+        let (args, function) = match &e.kind {
+            ExprKind::Constant(_) => return,
+            ExprKind::Apply { args, function } => (args, function),
+        };
+        let scope = self.created.get(&e.id).copied().unwrap_or(scope);
+        if binding(function, args).is_some() || e.is_lambda() {
+            // the names bound here aren't uses
+        } else if function == "get" && args.len() == 1 && args[0].is_fn() {
+            if let ExprKind::Constant(Value::String(c)) = &args[0].kind {
+                self.use_name(scope, c, true)
+            }
+        } else {
+            self.use_name(scope, function, false);
+            // function names passed to higher order functions
+            for a in args {
+                if let ExprKind::Constant(Value::String(c)) = &a.kind {
+                    if a.is_fn() {
+                        self.use_name(scope, c, false)
+                    }
+                }
+            }
+        }
+        for a in args {
+            self.uses(scope, a)
+        }
+    }
+}
+
+/// Checks bscript expressions for unknown functions, wrong argument
+/// counts, unused and undefined variables, and arguments of the
+/// wrong kind. Names are resolved in the scope they are used in, so a
+/// variable bound in one lambda doesn't define it in another.
+#[derive(Debug, Clone)]
+pub struct Checker {
+    functions: FxHashMap<String, Signature>,
+    variables: FxHashSet<String>,
+}
+
+impl Checker {
+    /// a checker that knows the functions in the standard library
+    pub fn new() -> Self {
+        Self::from_ctx(&ExecCtx::<TestCtx, ()>::new(TestCtx::default()))
+    }
+
+    /// a checker that knows the functions registered in `ctx`, and the
+    /// variables already defined in it. Functions registered without
+    /// a signature accept any arguments.
+    pub fn from_ctx<C: Ctx + 'static, E: 'static>(ctx: &ExecCtx<C, E>) -> Self {
+        let mut functions = ctx.signatures.clone();
+        for name in ctx.functions.keys() {
+            if !functions.contains_key(name) {
+                functions.insert(name.clone(), Signature::any());
+            }
+        }
+        let mut variables = FxHashSet::default();
+        for vars in ctx.variables.values() {
+            variables.extend(vars.keys().map(|v| String::from(&**v)));
+        }
+        Checker { functions, variables }
+    }
+
+    /// add a function, or replace the signature of a builtin
+    pub fn add_fn(&mut self, name: String, sig: Signature) {
+        self.functions.insert(name, sig);
+    }
+
+    /// add a variable that is defined outside the checked expressions
+    pub fn add_var(&mut self, name: String) {
+        self.variables.insert(name);
+    }
+
+    /// check `expr` and return the problems found, if any
+    pub fn check(&self, expr: &Expr) -> Vec<Diagnostic> {
+        let names = Names::new(expr);
+        let mut diags = vec![];
+        self.check_expr(&names, 0, expr, &mut diags);
+        for (scope, name, e) in &names.lets {
+            if !name.starts_with('_') && !names.used.contains(&(*scope, name.clone())) {
+                diags.push(Diagnostic {
+                    id: e.id,
+                    severity: Severity::Warning,
+                    message: format!("unused variable {}", name),
+                    expr: e.clone(),
+                })
+            }
+        }
+        diags
+    }
+
+    // the kind of value expr produces
+    fn kind(&self, expr: &Expr) -> Kind {
+        match &expr.kind {
+            ExprKind::Constant(v) => Kind::of_value(v),
+            ExprKind::Apply { args, function } => match &**function {
+                "do" => args.last().map(|e| self.kind(e)).unwrap_or(Kind::Any),
+                f => match self.functions.get(f) {
+                    None => Kind::Any,
+                    Some(sig) => sig.returns,
+                },
+            },
+        }
+    }
+
+    fn check_expr(
+        &self,
+        names: &Names,
+        scope: usize,
+        expr: &Expr,
+        diags: &mut Vec<Diagnostic>,
+    ) {
+        let mut diag = |severity, message| {
+            diags.push(Diagnostic { id: expr.id, severity, message, expr: expr.clone() })
+        };
+        let (args, function) = match &expr.kind {
+            ExprKind::Constant(_) => return,
+            ExprKind::Apply { args, function } => (args, function),
+        };
+        let scope = names.created.get(&expr.id).copied().unwrap_or(scope);
+        // the argument that is a bare function name, it isn't a variable
+        let mut bare = None;
+        if expr.is_lambda() {
+            // a lambda isn't a call, only it's body needs checking
+        } else if let Some((_, arity)) = names.function(scope, function) {
+            if args.len() != arity {
+                diag(
+                    Severity::Error,
+                    format!(
+                        "{} expects {} arguments, found {}",
+                        function,
+                        arity,
+                        args.len()
+                    ),
+                )
+            }
+        } else {
+            match self.functions.get(function) {
+                // the functions in a module are only known once it's loaded
+                None if function.contains("::") => (),
+                None => diag(Severity::Error, format!("unknown function {}", function)),
+                Some(sig) => {
+                    let too_many = sig.max.map(|max| args.len() > max).unwrap_or(false);
+                    if args.len() < sig.min || too_many {
+                        diag(
+                            Severity::Error,
+                            format!(
+                                "{} expects {} arguments, found {}",
+                                function,
+                                sig.arity(),
+                                args.len()
+                            ),
+                        )
+                    }
+                    for (i, a) in args.iter().enumerate() {
+                        let (expected, found) = (sig.arg(i), self.kind(a));
+                        if expected != Kind::Any
+                            && found != Kind::Any
+                            && expected != found
+                        {
+                            diag(
+                                Severity::Warning,
+                                format!(
+                                    "argument {} of {} should be a {}, not a {}",
+                                    i + 1,
+                                    function,
+                                    expected,
+                                    found
+                                ),
+                            )
+                        }
+                    }
+                    if let Some((i, arity)) = sig.callback {
+                        if let Some(f) = args.get(i) {
+                            if self.check_callback(
+                                names, scope, function, f, arity, &mut diag,
+                            ) {
+                                bare = Some(i)
+                            }
+                        }
+                    }
+                }
+            }
+            if function == "match" {
+                // every third argument after the value is a pattern
+                for p in args.iter().skip(1).step_by(3) {
+                    if let ExprKind::Constant(Value::String(p)) = &p.kind {
+                        if p.parse::<Pattern>().is_err() {
+                            diag(Severity::Error, format!("invalid pattern {}", p))
+                        }
+                    }
+                }
+            }
+            if function == "get" && args.len() == 1 && args[0].is_fn() {
+                if let ExprKind::Constant(Value::String(c)) = &args[0].kind {
+                    if names.resolve(scope, c).is_none() && !self.variables.contains(&**c)
+                    {
+                        diag(Severity::Warning, format!("undefined variable {}", c))
+                    }
+                }
+            }
+        }
+        for (i, a) in args.iter().enumerate() {
+            if bare != Some(i) {
+                self.check_expr(names, scope, a, diags)
+            }
+        }
+    }
+
+    // check f, the function argument of function, which calls it with
+    // arity arguments. Returns true if f is a bare function name, e.g.
+    // the f in `map(a, f)`.
+    fn check_callback(
+        &self,
+        names: &Names,
+        scope: usize,
+        function: &str,
+        f: &Expr,
+        arity: usize,
+        diag: &mut impl FnMut(Severity, String),
+    ) -> bool {
+        let (found, bare) = match &f.kind {
+            ExprKind::Apply { args, .. } if f.is_lambda() => {
+                (Some(args.len() - 1), false)
+            }
+            ExprKind::Constant(Value::String(name)) => {
+                (self.fn_arity(names, scope, name, arity, diag), false)
+            }
+            ExprKind::Apply { args, function } if function == "get" => match &args[..] {
+                // a variable holding the name of a function
+                [Expr { kind: ExprKind::Constant(Value::String(name)), .. }]
+                    if names.function(scope, name).is_none()
+                        && !self.functions.contains_key(&**name)
+                        && (names.resolve(scope, name).is_some()
+                            || self.variables.contains(&**name)) =>
+                {
+                    (None, false)
+                }
+                [Expr { kind: ExprKind::Constant(Value::String(name)), .. }] => {
+                    (self.fn_arity(names, scope, name, arity, diag), true)
+                }
+                _ => (None, false),
+            },
+            _ => (None, false),
+        };
+        match found {
+            Some(n) if n != arity => diag(
+                Severity::Error,
+                format!(
+                    "{} calls it's function argument with {} arguments, not {}",
+                    function, arity, n
+                ),
+            ),
+            Some(_) | None => (),
+        }
+        bare
+    }
+
+    // the number of arguments the function name takes, if it is known
+    // and can't be called with arity arguments
+    fn fn_arity(
+        &self,
+        names: &Names,
+        scope: usize,
+        name: &str,
+        arity: usize,
+        diag: &mut impl FnMut(Severity, String),
+    ) -> Option<usize> {
+        match names.function(scope, name) {
+            Some((_, n)) => Some(n),
+            None => match self.functions.get(name) {
+                None if name.contains("::") => None,
+                None => {
+                    diag(Severity::Error, format!("unknown function {}", name));
+                    None
+                }
+                Some(sig) => match sig.max {
+                    Some(max) if arity > max || arity < sig.min => Some(max),
+                    Some(_) | None => None,
+                },
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn messages(s: &str) -> Vec<String> {
+        let e = s.parse::<Expr>().unwrap();
+        Checker::new().check(&e).into_iter().map(|d| d.message).collect()
+    }
+
+    #[test]
+    fn functions() {
+        assert_eq!(messages("sum(1, 2)"), Vec::<String>::new());
+        assert_eq!(messages("smu(1, 2)"), vec!["unknown function smu"]);
+        assert_eq!(
+            messages("trim(\"a\", \"b\")"),
+            vec!["trim expects 1 arguments, found 2"]
+        );
+        assert_eq!(
+            messages("{ let f <- |x, y| x + y; f(1) }"),
+            vec!["f expects 2 arguments, found 1"]
+        );
+        assert_eq!(
+            messages("map([1, 2], |x, y| x)"),
+            vec!["map calls it's function argument with 1 arguments, not 2"]
+        );
+        assert_eq!(
+            messages("sqrt(\"four\")"),
+            vec!["argument 1 of sqrt should be a number, not a string"]
+        );
+    }
+
+    #[test]
+    fn variables() {
+        assert_eq!(messages("{ let x <- 1; x + 1 }"), Vec::<String>::new());
+        assert_eq!(messages("{ let x <- 1; 2 }"), vec!["unused variable x"]);
+        assert_eq!(messages("y + 1"), vec!["undefined variable y"]);
+        assert_eq!(messages("{ y <- 1; y }"), Vec::<String>::new());
+        assert_eq!(
+            messages("{ import \"/lib/util\" as util; util::f(util::y) }"),
+            Vec::<String>::new()
+        );
+        assert_eq!(
+            messages("{ import \"/lib/util\" as util; f(y) }"),
+            vec!["unknown function f", "undefined variable y"]
+        );
+    }
+
+    #[test]
+    fn scopes() {
+        assert_eq!(
+            messages("{ let f <- |x| x + 1; let g <- |y| x + y; f(1) + g(2) }"),
+            vec!["undefined variable x"]
+        );
+        assert_eq!(messages("{ { let x <- 1; x }; x }"), vec!["undefined variable x"]);
+        assert_eq!(
+            messages("{ let f <- |v| { total <- v }; f(1); total }"),
+            Vec::<String>::new()
+        );
+        assert_eq!(
+            messages("{ let x <- 1; let f <- |y| { let x <- y; x }; f(2) }"),
+            vec!["unused variable x"]
+        );
+        assert_eq!(
+            messages("{ let f <- |a| { let g <- |x| x; g(a) }; g(1) + f(1) }"),
+            vec!["unknown function g"]
+        );
+    }
+
+    #[test]
+    fn bare_function_names() {
+        assert_eq!(messages("map([1, 2], sqrt)"), Vec::<String>::new());
+        assert_eq!(
+            messages("{ let inc <- |x| x + 1; map([1, 2], inc) }"),
+            Vec::<String>::new()
+        );
+        assert_eq!(
+            messages("{ let f <- \"sqrt\"; map([1, 2], f) }"),
+            Vec::<String>::new()
+        );
+        assert_eq!(
+            messages("map([1, 2], pow)"),
+            vec!["map calls it's function argument with 1 arguments, not 2"]
+        );
+        assert_eq!(messages("fold([1, 2], 0, add)"), Vec::<String>::new());
+        assert_eq!(messages("map([1, 2], nope)"), vec!["unknown function nope"]);
+    }
+
+    #[test]
+    fn patterns() {
+        assert_eq!(
+            messages("match load(\"/v\") { [x, ..rest] if x > 0 => rest, _ => [] }"),
+            Vec::<String>::new()
+        );
+        assert_eq!(
+            messages("match(1, \"float(x)\", |x| true, |x| x)"),
+            vec!["invalid pattern float(x)"]
+        );
+        assert_eq!(
+            messages("catch(load(\"/v\"), |e, f| e)"),
+            vec!["catch calls it's function argument with 1 arguments, not 2"]
+        );
+    }
+}
@@ -1,5 +1,9 @@
 use crate::parser;
-use netidx::{subscriber::Value, utils};
+use netidx::{
+    chars::Chars,
+    subscriber::{Typ, Value},
+    utils,
+};
 use regex::Regex;
 use serde::{
     de::{self, Visitor},
@@ -14,6 +18,9 @@ use std::{
 
 lazy_static! {
     pub static ref VNAME: Regex = Regex::new("^[a-z][a-z0-9_]*$").unwrap();
+    /// a name, optionally qualified by the module it's defined in
+    pub static ref QNAME: Regex =
+        Regex::new("^([a-z][a-z0-9_]*::)?[a-z][a-z0-9_]*$").unwrap();
 }
 
 atomic_id!(ExprId);
@@ -52,10 +59,13 @@ impl ExprKind {
                     buf.push_str(&*tmp);
                     Ok(())
                 } else {
-                    if function == "string_concat" {
+                    if function == "string_concat"
+                        || binop(function, args).is_some()
+                        || is_import(function, args)
+                    {
                         buf.push_str(&*tmp);
                         Ok(())
-                    } else if function == "get" && args.len() == 1 && args[0].is_fn() {
+                    } else if function == "get" && args.len() == 1 && args[0].is_qname() {
                         buf.push_str(&*tmp);
                         Ok(())
                     } else if (function == "set" || function == "let")
@@ -71,6 +81,30 @@ impl ExprKind {
                             }
                             _ => unreachable!(),
                         }
+                    } else if let Some(arms) = match_arms(function, args) {
+                        push_indent(indent, buf);
+                        writeln!(buf, "match {} {{", &args[0])?;
+                        for (i, (pat, guard, body)) in arms.iter().enumerate() {
+                            push_indent(indent + 2, buf);
+                            match guard {
+                                None => writeln!(buf, "{} =>", pat)?,
+                                Some(guard) => writeln!(buf, "{} if {} =>", pat, guard)?,
+                            }
+                            body.kind.pretty_print(indent + 4, limit, buf)?;
+                            if i < arms.len() - 1 {
+                                writeln!(buf, ",")?
+                            } else {
+                                writeln!(buf, "")?
+                            }
+                        }
+                        push_indent(indent, buf);
+                        writeln!(buf, "{}", "}")
+                    } else if function == "lambda" && is_lambda(args) {
+                        let (body, params) = args.split_last().unwrap();
+                        push_indent(indent, buf);
+                        write_params(buf, params)?;
+                        writeln!(buf, "")?;
+                        body.kind.pretty_print(indent + 2, limit, buf)
                     } else if function == "do" {
                         push_indent(indent, buf);
                         writeln!(buf, "{}", "{")?;
@@ -117,6 +151,138 @@ impl ExprKind {
     }
 }
 
+// true if args are the params and body of a lambda expression
+fn is_lambda(args: &[Expr]) -> bool {
+    match args.split_last() {
+        None => false,
+        Some((_, params)) => params.iter().all(|p| p.is_fn()),
+    }
+}
+
+// true if the expression can be written as import "path" as name
+fn is_import(function: &str, args: &[Expr]) -> bool {
+    function == "import"
+        && args.len() == 2
+        && args[1].is_fn()
+        && match &args[0].kind {
+            ExprKind::Constant(Value::String(_)) => true,
+            ExprKind::Apply { function, args } => {
+                function == "string_concat" && args.len() > 0
+            }
+            ExprKind::Constant(_) => false,
+        }
+}
+
+// the body of e if it's a lambda whose parameters are the names
+// bound by pat
+fn arm_body<'a>(e: &'a Expr, pat: &Pattern) -> Option<&'a Expr> {
+    match &e.kind {
+        ExprKind::Apply { function, args } if function == "lambda" && is_lambda(args) => {
+            let (body, params) = args.split_last().unwrap();
+            let binds = pat.binds();
+            let same = params.len() == binds.len()
+                && params.iter().zip(binds.iter()).all(|(p, b)| match &p.kind {
+                    ExprKind::Constant(Value::String(p)) => p == b,
+                    _ => false,
+                });
+            if same {
+                Some(body)
+            } else {
+                None
+            }
+        }
+        ExprKind::Apply { .. } | ExprKind::Constant(_) => None,
+    }
+}
+
+// the pattern, guard, and body of each arm, if the expression can be
+// written as match e { pat if guard => body, ... }. An arm without a
+// guard has the guard true.
+fn match_arms<'a>(
+    function: &str,
+    args: &'a [Expr],
+) -> Option<Vec<(Pattern, Option<&'a Expr>, &'a Expr)>> {
+    if function != "match" || args.len() < 4 || (args.len() - 1) % 3 != 0 {
+        return None;
+    }
+    args[1..]
+        .chunks(3)
+        .map(|arm| match arm {
+            [Expr { kind: ExprKind::Constant(Value::String(p)), .. }, guard, body] => {
+                let pat = p.parse::<Pattern>().ok()?;
+                if pat.to_string() != **p {
+                    return None;
+                }
+                let guard = match arm_body(guard, &pat)? {
+                    Expr { kind: ExprKind::Constant(Value::True), .. } => None,
+                    guard => Some(guard),
+                };
+                let body = arm_body(body, &pat)?;
+                Some((pat, guard, body))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+// the infix operators, their function names, and their precedence
+static BINOPS: [(&str, &str, usize); 11] = [
+    ("eq", "==", 1),
+    ("ne", "!=", 1),
+    ("lt", "<", 1),
+    ("gt", ">", 1),
+    ("lte", "<=", 1),
+    ("gte", ">=", 1),
+    ("add", "+", 2),
+    ("sub", "-", 2),
+    ("mul", "*", 3),
+    ("div", "/", 3),
+    ("mod", "%", 3),
+];
+
+// the operator and precedence of function if it's an infix operator
+fn binop(function: &str, args: &[Expr]) -> Option<(&'static str, usize)> {
+    if args.len() != 2 {
+        None
+    } else {
+        BINOPS.iter().find(|(f, _, _)| *f == function).map(|(_, op, p)| (*op, *p))
+    }
+}
+
+// how tightly an expression binds when it is the operand of an
+// infix operator. let, set, and lambda extend as far to the right as
+// possible, so they bind the least.
+fn precedence(e: &Expr) -> usize {
+    match &e.kind {
+        ExprKind::Constant(_) => 4,
+        ExprKind::Apply { function, args } => match binop(function, args) {
+            Some((_, p)) => p,
+            None if (function == "set" || function == "let")
+                && args.len() == 2
+                && args[0].is_fn() =>
+            {
+                0
+            }
+            None if function == "lambda" && is_lambda(args) => 0,
+            None => 4,
+        },
+    }
+}
+
+fn write_params<W: Write>(w: &mut W, params: &[Expr]) -> fmt::Result {
+    write!(w, "|")?;
+    for i in 0..params.len() {
+        match &params[i].kind {
+            ExprKind::Constant(Value::String(c)) => write!(w, "{}", c)?,
+            _ => unreachable!(),
+        }
+        if i < params.len() - 1 {
+            write!(w, ", ")?
+        }
+    }
+    write!(w, "|")
+}
+
 impl fmt::Display for ExprKind {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
@@ -140,7 +306,7 @@ impl fmt::Display for ExprKind {
                         }
                     }
                     write!(f, "\"")
-                } else if function == "get" && args.len() == 1 && args[0].is_fn() {
+                } else if function == "get" && args.len() == 1 && args[0].is_qname() {
                     // constant variable load
                     match &args[0].kind {
                         ExprKind::Constant(Value::String(c)) => write!(f, "{}", c),
@@ -158,6 +324,44 @@ impl fmt::Display for ExprKind {
                         }
                         _ => unreachable!(),
                     }
+                } else if is_import(function, args) {
+                    match &args[1].kind {
+                        ExprKind::Constant(Value::String(c)) => {
+                            write!(f, "import {} as {}", &args[0], c)
+                        }
+                        _ => unreachable!(),
+                    }
+                } else if let Some(arms) = match_arms(function, args) {
+                    write!(f, "match {} {{", &args[0])?;
+                    for (i, (pat, guard, body)) in arms.iter().enumerate() {
+                        match guard {
+                            None => write!(f, "{} => {}", pat, body)?,
+                            Some(guard) => write!(f, "{} if {} => {}", pat, guard, body)?,
+                        }
+                        if i < arms.len() - 1 {
+                            write!(f, ", ")?
+                        }
+                    }
+                    write!(f, "{}", '}')
+                } else if let Some((op, p)) = binop(function, args) {
+                    // infix operator
+                    let (lhs, rhs) = (&args[0], &args[1]);
+                    if precedence(lhs) < p {
+                        write!(f, "({})", lhs)?
+                    } else {
+                        write!(f, "{}", lhs)?
+                    }
+                    write!(f, " {} ", op)?;
+                    if precedence(rhs) <= p {
+                        write!(f, "({})", rhs)
+                    } else {
+                        write!(f, "{}", rhs)
+                    }
+                } else if function == "lambda" && is_lambda(args) {
+                    // lambda
+                    let (body, params) = args.split_last().unwrap();
+                    write_params(f, params)?;
+                    write!(f, " {}", body)
                 } else if function == "do" {
                     // do block
                     write!(f, "{}", '{')?;
@@ -283,6 +487,22 @@ impl Expr {
         }
     }
 
+    /// true if the expression is a variable or function name,
+    /// optionally qualified by a module, `module::name`
+    pub fn is_qname(&self) -> bool {
+        match &self.kind {
+            ExprKind::Constant(Value::String(c)) => QNAME.is_match(&*c),
+            ExprKind::Constant(_) | ExprKind::Apply { .. } => false,
+        }
+    }
+
+    pub fn is_lambda(&self) -> bool {
+        match &self.kind {
+            ExprKind::Apply { args, function } => function == "lambda" && is_lambda(args),
+            ExprKind::Constant(_) => false,
+        }
+    }
+
     pub fn to_string_pretty(&self, col_limit: usize) -> String {
         self.kind.to_string_pretty(col_limit)
     }
@@ -302,6 +522,116 @@ impl FromStr for Expr {
     }
 }
 
+/// The kinds of value a pattern can test for, any `Typ`, any number,
+/// or an error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PatternTyp {
+    Typ(Typ),
+    Number,
+    Error,
+}
+
+impl fmt::Display for PatternTyp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PatternTyp::Typ(t) => write!(f, "{}", t),
+            PatternTyp::Number => write!(f, "number"),
+            PatternTyp::Error => write!(f, "error"),
+        }
+    }
+}
+
+impl FromStr for PatternTyp {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        match s {
+            "number" => Ok(PatternTyp::Number),
+            "error" => Ok(PatternTyp::Error),
+            s => Ok(PatternTyp::Typ(s.parse::<Typ>()?)),
+        }
+    }
+}
+
+/// A pattern in a match expression
+#[derive(Debug, Clone, PartialEq)]
+pub enum Pattern {
+    /// _, matches anything
+    Any,
+    /// a name, matches anything, and binds it to the name
+    Bind(Chars),
+    /// a literal value, matches an equal value of the same type,
+    /// numbers of any type are compared by value
+    Literal(Value),
+    /// e.g. i64(p), matches a value of the type that also matches
+    /// p. error(p) matches p against the error message.
+    Typ(PatternTyp, Box<Pattern>),
+    /// [p0, p1, ..rest], matches an array with an element for each
+    /// pattern, or, if there is a rest pattern, at least that many
+    /// elements, with the rest pattern matching the array of the
+    /// remaining elements.
+    Array(Vec<Pattern>, Option<Box<Pattern>>),
+}
+
+impl Pattern {
+    /// The names bound by the pattern, in order, without duplicates
+    pub fn binds(&self) -> Vec<Chars> {
+        fn walk(p: &Pattern, names: &mut Vec<Chars>) {
+            match p {
+                Pattern::Any | Pattern::Literal(_) => (),
+                Pattern::Bind(n) => {
+                    if !names.contains(n) {
+                        names.push(n.clone())
+                    }
+                }
+                Pattern::Typ(_, p) => walk(p, names),
+                Pattern::Array(elts, rest) => {
+                    for p in elts.iter().chain(rest.iter().map(|r| &**r)) {
+                        walk(p, names)
+                    }
+                }
+            }
+        }
+        let mut names = vec![];
+        walk(self, &mut names);
+        names
+    }
+}
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Pattern::Any => write!(f, "_"),
+            Pattern::Bind(n) => write!(f, "{}", n),
+            Pattern::Literal(v) => v.fmt_ext(f, &parser::BSCRIPT_ESC, true),
+            Pattern::Typ(t, p) => write!(f, "{}({})", t, p),
+            Pattern::Array(elts, rest) => {
+                write!(f, "[")?;
+                for i in 0..elts.len() {
+                    write!(f, "{}", &elts[i])?;
+                    if i < elts.len() - 1 || rest.is_some() {
+                        write!(f, ", ")?
+                    }
+                }
+                match rest.as_ref().map(|r| &**r) {
+                    None => (),
+                    Some(Pattern::Bind(n)) => write!(f, "..{}", n)?,
+                    Some(_) => write!(f, "..")?,
+                }
+                write!(f, "]")
+            }
+        }
+    }
+}
+
+impl FromStr for Pattern {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        parser::parse_pattern(s)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -401,6 +731,22 @@ mod tests {
             Just(String::from("store")),
             Just(String::from("set")),
             Just(String::from("let")),
+            Just(String::from("lambda")),
+            Just(String::from("import")),
+            Just(String::from("match")),
+            Just(String::from("try")),
+            Just(String::from("catch")),
+            Just(String::from("add")),
+            Just(String::from("sub")),
+            Just(String::from("mul")),
+            Just(String::from("div")),
+            Just(String::from("mod")),
+            Just(String::from("eq")),
+            Just(String::from("ne")),
+            Just(String::from("lt")),
+            Just(String::from("gt")),
+            Just(String::from("lte")),
+            Just(String::from("gte")),
         ]
     }
 
@@ -0,0 +1,980 @@
+use crate::{
+    check::{Kind, Signature},
+    stdfn::{CachedCur, CachedCurEval, CachedVals},
+};
+use netidx::{
+    chars::Chars,
+    subscriber::{Typ, Value},
+};
+use rust_decimal::{prelude::ToPrimitive, Decimal, RoundingStrategy};
+use std::cmp::Ordering;
+
+// An integer, whether it's type is signed, and whether it's type is
+// 64 bits wide
+fn int(v: &Value) -> Option<(i128, bool, bool)> {
+    match v {
+        Value::U32(i) | Value::V32(i) => Some((*i as i128, false, false)),
+        Value::I32(i) | Value::Z32(i) => Some((*i as i128, true, false)),
+        Value::U64(i) | Value::V64(i) => Some((*i as i128, false, true)),
+        Value::I64(i) | Value::Z64(i) => Some((*i as i128, true, true)),
+        _ => None,
+    }
+}
+
+// Build an integer of the type described by signed and wide, or an
+// error if it doesn't fit.
+fn to_int(i: i128, signed: bool, wide: bool) -> Value {
+    let v = match (signed, wide) {
+        (false, false) => u32::try_from(i).map(Value::U32).ok(),
+        (true, false) => i32::try_from(i).map(Value::I32).ok(),
+        (false, true) => u64::try_from(i).map(Value::U64).ok(),
+        (true, true) => i64::try_from(i).map(Value::I64).ok(),
+    };
+    v.unwrap_or_else(|| Value::Error(Chars::from("integer overflow")))
+}
+
+fn float(v: &Value) -> Option<f64> {
+    match v {
+        Value::F32(f) => Some(*f as f64),
+        Value::F64(f) => Some(*f),
+        Value::Decimal(d) => d.to_f64(),
+        v => int(v).map(|(i, _, _)| i as f64),
+    }
+}
+
+fn decimal(v: &Value) -> Result<Decimal, Value> {
+    let err = || Value::Error(Chars::from(format!("can't represent {} as a decimal", v)));
+    match v {
+        Value::Decimal(d) => Ok(*d),
+        Value::F32(f) => Decimal::try_from(*f).map_err(|_| err()),
+        Value::F64(f) => Decimal::try_from(*f).map_err(|_| err()),
+        v => match int(v) {
+            Some((i, _, _)) => Decimal::try_from_i128_with_scale(i, 0).map_err(|_| err()),
+            None => Err(err()),
+        },
+    }
+}
+
+fn is_decimal(l: &Value, r: &Value) -> bool {
+    match (l, r) {
+        (Value::Decimal(_), _) | (_, Value::Decimal(_)) => true,
+        (_, _) => false,
+    }
+}
+
+fn is_f32(l: &Value, r: &Value) -> bool {
+    match (l, r) {
+        (Value::F32(_), Value::F32(_)) => true,
+        (_, _) => false,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+}
+
+// Numbers of different types are promoted to the wider type,
+// integers follow the same promotion rules as `Value`, but overflow
+// is an error instead of wrapping around. Floats are F64 unless both
+// sides are F32, and dividing a float by zero is an error, as it is
+// for integers. Everything else is delegated to the operators of
+// `Value`.
+fn arith(op: Op, l: Value, r: Value) -> Value {
+    let div_zero = || Value::Error(Chars::from("can't divide by zero"));
+    match (&l, &r) {
+        (Value::Error(_), _) => return l,
+        (_, Value::Error(_)) => return r,
+        (_, _) => (),
+    }
+    if let (Some((li, ls, lw)), Some((ri, rs, rw))) = (int(&l), int(&r)) {
+        let res = match op {
+            Op::Add => li.checked_add(ri),
+            Op::Sub => li.checked_sub(ri),
+            Op::Mul => li.checked_mul(ri),
+            Op::Div => li.checked_div(ri),
+            Op::Mod => li.checked_rem(ri),
+        };
+        match res {
+            None if ri == 0 => div_zero(),
+            None => Value::Error(Chars::from("integer overflow")),
+            Some(i) => to_int(i, ls || rs, lw || rw || ls != rs),
+        }
+    } else if l.number() && r.number() && is_decimal(&l, &r) {
+        match (decimal(&l), decimal(&r)) {
+            (Err(e), _) | (_, Err(e)) => e,
+            (Ok(ld), Ok(rd)) => {
+                let res = match op {
+                    Op::Add => ld.checked_add(rd),
+                    Op::Sub => ld.checked_sub(rd),
+                    Op::Mul => ld.checked_mul(rd),
+                    Op::Div => ld.checked_div(rd),
+                    Op::Mod => ld.checked_rem(rd),
+                };
+                match res {
+                    None if rd.is_zero() => div_zero(),
+                    None => Value::Error(Chars::from("decimal overflow")),
+                    Some(d) => Value::Decimal(d),
+                }
+            }
+        }
+    } else if l.number() && r.number() {
+        let (lf, rf) = (float(&l).unwrap(), float(&r).unwrap());
+        if rf == 0. && (op == Op::Div || op == Op::Mod) {
+            return div_zero();
+        }
+        let res = match op {
+            Op::Add => lf + rf,
+            Op::Sub => lf - rf,
+            Op::Mul => lf * rf,
+            Op::Div => lf / rf,
+            Op::Mod => lf % rf,
+        };
+        if is_f32(&l, &r) {
+            Value::F32(res as f32)
+        } else {
+            Value::F64(res)
+        }
+    } else {
+        match op {
+            Op::Add => l + r,
+            Op::Sub => l - r,
+            Op::Mul => l * r,
+            Op::Div => l / r,
+            Op::Mod => Value::Error(Chars::from(format!(
+                "can't take the remainder of {} and {}",
+                l, r
+            ))),
+        }
+    }
+}
+
+fn eval_arith(from: &CachedVals, name: &str, op: Op) -> Option<Value> {
+    match &*from.0 {
+        [Some(l), Some(r)] => Some(arith(op, l.clone(), r.clone())),
+        [None, _] | [_, None] => None,
+        _ => Some(Value::Error(Chars::from(format!(
+            "{}(lhs, rhs): expected 2 arguments",
+            name
+        )))),
+    }
+}
+
+pub struct AddEv;
+
+impl CachedCurEval for AddEv {
+    fn eval(from: &CachedVals) -> Option<Value> {
+        eval_arith(from, "add", Op::Add)
+    }
+
+    fn name() -> &'static str {
+        "add"
+    }
+
+    fn signature() -> Signature {
+        Signature::new(2, Some(2), &[Kind::Any], Kind::Any)
+    }
+}
+
+pub type Add = CachedCur<AddEv>;
+
+pub struct SubEv;
+
+impl CachedCurEval for SubEv {
+    fn eval(from: &CachedVals) -> Option<Value> {
+        eval_arith(from, "sub", Op::Sub)
+    }
+
+    fn name() -> &'static str {
+        "sub"
+    }
+
+    fn signature() -> Signature {
+        Signature::new(2, Some(2), &[Kind::Any], Kind::Any)
+    }
+}
+
+pub type Sub = CachedCur<SubEv>;
+
+pub struct MulEv;
+
+impl CachedCurEval for MulEv {
+    fn eval(from: &CachedVals) -> Option<Value> {
+        eval_arith(from, "mul", Op::Mul)
+    }
+
+    fn name() -> &'static str {
+        "mul"
+    }
+
+    fn signature() -> Signature {
+        Signature::new(2, Some(2), &[Kind::Any], Kind::Any)
+    }
+}
+
+pub type Mul = CachedCur<MulEv>;
+
+pub struct DivEv;
+
+impl CachedCurEval for DivEv {
+    fn eval(from: &CachedVals) -> Option<Value> {
+        eval_arith(from, "div", Op::Div)
+    }
+
+    fn name() -> &'static str {
+        "div"
+    }
+
+    fn signature() -> Signature {
+        Signature::new(2, Some(2), &[Kind::Any], Kind::Any)
+    }
+}
+
+pub type Div = CachedCur<DivEv>;
+
+pub struct ModEv;
+
+impl CachedCurEval for ModEv {
+    fn eval(from: &CachedVals) -> Option<Value> {
+        eval_arith(from, "mod", Op::Mod)
+    }
+
+    fn name() -> &'static str {
+        "mod"
+    }
+
+    fn signature() -> Signature {
+        Signature::new(2, Some(2), &[Kind::Any], Kind::Any)
+    }
+}
+
+pub type Mod = CachedCur<ModEv>;
+
+// Compare numbers of different types without losing precision,
+// everything else is compared by `Value`.
+fn compare(l: &Value, r: &Value) -> Option<Ordering> {
+    if let (Some((li, _, _)), Some((ri, _, _))) = (int(l), int(r)) {
+        return Some(li.cmp(&ri));
+    }
+    if l.number() && r.number() && is_decimal(l, r) {
+        if let (Ok(ld), Ok(rd)) = (decimal(l), decimal(r)) {
+            return Some(ld.cmp(&rd));
+        }
+    }
+    l.partial_cmp(r)
+}
+
+fn equal(l: &Value, r: &Value) -> bool {
+    if l.number() && r.number() {
+        compare(l, r) == Some(Ordering::Equal)
+    } else {
+        l == r
+    }
+}
+
+fn eval_cmp(
+    from: &CachedVals,
+    name: &str,
+    f: impl Fn(&Value, &Value) -> bool,
+) -> Option<Value> {
+    match &*from.0 {
+        [Some(e @ Value::Error(_)), Some(_)] | [Some(_), Some(e @ Value::Error(_))] => {
+            Some(e.clone())
+        }
+        [Some(l), Some(r)] => Some(if f(l, r) { Value::True } else { Value::False }),
+        [None, _] | [_, None] => None,
+        _ => Some(Value::Error(Chars::from(format!(
+            "{}(lhs, rhs): expected 2 arguments",
+            name
+        )))),
+    }
+}
+
+pub struct EqEv;
+
+impl CachedCurEval for EqEv {
+    fn eval(from: &CachedVals) -> Option<Value> {
+        eval_cmp(from, "eq", equal)
+    }
+
+    fn name() -> &'static str {
+        "eq"
+    }
+
+    fn signature() -> Signature {
+        Signature::new(2, Some(2), &[Kind::Any], Kind::Bool)
+    }
+}
+
+pub type Eq = CachedCur<EqEv>;
+
+pub struct NeEv;
+
+impl CachedCurEval for NeEv {
+    fn eval(from: &CachedVals) -> Option<Value> {
+        eval_cmp(from, "ne", |l, r| !equal(l, r))
+    }
+
+    fn name() -> &'static str {
+        "ne"
+    }
+
+    fn signature() -> Signature {
+        Signature::new(2, Some(2), &[Kind::Any], Kind::Bool)
+    }
+}
+
+pub type Ne = CachedCur<NeEv>;
+
+pub struct LtEv;
+
+impl CachedCurEval for LtEv {
+    fn eval(from: &CachedVals) -> Option<Value> {
+        eval_cmp(from, "lt", |l, r| compare(l, r) == Some(Ordering::Less))
+    }
+
+    fn name() -> &'static str {
+        "lt"
+    }
+
+    fn signature() -> Signature {
+        Signature::new(2, Some(2), &[Kind::Any], Kind::Bool)
+    }
+}
+
+pub type Lt = CachedCur<LtEv>;
+
+pub struct GtEv;
+
+impl CachedCurEval for GtEv {
+    fn eval(from: &CachedVals) -> Option<Value> {
+        eval_cmp(from, "gt", |l, r| compare(l, r) == Some(Ordering::Greater))
+    }
+
+    fn name() -> &'static str {
+        "gt"
+    }
+
+    fn signature() -> Signature {
+        Signature::new(2, Some(2), &[Kind::Any], Kind::Bool)
+    }
+}
+
+pub type Gt = CachedCur<GtEv>;
+
+pub struct LteEv;
+
+impl CachedCurEval for LteEv {
+    fn eval(from: &CachedVals) -> Option<Value> {
+        eval_cmp(from, "lte", |l, r| match compare(l, r) {
+            Some(Ordering::Less | Ordering::Equal) => true,
+            Some(Ordering::Greater) | None => false,
+        })
+    }
+
+    fn name() -> &'static str {
+        "lte"
+    }
+
+    fn signature() -> Signature {
+        Signature::new(2, Some(2), &[Kind::Any], Kind::Bool)
+    }
+}
+
+pub type Lte = CachedCur<LteEv>;
+
+pub struct GteEv;
+
+impl CachedCurEval for GteEv {
+    fn eval(from: &CachedVals) -> Option<Value> {
+        eval_cmp(from, "gte", |l, r| match compare(l, r) {
+            Some(Ordering::Greater | Ordering::Equal) => true,
+            Some(Ordering::Less) | None => false,
+        })
+    }
+
+    fn name() -> &'static str {
+        "gte"
+    }
+
+    fn signature() -> Signature {
+        Signature::new(2, Some(2), &[Kind::Any], Kind::Bool)
+    }
+}
+
+pub type Gte = CachedCur<GteEv>;
+
+pub struct AbsEv;
+
+impl CachedCurEval for AbsEv {
+    fn eval(from: &CachedVals) -> Option<Value> {
+        match &*from.0 {
+            [None] => None,
+            [Some(v)] => Some(match v {
+                Value::F32(f) => Value::F32(f.abs()),
+                Value::F64(f) => Value::F64(f.abs()),
+                Value::Decimal(d) => Value::Decimal(d.abs()),
+                v => match int(v) {
+                    Some((i, signed, wide)) => to_int(i.abs(), signed, wide),
+                    None => Value::Error(Chars::from(format!(
+                        "abs(x): expected a number, not {}",
+                        v
+                    ))),
+                },
+            }),
+            _ => Some(Value::Error(Chars::from("abs(x): expected 1 argument"))),
+        }
+    }
+
+    fn name() -> &'static str {
+        "abs"
+    }
+
+    fn signature() -> Signature {
+        Signature::new(1, Some(1), &[Kind::Number], Kind::Number)
+    }
+}
+
+pub type Abs = CachedCur<AbsEv>;
+
+// Apply a rounding function, integers are already round
+fn eval_round(
+    from: &CachedVals,
+    name: &str,
+    ff: impl Fn(f64) -> f64,
+    fd: impl Fn(&Decimal) -> Decimal,
+) -> Option<Value> {
+    match &*from.0 {
+        [None] => None,
+        [Some(v)] => Some(match v {
+            Value::F32(f) => Value::F32(ff(*f as f64) as f32),
+            Value::F64(f) => Value::F64(ff(*f)),
+            Value::Decimal(d) => Value::Decimal(fd(d)),
+            v if int(v).is_some() => v.clone(),
+            v => Value::Error(Chars::from(format!(
+                "{}(x): expected a number, not {}",
+                name, v
+            ))),
+        }),
+        _ => Some(Value::Error(Chars::from(format!("{}(x): expected 1 argument", name)))),
+    }
+}
+
+pub struct FloorEv;
+
+impl CachedCurEval for FloorEv {
+    fn eval(from: &CachedVals) -> Option<Value> {
+        eval_round(from, "floor", f64::floor, Decimal::floor)
+    }
+
+    fn name() -> &'static str {
+        "floor"
+    }
+
+    fn signature() -> Signature {
+        Signature::new(1, Some(1), &[Kind::Number], Kind::Number)
+    }
+}
+
+pub type Floor = CachedCur<FloorEv>;
+
+pub struct CeilEv;
+
+impl CachedCurEval for CeilEv {
+    fn eval(from: &CachedVals) -> Option<Value> {
+        eval_round(from, "ceil", f64::ceil, Decimal::ceil)
+    }
+
+    fn name() -> &'static str {
+        "ceil"
+    }
+
+    fn signature() -> Signature {
+        Signature::new(1, Some(1), &[Kind::Number], Kind::Number)
+    }
+}
+
+pub type Ceil = CachedCur<CeilEv>;
+
+/// round(x, [digits]), round half away from zero to `digits` places
+/// after the decimal point, 0 if digits is omitted.
+pub struct RoundEv;
+
+impl CachedCurEval for RoundEv {
+    fn eval(from: &CachedVals) -> Option<Value> {
+        let digits = match &*from.0 {
+            [_] => 0,
+            [_, None] => return None,
+            [_, Some(d)] => match d.clone().cast_to::<u32>() {
+                Ok(d) => d,
+                Err(_) => {
+                    return Some(Value::Error(Chars::from(
+                        "round(x, digits): expected digits to be a positive integer",
+                    )))
+                }
+            },
+            _ => {
+                return Some(Value::Error(Chars::from(
+                    "round(x, [digits]): expected 1 or 2 arguments",
+                )))
+            }
+        };
+        // past 10^308 the scale is infinite, but no float has a digit
+        // that far after the point, so it is already rounded.
+        let scale = 10f64.powi(digits.min(400) as i32);
+        let x = CachedVals(from.0[0..1].to_vec());
+        eval_round(
+            &x,
+            "round",
+            |f| {
+                let scaled = f * scale;
+                if scaled.is_finite() {
+                    scaled.round() / scale
+                } else {
+                    f
+                }
+            },
+            |d| d.round_dp_with_strategy(digits, RoundingStrategy::MidpointAwayFromZero),
+        )
+    }
+
+    fn name() -> &'static str {
+        "round"
+    }
+
+    fn signature() -> Signature {
+        Signature::new(1, Some(2), &[Kind::Number], Kind::Number)
+    }
+}
+
+pub type Round = CachedCur<RoundEv>;
+
+/// clamp(x, min, max), the result has the type of x
+pub struct ClampEv;
+
+impl CachedCurEval for ClampEv {
+    fn eval(from: &CachedVals) -> Option<Value> {
+        match &*from.0 {
+            [Some(x), Some(min), Some(max)] => {
+                if compare(min, max) == Some(Ordering::Greater) {
+                    Some(Value::Error(Chars::from(
+                        "clamp(x, min, max): expected min <= max",
+                    )))
+                } else if compare(x, min) == Some(Ordering::Less) {
+                    min.clone().cast(Typ::get(x))
+                } else if compare(x, max) == Some(Ordering::Greater) {
+                    max.clone().cast(Typ::get(x))
+                } else {
+                    Some(x.clone())
+                }
+            }
+            [None, _, _] | [_, None, _] | [_, _, None] => None,
+            _ => Some(Value::Error(Chars::from(
+                "clamp(x, min, max): expected 3 arguments",
+            ))),
+        }
+    }
+
+    fn name() -> &'static str {
+        "clamp"
+    }
+
+    fn signature() -> Signature {
+        Signature::new(3, Some(3), &[Kind::Number], Kind::Number)
+    }
+}
+
+pub type Clamp = CachedCur<ClampEv>;
+
+// Apply a function that is only defined on floats. F32 arguments
+// produce an F32, any other number, including decimals, an F64.
+fn eval_float(
+    from: &CachedVals,
+    usage: &'static str,
+    f: impl Fn(&[f64]) -> f64,
+) -> Option<Value> {
+    let mut args = Vec::with_capacity(from.0.len());
+    let mut narrow = true;
+    for v in &from.0 {
+        match v {
+            None => return None,
+            Some(Value::F32(x)) => args.push(*x as f64),
+            Some(v) => match float(v) {
+                Some(x) => {
+                    narrow = false;
+                    args.push(x)
+                }
+                None => return Some(Value::Error(Chars::from(usage))),
+            },
+        }
+    }
+    if narrow {
+        Some(Value::F32(f(&args) as f32))
+    } else {
+        Some(Value::F64(f(&args)))
+    }
+}
+
+pub struct SqrtEv;
+
+impl CachedCurEval for SqrtEv {
+    fn eval(from: &CachedVals) -> Option<Value> {
+        match &*from.0 {
+            [_] => eval_float(from, "sqrt(x): expected a number", |a| a[0].sqrt()),
+            _ => Some(Value::Error(Chars::from("sqrt(x): expected 1 argument"))),
+        }
+    }
+
+    fn name() -> &'static str {
+        "sqrt"
+    }
+
+    fn signature() -> Signature {
+        Signature::new(1, Some(1), &[Kind::Number], Kind::Number)
+    }
+}
+
+pub type Sqrt = CachedCur<SqrtEv>;
+
+// exponentiation by squaring, None on overflow
+fn decimal_pow(mut x: Decimal, mut y: u64) -> Option<Decimal> {
+    let mut res = Decimal::ONE;
+    while y > 0 {
+        if y & 1 == 1 {
+            res = res.checked_mul(x)?;
+        }
+        y >>= 1;
+        if y > 0 {
+            x = x.checked_mul(x)?;
+        }
+    }
+    Some(res)
+}
+
+/// pow(x, y), an integer or decimal raised to a positive integer
+/// power is an integer or decimal, and overflow is an error.
+pub struct PowEv;
+
+impl CachedCurEval for PowEv {
+    fn eval(from: &CachedVals) -> Option<Value> {
+        match &*from.0 {
+            [Some(x), Some(y)] => match (int(x), int(y)) {
+                (Some((x, signed, wide)), Some((y, _, _))) if y >= 0 => {
+                    match u32::try_from(y).ok().and_then(|y| x.checked_pow(y)) {
+                        Some(i) => Some(to_int(i, signed, wide)),
+                        None => Some(Value::Error(Chars::from("integer overflow"))),
+                    }
+                }
+                (None, Some((y, _, _))) if y >= 0 && is_decimal(x, x) => {
+                    match decimal(x).ok().and_then(|x| decimal_pow(x, y as u64)) {
+                        Some(d) => Some(Value::Decimal(d)),
+                        None => Some(Value::Error(Chars::from("decimal overflow"))),
+                    }
+                }
+                (_, _) => {
+                    eval_float(from, "pow(x, y): expected numbers", |a| a[0].powf(a[1]))
+                }
+            },
+            [None, _] | [_, None] => None,
+            _ => Some(Value::Error(Chars::from("pow(x, y): expected 2 arguments"))),
+        }
+    }
+
+    fn name() -> &'static str {
+        "pow"
+    }
+
+    fn signature() -> Signature {
+        Signature::new(2, Some(2), &[Kind::Number], Kind::Number)
+    }
+}
+
+pub type Pow = CachedCur<PowEv>;
+
+/// log(x, [base]), the natural logarithm if base is omitted
+pub struct LogEv;
+
+impl CachedCurEval for LogEv {
+    fn eval(from: &CachedVals) -> Option<Value> {
+        let usage = "log(x, [base]): expected numbers";
+        match &*from.0 {
+            [_] => eval_float(from, usage, |a| a[0].ln()),
+            [_, _] => eval_float(from, usage, |a| a[0].log(a[1])),
+            _ => Some(Value::Error(Chars::from(
+                "log(x, [base]): expected 1 or 2 arguments",
+            ))),
+        }
+    }
+
+    fn name() -> &'static str {
+        "log"
+    }
+
+    fn signature() -> Signature {
+        Signature::new(1, Some(2), &[Kind::Number], Kind::Number)
+    }
+}
+
+pub type Log = CachedCur<LogEv>;
+
+pub struct ExpEv;
+
+impl CachedCurEval for ExpEv {
+    fn eval(from: &CachedVals) -> Option<Value> {
+        match &*from.0 {
+            [_] => eval_float(from, "exp(x): expected a number", |a| a[0].exp()),
+            _ => Some(Value::Error(Chars::from("exp(x): expected 1 argument"))),
+        }
+    }
+
+    fn name() -> &'static str {
+        "exp"
+    }
+
+    fn signature() -> Signature {
+        Signature::new(1, Some(1), &[Kind::Number], Kind::Number)
+    }
+}
+
+pub type Exp = CachedCur<ExpEv>;
+
+pub struct SinEv;
+
+impl CachedCurEval for SinEv {
+    fn eval(from: &CachedVals) -> Option<Value> {
+        match &*from.0 {
+            [_] => eval_float(from, "sin(x): expected a number", |a| a[0].sin()),
+            _ => Some(Value::Error(Chars::from("sin(x): expected 1 argument"))),
+        }
+    }
+
+    fn name() -> &'static str {
+        "sin"
+    }
+
+    fn signature() -> Signature {
+        Signature::new(1, Some(1), &[Kind::Number], Kind::Number)
+    }
+}
+
+pub type Sin = CachedCur<SinEv>;
+
+pub struct CosEv;
+
+impl CachedCurEval for CosEv {
+    fn eval(from: &CachedVals) -> Option<Value> {
+        match &*from.0 {
+            [_] => eval_float(from, "cos(x): expected a number", |a| a[0].cos()),
+            _ => Some(Value::Error(Chars::from("cos(x): expected 1 argument"))),
+        }
+    }
+
+    fn name() -> &'static str {
+        "cos"
+    }
+
+    fn signature() -> Signature {
+        Signature::new(1, Some(1), &[Kind::Number], Kind::Number)
+    }
+}
+
+pub type Cos = CachedCur<CosEv>;
+
+pub struct TanEv;
+
+impl CachedCurEval for TanEv {
+    fn eval(from: &CachedVals) -> Option<Value> {
+        match &*from.0 {
+            [_] => eval_float(from, "tan(x): expected a number", |a| a[0].tan()),
+            _ => Some(Value::Error(Chars::from("tan(x): expected 1 argument"))),
+        }
+    }
+
+    fn name() -> &'static str {
+        "tan"
+    }
+
+    fn signature() -> Signature {
+        Signature::new(1, Some(1), &[Kind::Number], Kind::Number)
+    }
+}
+
+pub type Tan = CachedCur<TanEv>;
+
+pub struct AsinEv;
+
+impl CachedCurEval for AsinEv {
+    fn eval(from: &CachedVals) -> Option<Value> {
+        match &*from.0 {
+            [_] => eval_float(from, "asin(x): expected a number", |a| a[0].asin()),
+            _ => Some(Value::Error(Chars::from("asin(x): expected 1 argument"))),
+        }
+    }
+
+    fn name() -> &'static str {
+        "asin"
+    }
+
+    fn signature() -> Signature {
+        Signature::new(1, Some(1), &[Kind::Number], Kind::Number)
+    }
+}
+
+pub type Asin = CachedCur<AsinEv>;
+
+pub struct AcosEv;
+
+impl CachedCurEval for AcosEv {
+    fn eval(from: &CachedVals) -> Option<Value> {
+        match &*from.0 {
+            [_] => eval_float(from, "acos(x): expected a number", |a| a[0].acos()),
+            _ => Some(Value::Error(Chars::from("acos(x): expected 1 argument"))),
+        }
+    }
+
+    fn name() -> &'static str {
+        "acos"
+    }
+
+    fn signature() -> Signature {
+        Signature::new(1, Some(1), &[Kind::Number], Kind::Number)
+    }
+}
+
+pub type Acos = CachedCur<AcosEv>;
+
+/// atan(x), or atan(y, x), the four quadrant arctangent of y / x
+pub struct AtanEv;
+
+impl CachedCurEval for AtanEv {
+    fn eval(from: &CachedVals) -> Option<Value> {
+        let usage = "atan(x) or atan(y, x): expected numbers";
+        match &*from.0 {
+            [_] => eval_float(from, usage, |a| a[0].atan()),
+            [_, _] => eval_float(from, usage, |a| a[0].atan2(a[1])),
+            _ => Some(Value::Error(Chars::from("atan: expected 1 or 2 arguments"))),
+        }
+    }
+
+    fn name() -> &'static str {
+        "atan"
+    }
+
+    fn signature() -> Signature {
+        Signature::new(1, Some(2), &[Kind::Number], Kind::Number)
+    }
+}
+
+pub type Atan = CachedCur<AtanEv>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn err(s: &str) -> Value {
+        Value::Error(Chars::from(String::from(s)))
+    }
+
+    // Value's == compares numbers of different types by value
+    #[track_caller]
+    fn check(op: Op, l: Value, r: Value, expected: Value) {
+        let res = arith(op, l, r);
+        assert_eq!(Typ::get(&res), Typ::get(&expected), "{} is not a {}", res, expected);
+        assert_eq!(res, expected)
+    }
+
+    #[test]
+    fn overflow() {
+        let overflow = || err("integer overflow");
+        check(Op::Add, Value::I64(i64::MAX), Value::I64(1), overflow());
+        check(Op::Mul, Value::I64(i64::MIN), Value::I64(2), overflow());
+        check(Op::Div, Value::I64(i64::MIN), Value::I64(-1), overflow());
+        check(Op::Add, Value::I32(i32::MAX), Value::I32(1), overflow());
+        check(Op::Sub, Value::U32(0), Value::U32(1), overflow());
+        check(Op::Sub, Value::U64(1), Value::U64(2), overflow());
+        check(
+            Op::Add,
+            Value::U32(u32::MAX),
+            Value::U64(1),
+            Value::U64(u32::MAX as u64 + 1),
+        );
+        let div_zero = || err("can't divide by zero");
+        check(Op::Div, Value::I64(1), Value::I64(0), div_zero());
+        check(Op::Mod, Value::U32(1), Value::U32(0), div_zero());
+        let max = Value::Decimal(Decimal::MAX);
+        check(Op::Add, max.clone(), max, err("decimal overflow"));
+        let one = Value::Decimal(Decimal::ONE);
+        check(Op::Div, one, Value::Decimal(Decimal::ZERO), div_zero());
+        check(Op::Div, Value::F64(1.), Value::F64(0.), div_zero());
+        check(Op::Div, Value::F32(1.), Value::I64(0), div_zero());
+        check(Op::Mod, Value::F64(1.), Value::F32(-0.), div_zero());
+        check(Op::Mod, Value::I64(1), Value::F64(0.), div_zero());
+    }
+
+    #[test]
+    fn decimal_arith() {
+        let d = |i: i64, scale: u32| Value::Decimal(Decimal::new(i, scale));
+        check(Op::Add, d(11, 1), d(22, 1), d(33, 1));
+        check(Op::Add, d(11, 1), Value::I64(1), d(21, 1));
+        check(Op::Mul, Value::U32(3), d(5, 1), d(15, 1));
+        check(Op::Sub, d(1, 0), Value::F64(0.25), d(75, 2));
+        check(Op::Div, d(1, 0), d(4, 0), d(25, 2));
+        check(Op::Mod, d(75, 1), d(2, 0), d(15, 1));
+        let nan = arith(Op::Add, d(1, 0), Value::F64(f64::NAN));
+        assert!(matches!(nan, Value::Error(_)), "{}", nan);
+        assert_eq!(compare(&d(1, 1), &Value::I64(0)), Some(Ordering::Greater));
+        assert!(equal(&d(10, 1), &Value::I64(1)));
+        assert!(!equal(&d(11, 1), &Value::I64(1)));
+    }
+
+    #[test]
+    fn promotion() {
+        check(Op::Add, Value::I32(1), Value::I32(2), Value::I32(3));
+        check(Op::Add, Value::U32(1), Value::U64(2), Value::U64(3));
+        check(Op::Add, Value::U32(1), Value::I32(-2), Value::I64(-1));
+        check(Op::Add, Value::V32(1), Value::Z64(-2), Value::I64(-1));
+        check(Op::Mul, Value::F32(2.), Value::F32(1.5), Value::F32(3.));
+        check(Op::Mul, Value::I64(2), Value::F32(1.5), Value::F64(3.));
+        check(Op::Add, Value::U32(1), Value::F32(0.5), Value::F64(1.5));
+        check(
+            Op::Add,
+            Value::I64(1 << 40),
+            Value::F32(1.),
+            Value::F64(((1u64 << 40) + 1) as f64),
+        );
+        check(Op::Mul, Value::I32(2), Value::F64(1.5), Value::F64(3.));
+        check(Op::Add, Value::F32(0.5), Value::F64(0.25), Value::F64(0.75));
+        check(Op::Mod, Value::F64(7.5), Value::I64(2), Value::F64(1.5));
+        let e = err("boom");
+        check(Op::Add, e.clone(), Value::I64(1), e.clone());
+        check(Op::Add, Value::I64(1), e.clone(), e);
+        assert_eq!(
+            compare(&Value::U64(u64::MAX), &Value::I64(-1)),
+            Some(Ordering::Greater)
+        );
+        assert_eq!(compare(&Value::I64(-1), &Value::U32(0)), Some(Ordering::Less));
+        assert!(equal(&Value::I64(1), &Value::F64(1.)));
+        assert!(equal(&Value::U32(7), &Value::I64(7)));
+    }
+
+    #[test]
+    fn round() {
+        let round = |x: Value, digits: u32| {
+            RoundEv::eval(&CachedVals(vec![Some(x), Some(Value::U32(digits))])).unwrap()
+        };
+        assert_eq!(round(Value::F64(1.2345), 2), Value::F64(1.23));
+        assert_eq!(round(Value::F64(-1.5), 0), Value::F64(-2.));
+        assert_eq!(round(Value::F64(1.2345), 400), Value::F64(1.2345));
+        assert_eq!(round(Value::F64(1e300), 10), Value::F64(1e300));
+        assert_eq!(round(Value::F64(0.), u32::MAX), Value::F64(0.));
+        assert_eq!(round(Value::F32(1.25), 1), Value::F32(1.3));
+        let d = Value::Decimal(Decimal::new(12345, 4));
+        assert_eq!(round(d.clone(), 2), Value::Decimal(Decimal::new(123, 2)));
+        assert_eq!(round(d.clone(), u32::MAX), d);
+        assert_eq!(round(Value::I64(7), 3), Value::I64(7));
+    }
+}
@@ -1,17 +1,17 @@
-use crate::expr::{Expr, ExprId, ExprKind};
+use crate::expr::{Expr, ExprId, ExprKind, Pattern, PatternTyp};
 use combine::{
-    attempt, between, choice, many,
+    attempt, between, chainl1, choice, eof, many, none_of, not_followed_by, optional,
     parser::{
         char::{spaces, string},
         combinator::recognize,
         range::{take_while, take_while1},
     },
-    sep_by,
+    satisfy, sep_by, sep_by1,
     stream::{position, Range},
     token, unexpected_any, value, EasyParser, ParseError, Parser, RangeStream,
 };
 use netidx::{chars::Chars, publisher::Value};
-use netidx_netproto::value_parser::{close_expr, escaped_string, value as netidx_value};
+use netidx_netproto::value_parser::{escaped_string, value as netidx_value};
 
 pub static BSCRIPT_ESC: [char; 4] = ['"', '\\', '[', ']'];
 
@@ -36,6 +36,52 @@ where
     })
 }
 
+// the end of a name or a keyword. Unlike in a netidx value, an infix
+// operator may follow immediately, e.g. x+1 or true==y
+fn close_expr<I>() -> impl Parser<I, Output = ()>
+where
+    I: RangeStream<Token = char>,
+    I::Error: ParseError<I::Token, I::Range, I::Position>,
+    I::Range: Range,
+{
+    not_followed_by(none_of([
+        ' ', '\n', '\t', ';', ')', ',', ']', '}', '"', '+', '-', '*', '/', '%', '=', '!',
+        '<', '>',
+    ]))
+}
+
+// the constants written as keywords
+fn keyword<I>() -> impl Parser<I, Output = Value>
+where
+    I: RangeStream<Token = char>,
+    I::Error: ParseError<I::Token, I::Range, I::Position>,
+    I::Range: Range,
+{
+    choice((
+        string("true").map(|_| Value::True),
+        string("false").map(|_| Value::False),
+        string("null").map(|_| Value::Null),
+        string("ok").map(|_| Value::Ok),
+    ))
+    .skip(close_expr())
+}
+
+// a name, optionally qualified by the module it's defined in,
+// module::name
+fn qname<I>() -> impl Parser<I, Output = String>
+where
+    I: RangeStream<Token = char>,
+    I::Error: ParseError<I::Token, I::Range, I::Position>,
+    I::Range: Range,
+{
+    (fname(), optional(attempt(string("::").with(fname())))).map(
+        |(module, name): (String, Option<String>)| match name {
+            None => module,
+            Some(name) => format!("{}::{}", module, name),
+        },
+    )
+}
+
 fn interpolated_<I>() -> impl Parser<I, Output = Expr>
 where
     I: RangeStream<Token = char>,
@@ -119,13 +165,160 @@ parser! {
     }
 }
 
-fn expr_<I>() -> impl Parser<I, Output = Expr>
+fn is_name_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+fn pattern_<I>() -> impl Parser<I, Output = Pattern>
+where
+    I: RangeStream<Token = char>,
+    I::Error: ParseError<I::Token, I::Range, I::Position>,
+    I::Range: Range,
+{
+    enum Elt {
+        Pat(Pattern),
+        Rest(Pattern),
+    }
+    let elt = choice((
+        attempt(string("..").with(optional(fname()))).map(|name| {
+            Elt::Rest(match name {
+                None => Pattern::Any,
+                Some(name) => Pattern::Bind(Chars::from(name)),
+            })
+        }),
+        pattern().map(Elt::Pat),
+    ));
+    spaces().with(choice((
+        attempt(token('_').skip(not_followed_by(satisfy(is_name_char))))
+            .map(|_| Pattern::Any),
+        attempt(
+            between(
+                token('['),
+                spaces().with(token(']')),
+                spaces()
+                    .with(sep_by(spaces().with(elt), attempt(spaces().with(token(','))))),
+            )
+            .then(|elts: Vec<Elt>| {
+                let mut pats = vec![];
+                let mut rest = None;
+                for elt in elts {
+                    match elt {
+                        Elt::Pat(_) | Elt::Rest(_) if rest.is_some() => {
+                            return unexpected_any("..rest must be the last element")
+                                .right()
+                        }
+                        Elt::Pat(p) => pats.push(p),
+                        Elt::Rest(p) => rest = Some(Box::new(p)),
+                    }
+                }
+                value(Pattern::Array(pats, rest)).left()
+            }),
+        ),
+        attempt(
+            (
+                recognize(take_while1(|c: char| c.is_lowercase() || c.is_numeric())),
+                between(spaces().with(token('(')), spaces().with(token(')')), pattern()),
+            )
+                .then(|(typ, p): (String, Pattern)| {
+                    match typ.parse::<PatternTyp>() {
+                        Ok(typ) => value(Pattern::Typ(typ, Box::new(p))).left(),
+                        Err(_) => unexpected_any("unknown type").right(),
+                    }
+                }),
+        ),
+        attempt(netidx_value(&BSCRIPT_ESC).map(Pattern::Literal)),
+        fname().map(|name| Pattern::Bind(Chars::from(name))),
+    )))
+}
+
+parser! {
+    fn pattern[I]()(I) -> Pattern
+    where [I: RangeStream<Token = char>, I::Range: Range]
+    {
+        pattern_()
+    }
+}
+
+// match e { pat if guard => body, ... } desugars to
+// match(e, "pat", |binds| guard, |binds| body, ...), where binds are
+// the names the pattern binds. An arm without a guard has the guard
+// true.
+fn match_<I>() -> impl Parser<I, Output = Expr>
+where
+    I: RangeStream<Token = char>,
+    I::Error: ParseError<I::Token, I::Range, I::Position>,
+    I::Range: Range,
+{
+    let arm = (
+        pattern(),
+        optional(attempt(spaces().with(string("if")).with(expr()))),
+        spaces().with(string("=>")),
+        expr(),
+    )
+        .map(|(pat, guard, _, body): (Pattern, Option<Expr>, _, Expr)| {
+            let lambda = |body: Expr| {
+                let mut args = pat
+                    .binds()
+                    .into_iter()
+                    .map(|n| ExprKind::Constant(Value::String(n)).to_expr())
+                    .collect::<Vec<_>>();
+                args.push(body);
+                ExprKind::Apply { function: "lambda".into(), args }.to_expr()
+            };
+            let guard = lambda(
+                guard.unwrap_or_else(|| ExprKind::Constant(Value::True).to_expr()),
+            );
+            let body = lambda(body);
+            let pat = ExprKind::Constant(Value::String(Chars::from(pat.to_string())));
+            [pat.to_expr(), guard, body]
+        });
+    (
+        string("match").skip(not_followed_by(satisfy(is_name_char))),
+        expr(),
+        between(
+            spaces().with(token('{')),
+            spaces().with(token('}')),
+            sep_by1(arm, attempt(spaces().with(token(',')))),
+        ),
+    )
+        .map(|(_, e, arms): (_, Expr, Vec<[Expr; 3]>)| {
+            let mut args = vec![e];
+            args.extend(arms.into_iter().flatten());
+            ExprKind::Apply { function: "match".into(), args }.to_expr()
+        })
+}
+
+fn term_<I>() -> impl Parser<I, Output = Expr>
 where
     I: RangeStream<Token = char>,
     I::Error: ParseError<I::Token, I::Range, I::Position>,
     I::Range: Range,
 {
     spaces().with(choice((
+        attempt(between(token('('), spaces().with(token(')')), expr())),
+        attempt(
+            (
+                between(
+                    spaces().with(token('|')),
+                    spaces().with(token('|')),
+                    spaces().with(sep_by(
+                        spaces().with(fname()),
+                        attempt(spaces().with(token(','))),
+                    )),
+                ),
+                expr(),
+            )
+                .map(|(params, body): (Vec<String>, Expr)| {
+                    let mut args = params
+                        .into_iter()
+                        .map(|p| {
+                            ExprKind::Constant(Value::String(Chars::from(p))).to_expr()
+                        })
+                        .collect::<Vec<_>>();
+                    args.push(body);
+                    ExprKind::Apply { function: "lambda".into(), args }.to_expr()
+                }),
+        ),
         attempt(
             between(
                 spaces().with(token('{')),
@@ -142,9 +335,10 @@ where
             )
             .map(|args| ExprKind::Apply { function: "array".into(), args }.to_expr()),
         ),
+        attempt(match_()),
         attempt(
             (
-                fname(),
+                qname(),
                 between(
                     spaces().with(token('(')),
                     spaces().with(token(')')),
@@ -166,6 +360,19 @@ where
                     .to_expr()
                 }),
         ),
+        attempt(
+            (
+                string("import"),
+                interpolated(),
+                spaces().with(string("as")).skip(not_followed_by(satisfy(is_name_char))),
+                spaces().with(fname()),
+            )
+                .map(|(_, path, _, name)| {
+                    let name = ExprKind::Constant(Value::String(Chars::from(name)));
+                    let args = vec![path, name.to_expr()];
+                    ExprKind::Apply { function: "import".into(), args }.to_expr()
+                }),
+        ),
         attempt((fname(), spaces().with(string("<-")), expr()).map(|(var, _, e)| {
             ExprKind::Apply {
                 function: "set".into(),
@@ -177,8 +384,9 @@ where
             .to_expr()
         })),
         attempt(interpolated()),
+        attempt(keyword().map(|v| ExprKind::Constant(v).to_expr())),
         attempt(netidx_value(&BSCRIPT_ESC).map(|v| ExprKind::Constant(v).to_expr())),
-        fname().skip(close_expr()).map(|var| {
+        qname().skip(close_expr()).map(|var| {
             ExprKind::Apply {
                 function: "get".into(),
                 args: vec![ExprKind::Constant(Value::String(Chars::from(var))).to_expr()],
@@ -188,6 +396,59 @@ where
     )))
 }
 
+parser! {
+    fn term[I]()(I) -> Expr
+    where [I: RangeStream<Token = char>, I::Range: Range]
+    {
+        term_()
+    }
+}
+
+fn binop(function: &'static str) -> impl Fn(Expr, Expr) -> Expr {
+    move |lhs, rhs| {
+        ExprKind::Apply { function: function.into(), args: vec![lhs, rhs] }.to_expr()
+    }
+}
+
+// infix operators desugar to calls of the functions they name. From
+// lowest to highest precedence they are comparisons, + and -, then *,
+// /, and %. All the operators are left associative.
+fn expr_<I>() -> impl Parser<I, Output = Expr>
+where
+    I: RangeStream<Token = char>,
+    I::Error: ParseError<I::Token, I::Range, I::Position>,
+    I::Range: Range,
+{
+    let factor = chainl1(
+        term(),
+        attempt(spaces().with(choice((
+            token('*').map(|_| "mul"),
+            token('/').map(|_| "div"),
+            token('%').map(|_| "mod"),
+        ))))
+        .map(binop),
+    );
+    let arith = chainl1(
+        factor,
+        attempt(
+            spaces().with(choice((token('+').map(|_| "add"), token('-').map(|_| "sub")))),
+        )
+        .map(binop),
+    );
+    chainl1(
+        arith,
+        attempt(spaces().with(choice((
+            attempt(string("==")).map(|_| "eq"),
+            attempt(string("!=")).map(|_| "ne"),
+            attempt(string("<=")).map(|_| "lte"),
+            attempt(string(">=")).map(|_| "gte"),
+            attempt(token('<').skip(not_followed_by(token('-')))).map(|_| "lt"),
+            token('>').map(|_| "gt"),
+        ))))
+        .map(binop),
+    )
+}
+
 parser! {
     fn expr[I]()(I) -> Expr
     where [I: RangeStream<Token = char>, I::Range: Range]
@@ -203,6 +464,15 @@ pub fn parse_expr(s: &str) -> anyhow::Result<Expr> {
         .map_err(|e| anyhow::anyhow!(format!("{}", e)))
 }
 
+pub fn parse_pattern(s: &str) -> anyhow::Result<Pattern> {
+    pattern()
+        .skip(spaces())
+        .skip(eof())
+        .easy_parse(position::Stream::new(s))
+        .map(|(r, _)| r)
+        .map_err(|e| anyhow::anyhow!(format!("{}", e)))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -355,4 +625,185 @@ mod tests {
             r#"sum(f32:1., load("/foo/bar"), max(f32:675.6, load("/foo/baz")), rand())"#;
         assert_eq!(src, parse_expr(chs).unwrap());
     }
+
+    #[test]
+    fn lambda_parse() {
+        let var = |name: &str| {
+            ExprKind::Apply {
+                function: "get".into(),
+                args: vec![ExprKind::Constant(Value::from(String::from(name))).to_expr()],
+            }
+            .to_expr()
+        };
+        let src = ExprKind::Apply {
+            function: "let".into(),
+            args: vec![
+                ExprKind::Constant(Value::from("f")).to_expr(),
+                ExprKind::Apply {
+                    function: "lambda".into(),
+                    args: vec![
+                        ExprKind::Constant(Value::from("x")).to_expr(),
+                        ExprKind::Constant(Value::from("y")).to_expr(),
+                        ExprKind::Apply {
+                            function: "sum".into(),
+                            args: vec![var("x"), var("y")],
+                        }
+                        .to_expr(),
+                    ],
+                }
+                .to_expr(),
+            ],
+        }
+        .to_expr();
+        assert_eq!(src, parse_expr("let f <- |x, y| sum(x, y)").unwrap());
+        let src = ExprKind::Apply {
+            function: "lambda".into(),
+            args: vec![ExprKind::Constant(Value::I64(42)).to_expr()],
+        }
+        .to_expr();
+        assert_eq!(src, parse_expr("|| 42").unwrap());
+    }
+
+    #[test]
+    fn infix_parse() {
+        let var = |name: &str| {
+            ExprKind::Apply {
+                function: "get".into(),
+                args: vec![ExprKind::Constant(Value::from(String::from(name))).to_expr()],
+            }
+            .to_expr()
+        };
+        let op = |function: &str, lhs: Expr, rhs: Expr| {
+            ExprKind::Apply { function: function.into(), args: vec![lhs, rhs] }.to_expr()
+        };
+        let int = |i: i64| ExprKind::Constant(Value::I64(i)).to_expr();
+        let src = op(
+            "lte",
+            op("sub", op("add", var("a"), op("mul", var("b"), int(2))), int(-1)),
+            op("mod", op("div", var("c"), op("add", var("d"), int(1))), int(3)),
+        );
+        assert_eq!(src, parse_expr("a+b*2 - -1 <= c / (d + 1) % 3").unwrap());
+        let src = ExprKind::Apply {
+            function: "set".into(),
+            args: vec![
+                ExprKind::Constant(Value::from("x")).to_expr(),
+                op("lt", var("y"), int(1)),
+            ],
+        }
+        .to_expr();
+        assert_eq!(src, parse_expr("x <- y < 1").unwrap());
+        let src = op("eq", ExprKind::Constant(Value::True).to_expr(), var("x"));
+        assert_eq!(src, parse_expr("true==x").unwrap());
+        let src = op("ne", var("x"), ExprKind::Constant(Value::Null).to_expr());
+        assert_eq!(src, parse_expr("x!=null").unwrap());
+        assert_eq!(var("nullable"), parse_expr("nullable").unwrap());
+    }
+
+    #[test]
+    fn import_parse() {
+        let s = |s: &str| ExprKind::Constant(Value::from(String::from(s))).to_expr();
+        let import = |path: &str, name: &str| {
+            ExprKind::Apply { function: "import".into(), args: vec![s(path), s(name)] }
+                .to_expr()
+        };
+        let get = |name: &str| {
+            ExprKind::Apply { function: "get".into(), args: vec![s(name)] }.to_expr()
+        };
+        let src = import("/lib/util", "util");
+        assert_eq!(src, parse_expr(r#"import "/lib/util" as util"#).unwrap());
+        assert_eq!(src, parse_expr(r#"import("/lib/util", "util")"#).unwrap());
+        assert!(parse_expr(r#"{import "/lib/util"; 1}"#).is_err());
+        assert!(parse_expr(r#"{import "/lib/util" asutil; 1}"#).is_err());
+        let src = ExprKind::Apply {
+            function: "do".into(),
+            args: vec![import("file:util.bs", "util"), get("importance")],
+        }
+        .to_expr();
+        assert_eq!(
+            src,
+            parse_expr(r#"{import "file:util.bs" as util; importance}"#).unwrap()
+        );
+        let src = ExprKind::Apply {
+            function: "util::f".into(),
+            args: vec![get("util::x"), get("y")],
+        }
+        .to_expr();
+        assert_eq!(src, parse_expr("util::f(util::x, y)").unwrap());
+        assert!(parse_expr("{util::}").is_err());
+        assert!(parse_expr("{a::b::c}").is_err());
+    }
+
+    #[test]
+    fn pattern_parse() {
+        let bind = |n: &str| Pattern::Bind(Chars::from(String::from(n)));
+        assert_eq!(Pattern::Any, parse_pattern("_").unwrap());
+        assert_eq!(bind("x"), parse_pattern("x").unwrap());
+        assert_eq!(Pattern::Literal(Value::I64(-1)), parse_pattern("-1").unwrap());
+        assert_eq!(Pattern::Literal(Value::Null), parse_pattern("null").unwrap());
+        assert_eq!(Pattern::Literal(Value::from("a")), parse_pattern(r#""a""#).unwrap());
+        let p = Pattern::Typ(PatternTyp::Error, Box::new(bind("e")));
+        assert_eq!(p, parse_pattern("error(e)").unwrap());
+        let p = Pattern::Array(
+            vec![
+                Pattern::Typ(PatternTyp::Number, Box::new(bind("x"))),
+                Pattern::Literal(Value::True),
+            ],
+            Some(Box::new(bind("rest"))),
+        );
+        assert_eq!(p, parse_pattern("[number(x), true, ..rest]").unwrap());
+        assert_eq!(p.to_string(), "[number(x), true, ..rest]");
+        assert_eq!(p.binds(), vec![Chars::from("x"), Chars::from("rest")]);
+        let p = Pattern::Array(vec![], Some(Box::new(Pattern::Any)));
+        assert_eq!(p, parse_pattern("[..]").unwrap());
+        assert!(parse_pattern("[.., x]").is_err());
+        assert!(parse_pattern("float(x)").is_err());
+    }
+
+    #[test]
+    fn match_parse() {
+        let lambda = |params: &[&'static str], body: Expr| {
+            let mut args = params
+                .iter()
+                .map(|p| ExprKind::Constant(Value::from(*p)).to_expr())
+                .collect::<Vec<_>>();
+            args.push(body);
+            ExprKind::Apply { function: "lambda".into(), args }.to_expr()
+        };
+        let get = |n: &'static str| {
+            ExprKind::Apply {
+                function: "get".into(),
+                args: vec![ExprKind::Constant(Value::from(n)).to_expr()],
+            }
+            .to_expr()
+        };
+        let c = |v: Value| ExprKind::Constant(v).to_expr();
+        let src = ExprKind::Apply {
+            function: "match".into(),
+            args: vec![
+                get("v"),
+                c(Value::from("i64(n)")),
+                lambda(
+                    &["n"],
+                    ExprKind::Apply {
+                        function: "gt".into(),
+                        args: vec![get("n"), c(Value::I64(0))],
+                    }
+                    .to_expr(),
+                ),
+                lambda(&["n"], get("n")),
+                c(Value::from("_")),
+                lambda(&[], c(Value::True)),
+                lambda(&[], c(Value::I64(0))),
+            ],
+        }
+        .to_expr();
+        let s = r#"match v {i64(n) if n > 0 => n, _ => 0}"#;
+        assert_eq!(src, parse_expr(s).unwrap());
+        assert_eq!(s, src.to_string());
+        assert_eq!(src, parse_expr(&src.to_string_pretty(10)).unwrap());
+        let s = r#"match(v, "i64(n)", |n| n > 0, |n| n, "_", || true, || 0)"#;
+        assert_eq!(src, parse_expr(s).unwrap());
+        let s = r#"match(v, "_", || true)"#;
+        assert_eq!(s, parse_expr(s).unwrap().to_string());
+    }
 }
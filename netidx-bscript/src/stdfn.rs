@@ -1,6 +1,12 @@
 use crate::{
-    expr::{Expr, ExprId, VNAME},
-    vm::{Apply, Ctx, Event, ExecCtx, InitFn, Node, Register},
+    check::{Kind, Signature},
+    expr::{Expr, ExprId, ExprKind, Pattern, PatternTyp, QNAME, VNAME},
+    vm::{self, Apply, Ctx, Event, ExecCtx, InitFn, Node, Register},
+};
+use chrono::{
+    format::{Item, StrftimeItems},
+    DateTime, Datelike, Duration as ChronoDuration, FixedOffset, Local, NaiveDate,
+    NaiveDateTime, TimeZone, Timelike, Utc,
 };
 use fxhash::{FxBuildHasher, FxHashSet};
 use netidx::{
@@ -9,7 +15,19 @@ use netidx::{
     subscriber::{self, Dval, Typ, UpdatesFlags, Value},
 };
 use netidx_core::utils::Either;
-use std::{collections::HashSet, iter, marker::PhantomData, sync::Arc};
+use regex::Regex;
+use std::{
+    cmp::Ordering as CmpOrdering,
+    collections::HashSet,
+    iter,
+    marker::PhantomData,
+    mem, slice,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
 pub struct CachedVals(pub Vec<Option<Value>>);
 
@@ -54,6 +72,8 @@ impl<C: Ctx, E: Clone> Register<C, E> for Any {
             Box::new(Any(from.iter().find_map(|s| s.current(ctx))))
         });
         ctx.functions.insert("any".into(), f);
+        ctx.signatures
+            .insert("any".into(), Signature::new(0, None, &[Kind::Any], Kind::Any));
         ctx.user.register_fn("any".into(), Path::root());
     }
 }
@@ -93,6 +113,8 @@ impl<C: Ctx, E: Clone> Register<C, E> for Once {
             _ => Box::new(Once { val: None, invalid: true }),
         });
         ctx.functions.insert("once".into(), f);
+        ctx.signatures
+            .insert("once".into(), Signature::new(1, Some(1), &[Kind::Any], Kind::Any));
         ctx.user.register_fn("once".into(), Path::root());
     }
 }
@@ -152,6 +174,8 @@ impl<C: Ctx, E: Clone> Register<C, E> for Do {
             Box::new(Do(from.iter().fold(None, |_, s| s.current(ctx))))
         });
         ctx.functions.insert("do".into(), f);
+        ctx.signatures
+            .insert("do".into(), Signature::new(0, None, &[Kind::Any], Kind::Any));
         ctx.user.register_fn("do".into(), Path::root());
     }
 }
@@ -175,6 +199,7 @@ impl<C: Ctx, E: Clone> Apply<C, E> for Do {
 pub trait CachedCurEval {
     fn eval(from: &CachedVals) -> Option<Value>;
     fn name() -> &'static str;
+    fn signature() -> Signature;
 }
 
 pub struct CachedCur<T: CachedCurEval + Send + Sync> {
@@ -193,6 +218,7 @@ impl<C: Ctx, E: Clone, T: CachedCurEval + Send + Sync + 'static> Register<C, E>
             Box::new(CachedCur::<T> { cached, current, t: PhantomData })
         });
         ctx.functions.insert(T::name().into(), f);
+        ctx.signatures.insert(T::name().into(), T::signature());
         ctx.user.register_fn(T::name().into(), Path::root());
     }
 }
@@ -242,6 +268,10 @@ impl CachedCurEval for AllEv {
     fn name() -> &'static str {
         "all"
     }
+
+    fn signature() -> Signature {
+        Signature::new(0, None, &[Kind::Any], Kind::Any)
+    }
 }
 
 pub type All = CachedCur<AllEv>;
@@ -262,6 +292,10 @@ impl CachedCurEval for ArrayEv {
     fn name() -> &'static str {
         "array"
     }
+
+    fn signature() -> Signature {
+        Signature::new(0, None, &[Kind::Any], Kind::Array)
+    }
 }
 
 pub type Array = CachedCur<ArrayEv>;
@@ -287,6 +321,10 @@ impl CachedCurEval for SumEv {
     fn name() -> &'static str {
         "sum"
     }
+
+    fn signature() -> Signature {
+        Signature::new(0, None, &[Kind::Any], Kind::Any)
+    }
 }
 
 pub type Sum = CachedCur<SumEv>;
@@ -312,6 +350,10 @@ impl CachedCurEval for ProductEv {
     fn name() -> &'static str {
         "product"
     }
+
+    fn signature() -> Signature {
+        Signature::new(0, None, &[Kind::Any], Kind::Any)
+    }
 }
 
 pub type Product = CachedCur<ProductEv>;
@@ -337,6 +379,10 @@ impl CachedCurEval for DivideEv {
     fn name() -> &'static str {
         "divide"
     }
+
+    fn signature() -> Signature {
+        Signature::new(0, None, &[Kind::Any], Kind::Any)
+    }
 }
 
 pub type Divide = CachedCur<DivideEv>;
@@ -363,6 +409,10 @@ impl CachedCurEval for MinEv {
     fn name() -> &'static str {
         "min"
     }
+
+    fn signature() -> Signature {
+        Signature::new(0, None, &[Kind::Any], Kind::Any)
+    }
 }
 
 pub type Min = CachedCur<MinEv>;
@@ -389,6 +439,10 @@ impl CachedCurEval for MaxEv {
     fn name() -> &'static str {
         "max"
     }
+
+    fn signature() -> Signature {
+        Signature::new(0, None, &[Kind::Any], Kind::Any)
+    }
 }
 
 pub type Max = CachedCur<MaxEv>;
@@ -413,6 +467,10 @@ impl CachedCurEval for AndEv {
     fn name() -> &'static str {
         "and"
     }
+
+    fn signature() -> Signature {
+        Signature::new(0, None, &[Kind::Bool], Kind::Bool)
+    }
 }
 
 pub type And = CachedCur<AndEv>;
@@ -437,6 +495,10 @@ impl CachedCurEval for OrEv {
     fn name() -> &'static str {
         "or"
     }
+
+    fn signature() -> Signature {
+        Signature::new(0, None, &[Kind::Bool], Kind::Bool)
+    }
 }
 
 pub type Or = CachedCur<OrEv>;
@@ -454,6 +516,10 @@ impl CachedCurEval for NotEv {
     fn name() -> &'static str {
         "not"
     }
+
+    fn signature() -> Signature {
+        Signature::new(1, Some(1), &[Kind::Bool], Kind::Bool)
+    }
 }
 
 pub type Not = CachedCur<NotEv>;
@@ -474,6 +540,10 @@ impl CachedCurEval for IsErrEv {
     fn name() -> &'static str {
         "is_error"
     }
+
+    fn signature() -> Signature {
+        Signature::new(1, Some(1), &[Kind::Any], Kind::Bool)
+    }
 }
 
 pub type IsErr = CachedCur<IsErrEv>;
@@ -499,6 +569,10 @@ impl CachedCurEval for StartsWithEv {
     fn name() -> &'static str {
         "starts_with"
     }
+
+    fn signature() -> Signature {
+        Signature::new(2, Some(2), &[Kind::String], Kind::Bool)
+    }
 }
 
 pub type StartsWith = CachedCur<StartsWithEv>;
@@ -526,6 +600,10 @@ impl CachedCurEval for IndexEv {
     fn name() -> &'static str {
         "index"
     }
+
+    fn signature() -> Signature {
+        Signature::new(2, Some(2), &[Kind::Array, Kind::Number], Kind::Any)
+    }
 }
 
 pub type Index = CachedCur<IndexEv>;
@@ -551,6 +629,10 @@ impl CachedCurEval for EndsWithEv {
     fn name() -> &'static str {
         "ends_with"
     }
+
+    fn signature() -> Signature {
+        Signature::new(2, Some(2), &[Kind::String], Kind::Bool)
+    }
 }
 
 pub type EndsWith = CachedCur<EndsWithEv>;
@@ -576,6 +658,10 @@ impl CachedCurEval for ContainsEv {
     fn name() -> &'static str {
         "contains"
     }
+
+    fn signature() -> Signature {
+        Signature::new(2, Some(2), &[Kind::String], Kind::Bool)
+    }
 }
 
 pub type Contains = CachedCur<ContainsEv>;
@@ -596,6 +682,10 @@ impl CachedCurEval for StripPrefixEv {
     fn name() -> &'static str {
         "strip_prefix"
     }
+
+    fn signature() -> Signature {
+        Signature::new(2, Some(2), &[Kind::String], Kind::Any)
+    }
 }
 
 pub type StripPrefix = CachedCur<StripPrefixEv>;
@@ -616,6 +706,10 @@ impl CachedCurEval for StripSuffixEv {
     fn name() -> &'static str {
         "strip_suffix"
     }
+
+    fn signature() -> Signature {
+        Signature::new(2, Some(2), &[Kind::String], Kind::Any)
+    }
 }
 
 pub type StripSuffix = CachedCur<StripSuffixEv>;
@@ -636,6 +730,10 @@ impl CachedCurEval for TrimEv {
     fn name() -> &'static str {
         "trim"
     }
+
+    fn signature() -> Signature {
+        Signature::new(1, Some(1), &[Kind::String], Kind::String)
+    }
 }
 
 pub type Trim = CachedCur<TrimEv>;
@@ -656,6 +754,10 @@ impl CachedCurEval for TrimStartEv {
     fn name() -> &'static str {
         "trim_start"
     }
+
+    fn signature() -> Signature {
+        Signature::new(1, Some(1), &[Kind::String], Kind::String)
+    }
 }
 
 pub type TrimStart = CachedCur<TrimStartEv>;
@@ -676,6 +778,10 @@ impl CachedCurEval for TrimEndEv {
     fn name() -> &'static str {
         "trim_end"
     }
+
+    fn signature() -> Signature {
+        Signature::new(1, Some(1), &[Kind::String], Kind::String)
+    }
 }
 
 pub type TrimEnd = CachedCur<TrimEndEv>;
@@ -698,6 +804,10 @@ impl CachedCurEval for ReplaceEv {
     fn name() -> &'static str {
         "replace"
     }
+
+    fn signature() -> Signature {
+        Signature::new(3, Some(3), &[Kind::String], Kind::String)
+    }
 }
 
 pub type Replace = CachedCur<ReplaceEv>;
@@ -719,6 +829,10 @@ impl CachedCurEval for DirnameEv {
     fn name() -> &'static str {
         "dirname"
     }
+
+    fn signature() -> Signature {
+        Signature::new(1, Some(1), &[Kind::String], Kind::Any)
+    }
 }
 
 pub type Dirname = CachedCur<DirnameEv>;
@@ -740,6 +854,10 @@ impl CachedCurEval for BasenameEv {
     fn name() -> &'static str {
         "basename"
     }
+
+    fn signature() -> Signature {
+        Signature::new(1, Some(1), &[Kind::String], Kind::String)
+    }
 }
 
 pub type Basename = CachedCur<BasenameEv>;
@@ -751,6 +869,10 @@ impl CachedCurEval for CmpEv {
         "cmp"
     }
 
+    fn signature() -> Signature {
+        Signature::new(3, Some(3), &[Kind::String, Kind::Any], Kind::Bool)
+    }
+
     fn eval(from: &CachedVals) -> Option<Value> {
         match &*from.0 {
             [op, v0, v1] => match op {
@@ -817,6 +939,10 @@ impl CachedCurEval for IfEv {
         "if"
     }
 
+    fn signature() -> Signature {
+        Signature::new(2, Some(3), &[Kind::Bool, Kind::Any], Kind::Any)
+    }
+
     fn eval(from: &CachedVals) -> Option<Value> {
         match &*from.0 {
             [cond, b1] => match cond {
@@ -851,6 +977,10 @@ impl CachedCurEval for FilterEv {
         "filter"
     }
 
+    fn signature() -> Signature {
+        Signature::new(2, Some(2), &[Kind::Bool, Kind::Any], Kind::Any)
+    }
+
     fn eval(from: &CachedVals) -> Option<Value> {
         match &*from.0 {
             [pred, s] => match pred {
@@ -877,6 +1007,10 @@ impl CachedCurEval for FilterErrEv {
         "filter_err"
     }
 
+    fn signature() -> Signature {
+        Signature::new(1, Some(1), &[Kind::Any], Kind::Any)
+    }
+
     fn eval(from: &CachedVals) -> Option<Value> {
         match &*from.0 {
             [s] => match s {
@@ -892,6 +1026,30 @@ impl CachedCurEval for FilterErrEv {
 
 pub type FilterErr = CachedCur<FilterErrEv>;
 
+pub struct TryEv;
+
+impl CachedCurEval for TryEv {
+    fn name() -> &'static str {
+        "try"
+    }
+
+    fn signature() -> Signature {
+        Signature::new(2, Some(2), &[Kind::Any], Kind::Any)
+    }
+
+    fn eval(from: &CachedVals) -> Option<Value> {
+        match &*from.0 {
+            [Some(Value::Error(_)), fallback] => fallback.clone(),
+            [v, _] => v.clone(),
+            _ => Some(Value::Error(Chars::from(
+                "try(expr, fallback): expected 2 arguments",
+            ))),
+        }
+    }
+}
+
+pub type Try = CachedCur<TryEv>;
+
 pub struct CastEv;
 
 fn with_typ_prefix(
@@ -923,6 +1081,10 @@ impl CachedCurEval for CastEv {
         "cast"
     }
 
+    fn signature() -> Signature {
+        Signature::new(2, Some(2), &[Kind::String, Kind::Any], Kind::Any)
+    }
+
     fn eval(from: &CachedVals) -> Option<Value> {
         with_typ_prefix(from, "cast(typ, src)", |typ, v| {
             v.as_ref().and_then(|v| v.clone().cast(typ))
@@ -939,6 +1101,10 @@ impl CachedCurEval for IsaEv {
         "isa"
     }
 
+    fn signature() -> Signature {
+        Signature::new(2, Some(2), &[Kind::String, Kind::Any], Kind::Bool)
+    }
+
     fn eval(from: &CachedVals) -> Option<Value> {
         with_typ_prefix(from, "isa(typ, src)", |typ, v| match (typ, v) {
             (_, None) => None,
@@ -995,6 +1161,10 @@ impl CachedCurEval for StringJoinEv {
         "string_join"
     }
 
+    fn signature() -> Signature {
+        Signature::new(2, None, &[Kind::Any], Kind::String)
+    }
+
     fn eval(from: &CachedVals) -> Option<Value> {
         use bytes::BytesMut;
         match &from.0[..] {
@@ -1055,6 +1225,10 @@ impl CachedCurEval for StringConcatEv {
         "string_concat"
     }
 
+    fn signature() -> Signature {
+        Signature::new(1, None, &[Kind::Any], Kind::String)
+    }
+
     fn eval(from: &CachedVals) -> Option<Value> {
         use bytes::BytesMut;
         match &from.0[..] {
@@ -1090,6 +1264,143 @@ impl CachedCurEval for StringConcatEv {
 
 pub type StringConcat = CachedCur<StringConcatEv>;
 
+pub struct ZipEv;
+
+impl CachedCurEval for ZipEv {
+    fn name() -> &'static str {
+        "zip"
+    }
+
+    fn signature() -> Signature {
+        Signature::new(1, None, &[Kind::Array], Kind::Array)
+    }
+
+    fn eval(from: &CachedVals) -> Option<Value> {
+        if from.0.is_empty() {
+            return Some(Value::Error(Chars::from(
+                "zip(a0, a1, ...): expected at least 1 argument",
+            )));
+        }
+        let mut arrays = Vec::with_capacity(from.0.len());
+        for v in &from.0 {
+            match v {
+                None => return None,
+                Some(Value::Array(a)) => arrays.push(a),
+                Some(_) => {
+                    return Some(Value::Error(Chars::from(
+                        "zip(a0, a1, ...): expected arrays",
+                    )))
+                }
+            }
+        }
+        let len = arrays.iter().map(|a| a.len()).min().unwrap_or(0);
+        Some(Value::Array(Arc::from_iter(
+            (0..len).map(|i| {
+                Value::Array(Arc::from_iter(arrays.iter().map(|a| a[i].clone())))
+            }),
+        )))
+    }
+}
+
+pub type Zip = CachedCur<ZipEv>;
+
+pub struct FlattenEv;
+
+impl CachedCurEval for FlattenEv {
+    fn name() -> &'static str {
+        "flatten"
+    }
+
+    fn signature() -> Signature {
+        Signature::new(1, Some(1), &[Kind::Array], Kind::Array)
+    }
+
+    fn eval(from: &CachedVals) -> Option<Value> {
+        match &*from.0 {
+            [None] => None,
+            [Some(Value::Array(a))] => {
+                Some(Value::Array(Arc::from_iter(a.iter().flat_map(|v| match v {
+                    Value::Array(a) => Either::Left(a.iter().cloned()),
+                    v => Either::Right(iter::once(v.clone())),
+                }))))
+            }
+            _ => Some(Value::Error(Chars::from("flatten(a): expected 1 array argument"))),
+        }
+    }
+}
+
+pub type Flatten = CachedCur<FlattenEv>;
+
+pub struct SliceEv;
+
+impl CachedCurEval for SliceEv {
+    fn name() -> &'static str {
+        "slice"
+    }
+
+    fn signature() -> Signature {
+        Signature::new(2, Some(3), &[Kind::Array, Kind::Any], Kind::Array)
+    }
+
+    fn eval(from: &CachedVals) -> Option<Value> {
+        let usage = || {
+            Some(Value::Error(Chars::from(
+                "slice(a: array, start: int, [end: int]): expected positive indexes",
+            )))
+        };
+        let (a, start, end) = match &*from.0 {
+            [a, start] => (a, start, None),
+            [a, start, end] => (a, start, Some(end)),
+            _ => return usage(),
+        };
+        match (a, start, end) {
+            (None, _, _) | (_, None, _) | (_, _, Some(None)) => None,
+            (Some(Value::Array(a)), Some(start), end) => {
+                let start = match start.clone().cast_to::<u64>() {
+                    Ok(i) => (i as usize).min(a.len()),
+                    Err(_) => return usage(),
+                };
+                let end = match end {
+                    None | Some(None) | Some(Some(Value::Null)) => a.len(),
+                    Some(Some(end)) => match end.clone().cast_to::<u64>() {
+                        Ok(i) => (i as usize).min(a.len()).max(start),
+                        Err(_) => return usage(),
+                    },
+                };
+                Some(Value::Array(Arc::from(&a[start..end])))
+            }
+            (Some(_), Some(_), _) => usage(),
+        }
+    }
+}
+
+pub type Slice = CachedCur<SliceEv>;
+
+pub struct LenEv;
+
+impl CachedCurEval for LenEv {
+    fn name() -> &'static str {
+        "len"
+    }
+
+    fn signature() -> Signature {
+        Signature::new(1, Some(1), &[Kind::Any], Kind::Number)
+    }
+
+    fn eval(from: &CachedVals) -> Option<Value> {
+        match &*from.0 {
+            [None] => None,
+            [Some(Value::Array(a))] => Some(Value::I64(a.len() as i64)),
+            [Some(Value::String(s))] => Some(Value::I64(s.chars().count() as i64)),
+            _ => Some(Value::Error(Chars::from(
+                "len(a): expected 1 array or string argument",
+            ))),
+        }
+    }
+}
+
+pub type Len = CachedCur<LenEv>;
+
 pub struct Eval<C: Ctx, E> {
     cached: CachedVals,
     current: Result<Node<C, E>, Value>,
@@ -1130,6 +1441,10 @@ impl<C: Ctx, E: Clone> Register<C, E> for Eval<C, E> {
             Box::new(t)
         });
         ctx.functions.insert("eval".into(), f);
+        ctx.signatures.insert(
+            "eval".into(),
+            Signature::new(1, Some(1), &[Kind::String], Kind::Any),
+        );
         ctx.user.register_fn("eval".into(), Path::root());
     }
 }
@@ -1160,71 +1475,123 @@ impl<C: Ctx, E: Clone> Apply<C, E> for Eval<C, E> {
     }
 }
 
-pub struct Count {
-    from: CachedVals,
-    count: u64,
-}
-
-impl<C: Ctx, E: Clone> Register<C, E> for Count {
-    fn register(ctx: &mut ExecCtx<C, E>) {
-        let f: InitFn<C, E> = Arc::new(|ctx, from, _, _| {
-            Box::new(Count { from: CachedVals::new(from, ctx), count: 0 })
-        });
-        ctx.functions.insert("count".into(), f);
-        ctx.user.register_fn("count".into(), Path::root());
+// true if e is an import written without a body
+fn is_bare_import(e: &Expr) -> bool {
+    match &e.kind {
+        ExprKind::Apply { function, args } => function == "import" && args.len() == 2,
+        ExprKind::Constant(_) => false,
     }
 }
 
-impl<C: Ctx, E: Clone> Apply<C, E> for Count {
-    fn current(&self, _ctx: &mut ExecCtx<C, E>) -> Option<Value> {
-        match &*self.from.0 {
-            [] => Some(Value::Error(Chars::from("count(s): requires 1 argument"))),
-            [_] => Some(Value::U64(self.count)),
-            _ => Some(Value::Error(Chars::from("count(s): requires 1 argument"))),
-        }
+/// true if the arguments of a do block contain an import that is
+/// followed by other expressions
+pub(crate) fn needs_desugar(args: &[Expr]) -> bool {
+    match args.split_last() {
+        None => false,
+        Some((_, init)) => init.iter().any(is_bare_import),
     }
+}
 
-    fn update(
-        &mut self,
-        ctx: &mut ExecCtx<C, E>,
-        from: &mut [Node<C, E>],
-        event: &Event<E>,
-    ) -> Option<Value> {
-        if self.from.update(ctx, from, event) {
-            self.count += 1;
-            Apply::<C, E>::current(self, ctx)
-        } else {
-            None
+/// Rewrite the arguments of a do block so the expressions after an
+/// import become it's body. The body is passed as a lambda with no
+/// arguments so it won't be compiled until the module is loaded.
+pub(crate) fn desugar_imports(args: &[Expr]) -> Vec<Expr> {
+    match args.iter().position(is_bare_import) {
+        Some(i) if i < args.len() - 1 => {
+            let body = desugar_imports(&args[i + 1..]);
+            let body = ExprKind::Apply { function: "do".into(), args: body }.to_expr();
+            let body = ExprKind::Apply { function: "lambda".into(), args: vec![body] };
+            let kind = match &args[i].kind {
+                ExprKind::Apply { function, args } => ExprKind::Apply {
+                    function: function.clone(),
+                    args: vec![args[0].clone(), args[1].clone(), body.to_expr()],
+                },
+                ExprKind::Constant(_) => unreachable!(),
+            };
+            let mut res = args[..i].to_vec();
+            res.push(Expr { id: args[i].id, kind });
+            res
         }
+        _ => args.to_vec(),
     }
 }
 
-pub struct Sample {
-    current: Option<Value>,
+/// import(path, name), written import "path" as name: load the
+/// module at path, and compile it in it's own scope, name, below the
+/// scope import was called from. The functions and variables the
+/// module defines are referred to as name::f and name::x from the
+/// scope import was called from and below it. A module is a netidx
+/// path containing bscript source, or, if the `Ctx` supports it, a
+/// file named by file:path. A module in netidx is reloaded whenever
+/// it's value changes, but a file is read once, the first time any
+/// expression imports it, and is not reloaded if it changes. In a do
+/// block the expressions after an import are it's body, they are
+/// compiled after the module is loaded, and compiled again whenever
+/// the module changes.
+pub struct Import<C: Ctx + 'static, E: 'static> {
+    scope: Path,
+    /// the scope the module is compiled in
+    module_scope: Path,
+    top_id: ExprId,
+    /// the modules that contain this import
+    chain: Vec<Chars>,
+    path: Option<Chars>,
+    dv: Option<(Path, Dval)>,
+    src: Option<Chars>,
+    module: Result<Vec<Node<C, E>>, Value>,
+    body: Vec<Node<C, E>>,
+    invalid: bool,
 }
 
-impl<C: Ctx, E: Clone> Register<C, E> for Sample {
+impl<C: Ctx, E: Clone> Register<C, E> for Import<C, E> {
     fn register(ctx: &mut ExecCtx<C, E>) {
-        let f: InitFn<C, E> = Arc::new(|ctx, from, _, _| {
-            let current = match from {
-                [trigger, source] => match trigger.current(ctx) {
-                    None => None,
-                    Some(_) => source.current(ctx),
-                },
-                _ => Some(Value::Error(Chars::from(
-                    "sample(trigger, source): expected 2 arguments",
-                ))),
+        let f: InitFn<C, E> = Arc::new(|ctx, from, scope, top_id| {
+            let mut t = Import {
+                module_scope: scope.clone(),
+                scope,
+                top_id,
+                chain: ctx.importing.clone(),
+                path: None,
+                dv: None,
+                src: None,
+                module: Err(Value::Null),
+                body: vec![],
+                invalid: false,
             };
-            Box::new(Sample { current })
+            match from {
+                [path, name] | [path, name, Node::Lambda(_, _)] => {
+                    match name.current(ctx) {
+                        Some(Value::String(name)) if VNAME.is_match(&name) => {
+                            t.module_scope = t.scope.append(&*name);
+                            let path = path.current(ctx);
+                            if let Some(src) = t.set_path(ctx, path) {
+                                t.load(ctx, src, Import::body(from))
+                            }
+                        }
+                        _ => t.invalid = true,
+                    }
+                }
+                _ => t.invalid = true,
+            }
+            Box::new(t)
         });
-        ctx.functions.insert("sample".into(), f);
-        ctx.user.register_fn("sample".into(), Path::root());
+        ctx.functions.insert("import".into(), f);
+        ctx.signatures.insert(
+            "import".into(),
+            Signature::new(2, Some(2), &[Kind::String, Kind::String], Kind::Any),
+        );
+        ctx.user.register_fn("import".into(), Path::root());
     }
 }
 
-impl<C: Ctx, E: Clone> Apply<C, E> for Sample {
-    fn current(&self, _ctx: &mut ExecCtx<C, E>) -> Option<Value> {
-        self.current.clone()
+impl<C: Ctx, E: Clone> Apply<C, E> for Import<C, E> {
+    fn current(&self, ctx: &mut ExecCtx<C, E>) -> Option<Value> {
+        match &self.module {
+            _ if self.invalid => Import::<C, E>::usage(),
+            Err(Value::Null) => None,
+            Err(e) => Some(e.clone()),
+            Ok(_) => self.body.iter().fold(None, |_, n| n.current(ctx)),
+        }
     }
 
     fn update(
@@ -1233,101 +1600,294 @@ impl<C: Ctx, E: Clone> Apply<C, E> for Sample {
         from: &mut [Node<C, E>],
         event: &Event<E>,
     ) -> Option<Value> {
-        match from {
-            [trigger, source] => {
-                source.update(ctx, event);
-                if trigger.update(ctx, event).is_none() {
-                    None
-                } else {
-                    let v = source.current(ctx);
-                    self.current = v.clone();
-                    v
+        if self.invalid {
+            let mut up = false;
+            for n in from {
+                up = n.update(ctx, event).is_some() || up;
+            }
+            return if up { Import::<C, E>::usage() } else { None };
+        }
+        let src = match from[0].update(ctx, event) {
+            None => None,
+            path => self.set_path(ctx, path),
+        };
+        let src = match (src, event) {
+            (Some(src), _) => Some(src),
+            (None, Event::Netidx(id, v))
+                if self.dv.as_ref().map(|(_, dv)| dv.id()) == Some(*id) =>
+            {
+                match Import::<C, E>::source(v.clone()) {
+                    Some(Ok(src)) if self.src.as_ref() == Some(&src) => None,
+                    src => src,
                 }
             }
-            _ => {
-                let v = Some(Value::Error(Chars::from(
-                    "sample(trigger, source): expected 2 arguments",
-                )));
-                self.current = v.clone();
-                v
+            (None, _) => None,
+        };
+        match src {
+            Some(src) => {
+                self.load(ctx, src, Import::body(from));
+                Apply::<C, E>::current(self, ctx)
+            }
+            None => {
+                if let Ok(module) = &mut self.module {
+                    for n in module {
+                        n.update(ctx, event);
+                    }
+                }
+                self.body.iter_mut().fold(None, |_, n| n.update(ctx, event))
             }
         }
     }
-}
 
-pub struct Mean {
-    from: CachedVals,
-    total: f64,
-    samples: usize,
+    fn delete(&mut self, ctx: &mut ExecCtx<C, E>) {
+        if let Some((path, dv)) = self.dv.take() {
+            ctx.user.unsubscribe(path, dv, self.top_id);
+        }
+        self.unload(ctx);
+    }
 }
 
-impl<C: Ctx, E: Clone> Register<C, E> for Mean {
-    fn register(ctx: &mut ExecCtx<C, E>) {
-        let f: InitFn<C, E> = Arc::new(|ctx, from, _, _| {
-            Box::new(Mean { from: CachedVals::new(from, ctx), total: 0., samples: 0 })
-        });
-        ctx.functions.insert("mean".into(), f);
-        ctx.user.register_fn("mean".into(), Path::root());
+impl<C: Ctx, E: Clone> Import<C, E> {
+    fn usage() -> Option<Value> {
+        Some(Value::Error(Chars::from(
+            "import(path, name: string [a-z][a-z0-9_]+): expected a path and a module name",
+        )))
     }
-}
 
-impl<C: Ctx, E: Clone> Apply<C, E> for Mean {
-    fn current(&self, _ctx: &mut ExecCtx<C, E>) -> Option<Value> {
-        match &*self.from.0 {
-            [] => Some(Value::Error(Chars::from("mean(s): requires 1 argument"))),
-            [_] => {
-                if self.samples > 0 {
-                    Some(Value::F64(self.total / (self.samples as f64)))
-                } else {
-                    None
-                }
+    // the expressions after the import, see desugar_imports
+    fn body(from: &[Node<C, E>]) -> Vec<Expr> {
+        match from.get(2) {
+            Some(Node::Lambda(spec, _)) => match &spec.kind {
+                ExprKind::Apply { args, .. } => match args.last().map(|e| &e.kind) {
+                    Some(ExprKind::Apply { args, .. }) => args.clone(),
+                    Some(ExprKind::Constant(_)) | None => vec![],
+                },
+                ExprKind::Constant(_) => vec![],
+            },
+            _ => vec![],
+        }
+    }
+
+    fn source(v: Value) -> Option<Result<Chars, Value>> {
+        match v {
+            Value::Null => None,
+            Value::String(s) => Some(Ok(s)),
+            v => {
+                let e = format!("import: expected module source, not {}", v);
+                Some(Err(Value::Error(Chars::from(e))))
             }
-            _ => Some(Value::Error(Chars::from("mean(s): requires 1 argument"))),
         }
     }
 
-    fn update(
+    // change the module path, returning the module source if it's
+    // available now
+    fn set_path(
         &mut self,
         ctx: &mut ExecCtx<C, E>,
-        from: &mut [Node<C, E>],
-        event: &Event<E>,
-    ) -> Option<Value> {
-        if self.from.update(ctx, from, event) {
-            for v in self.from.flat_iter() {
-                if let Some(v) = v {
-                    if let Ok(v) = v.clone().cast_to::<f64>() {
-                        self.total += v;
-                        self.samples += 1;
-                    }
-                }
+        path: Option<Value>,
+    ) -> Option<Result<Chars, Value>> {
+        let path = match path? {
+            Value::String(p) if Some(&p) == self.path.as_ref() => return None,
+            Value::String(p) => p,
+            v => {
+                let e = format!("import(path): expected a string, not {}", v);
+                return Some(Err(Value::Error(Chars::from(e))));
             }
-            Apply::<C, E>::current(self, ctx)
+        };
+        if let Some((path, dv)) = self.dv.take() {
+            ctx.user.unsubscribe(path, dv, self.top_id);
+        }
+        self.path = Some(path.clone());
+        self.src = None;
+        if let Some(file) = path.strip_prefix("file:") {
+            match ctx.modules.get(&path) {
+                Some((src, _)) => Some(Ok(src.clone())),
+                None => Some(ctx.user.load_file(file).map_err(|e| {
+                    Value::Error(Chars::from(format!("import: {}, {}", path, e)))
+                })),
+            }
+        } else if Path::is_absolute(&*path) {
+            let p = Path::from(String::from(&*path));
+            let flags = UpdatesFlags::BEGIN_WITH_LAST;
+            let dv = ctx.user.durable_subscribe(flags, p.clone(), self.top_id);
+            let src = match dv.last() {
+                subscriber::Event::Unsubscribed => None,
+                subscriber::Event::Update(v) => Import::<C, E>::source(v),
+            };
+            self.dv = Some((p, dv));
+            src
         } else {
-            None
+            Some(Err(Value::Error(Chars::from(
+                "import(path): expected an absolute path, or file:path",
+            ))))
+        }
+    }
+
+    fn compile(
+        &mut self,
+        ctx: &mut ExecCtx<C, E>,
+        src: Result<Chars, Value>,
+    ) -> Result<Vec<Node<C, E>>, Value> {
+        let (path, src) = match (&self.path, src?) {
+            (None, _) => return Err(Value::Null),
+            (Some(path), src) => (path.clone(), src),
+        };
+        if self.chain.contains(&path) {
+            let mut chain = self.chain.iter().map(|p| &**p).collect::<Vec<_>>();
+            chain.push(&*path);
+            let e = format!("import: cycle {}", chain.join(" -> "));
+            return Err(Value::Error(Chars::from(e)));
+        }
+        let spec = match ctx.modules.get(&path) {
+            Some((cached, spec)) if cached == &src => spec.clone(),
+            Some(_) | None => match src.parse::<Expr>() {
+                Ok(spec) => {
+                    ctx.modules.insert(path.clone(), (src.clone(), spec.clone()));
+                    spec
+                }
+                Err(e) => {
+                    let e = format!("import: error parsing {}, {}", path, e);
+                    return Err(Value::Error(Chars::from(e)));
+                }
+            },
+        };
+        self.src = Some(src);
+        // the definitions in the module's top level do block are
+        // bound directly in the module's scope
+        let specs = match spec.kind {
+            ExprKind::Apply { function, args } if function == "do" => {
+                desugar_imports(&args)
+            }
+            kind => vec![Expr { id: spec.id, kind }],
+        };
+        let mut chain = self.chain.clone();
+        chain.push(path);
+        let saved = mem::replace(&mut ctx.importing, chain);
+        let nodes = specs
+            .into_iter()
+            .map(|s| Node::compile_int(ctx, s, self.module_scope.clone(), self.top_id))
+            .collect();
+        ctx.importing = saved;
+        Ok(nodes)
+    }
+
+    // delete the nodes of the current version of the module, and
+    // forget it's definitions
+    fn unload(&mut self, ctx: &mut ExecCtx<C, E>) {
+        for mut n in self.body.drain(..) {
+            n.delete(ctx)
+        }
+        if let Ok(module) = &mut self.module {
+            for mut n in module.drain(..) {
+                n.delete(ctx)
+            }
+        }
+        let scope = &self.module_scope;
+        ctx.variables.retain(|p, _| !Path::is_parent(scope, p));
+        ctx.lambdas.retain(|p, _| !Path::is_parent(scope, p));
+    }
+
+    fn load(
+        &mut self,
+        ctx: &mut ExecCtx<C, E>,
+        src: Result<Chars, Value>,
+        body: Vec<Expr>,
+    ) {
+        self.unload(ctx);
+        self.module = self.compile(ctx, src);
+        if self.module.is_ok() {
+            let saved = mem::replace(&mut ctx.importing, self.chain.clone());
+            self.body = body
+                .into_iter()
+                .map(|s| Node::compile_int(ctx, s, self.scope.clone(), self.top_id))
+                .collect();
+            ctx.importing = saved;
         }
     }
 }
 
-pub(crate) struct Uniq(Option<Value>);
+/// A call to a user defined function. Every call site compiles it's
+/// own copy of the function body, in a new scope below the scope the
+/// function was defined in, with the arguments bound to local
+/// variables in that scope.
+pub struct Lambda<C: Ctx + 'static, E: 'static> {
+    params: Arc<[Chars]>,
+    scope: Path,
+    body: Result<Node<C, E>, Value>,
+}
 
-impl<C: Ctx, E: Clone> Register<C, E> for Uniq {
-    fn register(ctx: &mut ExecCtx<C, E>) {
-        let f: InitFn<C, E> = Arc::new(|ctx, from, _, _| {
-            let mut t = Uniq(None);
-            match from {
-                [e] => t.0 = e.current(ctx),
-                _ => t.0 = Uniq::usage(),
+impl<C: Ctx, E: Clone> Lambda<C, E> {
+    /// Build the init function of `spec`, a lambda expression named
+    /// `name` that is defined in `scope`.
+    pub fn init(name: Chars, spec: &Expr, scope: Path) -> Result<InitFn<C, E>, Value> {
+        let (params, body): (Arc<[Chars]>, Expr) = match &spec.kind {
+            ExprKind::Apply { args, function } if function == "lambda" => {
+                match args.split_last() {
+                    None => return Err(Lambda::<C, E>::usage()),
+                    Some((body, params)) => {
+                        let params = params
+                            .iter()
+                            .map(|p| match &p.kind {
+                                ExprKind::Constant(Value::String(p))
+                                    if VNAME.is_match(p) =>
+                                {
+                                    Ok(p.clone())
+                                }
+                                _ => Err(Lambda::<C, E>::usage()),
+                            })
+                            .collect::<Result<Vec<_>, _>>()?;
+                        (Arc::from(params), body.clone())
+                    }
+                }
             }
-            Box::new(t)
+            _ => return Err(Lambda::<C, E>::usage()),
+        };
+        // the body is compiled when the function is called, so a
+        // function that calls itself would never finish compiling
+        let compiling = Arc::new(AtomicBool::new(false));
+        let f: InitFn<C, E> = Arc::new(move |ctx, from, _, top_id| {
+            let params = Arc::clone(&params);
+            let scope = scope.append(&format!("fn{:?}", ExprId::new()));
+            let body = if from.len() != params.len() {
+                let e = format!("{}: expected {} arguments", name, params.len());
+                Err(Value::Error(Chars::from(e)))
+            } else if compiling.swap(true, Ordering::Relaxed) {
+                let e = format!("{}: recursive functions are not supported", name);
+                Err(Value::Error(Chars::from(e)))
+            } else {
+                for (param, arg) in params.iter().zip(from.iter()) {
+                    if let Some(v) = arg.current(ctx) {
+                        ctx.user.set_var(
+                            &mut ctx.variables,
+                            true,
+                            scope.clone(),
+                            param.clone(),
+                            v,
+                        )
+                    }
+                }
+                let node = Node::compile_int(ctx, body.clone(), scope.clone(), top_id);
+                compiling.store(false, Ordering::Relaxed);
+                Ok(node)
+            };
+            Box::new(Lambda { params, scope, body })
         });
-        ctx.functions.insert("uniq".into(), f);
-        ctx.user.register_fn("uniq".into(), Path::root());
+        Ok(f)
+    }
+
+    fn usage() -> Value {
+        Value::Error(Chars::from(
+            "lambda(arg: string [a-z][a-z0-9_]+, ..., body): expected arguments and a body",
+        ))
     }
 }
 
-impl<C: Ctx, E: Clone> Apply<C, E> for Uniq {
-    fn current(&self, _ctx: &mut ExecCtx<C, E>) -> Option<Value> {
-        self.0.as_ref().cloned()
+impl<C: Ctx, E: Clone> Apply<C, E> for Lambda<C, E> {
+    fn current(&self, ctx: &mut ExecCtx<C, E>) -> Option<Value> {
+        match &self.body {
+            Ok(body) => body.current(ctx),
+            Err(e) => Some(e.clone()),
+        }
     }
 
     fn update(
@@ -1336,90 +1896,374 @@ impl<C: Ctx, E: Clone> Apply<C, E> for Uniq {
         from: &mut [Node<C, E>],
         event: &Event<E>,
     ) -> Option<Value> {
-        match from {
-            [e] => e.update(ctx, event).and_then(|v| {
-                if Some(&v) != self.0.as_ref() {
-                    self.0 = Some(v.clone());
-                    Some(v)
-                } else {
-                    None
+        match &mut self.body {
+            Ok(body) => {
+                for (param, arg) in self.params.iter().zip(from.iter_mut()) {
+                    if let Some(v) = arg.update(ctx, event) {
+                        ctx.user.set_var(
+                            &mut ctx.variables,
+                            true,
+                            self.scope.clone(),
+                            param.clone(),
+                            v,
+                        )
+                    }
                 }
-            }),
-            exprs => {
+                body.update(ctx, event)
+            }
+            Err(e) => {
                 let mut up = false;
-                for e in exprs {
-                    up = e.update(ctx, event).is_some() || up;
+                for arg in from {
+                    up |= arg.update(ctx, event).is_some();
                 }
-                self.0 = Uniq::usage();
                 if up {
-                    Apply::<C, E>::current(self, ctx)
+                    Some(e.clone())
                 } else {
                     None
                 }
             }
         }
     }
+
+    fn delete(&mut self, ctx: &mut ExecCtx<C, E>) {
+        if let Ok(body) = &mut self.body {
+            body.delete(ctx)
+        }
+        let scope = &self.scope;
+        ctx.variables.retain(|p, _| !Path::is_parent(scope, p));
+        ctx.lambdas.retain(|p, _| !Path::is_parent(scope, p));
+    }
 }
 
-impl Uniq {
-    fn usage() -> Option<Value> {
-        Some(Value::Error(Chars::from("uniq(e): expected 1 argument")))
+/// The function argument of a higher order function, either a
+/// lambda, a function named by a bare name, e.g. the f in `map(a,
+/// f)`, or an expression whose value is the name of a function.
+struct FnArg<C: Ctx + 'static, E: 'static>(Option<Result<InitFn<C, E>, Value>>);
+
+impl<C: Ctx, E: Clone> FnArg<C, E> {
+    fn new(ctx: &mut ExecCtx<C, E>, scope: &Path, f: &Node<C, E>) -> Self {
+        match f {
+            Node::Lambda(_, init) => FnArg(Some(Ok(Arc::clone(init)))),
+            f => match FnArg::bare_name(f) {
+                Some(name) => match ctx.lookup_fn(scope, name) {
+                    Some(init) => FnArg(Some(Ok(init))),
+                    // a variable holding the name of a function
+                    None => match f.current(ctx) {
+                        Some(v) => FnArg::resolve(ctx, scope, Some(v)),
+                        None => FnArg(Some(Err(Value::Error(Chars::from(format!(
+                            "unknown function {}",
+                            name
+                        )))))),
+                    },
+                },
+                None => {
+                    let name = f.current(ctx);
+                    FnArg::resolve(ctx, scope, name)
+                }
+            },
+        }
+    }
+
+    // the name if f is a bare name, which parses as a variable lookup
+    fn bare_name(f: &Node<C, E>) -> Option<&Chars> {
+        match f {
+            Node::Apply {
+                spec: Expr { kind: ExprKind::Apply { function, args }, .. },
+                ..
+            } if function == "get" => match &args[..] {
+                [Expr { kind: ExprKind::Constant(Value::String(name)), .. }] => {
+                    Some(name)
+                }
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    fn resolve(ctx: &ExecCtx<C, E>, scope: &Path, name: Option<Value>) -> Self {
+        FnArg(name.map(|name| match name {
+            Value::String(name) => ctx.lookup_fn(scope, &name).ok_or_else(|| {
+                Value::Error(Chars::from(format!("unknown function {}", name)))
+            }),
+            v => Err(Value::Error(Chars::from(format!(
+                "expected a lambda or a function name, not {}",
+                v
+            )))),
+        }))
+    }
+
+    // returns true if the function changed
+    fn update(
+        &mut self,
+        ctx: &mut ExecCtx<C, E>,
+        scope: &Path,
+        f: &mut Node<C, E>,
+        event: &Event<E>,
+    ) -> bool {
+        match f.update(ctx, event) {
+            None => false,
+            // a bare function name always names the function
+            Some(_)
+                if FnArg::bare_name(f)
+                    .map_or(false, |n| ctx.lookup_fn(scope, n).is_some()) =>
+            {
+                false
+            }
+            name => {
+                *self = FnArg::resolve(ctx, scope, name);
+                true
+            }
+        }
     }
 }
 
-fn pathname(invalid: &mut bool, path: Option<Value>) -> Option<Path> {
-    *invalid = false;
-    match path.map(|v| v.cast_to::<String>()) {
-        None => None,
-        Some(Ok(p)) => {
-            if Path::is_absolute(&p) {
-                Some(Path::from(p))
-            } else {
-                *invalid = true;
-                None
+/// One call of the function argument of a higher order function
+struct Call<C: Ctx + 'static, E: 'static> {
+    args: Vec<Node<C, E>>,
+    function: Box<dyn Apply<C, E> + Send + Sync>,
+}
+
+impl<C: Ctx, E: Clone> Call<C, E> {
+    fn new(
+        ctx: &mut ExecCtx<C, E>,
+        f: &InitFn<C, E>,
+        args: &[Value],
+        scope: &Path,
+        top_id: ExprId,
+    ) -> Self {
+        let args = args
+            .iter()
+            .map(|v| Node::Constant(ExprKind::Constant(v.clone()).to_expr(), v.clone()))
+            .collect::<Vec<_>>();
+        let function = f(ctx, &args, scope.clone(), top_id);
+        Call { args, function }
+    }
+
+    fn is_call_of(&self, args: &[Value]) -> bool {
+        self.args.len() == args.len()
+            && self.args.iter().zip(args.iter()).all(|(n, v)| match n {
+                Node::Constant(_, c) => c == v,
+                _ => false,
+            })
+    }
+
+    fn current(&self, ctx: &mut ExecCtx<C, E>) -> Option<Value> {
+        self.function.current(ctx)
+    }
+
+    fn update(&mut self, ctx: &mut ExecCtx<C, E>, event: &Event<E>) -> Option<Value> {
+        self.function.update(ctx, &mut self.args, event)
+    }
+
+    /// Drop the call, and the scope it's function was compiled in
+    fn delete(mut self, ctx: &mut ExecCtx<C, E>) {
+        self.function.delete(ctx)
+    }
+
+    /// Replace `calls[i]`, or append to `calls` if `i` is past the end
+    fn set(
+        ctx: &mut ExecCtx<C, E>,
+        calls: &mut Vec<Self>,
+        i: usize,
+        f: &InitFn<C, E>,
+        args: &[Value],
+        scope: &Path,
+        top_id: ExprId,
+    ) {
+        if i >= calls.len() {
+            calls.push(Call::new(ctx, f, args, scope, top_id))
+        } else if !calls[i].is_call_of(args) {
+            let call = Call::new(ctx, f, args, scope, top_id);
+            mem::replace(&mut calls[i], call).delete(ctx)
+        }
+    }
+
+    fn truncate(ctx: &mut ExecCtx<C, E>, calls: &mut Vec<Self>, len: usize) {
+        if len < calls.len() {
+            for call in calls.drain(len..) {
+                call.delete(ctx)
             }
         }
-        Some(Err(_)) => {
-            *invalid = true;
-            None
+    }
+}
+
+// bind the names in pat to the parts of v it matches, returns false
+// if v doesn't match
+fn bind_pattern(pat: &Pattern, v: &Value, binds: &mut Vec<(Chars, Value)>) -> bool {
+    match pat {
+        Pattern::Any => true,
+        Pattern::Bind(name) => {
+            binds.push((name.clone(), v.clone()));
+            true
+        }
+        Pattern::Literal(l) => {
+            (Typ::get(l) == Typ::get(v) || (l.number() && v.number())) && l == v
         }
+        Pattern::Typ(typ, pat) => match (typ, v) {
+            (PatternTyp::Error, Value::Error(e)) => {
+                bind_pattern(pat, &Value::String(e.clone()), binds)
+            }
+            (PatternTyp::Number, v) if v.number() => bind_pattern(pat, v, binds),
+            (PatternTyp::Typ(typ), v) if Typ::get(v) == *typ => {
+                bind_pattern(pat, v, binds)
+            }
+            (_, _) => false,
+        },
+        Pattern::Array(pats, rest) => match v {
+            Value::Array(elts) => {
+                let n = pats.len();
+                let len_ok =
+                    if rest.is_some() { elts.len() >= n } else { elts.len() == n };
+                len_ok
+                    && pats
+                        .iter()
+                        .zip(elts.iter())
+                        .all(|(p, v)| bind_pattern(p, v, binds))
+                    && match rest {
+                        None => true,
+                        Some(rest) => {
+                            let v = Value::Array(Arc::from(&elts[n..]));
+                            bind_pattern(rest, &v, binds)
+                        }
+                    }
+            }
+            _ => false,
+        },
     }
 }
 
-pub struct Store {
-    queued: Vec<Value>,
-    top_id: ExprId,
-    dv: Option<(Path, Dval)>,
+struct Arm<C: Ctx + 'static, E: 'static> {
+    pattern: Pattern,
+    scope: Path,
+    guard: Node<C, E>,
+    body: Node<C, E>,
+    /// the pattern matches the current value
+    matched: bool,
+    /// the last value the body produced during the current update
+    updated: Option<Value>,
+}
+
+impl<C: Ctx, E: Clone> Arm<C, E> {
+    // the body of the lambda an arm's guard or body is passed as
+    fn spec(n: &Node<C, E>) -> Option<Expr> {
+        match n {
+            Node::Lambda(spec, _) => match &spec.kind {
+                ExprKind::Apply { args, .. } => args.last().cloned(),
+                ExprKind::Constant(_) => None,
+            },
+            _ => None,
+        }
+    }
+
+    fn compile(
+        ctx: &mut ExecCtx<C, E>,
+        arm: &[Node<C, E>],
+        value: &Option<Value>,
+        scope: &Path,
+        top_id: ExprId,
+    ) -> Option<Self> {
+        let (pattern, guard, body) = match arm {
+            [Node::Constant(_, Value::String(pat)), guard, body] => {
+                (pat.parse::<Pattern>().ok()?, Arm::spec(guard)?, Arm::spec(body)?)
+            }
+            _ => return None,
+        };
+        let scope = scope.append(&format!("match{:?}", ExprId::new()));
+        let mut binds = vec![];
+        let matched = match value {
+            None => false,
+            Some(v) => bind_pattern(&pattern, v, &mut binds),
+        };
+        for (name, v) in binds {
+            vm::store_var(&mut ctx.variables, true, &scope, &name, v);
+        }
+        let guard = Node::compile_int(ctx, guard, scope.clone(), top_id);
+        let body = Node::compile_int(ctx, body, scope.clone(), top_id);
+        Some(Arm { pattern, scope, guard, body, matched, updated: None })
+    }
+
+    // match the pattern against a new value, and deliver the changed
+    // bindings to the guard and the body right away, so the arm is
+    // consistent with the value before anything is selected.
+    fn bind(&mut self, ctx: &mut ExecCtx<C, E>, value: &Value) {
+        let mut binds = vec![];
+        self.matched = bind_pattern(&self.pattern, value, &mut binds);
+        if self.matched {
+            for (name, v) in binds {
+                let cur = ctx.variables.get(&self.scope).and_then(|vars| vars.get(&name));
+                if cur != Some(&v) {
+                    vm::store_var(
+                        &mut ctx.variables,
+                        true,
+                        &self.scope,
+                        &name,
+                        v.clone(),
+                    );
+                    let event = Event::Variable(self.scope.clone(), name, v);
+                    self.guard.update(ctx, &event);
+                    if let Some(v) = self.body.update(ctx, &event) {
+                        self.updated = Some(v);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// match(v, pattern, guard, body, ...): the value of the body of the
+/// first arm whose pattern matches v and whose guard is true. The
+/// guard and body of each arm are passed as lambdas whose parameters
+/// are the names the pattern binds, they are compiled in a scope of
+/// their own with those names bound to the matching parts of v. Every
+/// arm stays compiled, so their state survives switching between
+/// them.
+pub struct Match<C: Ctx + 'static, E: 'static> {
+    value: Option<Value>,
+    arms: Vec<Arm<C, E>>,
+    /// None until an arm can be selected, Some(None) if no arm matches
+    selected: Option<Option<usize>>,
     invalid: bool,
 }
 
-impl<C: Ctx, E: Clone> Register<C, E> for Store {
+impl<C: Ctx, E: Clone> Register<C, E> for Match<C, E> {
     fn register(ctx: &mut ExecCtx<C, E>) {
-        let f: InitFn<C, E> = Arc::new(|ctx, from, _, top_id| {
-            let mut t = Store { queued: Vec::new(), dv: None, invalid: false, top_id };
+        let f: InitFn<C, E> = Arc::new(|ctx, from, scope, top_id| {
+            let mut t =
+                Match { value: None, arms: vec![], selected: None, invalid: false };
             match from {
-                [to, val] => {
-                    let to = to.current(ctx);
-                    let val = val.current(ctx);
-                    t.set(ctx, to, val)
+                [v, arms @ ..] if !arms.is_empty() && arms.len() % 3 == 0 => {
+                    t.value = v.current(ctx);
+                    for arm in arms.chunks(3) {
+                        match Arm::compile(ctx, arm, &t.value, &scope, top_id) {
+                            Some(arm) => t.arms.push(arm),
+                            None => {
+                                t.invalid = true;
+                                break;
+                            }
+                        }
+                    }
+                    t.selected = t.select(ctx);
                 }
                 _ => t.invalid = true,
             }
             Box::new(t)
         });
-        ctx.functions.insert("store".into(), f);
-        ctx.user.register_fn("store".into(), Path::root());
+        ctx.functions.insert("match".into(), f);
+        ctx.signatures
+            .insert("match".into(), Signature::new(4, None, &[Kind::Any], Kind::Any));
+        ctx.user.register_fn("match".into(), Path::root());
     }
 }
 
-impl<C: Ctx, E: Clone> Apply<C, E> for Store {
-    fn current(&self, _ctx: &mut ExecCtx<C, E>) -> Option<Value> {
+impl<C: Ctx, E: Clone> Apply<C, E> for Match<C, E> {
+    fn current(&self, ctx: &mut ExecCtx<C, E>) -> Option<Value> {
         if self.invalid {
-            Some(Value::Error(Chars::from(
-                "store(tgt: absolute path, val): expected 2 arguments",
-            )))
-        } else {
-            None
+            return Match::<C, E>::usage();
+        }
+        match self.selected {
+            None => None,
+            Some(Some(i)) => self.arms[i].body.current(ctx),
+            Some(None) => self.no_match(),
         }
     }
 
@@ -1429,154 +2273,130 @@ impl<C: Ctx, E: Clone> Apply<C, E> for Store {
         from: &mut [Node<C, E>],
         event: &Event<E>,
     ) -> Option<Value> {
-        match from {
-            [path, val] => {
-                let path = path.update(ctx, event);
-                let value = val.update(ctx, event);
-                let value = if path.is_some() && !self.same_path(&path) {
-                    value.or_else(|| val.current(ctx))
-                } else {
-                    value
-                };
-                let up = value.is_some();
-                self.set(ctx, path, value);
-                if up {
-                    Apply::<C, E>::current(self, ctx)
-                } else {
-                    None
-                }
+        if self.invalid {
+            let mut up = false;
+            for n in from {
+                up = n.update(ctx, event).is_some() || up;
             }
-            exprs => {
-                let mut up = false;
-                for expr in exprs {
-                    up = expr.update(ctx, event).is_some() || up;
-                }
-                self.invalid = true;
-                if up {
-                    Apply::<C, E>::current(self, ctx)
-                } else {
-                    None
+            return if up { Match::<C, E>::usage() } else { None };
+        }
+        let changed = match from[0].update(ctx, event) {
+            None => false,
+            Some(v) => {
+                self.value = Some(v);
+                true
+            }
+        };
+        for arm in &mut self.arms {
+            arm.guard.update(ctx, event);
+            arm.updated = arm.body.update(ctx, event);
+        }
+        if changed {
+            if let Some(v) = &self.value {
+                for arm in &mut self.arms {
+                    arm.bind(ctx, v);
                 }
             }
         }
+        match self.select(ctx) {
+            None => None,
+            selected if changed || selected != self.selected => {
+                self.selected = selected;
+                Apply::<C, E>::current(self, ctx)
+            }
+            Some(Some(i)) => self.arms[i].updated.take(),
+            Some(None) => None,
+        }
     }
 }
 
-impl Store {
-    fn queue(&mut self, v: Value) {
-        self.queued.push(v)
-    }
-
-    fn set<C: Ctx, E>(
-        &mut self,
-        ctx: &mut ExecCtx<C, E>,
-        to: Option<Value>,
-        val: Option<Value>,
-    ) {
-        match (pathname(&mut self.invalid, to), val) {
-            (None, None) => (),
-            (None, Some(v)) => match self.dv.as_ref() {
-                None => self.queue(v),
-                Some((_, dv)) => {
-                    dv.write(v);
-                }
-            },
-            (Some(path), val) => {
-                if let Some(v) = val {
-                    self.queue(v)
-                }
-                match &self.dv {
-                    Some((cur, dv)) if &path == cur => {
-                        for v in self.queued.drain(..) {
-                            dv.write(v);
-                        }
-                    }
-                    None | Some((_, _)) => {
-                        if let Some((cur, dv)) = self.dv.take() {
-                            ctx.user.unsubscribe(cur, dv, self.top_id);
-                        }
-                        let dv = ctx.user.durable_subscribe(
-                            UpdatesFlags::empty(),
-                            path.clone(),
-                            self.top_id,
-                        );
-                        for v in self.queued.drain(..) {
-                            dv.write(v);
-                        }
-                        self.dv = Some((path, dv));
-                    }
-                }
-            }
-        }
+impl<C: Ctx, E: Clone> Match<C, E> {
+    fn usage() -> Option<Value> {
+        Some(Value::Error(Chars::from(
+            "match(v, pattern, guard, body, ...): expected a value, and a pattern, guard lambda, and body lambda for each arm",
+        )))
     }
 
-    fn same_path(&self, new_path: &Option<Value>) -> bool {
-        match (new_path.as_ref(), self.dv.as_ref()) {
-            (Some(Value::String(p0)), Some((p1, _))) => &**p0 == &**p1,
-            _ => false,
-        }
+    fn no_match(&self) -> Option<Value> {
+        self.value.as_ref().map(|v| {
+            Value::Error(Chars::from(format!("match: no pattern matches {}", v)))
+        })
     }
-}
 
-fn varname(invalid: &mut bool, name: Option<Value>) -> Option<Chars> {
-    *invalid = false;
-    match name.map(|n| n.cast_to::<Chars>()) {
-        None => None,
-        Some(Err(_)) => {
-            *invalid = true;
-            None
+    // the first arm that matches with a true guard, None if that
+    // can't be known yet because the value, or a guard that must be
+    // consulted, has no value yet
+    fn select(&self, ctx: &mut ExecCtx<C, E>) -> Option<Option<usize>> {
+        if self.value.is_none() {
+            return None;
         }
-        Some(Ok(n)) => {
-            if VNAME.is_match(&n) {
-                Some(n)
-            } else {
-                *invalid = true;
-                None
+        for (i, arm) in self.arms.iter().enumerate() {
+            if arm.matched {
+                if let Value::True = arm.guard.current(ctx)? {
+                    return Some(Some(i));
+                }
             }
         }
+        Some(None)
     }
 }
 
-pub struct Set {
-    queued: Vec<Value>,
-    local: bool,
+/// catch(expr, f): the value of expr, unless it is an error, then
+/// the value of calling f with the error message. f is called again
+/// when the message changes.
+pub struct Catch<C: Ctx + 'static, E: 'static> {
     scope: Path,
-    name: Option<Chars>,
+    top_id: ExprId,
+    f: FnArg<C, E>,
+    value: Option<Value>,
+    call: Option<Call<C, E>>,
     invalid: bool,
 }
 
-impl<C: Ctx, E: Clone> Register<C, E> for Set {
+impl<C: Ctx, E: Clone> Register<C, E> for Catch<C, E> {
     fn register(ctx: &mut ExecCtx<C, E>) {
-        let f = |local| -> InitFn<C, E> {
-            Arc::new(move |ctx, from, scope, _| {
-                let mut t =
-                    Set { queued: Vec::new(), local, scope, name: None, invalid: false };
-                match from {
-                    [name, value] => {
-                        let name = name.current(ctx);
-                        let value = value.current(ctx);
-                        t.set(ctx, name, value)
-                    }
-                    _ => t.invalid = true,
+        let f: InitFn<C, E> = Arc::new(|ctx, from, scope, top_id| {
+            let mut t = Catch {
+                scope,
+                top_id,
+                f: FnArg(None),
+                value: None,
+                call: None,
+                invalid: false,
+            };
+            match from {
+                [v, f] => {
+                    t.f = FnArg::new(ctx, &t.scope, f);
+                    t.value = v.current(ctx);
+                    t.sync(ctx)
                 }
-                Box::new(t)
-            })
-        };
-        ctx.functions.insert("set".into(), f(false));
-        ctx.user.register_fn("set".into(), Path::root());
-        ctx.functions.insert("let".into(), f(true));
-        ctx.user.register_fn("let".into(), Path::root());
+                _ => t.invalid = true,
+            }
+            Box::new(t)
+        });
+        ctx.functions.insert("catch".into(), f);
+        ctx.signatures.insert(
+            "catch".into(),
+            Signature::new(2, Some(2), &[Kind::Any], Kind::Any).callback(1, 1),
+        );
+        ctx.user.register_fn("catch".into(), Path::root());
     }
 }
 
-impl<C: Ctx, E: Clone> Apply<C, E> for Set {
-    fn current(&self, _ctx: &mut ExecCtx<C, E>) -> Option<Value> {
+impl<C: Ctx, E: Clone> Apply<C, E> for Catch<C, E> {
+    fn current(&self, ctx: &mut ExecCtx<C, E>) -> Option<Value> {
         if self.invalid {
-            Some(Value::Error(Chars::from(
-                "set/let(name: string [a-z][a-z0-9_]+, value): expected 2 arguments",
-            )))
-        } else {
-            None
+            return Some(Value::Error(Chars::from(
+                "catch(expr, f: lambda or function name): expected 2 arguments",
+            )));
+        }
+        match (&self.f, &self.value) {
+            (_, None) | (FnArg(None), Some(Value::Error(_))) => None,
+            (FnArg(Some(Err(e))), Some(Value::Error(_))) => Some(e.clone()),
+            (FnArg(Some(Ok(_))), Some(Value::Error(_))) => {
+                self.call.as_ref().and_then(|call| call.current(ctx))
+            }
+            (_, Some(v)) => Some(v.clone()),
         }
     }
 
@@ -1587,17 +2407,23 @@ impl<C: Ctx, E: Clone> Apply<C, E> for Set {
         event: &Event<E>,
     ) -> Option<Value> {
         match from {
-            [name, val] => {
-                let name = name.update(ctx, event);
-                let value = val.update(ctx, event);
-                let value = if name.is_some() && !self.same_name(&name) {
-                    value.or_else(|| val.current(ctx))
-                } else {
-                    value
-                };
-                let up = value.is_some();
-                self.set(ctx, name, value);
+            [v, f] => {
+                let mut up = false;
+                if self.f.update(ctx, &self.scope, f, event) {
+                    if let Some(call) = self.call.take() {
+                        call.delete(ctx)
+                    }
+                    up = true;
+                }
+                if let Some(v) = v.update(ctx, event) {
+                    self.value = Some(v);
+                    up = true;
+                }
+                if let Some(call) = &mut self.call {
+                    up |= call.update(ctx, event).is_some();
+                }
                 if up {
+                    self.sync(ctx);
                     Apply::<C, E>::current(self, ctx)
                 } else {
                     None
@@ -1605,8 +2431,8 @@ impl<C: Ctx, E: Clone> Apply<C, E> for Set {
             }
             exprs => {
                 let mut up = false;
-                for expr in exprs {
-                    up = expr.update(ctx, event).is_some() || up;
+                for e in exprs {
+                    up |= e.update(ctx, event).is_some();
                 }
                 self.invalid = true;
                 if up {
@@ -1619,87 +2445,106 @@ impl<C: Ctx, E: Clone> Apply<C, E> for Set {
     }
 }
 
-impl Set {
-    fn queue_set(&mut self, v: Value) {
-        self.queued.push(v)
-    }
-
-    fn set<C: Ctx, E>(
-        &mut self,
-        ctx: &mut ExecCtx<C, E>,
-        name: Option<Value>,
-        value: Option<Value>,
-    ) {
-        if let Some(name) = varname(&mut self.invalid, name) {
-            for v in self.queued.drain(..) {
-                ctx.user.set_var(
-                    &mut ctx.variables,
-                    self.local,
-                    self.scope.clone(),
-                    name.clone(),
-                    v,
-                )
+impl<C: Ctx, E: Clone> Catch<C, E> {
+    fn sync(&mut self, ctx: &mut ExecCtx<C, E>) {
+        match (&self.f, &self.value) {
+            (FnArg(Some(Ok(f))), Some(Value::Error(e))) => {
+                let args = [Value::String(e.clone())];
+                if !self.call.as_ref().map(|c| c.is_call_of(&args)).unwrap_or(false) {
+                    let call = Call::new(ctx, f, &args, &self.scope, self.top_id);
+                    if let Some(call) = self.call.replace(call) {
+                        call.delete(ctx)
+                    }
+                }
             }
-            self.name = Some(name);
-        }
-        if let Some(value) = value {
-            match self.name.as_ref() {
-                None => self.queue_set(value),
-                Some(name) => ctx.user.set_var(
-                    &mut ctx.variables,
-                    self.local,
-                    self.scope.clone(),
-                    name.clone(),
-                    value,
-                ),
+            (_, _) => {
+                if let Some(call) = self.call.take() {
+                    call.delete(ctx)
+                }
             }
         }
     }
+}
 
-    fn same_name(&self, new_name: &Option<Value>) -> bool {
-        match (new_name, self.name.as_ref()) {
-            (Some(Value::String(n0)), Some(n1)) => n0 == n1,
-            _ => false,
-        }
-    }
+pub trait ArrayMapEval {
+    fn name() -> &'static str;
+    fn signature() -> Signature;
+
+    /// combine the elements of the array with the results of calling
+    /// the function on each of them
+    fn eval(elts: &[Value], results: Vec<Value>) -> Value;
 }
 
-pub(crate) struct Load {
-    path: Option<Path>,
-    cur: Option<Dval>,
+/// Call a function on every element of an array. Each element has
+/// it's own call, which is only rebuilt when the element changes.
+pub struct ArrayMap<C: Ctx + 'static, E: 'static, T: ArrayMapEval> {
+    scope: Path,
     top_id: ExprId,
+    f: FnArg<C, E>,
+    elts: Option<Value>,
+    calls: Vec<Call<C, E>>,
     invalid: bool,
+    t: PhantomData<T>,
 }
 
-impl<C: Ctx, E: Clone> Register<C, E> for Load {
+impl<C: Ctx, E: Clone, T: ArrayMapEval + Send + Sync + 'static> Register<C, E>
+    for ArrayMap<C, E, T>
+{
     fn register(ctx: &mut ExecCtx<C, E>) {
-        let f: InitFn<C, E> = Arc::new(|ctx, from, _, top_id| {
-            let mut t = Load { path: None, cur: None, invalid: false, top_id };
+        let f: InitFn<C, E> = Arc::new(|ctx, from, scope, top_id| {
+            let mut t = ArrayMap::<C, E, T> {
+                f: FnArg(None),
+                scope,
+                top_id,
+                elts: None,
+                calls: Vec::new(),
+                invalid: false,
+                t: PhantomData,
+            };
             match from {
-                [path] => {
-                    let path = path.current(ctx);
-                    t.subscribe(ctx, path)
-                },
+                [a, f] => {
+                    t.f = FnArg::new(ctx, &t.scope, f);
+                    t.elts = a.current(ctx);
+                    t.sync(ctx)
+                }
                 _ => t.invalid = true,
             }
             Box::new(t)
         });
-        ctx.functions.insert("load".into(), f);
-        ctx.user.register_fn("load".into(), Path::root());
+        ctx.functions.insert(T::name().into(), f);
+        ctx.signatures.insert(T::name().into(), T::signature());
+        ctx.user.register_fn(T::name().into(), Path::root());
     }
 }
 
-impl<C: Ctx, E: Clone> Apply<C, E> for Load {
-    fn current(&self, _ctx: &mut ExecCtx<C, E>) -> Option<Value> {
+impl<C: Ctx, E: Clone, T: ArrayMapEval + Send + Sync + 'static> Apply<C, E>
+    for ArrayMap<C, E, T>
+{
+    fn current(&self, ctx: &mut ExecCtx<C, E>) -> Option<Value> {
         if self.invalid {
-            Load::err()
-        } else {
-            self.cur.as_ref().and_then(|dv| match dv.last() {
-                subscriber::Event::Unsubscribed => {
-                    Some(Value::Error(Chars::from("#LOST")))
+            return Some(Value::Error(Chars::from(format!(
+                "{}(a: array, f: lambda or function name): expected 2 arguments",
+                T::name()
+            ))));
+        }
+        match (&self.f, &self.elts) {
+            (FnArg(None), _) | (_, None) => None,
+            (FnArg(Some(Err(e))), _) => Some(e.clone()),
+            (FnArg(Some(Ok(_))), Some(Value::Array(elts))) => {
+                let mut results = Vec::with_capacity(elts.len());
+                for call in &self.calls {
+                    match call.current(ctx) {
+                        None => return None,
+                        Some(v) => results.push(v),
+                    }
                 }
-                subscriber::Event::Update(v) => Some(v),
-            })
+                Some(T::eval(elts, results))
+            }
+            (FnArg(Some(Ok(_))), Some(v)) => Some(Value::Error(Chars::from(format!(
+                "{}: expected an array, not {}",
+                T::name(),
+                v
+            )))),
         }
     }
 
@@ -1710,39 +2555,34 @@ impl<C: Ctx, E: Clone> Apply<C, E> for Load {
         event: &Event<E>,
     ) -> Option<Value> {
         match from {
-            [name] => {
-                let up = match name.update(ctx, event) {
-                    None => false,
-                    Some(target) => {
-                        self.subscribe(ctx, Some(target));
-                        true
-                    }
-                };
-                if self.invalid {
-                    if up {
-                        Load::err()
-                    } else {
-                        None
-                    }
+            [a, f] => {
+                let mut up = false;
+                if self.f.update(ctx, &self.scope, f, event) {
+                    Call::truncate(ctx, &mut self.calls, 0);
+                    up = true;
+                }
+                if let Some(a) = a.update(ctx, event) {
+                    self.elts = Some(a);
+                    up = true;
+                }
+                for call in &mut self.calls {
+                    up |= call.update(ctx, event).is_some();
+                }
+                if up {
+                    self.sync(ctx);
+                    Apply::<C, E>::current(self, ctx)
                 } else {
-                    self.cur.as_ref().and_then(|dv| match event {
-                        Event::Variable(_, _, _)
-                        | Event::Rpc(_, _)
-                        | Event::Timer(_)
-                        | Event::User(_) => None,
-                        Event::Netidx(id, value) if dv.id() == *id => Some(value.clone()),
-                        Event::Netidx(_, _) => None,
-                    })
+                    None
                 }
             }
             exprs => {
                 let mut up = false;
                 for e in exprs {
-                    up = e.update(ctx, event).is_some() || up;
+                    up |= e.update(ctx, event).is_some();
                 }
                 self.invalid = true;
                 if up {
-                    Load::err()
+                    Apply::<C, E>::current(self, ctx)
                 } else {
                     None
                 }
@@ -1751,67 +2591,236 @@ impl<C: Ctx, E: Clone> Apply<C, E> for Load {
     }
 }
 
-impl Load {
-    fn subscribe<C: Ctx, E>(&mut self, ctx: &mut ExecCtx<C, E>, name: Option<Value>) {
-        if let Some(path) = pathname(&mut self.invalid, name) {
-            if Some(&path) != self.path.as_ref() {
-                if let (Some(path), Some(dv)) = (self.path.take(), self.cur.take()) {
-                    ctx.user.unsubscribe(path, dv, self.top_id);
+impl<C: Ctx, E: Clone, T: ArrayMapEval> ArrayMap<C, E, T> {
+    fn sync(&mut self, ctx: &mut ExecCtx<C, E>) {
+        match (&self.f, &self.elts) {
+            (FnArg(Some(Ok(f))), Some(Value::Array(elts))) => {
+                Call::truncate(ctx, &mut self.calls, elts.len());
+                for (i, elt) in elts.iter().enumerate() {
+                    let args = slice::from_ref(elt);
+                    Call::set(ctx, &mut self.calls, i, f, args, &self.scope, self.top_id)
                 }
-                self.path = Some(path.clone());
-                self.cur = Some(ctx.user.durable_subscribe(
-                    UpdatesFlags::BEGIN_WITH_LAST,
-                    path,
-                    self.top_id,
-                ));
             }
+            (_, _) => Call::truncate(ctx, &mut self.calls, 0),
         }
     }
+}
 
-    fn err() -> Option<Value> {
-        Some(Value::Error(Chars::from(
-            "load(expr: path) expected 1 absolute path as argument",
-        )))
+pub struct MapEv;
+
+impl ArrayMapEval for MapEv {
+    fn name() -> &'static str {
+        "map"
+    }
+
+    fn signature() -> Signature {
+        Signature::new(2, Some(2), &[Kind::Array, Kind::Any], Kind::Array).callback(1, 1)
+    }
+
+    fn eval(_elts: &[Value], results: Vec<Value>) -> Value {
+        Value::Array(Arc::from(results))
     }
 }
 
-struct BoundVar {
-    scope: Path,
-    value: Value,
+pub type Map<C, E> = ArrayMap<C, E, MapEv>;
+
+pub struct FilterArrayEv;
+
+impl ArrayMapEval for FilterArrayEv {
+    fn name() -> &'static str {
+        "filter_array"
+    }
+
+    fn signature() -> Signature {
+        Signature::new(2, Some(2), &[Kind::Array, Kind::Any], Kind::Array).callback(1, 1)
+    }
+
+    fn eval(elts: &[Value], results: Vec<Value>) -> Value {
+        let mut res = Vec::new();
+        for (elt, keep) in elts.iter().zip(results.into_iter()) {
+            match keep {
+                Value::True => res.push(elt.clone()),
+                Value::False => (),
+                _ => {
+                    return Value::Error(Chars::from(
+                        "filter_array(a, f): expected f to return a boolean",
+                    ))
+                }
+            }
+        }
+        Value::Array(Arc::from(res))
+    }
 }
 
-pub struct Get {
+pub type FilterArray<C, E> = ArrayMap<C, E, FilterArrayEv>;
+
+pub struct SortByEv;
+
+impl SortByEv {
+    /// A total order on values. Values of different types are ordered
+    /// by type, values of the same type are ordered by value.
+    fn cmp(v0: &Value, v1: &Value) -> CmpOrdering {
+        fn rank(v: &Value) -> u8 {
+            match v {
+                Value::Null => 0,
+                Value::False | Value::True => 1,
+                Value::U32(_) | Value::V32(_) => 2,
+                Value::I32(_) | Value::Z32(_) => 3,
+                Value::U64(_) | Value::V64(_) => 4,
+                Value::I64(_) | Value::Z64(_) => 5,
+                Value::F32(_) => 6,
+                Value::F64(_) => 7,
+                Value::Decimal(_) => 8,
+                Value::DateTime(_) => 9,
+                Value::Duration(_) => 10,
+                Value::String(_) => 11,
+                Value::Bytes(_) => 12,
+                Value::Ok => 13,
+                Value::Error(_) => 14,
+                Value::Array(_) => 15,
+            }
+        }
+        match (v0, v1) {
+            (Value::False | Value::True, Value::False | Value::True) => {
+                (v0 == &Value::True).cmp(&(v1 == &Value::True))
+            }
+            (Value::U32(l) | Value::V32(l), Value::U32(r) | Value::V32(r)) => l.cmp(r),
+            (Value::I32(l) | Value::Z32(l), Value::I32(r) | Value::Z32(r)) => l.cmp(r),
+            (Value::U64(l) | Value::V64(l), Value::U64(r) | Value::V64(r)) => l.cmp(r),
+            (Value::I64(l) | Value::Z64(l), Value::I64(r) | Value::Z64(r)) => l.cmp(r),
+            (Value::F32(l), Value::F32(r)) => l.total_cmp(r),
+            (Value::F64(l), Value::F64(r)) => l.total_cmp(r),
+            (Value::Decimal(l), Value::Decimal(r)) => l.cmp(r),
+            (Value::DateTime(l), Value::DateTime(r)) => l.cmp(r),
+            (Value::Duration(l), Value::Duration(r)) => l.cmp(r),
+            (Value::String(l), Value::String(r)) => l.cmp(r),
+            (Value::Bytes(l), Value::Bytes(r)) => l.cmp(r),
+            (Value::Error(l), Value::Error(r)) => l.cmp(r),
+            (Value::Array(l), Value::Array(r)) => l
+                .iter()
+                .zip(r.iter())
+                .map(|(v0, v1)| SortByEv::cmp(v0, v1))
+                .find(|o| *o != CmpOrdering::Equal)
+                .unwrap_or_else(|| l.len().cmp(&r.len())),
+            (v0, v1) => rank(v0).cmp(&rank(v1)),
+        }
+    }
+}
+
+impl ArrayMapEval for SortByEv {
+    fn name() -> &'static str {
+        "sort_by"
+    }
+
+    fn signature() -> Signature {
+        Signature::new(2, Some(2), &[Kind::Array, Kind::Any], Kind::Array).callback(1, 1)
+    }
+
+    fn eval(elts: &[Value], results: Vec<Value>) -> Value {
+        let mut keyed = results.into_iter().zip(elts.iter()).collect::<Vec<_>>();
+        keyed.sort_by(|(k0, _), (k1, _)| SortByEv::cmp(k0, k1));
+        Value::Array(Arc::from_iter(keyed.into_iter().map(|(_, v)| v.clone())))
+    }
+}
+
+pub type SortBy<C, E> = ArrayMap<C, E, SortByEv>;
+
+pub struct GroupByEv;
+
+impl ArrayMapEval for GroupByEv {
+    fn name() -> &'static str {
+        "group_by"
+    }
+
+    fn signature() -> Signature {
+        Signature::new(2, Some(2), &[Kind::Array, Kind::Any], Kind::Array).callback(1, 1)
+    }
+
+    fn eval(elts: &[Value], results: Vec<Value>) -> Value {
+        let mut groups: Vec<(Value, Vec<Value>)> = Vec::new();
+        for (key, elt) in results.into_iter().zip(elts.iter()) {
+            match groups.iter_mut().find(|(k, _)| k == &key) {
+                Some((_, group)) => group.push(elt.clone()),
+                None => groups.push((key, vec![elt.clone()])),
+            }
+        }
+        Value::Array(Arc::from_iter(groups.into_iter().map(|(key, group)| {
+            Value::Array(Arc::from_iter([key, Value::Array(Arc::from(group))]))
+        })))
+    }
+}
+
+pub type GroupBy<C, E> = ArrayMap<C, E, GroupByEv>;
+
+/// Call a function on an accumulator and each element of an array
+/// in turn. The calls are chained, when a call's result changes
+/// every call after it is rebuilt.
+pub struct Fold<C: Ctx + 'static, E: 'static> {
     scope: Path,
-    name: Option<Chars>,
-    var: Option<BoundVar>,
     top_id: ExprId,
+    f: FnArg<C, E>,
+    elts: Option<Value>,
+    init: Option<Value>,
+    calls: Vec<Call<C, E>>,
     invalid: bool,
 }
 
-impl<C: Ctx, E: Clone> Register<C, E> for Get {
+impl<C: Ctx, E: Clone> Register<C, E> for Fold<C, E> {
     fn register(ctx: &mut ExecCtx<C, E>) {
         let f: InitFn<C, E> = Arc::new(|ctx, from, scope, top_id| {
-            let mut t = Get { scope, name: None, var: None, invalid: false, top_id };
+            let mut t = Fold {
+                f: FnArg(None),
+                scope,
+                top_id,
+                elts: None,
+                init: None,
+                calls: Vec::new(),
+                invalid: false,
+            };
             match from {
-                [name] => {
-                    let name = name.current(ctx);
-                    t.subscribe(ctx, name)
-                },
+                [a, init, f] => {
+                    t.f = FnArg::new(ctx, &t.scope, f);
+                    t.elts = a.current(ctx);
+                    t.init = init.current(ctx);
+                    t.sync(ctx)
+                }
                 _ => t.invalid = true,
             }
             Box::new(t)
         });
-        ctx.functions.insert("get".into(), f);
-        ctx.user.register_fn("get".into(), Path::root());
+        ctx.functions.insert("fold".into(), f);
+        ctx.signatures.insert(
+            "fold".into(),
+            Signature::new(3, Some(3), &[Kind::Array, Kind::Any], Kind::Any)
+                .callback(2, 2),
+        );
+        ctx.user.register_fn("fold".into(), Path::root());
     }
 }
 
-impl<C: Ctx, E: Clone> Apply<C, E> for Get {
-    fn current(&self, _ctx: &mut ExecCtx<C, E>) -> Option<Value> {
+impl<C: Ctx, E: Clone> Apply<C, E> for Fold<C, E> {
+    fn current(&self, ctx: &mut ExecCtx<C, E>) -> Option<Value> {
         if self.invalid {
-            Get::err()
-        } else {
-            self.var.as_ref().map(|bv| bv.value.clone())
+            return Some(Value::Error(Chars::from(
+                "fold(a: array, init, f: lambda or function name): expected 3 arguments",
+            )));
+        }
+        match (&self.f, &self.elts, &self.init) {
+            (FnArg(None), _, _) | (_, None, _) | (_, _, None) => None,
+            (FnArg(Some(Err(e))), _, _) => Some(e.clone()),
+            (FnArg(Some(Ok(_))), Some(Value::Array(elts)), Some(init)) => {
+                if self.calls.len() < elts.len() {
+                    None
+                } else {
+                    match self.calls.last() {
+                        None => Some(init.clone()),
+                        Some(call) => call.current(ctx),
+                    }
+                }
+            }
+            (FnArg(Some(Ok(_))), Some(v), Some(_)) => Some(Value::Error(Chars::from(
+                format!("fold: expected an array, not {}", v),
+            ))),
         }
     }
 
@@ -1822,57 +2831,38 @@ impl<C: Ctx, E: Clone> Apply<C, E> for Get {
         event: &Event<E>,
     ) -> Option<Value> {
         match from {
-            [name] => {
-                let up = match name.update(ctx, event) {
-                    None => false,
-                    Some(target) => {
-                        self.subscribe(ctx, Some(target));
-                        true
-                    }
-                };
-                if self.invalid {
-                    if up {
-                        Get::err()
-                    } else {
-                        None
-                    }
+            [a, init, f] => {
+                let mut up = false;
+                if self.f.update(ctx, &self.scope, f, event) {
+                    Call::truncate(ctx, &mut self.calls, 0);
+                    up = true;
+                }
+                if let Some(a) = a.update(ctx, event) {
+                    self.elts = Some(a);
+                    up = true;
+                }
+                if let Some(init) = init.update(ctx, event) {
+                    self.init = Some(init);
+                    up = true;
+                }
+                for call in &mut self.calls {
+                    up |= call.update(ctx, event).is_some();
+                }
+                if up {
+                    self.sync(ctx);
+                    Apply::<C, E>::current(self, ctx)
                 } else {
-                    match (self.name.as_ref(), self.var.as_mut(), event) {
-                        (Some(vn), Some(bv), Event::Variable(es, en, v))
-                            if vn == en
-                                && Path::is_parent(es, &self.scope)
-                                && Path::is_parent(&bv.scope, es) =>
-                        {
-                            if &bv.scope != es {
-                                bv.scope = es.clone();
-                            }
-                            bv.value = v.clone();
-                            Some(v.clone())
-                        }
-                        (Some(vn), None, Event::Variable(es, en, v))
-                            if vn == en && Path::is_parent(es, &self.scope) =>
-                        {
-                            self.var =
-                                Some(BoundVar { scope: es.clone(), value: v.clone() });
-                            Some(v.clone())
-                        }
-                        (None, _, _)
-                        | (Some(_), _, Event::Netidx(_, _))
-                        | (Some(_), _, Event::User(_))
-                        | (Some(_), _, Event::Rpc(_, _))
-                        | (Some(_), _, Event::Timer(_))
-                        | (Some(_), _, Event::Variable(_, _, _)) => None,
-                    }
+                    None
                 }
             }
             exprs => {
                 let mut up = false;
                 for e in exprs {
-                    up = e.update(ctx, event).is_some() || up;
+                    up |= e.update(ctx, event).is_some();
                 }
                 self.invalid = true;
                 if up {
-                    Get::err()
+                    Apply::<C, E>::current(self, ctx)
                 } else {
                     None
                 }
@@ -1881,82 +2871,106 @@ impl<C: Ctx, E: Clone> Apply<C, E> for Get {
     }
 }
 
-impl Get {
-    fn err() -> Option<Value> {
-        Some(Value::Error(Chars::from(
-            "load_var(expr: variable name): expected 1 variable name as argument",
-        )))
+impl<C: Ctx, E: Clone> Fold<C, E> {
+    fn sync(&mut self, ctx: &mut ExecCtx<C, E>) {
+        match (&self.f, &self.elts) {
+            (FnArg(Some(Ok(f))), Some(Value::Array(elts))) => {
+                Call::truncate(ctx, &mut self.calls, elts.len());
+                let mut acc = self.init.clone();
+                for (i, elt) in elts.iter().enumerate() {
+                    let args = match acc {
+                        None => {
+                            Call::truncate(ctx, &mut self.calls, i);
+                            break;
+                        }
+                        Some(acc) => [acc, elt.clone()],
+                    };
+                    Call::set(
+                        ctx,
+                        &mut self.calls,
+                        i,
+                        f,
+                        &args,
+                        &self.scope,
+                        self.top_id,
+                    );
+                    acc = self.calls[i].current(ctx);
+                }
+            }
+            (_, _) => Call::truncate(ctx, &mut self.calls, 0),
+        }
     }
+}
 
-    fn subscribe<C: Ctx, E: Clone>(
+pub struct Count {
+    from: CachedVals,
+    count: u64,
+}
+
+impl<C: Ctx, E: Clone> Register<C, E> for Count {
+    fn register(ctx: &mut ExecCtx<C, E>) {
+        let f: InitFn<C, E> = Arc::new(|ctx, from, _, _| {
+            Box::new(Count { from: CachedVals::new(from, ctx), count: 0 })
+        });
+        ctx.functions.insert("count".into(), f);
+        ctx.signatures.insert(
+            "count".into(),
+            Signature::new(1, Some(1), &[Kind::Any], Kind::Number),
+        );
+        ctx.user.register_fn("count".into(), Path::root());
+    }
+}
+
+impl<C: Ctx, E: Clone> Apply<C, E> for Count {
+    fn current(&self, _ctx: &mut ExecCtx<C, E>) -> Option<Value> {
+        match &*self.from.0 {
+            [] => Some(Value::Error(Chars::from("count(s): requires 1 argument"))),
+            [_] => Some(Value::U64(self.count)),
+            _ => Some(Value::Error(Chars::from("count(s): requires 1 argument"))),
+        }
+    }
+
+    fn update(
         &mut self,
         ctx: &mut ExecCtx<C, E>,
-        name: Option<Value>,
-    ) {
-        match varname(&mut self.invalid, name) {
-            None => {
-                self.var = None;
-                self.name = None;
-                if let Some(old) = self.name.take() {
-                    ctx.user.unref_var(old.clone(), self.scope.clone(), self.top_id);
-                }
-            }
-            Some(name) if self.name.as_ref() == Some(&name) => (),
-            Some(name) => {
-                if let Some(old) = self.name.take() {
-                    ctx.user.unref_var(old.clone(), self.scope.clone(), self.top_id);
-                }
-                self.var = ctx.lookup_var(&self.scope, &name).map(|(scope, value)| {
-                    BoundVar { scope: scope.clone(), value: value.clone() }
-                });
-                ctx.user.ref_var(name.clone(), self.scope.clone(), self.top_id);
-                self.name = Some(name);
-            }
+        from: &mut [Node<C, E>],
+        event: &Event<E>,
+    ) -> Option<Value> {
+        if self.from.update(ctx, from, event) {
+            self.count += 1;
+            Apply::<C, E>::current(self, ctx)
+        } else {
+            None
         }
     }
 }
 
-atomic_id!(RpcCallId);
-
-pub(crate) struct RpcCall {
-    args: CachedVals,
-    top_id: ExprId,
-    pending: FxHashSet<RpcCallId>,
+pub struct Sample {
     current: Option<Value>,
-    triggered: bool,
-    invalid: bool,
 }
 
-impl<C: Ctx, E: Clone> Register<C, E> for RpcCall {
+impl<C: Ctx, E: Clone> Register<C, E> for Sample {
     fn register(ctx: &mut ExecCtx<C, E>) {
-        let f: InitFn<C, E> = Arc::new(|ctx, from, _, top_id| {
-            let triggered = match from {
-                [trigger, ..] => trigger.current(ctx).is_some(),
-                _ => false,
-            };
-            let mut t = RpcCall {
-                args: CachedVals::new(
-                    match from {
-                        [_, rest @ ..] => rest,
-                        [] => &[],
-                    },
-                    ctx,
-                ),
-                current: None,
-                top_id,
-                pending: HashSet::with_hasher(FxBuildHasher::default()),
-                triggered,
-                invalid: false,
+        let f: InitFn<C, E> = Arc::new(|ctx, from, _, _| {
+            let current = match from {
+                [trigger, source] => match trigger.current(ctx) {
+                    None => None,
+                    Some(_) => source.current(ctx),
+                },
+                _ => Some(Value::Error(Chars::from(
+                    "sample(trigger, source): expected 2 arguments",
+                ))),
             };
-            t.maybe_call(ctx);
-            Box::new(t)
+            Box::new(Sample { current })
         });
-        ctx.functions.insert("call".into(), f);
-        ctx.user.register_fn("call".into(), Path::root());
+        ctx.functions.insert("sample".into(), f);
+        ctx.signatures
+            .insert("sample".into(), Signature::new(2, Some(2), &[Kind::Any], Kind::Any));
+        ctx.user.register_fn("sample".into(), Path::root());
     }
 }
 
-impl<C: Ctx, E: Clone> Apply<C, E> for RpcCall {
+impl<C: Ctx, E: Clone> Apply<C, E> for Sample {
     fn current(&self, _ctx: &mut ExecCtx<C, E>) -> Option<Value> {
         self.current.clone()
     }
@@ -1967,153 +2981,107 @@ impl<C: Ctx, E: Clone> Apply<C, E> for RpcCall {
         from: &mut [Node<C, E>],
         event: &Event<E>,
     ) -> Option<Value> {
-        match event {
-            Event::Rpc(id, v) if self.pending.remove(&id) => {
-                self.current = Some(v.clone());
-                Apply::<C, E>::current(self, ctx)
+        match from {
+            [trigger, source] => {
+                source.update(ctx, event);
+                if trigger.update(ctx, event).is_none() {
+                    None
+                } else {
+                    let v = source.current(ctx);
+                    self.current = v.clone();
+                    v
+                }
+            }
+            _ => {
+                let v = Some(Value::Error(Chars::from(
+                    "sample(trigger, source): expected 2 arguments",
+                )));
+                self.current = v.clone();
+                v
             }
-            event => match from {
-                [trigger, args @ ..] => {
-                    self.args.update(ctx, args, event);
-                    if trigger.update(ctx, event).is_some() {
-                        self.triggered = true;
-                    }
-                    let triggered = self.triggered;
-                    self.maybe_call(ctx);
-                    if triggered && self.invalid {
-                        Apply::<C, E>::current(self, ctx)
-                    } else {
-                        None
-                    }
-                }
-                [] => {
-                    self.invalid();
-                    Apply::<C, E>::current(self, ctx)
-                }
-            },
         }
     }
 }
 
-impl RpcCall {
-    fn invalid(&mut self) {
-        let m = "call(trigger, rpc, kwargs): expected at least 2 arguments, and an even number of kwargs";
-        self.current = Some(Value::Error(Chars::from(m)));
-        self.invalid = true;
+pub struct Mean {
+    from: CachedVals,
+    total: f64,
+    samples: usize,
+}
+
+impl<C: Ctx, E: Clone> Register<C, E> for Mean {
+    fn register(ctx: &mut ExecCtx<C, E>) {
+        let f: InitFn<C, E> = Arc::new(|ctx, from, _, _| {
+            Box::new(Mean { from: CachedVals::new(from, ctx), total: 0., samples: 0 })
+        });
+        ctx.functions.insert("mean".into(), f);
+        ctx.signatures.insert(
+            "mean".into(),
+            Signature::new(1, Some(1), &[Kind::Number], Kind::Number),
+        );
+        ctx.user.register_fn("mean".into(), Path::root());
     }
+}
 
-    fn get_args(&mut self) -> Option<(Path, Vec<(Chars, Value)>)> {
-        self.current = None;
-        self.invalid = false;
-        let len = self.args.0.len();
-        if len == 0 || (len > 1 && len.is_power_of_two()) {
-            self.invalid();
-            None
-        } else if self.args.0.iter().any(|v| v.is_none()) {
-            None
-        } else {
-            match &self.args.0[..] {
-                [] => {
-                    self.invalid();
+impl<C: Ctx, E: Clone> Apply<C, E> for Mean {
+    fn current(&self, _ctx: &mut ExecCtx<C, E>) -> Option<Value> {
+        match &*self.from.0 {
+            [] => Some(Value::Error(Chars::from("mean(s): requires 1 argument"))),
+            [_] => {
+                if self.samples > 0 {
+                    Some(Value::F64(self.total / (self.samples as f64)))
+                } else {
                     None
                 }
-                [path, args @ ..] => {
-                    match path.as_ref().unwrap().clone().cast_to::<Chars>() {
-                        Err(_) => {
-                            self.invalid();
-                            None
-                        }
-                        Ok(name) => {
-                            let mut iter = args.into_iter();
-                            let mut args = Vec::new();
-                            loop {
-                                match iter.next() {
-                                    None | Some(None) => break,
-                                    Some(Some(name)) => {
-                                        match name.clone().cast_to::<Chars>() {
-                                            Err(_) => {
-                                                self.invalid();
-                                                return None;
-                                            }
-                                            Ok(name) => match iter.next() {
-                                                None | Some(None) => {
-                                                    self.invalid();
-                                                    return None;
-                                                }
-                                                Some(Some(val)) => {
-                                                    args.push((name, val.clone()));
-                                                }
-                                            },
-                                        }
-                                    }
-                                }
-                            }
-                            Some((Path::from(name), args))
-                        }
-                    }
-                }
             }
+            _ => Some(Value::Error(Chars::from("mean(s): requires 1 argument"))),
         }
     }
 
-    fn maybe_call<C: Ctx, E>(&mut self, ctx: &mut ExecCtx<C, E>) {
-        if self.triggered {
-            if let Some((name, args)) = self.get_args() {
-                self.triggered = false;
-                let id = RpcCallId::new();
-                self.pending.insert(id);
-                ctx.user.call_rpc(Path::from(name), args, self.top_id, id);
+    fn update(
+        &mut self,
+        ctx: &mut ExecCtx<C, E>,
+        from: &mut [Node<C, E>],
+        event: &Event<E>,
+    ) -> Option<Value> {
+        if self.from.update(ctx, from, event) {
+            for v in self.from.flat_iter() {
+                if let Some(v) = v {
+                    if let Ok(v) = v.clone().cast_to::<f64>() {
+                        self.total += v;
+                        self.samples += 1;
+                    }
+                }
             }
+            Apply::<C, E>::current(self, ctx)
+        } else {
+            None
         }
     }
 }
 
-atomic_id!(TimerId);
-
-pub(crate) struct AfterIdle {
-    timeout: Option<Value>,
-    cur: Option<Value>,
-    updated: bool,
-    timer_set: bool,
-    id: TimerId,
-    eid: ExprId,
-    invalid: bool,
-}
+pub(crate) struct Uniq(Option<Value>);
 
-impl<C: Ctx, E: Clone> Register<C, E> for AfterIdle {
+impl<C: Ctx, E: Clone> Register<C, E> for Uniq {
     fn register(ctx: &mut ExecCtx<C, E>) {
-        let f: InitFn<C, E> = Arc::new(|ctx, from, _, eid| match from {
-            [timeout, cur] => {
-                let mut t = AfterIdle {
-                    timeout: timeout.current(ctx),
-                    cur: cur.current(ctx),
-                    updated: false,
-                    timer_set: false,
-                    id: TimerId::new(),
-                    eid,
-                    invalid: false,
-                };
-                t.maybe_set_timer(ctx);
-                Box::new(t)
+        let f: InitFn<C, E> = Arc::new(|ctx, from, _, _| {
+            let mut t = Uniq(None);
+            match from {
+                [e] => t.0 = e.current(ctx),
+                _ => t.0 = Uniq::usage(),
             }
-            _ => Box::new(AfterIdle {
-                timeout: None,
-                cur: None,
-                updated: false,
-                timer_set: false,
-                id: TimerId::new(),
-                eid,
-                invalid: true,
-            }),
+            Box::new(t)
         });
-        ctx.functions.insert("after_idle".into(), f);
-        ctx.user.register_fn("after_idle".into(), Path::root());
+        ctx.functions.insert("uniq".into(), f);
+        ctx.signatures
+            .insert("uniq".into(), Signature::new(1, Some(1), &[Kind::Any], Kind::Any));
+        ctx.user.register_fn("uniq".into(), Path::root());
     }
 }
 
-impl<C: Ctx, E: Clone> Apply<C, E> for AfterIdle {
+impl<C: Ctx, E: Clone> Apply<C, E> for Uniq {
     fn current(&self, _ctx: &mut ExecCtx<C, E>) -> Option<Value> {
-        self.usage()
+        self.0.as_ref().cloned()
     }
 
     fn update(
@@ -2123,44 +3091,22 @@ impl<C: Ctx, E: Clone> Apply<C, E> for AfterIdle {
         event: &Event<E>,
     ) -> Option<Value> {
         match from {
-            [timeout, cur] => {
-                if let Some(timeout) = timeout.update(ctx, event) {
-                    self.timeout = Some(timeout);
-                    self.maybe_set_timer(ctx);
-                }
-                if let Some(cur) = cur.update(ctx, event) {
-                    self.updated = true;
-                    self.cur = Some(cur);
-                    self.maybe_set_timer(ctx);
-                }
-                match event {
-                    Event::Variable(_, _, _)
-                    | Event::Netidx(_, _)
-                    | Event::Rpc(_, _)
-                    | Event::User(_) => self.usage(),
-                    Event::Timer(id) => {
-                        if id != &self.id {
-                            self.usage()
-                        } else {
-                            self.timer_set = false;
-                            if self.updated {
-                                self.maybe_set_timer(ctx);
-                                self.usage()
-                            } else {
-                                self.cur.clone()
-                            }
-                        }
-                    }
+            [e] => e.update(ctx, event).and_then(|v| {
+                if Some(&v) != self.0.as_ref() {
+                    self.0 = Some(v.clone());
+                    Some(v)
+                } else {
+                    None
                 }
-            }
+            }),
             exprs => {
                 let mut up = false;
-                self.invalid = true;
-                for expr in exprs {
-                    up |= expr.update(ctx, event).is_some();
+                for e in exprs {
+                    up = e.update(ctx, event).is_some() || up;
                 }
+                self.0 = Uniq::usage();
                 if up {
-                    self.usage()
+                    Apply::<C, E>::current(self, ctx)
                 } else {
                     None
                 }
@@ -2169,86 +3115,70 @@ impl<C: Ctx, E: Clone> Apply<C, E> for AfterIdle {
     }
 }
 
-impl AfterIdle {
-    fn maybe_set_timer<C: Ctx, E>(&mut self, ctx: &mut ExecCtx<C, E>) {
-        use std::time::Duration;
-        if !self.invalid && !self.timer_set {
-            match (&self.timeout, &self.cur) {
-                (Some(timeout), Some(_)) => match timeout.clone().cast_to::<f64>() {
-                    Err(_) => {
-                        self.invalid = true;
-                    }
-                    Ok(timeout) => {
-                        self.invalid = false;
-                        self.updated = false;
-                        self.timer_set = true;
-                        ctx.user.set_timer(
-                            self.id,
-                            Duration::from_secs_f64(timeout),
-                            self.eid,
-                        );
-                    }
-                },
-                (_, _) => (),
+impl Uniq {
+    fn usage() -> Option<Value> {
+        Some(Value::Error(Chars::from("uniq(e): expected 1 argument")))
+    }
+}
+
+fn pathname(invalid: &mut bool, path: Option<Value>) -> Option<Path> {
+    *invalid = false;
+    match path.map(|v| v.cast_to::<String>()) {
+        None => None,
+        Some(Ok(p)) => {
+            if Path::is_absolute(&p) {
+                Some(Path::from(p))
+            } else {
+                *invalid = true;
+                None
             }
         }
+        Some(Err(_)) => {
+            *invalid = true;
+            None
+        }
     }
+}
 
-    fn usage(&self) -> Option<Value> {
-        if self.invalid {
-            Some(Value::Error(Chars::from(
-                "after_idle(timeout: f64, v: any): expected two arguments",
-            )))
-        } else {
-            None
-        }
-    }
-}
-
-pub(crate) struct Timer {
-    id: TimerId,
-    eid: ExprId,
-    timeout: Option<Value>,
-    repeat: Option<Value>,
-    timer_set: bool,
+pub struct Store {
+    queued: Vec<Value>,
+    top_id: ExprId,
+    dv: Option<(Path, Dval)>,
     invalid: bool,
 }
 
-impl<C: Ctx, E: Clone> Register<C, E> for Timer {
+impl<C: Ctx, E: Clone> Register<C, E> for Store {
     fn register(ctx: &mut ExecCtx<C, E>) {
-        let f: InitFn<C, E> = Arc::new(|ctx, from, _, eid| match from {
-            [timeout, repeat] => {
-                let mut t = Self {
-                    id: TimerId::new(),
-                    eid,
-                    timeout: timeout.current(ctx),
-                    repeat: match repeat.current(ctx) {
-                        Some(Value::False) => Some(1.into()),
-                        v => v,
-                    },
-                    timer_set: false,
-                    invalid: false,
-                };
-                t.maybe_set_timer(ctx);
-                Box::new(t)
+        let f: InitFn<C, E> = Arc::new(|ctx, from, _, top_id| {
+            let mut t = Store { queued: Vec::new(), dv: None, invalid: false, top_id };
+            match from {
+                [to, val] => {
+                    let to = to.current(ctx);
+                    let val = val.current(ctx);
+                    t.set(ctx, to, val)
+                }
+                _ => t.invalid = true,
             }
-            _ => Box::new(Self {
-                id: TimerId::new(),
-                eid,
-                timeout: None,
-                repeat: None,
-                timer_set: false,
-                invalid: true,
-            }),
+            Box::new(t)
         });
-        ctx.functions.insert("timer".into(), f);
-        ctx.user.register_fn("timer".into(), Path::root());
+        ctx.functions.insert("store".into(), f);
+        ctx.signatures.insert(
+            "store".into(),
+            Signature::new(2, Some(2), &[Kind::String, Kind::Any], Kind::Any),
+        );
+        ctx.user.register_fn("store".into(), Path::root());
     }
 }
 
-impl<C: Ctx, E: Clone> Apply<C, E> for Timer {
+impl<C: Ctx, E: Clone> Apply<C, E> for Store {
     fn current(&self, _ctx: &mut ExecCtx<C, E>) -> Option<Value> {
-        self.usage()
+        if self.invalid {
+            Some(Value::Error(Chars::from(
+                "store(tgt: absolute path, val): expected 2 arguments",
+            )))
+        } else {
+            None
+        }
     }
 
     fn update(
@@ -2258,96 +3188,2545 @@ impl<C: Ctx, E: Clone> Apply<C, E> for Timer {
         event: &Event<E>,
     ) -> Option<Value> {
         match from {
-            [timeout, repeat] => {
-                if let Some(timeout) = timeout.update(ctx, event) {
-                    self.timeout = Some(timeout);
-                    self.maybe_set_timer(ctx);
-                }
-                if let Some(repeat) = repeat.update(ctx, event) {
-                    self.repeat = Some(repeat);
-                    self.maybe_set_timer(ctx);
-                }
-                match event {
-                    Event::Variable(_, _, _)
-                    | Event::Netidx(_, _)
-                    | Event::Rpc(_, _)
-                    | Event::User(_) => self.usage(),
-                    Event::Timer(id) => {
-                        if id != &self.id {
-                            self.usage()
-                        } else {
-                            self.timer_set = false;
-                            self.maybe_set_timer(ctx);
-                            Some(Value::Null)
-                        }
-                    }
+            [path, val] => {
+                let path = path.update(ctx, event);
+                let value = val.update(ctx, event);
+                let value = if path.is_some() && !self.same_path(&path) {
+                    value.or_else(|| val.current(ctx))
+                } else {
+                    value
+                };
+                let up = value.is_some();
+                self.set(ctx, path, value);
+                if up {
+                    Apply::<C, E>::current(self, ctx)
+                } else {
+                    None
                 }
             }
             exprs => {
                 let mut up = false;
-                self.invalid = true;
                 for expr in exprs {
-                    up |= expr.update(ctx, event).is_some();
+                    up = expr.update(ctx, event).is_some() || up;
                 }
+                self.invalid = true;
                 if up {
-                    self.usage()
+                    Apply::<C, E>::current(self, ctx)
                 } else {
                     None
                 }
             }
         }
     }
+
+    fn delete(&mut self, ctx: &mut ExecCtx<C, E>) {
+        if let Some((path, dv)) = self.dv.take() {
+            ctx.user.unsubscribe(path, dv, self.top_id);
+        }
+    }
 }
 
-impl Timer {
-    fn maybe_set_timer<C: Ctx, E>(&mut self, ctx: &mut ExecCtx<C, E>) {
-        use std::time::Duration;
-        if !self.invalid && !self.timer_set {
-            match (&self.timeout, &self.repeat) {
-                (None, _) | (_, None) => (),
-                (Some(timeout), Some(repeat)) => {
-                    let timeout = timeout.clone().cast_to::<f64>();
-                    let repeat = match repeat {
-                        Value::Null => Ok(None),
-                        Value::True => Ok(None),
-                        Value::False => Ok(Some(0)),
-                        v => v.clone().cast_to::<u64>().map(|v| Some(v)),
-                    };
-                    match (timeout, repeat) {
-                        (Ok(timeout), Ok(repeat)) => {
-                            let go = match repeat {
-                                None => true,
-                                Some(n) => {
-                                    if n == 0 {
-                                        false
-                                    } else {
-                                        self.repeat = Some((n - 1).into());
-                                        true
-                                    }
-                                }
-                            };
-                            if go {
-                                self.timer_set = true;
-                                let d = Duration::from_secs_f64(timeout);
-                                ctx.user.set_timer(self.id, d, self.eid);
-                            }
+impl Store {
+    fn queue(&mut self, v: Value) {
+        self.queued.push(v)
+    }
+
+    fn set<C: Ctx, E>(
+        &mut self,
+        ctx: &mut ExecCtx<C, E>,
+        to: Option<Value>,
+        val: Option<Value>,
+    ) {
+        match (pathname(&mut self.invalid, to), val) {
+            (None, None) => (),
+            (None, Some(v)) => match self.dv.as_ref() {
+                None => self.queue(v),
+                Some((path, dv)) => ctx.user.write(path, dv, v, self.top_id),
+            },
+            (Some(path), val) => {
+                if let Some(v) = val {
+                    self.queue(v)
+                }
+                match &self.dv {
+                    Some((cur, dv)) if &path == cur => {
+                        for v in self.queued.drain(..) {
+                            ctx.user.write(cur, dv, v, self.top_id);
                         }
-                        (_, _) => {
-                            self.invalid = true;
+                    }
+                    None | Some((_, _)) => {
+                        if let Some((cur, dv)) = self.dv.take() {
+                            ctx.user.unsubscribe(cur, dv, self.top_id);
+                        }
+                        let dv = ctx.user.durable_subscribe(
+                            UpdatesFlags::empty(),
+                            path.clone(),
+                            self.top_id,
+                        );
+                        for v in self.queued.drain(..) {
+                            ctx.user.write(&path, &dv, v, self.top_id);
                         }
+                        self.dv = Some((path, dv));
                     }
                 }
             }
         }
     }
 
-    fn usage(&self) -> Option<Value> {
+    fn same_path(&self, new_path: &Option<Value>) -> bool {
+        match (new_path.as_ref(), self.dv.as_ref()) {
+            (Some(Value::String(p0)), Some((p1, _))) => &**p0 == &**p1,
+            _ => false,
+        }
+    }
+}
+
+fn varname(re: &Regex, invalid: &mut bool, name: Option<Value>) -> Option<Chars> {
+    *invalid = false;
+    match name.map(|n| n.cast_to::<Chars>()) {
+        None => None,
+        Some(Err(_)) => {
+            *invalid = true;
+            None
+        }
+        Some(Ok(n)) => {
+            if re.is_match(&n) {
+                Some(n)
+            } else {
+                *invalid = true;
+                None
+            }
+        }
+    }
+}
+
+pub struct Set {
+    queued: Vec<Value>,
+    local: bool,
+    scope: Path,
+    name: Option<Chars>,
+    invalid: bool,
+}
+
+impl<C: Ctx, E: Clone> Register<C, E> for Set {
+    fn register(ctx: &mut ExecCtx<C, E>) {
+        let f = |local| -> InitFn<C, E> {
+            Arc::new(move |ctx, from, scope, _| {
+                let mut t =
+                    Set { queued: Vec::new(), local, scope, name: None, invalid: false };
+                match from {
+                    [name, value] => {
+                        let name = name.current(ctx);
+                        let value = value.current(ctx);
+                        t.set(ctx, name, value)
+                    }
+                    _ => t.invalid = true,
+                }
+                Box::new(t)
+            })
+        };
+        let sig = Signature::new(2, Some(2), &[Kind::String, Kind::Any], Kind::Any);
+        ctx.functions.insert("set".into(), f(false));
+        ctx.signatures.insert("set".into(), sig.clone());
+        ctx.user.register_fn("set".into(), Path::root());
+        ctx.functions.insert("let".into(), f(true));
+        ctx.signatures.insert("let".into(), sig);
+        ctx.user.register_fn("let".into(), Path::root());
+    }
+}
+
+impl<C: Ctx, E: Clone> Apply<C, E> for Set {
+    fn current(&self, _ctx: &mut ExecCtx<C, E>) -> Option<Value> {
         if self.invalid {
             Some(Value::Error(Chars::from(
-                "timer(timeout: f64, repeat): expected two arguments",
+                "set/let(name: string [a-z][a-z0-9_]+, value): expected 2 arguments",
             )))
         } else {
             None
         }
     }
+
+    fn update(
+        &mut self,
+        ctx: &mut ExecCtx<C, E>,
+        from: &mut [Node<C, E>],
+        event: &Event<E>,
+    ) -> Option<Value> {
+        match from {
+            [name, val] => {
+                let name = name.update(ctx, event);
+                let value = val.update(ctx, event);
+                let value = if name.is_some() && !self.same_name(&name) {
+                    value.or_else(|| val.current(ctx))
+                } else {
+                    value
+                };
+                let up = value.is_some();
+                self.set(ctx, name, value);
+                if up {
+                    Apply::<C, E>::current(self, ctx)
+                } else {
+                    None
+                }
+            }
+            exprs => {
+                let mut up = false;
+                for expr in exprs {
+                    up = expr.update(ctx, event).is_some() || up;
+                }
+                self.invalid = true;
+                if up {
+                    Apply::<C, E>::current(self, ctx)
+                } else {
+                    None
+                }
+            }
+        }
+    }
+}
+
+impl Set {
+    fn queue_set(&mut self, v: Value) {
+        self.queued.push(v)
+    }
+
+    fn set<C: Ctx, E>(
+        &mut self,
+        ctx: &mut ExecCtx<C, E>,
+        name: Option<Value>,
+        value: Option<Value>,
+    ) {
+        if let Some(name) = varname(&VNAME, &mut self.invalid, name) {
+            for v in self.queued.drain(..) {
+                ctx.user.set_var(
+                    &mut ctx.variables,
+                    self.local,
+                    self.scope.clone(),
+                    name.clone(),
+                    v,
+                )
+            }
+            self.name = Some(name);
+        }
+        if let Some(value) = value {
+            match self.name.as_ref() {
+                None => self.queue_set(value),
+                Some(name) => ctx.user.set_var(
+                    &mut ctx.variables,
+                    self.local,
+                    self.scope.clone(),
+                    name.clone(),
+                    value,
+                ),
+            }
+        }
+    }
+
+    fn same_name(&self, new_name: &Option<Value>) -> bool {
+        match (new_name, self.name.as_ref()) {
+            (Some(Value::String(n0)), Some(n1)) => n0 == n1,
+            _ => false,
+        }
+    }
+}
+
+pub(crate) struct Load {
+    path: Option<Path>,
+    cur: Option<Dval>,
+    top_id: ExprId,
+    invalid: bool,
+}
+
+impl<C: Ctx, E: Clone> Register<C, E> for Load {
+    fn register(ctx: &mut ExecCtx<C, E>) {
+        let f: InitFn<C, E> = Arc::new(|ctx, from, _, top_id| {
+            let mut t = Load { path: None, cur: None, invalid: false, top_id };
+            match from {
+                [path] => {
+                    let path = path.current(ctx);
+                    t.subscribe(ctx, path)
+                },
+                _ => t.invalid = true,
+            }
+            Box::new(t)
+        });
+        ctx.functions.insert("load".into(), f);
+        ctx.signatures.insert(
+            "load".into(),
+            Signature::new(1, Some(1), &[Kind::String], Kind::Any),
+        );
+        ctx.user.register_fn("load".into(), Path::root());
+    }
+}
+
+impl<C: Ctx, E: Clone> Apply<C, E> for Load {
+    fn current(&self, _ctx: &mut ExecCtx<C, E>) -> Option<Value> {
+        if self.invalid {
+            Load::err()
+        } else {
+            self.cur.as_ref().and_then(|dv| match dv.last() {
+                subscriber::Event::Unsubscribed => {
+                    Some(Value::Error(Chars::from("#LOST")))
+                }
+                subscriber::Event::Update(v) => Some(v),
+            })
+        }
+    }
+
+    fn update(
+        &mut self,
+        ctx: &mut ExecCtx<C, E>,
+        from: &mut [Node<C, E>],
+        event: &Event<E>,
+    ) -> Option<Value> {
+        match from {
+            [name] => {
+                let up = match name.update(ctx, event) {
+                    None => false,
+                    Some(target) => {
+                        self.subscribe(ctx, Some(target));
+                        true
+                    }
+                };
+                if self.invalid {
+                    if up {
+                        Load::err()
+                    } else {
+                        None
+                    }
+                } else {
+                    self.cur.as_ref().and_then(|dv| match event {
+                        Event::Variable(_, _, _)
+                        | Event::Rpc(_, _)
+                        | Event::Timer(_)
+                        | Event::User(_) => None,
+                        Event::Netidx(id, value) if dv.id() == *id => Some(value.clone()),
+                        Event::Netidx(_, _) => None,
+                    })
+                }
+            }
+            exprs => {
+                let mut up = false;
+                for e in exprs {
+                    up = e.update(ctx, event).is_some() || up;
+                }
+                self.invalid = true;
+                if up {
+                    Load::err()
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    fn delete(&mut self, ctx: &mut ExecCtx<C, E>) {
+        if let (Some(path), Some(dv)) = (self.path.take(), self.cur.take()) {
+            ctx.user.unsubscribe(path, dv, self.top_id);
+        }
+    }
+}
+
+impl Load {
+    fn subscribe<C: Ctx, E>(&mut self, ctx: &mut ExecCtx<C, E>, name: Option<Value>) {
+        if let Some(path) = pathname(&mut self.invalid, name) {
+            if Some(&path) != self.path.as_ref() {
+                if let (Some(path), Some(dv)) = (self.path.take(), self.cur.take()) {
+                    ctx.user.unsubscribe(path, dv, self.top_id);
+                }
+                self.path = Some(path.clone());
+                self.cur = Some(ctx.user.durable_subscribe(
+                    UpdatesFlags::BEGIN_WITH_LAST,
+                    path,
+                    self.top_id,
+                ));
+            }
+        }
+    }
+
+    fn err() -> Option<Value> {
+        Some(Value::Error(Chars::from(
+            "load(expr: path) expected 1 absolute path as argument",
+        )))
+    }
+}
+
+struct BoundVar {
+    scope: Path,
+    value: Value,
+}
+
+pub struct Get {
+    scope: Path,
+    name: Option<Chars>,
+    var: Option<BoundVar>,
+    top_id: ExprId,
+    invalid: bool,
+}
+
+impl<C: Ctx, E: Clone> Register<C, E> for Get {
+    fn register(ctx: &mut ExecCtx<C, E>) {
+        let f: InitFn<C, E> = Arc::new(|ctx, from, scope, top_id| {
+            let mut t = Get { scope, name: None, var: None, invalid: false, top_id };
+            match from {
+                [name] => {
+                    let name = name.current(ctx);
+                    t.subscribe(ctx, name)
+                },
+                _ => t.invalid = true,
+            }
+            Box::new(t)
+        });
+        ctx.functions.insert("get".into(), f);
+        ctx.signatures
+            .insert("get".into(), Signature::new(1, Some(1), &[Kind::String], Kind::Any));
+        ctx.user.register_fn("get".into(), Path::root());
+    }
+}
+
+impl<C: Ctx, E: Clone> Apply<C, E> for Get {
+    fn current(&self, _ctx: &mut ExecCtx<C, E>) -> Option<Value> {
+        if self.invalid {
+            Get::err()
+        } else {
+            self.var.as_ref().map(|bv| bv.value.clone())
+        }
+    }
+
+    fn update(
+        &mut self,
+        ctx: &mut ExecCtx<C, E>,
+        from: &mut [Node<C, E>],
+        event: &Event<E>,
+    ) -> Option<Value> {
+        match from {
+            [name] => {
+                let up = match name.update(ctx, event) {
+                    None => false,
+                    Some(target) => {
+                        self.subscribe(ctx, Some(target));
+                        true
+                    }
+                };
+                if self.invalid {
+                    if up {
+                        Get::err()
+                    } else {
+                        None
+                    }
+                } else {
+                    match event {
+                        Event::Variable(es, en, v) if self.binds(es, en) => {
+                            match self.var.as_mut() {
+                                Some(bv) => {
+                                    if &bv.scope != es {
+                                        bv.scope = es.clone();
+                                    }
+                                    bv.value = v.clone();
+                                }
+                                None => {
+                                    let scope = es.clone();
+                                    self.var = Some(BoundVar { scope, value: v.clone() });
+                                }
+                            }
+                            Some(v.clone())
+                        }
+                        Event::Netidx(_, _)
+                        | Event::User(_)
+                        | Event::Rpc(_, _)
+                        | Event::Timer(_)
+                        | Event::Variable(_, _, _) => None,
+                    }
+                }
+            }
+            exprs => {
+                let mut up = false;
+                for e in exprs {
+                    up = e.update(ctx, event).is_some() || up;
+                }
+                self.invalid = true;
+                if up {
+                    Get::err()
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    fn delete(&mut self, ctx: &mut ExecCtx<C, E>) {
+        if let Some(name) = self.name.take() {
+            ctx.user.unref_var(name, self.scope.clone(), self.top_id);
+        }
+    }
+}
+
+impl Get {
+    // true if setting the variable en in es changes the variable we
+    // refer to, because it is the same one, or it's closer to us
+    fn binds(&self, es: &Path, en: &Chars) -> bool {
+        let name = match &self.name {
+            None => return false,
+            Some(name) => name,
+        };
+        vm::split_qualified(name).1 == &**en
+            && match vm::visible_below(es, name) {
+                None => false,
+                Some(below) => {
+                    Path::is_parent(below, &self.scope)
+                        && match &self.var {
+                            None => true,
+                            Some(bv) => vm::visible_below(&bv.scope, name)
+                                .map(|b| Path::is_parent(b, below))
+                                .unwrap_or(false),
+                        }
+                }
+            }
+    }
+
+    fn err() -> Option<Value> {
+        Some(Value::Error(Chars::from(
+            "load_var(expr: variable name): expected 1 variable name as argument",
+        )))
+    }
+
+    fn subscribe<C: Ctx, E: Clone>(
+        &mut self,
+        ctx: &mut ExecCtx<C, E>,
+        name: Option<Value>,
+    ) {
+        match varname(&QNAME, &mut self.invalid, name) {
+            None => {
+                self.var = None;
+                self.name = None;
+                if let Some(old) = self.name.take() {
+                    ctx.user.unref_var(old.clone(), self.scope.clone(), self.top_id);
+                }
+            }
+            Some(name) if self.name.as_ref() == Some(&name) => (),
+            Some(name) => {
+                if let Some(old) = self.name.take() {
+                    ctx.user.unref_var(old.clone(), self.scope.clone(), self.top_id);
+                }
+                self.var = ctx.lookup_var(&self.scope, &name).map(|(scope, value)| {
+                    BoundVar { scope: scope.clone(), value: value.clone() }
+                });
+                ctx.user.ref_var(name.clone(), self.scope.clone(), self.top_id);
+                self.name = Some(name);
+            }
+        }
+    }
+}
+
+atomic_id!(RpcCallId);
+
+pub(crate) struct RpcCall {
+    args: CachedVals,
+    top_id: ExprId,
+    pending: FxHashSet<RpcCallId>,
+    current: Option<Value>,
+    triggered: bool,
+    invalid: bool,
+}
+
+impl<C: Ctx, E: Clone> Register<C, E> for RpcCall {
+    fn register(ctx: &mut ExecCtx<C, E>) {
+        let f: InitFn<C, E> = Arc::new(|ctx, from, _, top_id| {
+            let triggered = match from {
+                [trigger, ..] => trigger.current(ctx).is_some(),
+                _ => false,
+            };
+            let mut t = RpcCall {
+                args: CachedVals::new(
+                    match from {
+                        [_, rest @ ..] => rest,
+                        [] => &[],
+                    },
+                    ctx,
+                ),
+                current: None,
+                top_id,
+                pending: HashSet::with_hasher(FxBuildHasher::default()),
+                triggered,
+                invalid: false,
+            };
+            t.maybe_call(ctx);
+            Box::new(t)
+        });
+        ctx.functions.insert("call".into(), f);
+        ctx.signatures
+            .insert("call".into(), Signature::new(2, None, &[Kind::Any], Kind::Any));
+        ctx.user.register_fn("call".into(), Path::root());
+    }
+}
+
+impl<C: Ctx, E: Clone> Apply<C, E> for RpcCall {
+    fn current(&self, _ctx: &mut ExecCtx<C, E>) -> Option<Value> {
+        self.current.clone()
+    }
+
+    fn update(
+        &mut self,
+        ctx: &mut ExecCtx<C, E>,
+        from: &mut [Node<C, E>],
+        event: &Event<E>,
+    ) -> Option<Value> {
+        match event {
+            Event::Rpc(id, v) if self.pending.remove(&id) => {
+                self.current = Some(v.clone());
+                Apply::<C, E>::current(self, ctx)
+            }
+            event => match from {
+                [trigger, args @ ..] => {
+                    self.args.update(ctx, args, event);
+                    if trigger.update(ctx, event).is_some() {
+                        self.triggered = true;
+                    }
+                    let triggered = self.triggered;
+                    self.maybe_call(ctx);
+                    if triggered && self.invalid {
+                        Apply::<C, E>::current(self, ctx)
+                    } else {
+                        None
+                    }
+                }
+                [] => {
+                    self.invalid();
+                    Apply::<C, E>::current(self, ctx)
+                }
+            },
+        }
+    }
+}
+
+impl RpcCall {
+    fn invalid(&mut self) {
+        let m = "call(trigger, rpc, kwargs): expected at least 2 arguments, and an even number of kwargs";
+        self.current = Some(Value::Error(Chars::from(m)));
+        self.invalid = true;
+    }
+
+    fn get_args(&mut self) -> Option<(Path, Vec<(Chars, Value)>)> {
+        self.current = None;
+        self.invalid = false;
+        let len = self.args.0.len();
+        if len == 0 || (len > 1 && len.is_power_of_two()) {
+            self.invalid();
+            None
+        } else if self.args.0.iter().any(|v| v.is_none()) {
+            None
+        } else {
+            match &self.args.0[..] {
+                [] => {
+                    self.invalid();
+                    None
+                }
+                [path, args @ ..] => {
+                    match path.as_ref().unwrap().clone().cast_to::<Chars>() {
+                        Err(_) => {
+                            self.invalid();
+                            None
+                        }
+                        Ok(name) => {
+                            let mut iter = args.into_iter();
+                            let mut args = Vec::new();
+                            loop {
+                                match iter.next() {
+                                    None | Some(None) => break,
+                                    Some(Some(name)) => {
+                                        match name.clone().cast_to::<Chars>() {
+                                            Err(_) => {
+                                                self.invalid();
+                                                return None;
+                                            }
+                                            Ok(name) => match iter.next() {
+                                                None | Some(None) => {
+                                                    self.invalid();
+                                                    return None;
+                                                }
+                                                Some(Some(val)) => {
+                                                    args.push((name, val.clone()));
+                                                }
+                                            },
+                                        }
+                                    }
+                                }
+                            }
+                            Some((Path::from(name), args))
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn maybe_call<C: Ctx, E>(&mut self, ctx: &mut ExecCtx<C, E>) {
+        if self.triggered {
+            if let Some((name, args)) = self.get_args() {
+                self.triggered = false;
+                let id = RpcCallId::new();
+                self.pending.insert(id);
+                ctx.user.call_rpc(Path::from(name), args, self.top_id, id);
+            }
+        }
+    }
+}
+
+atomic_id!(TimerId);
+
+pub(crate) struct AfterIdle {
+    timeout: Option<Value>,
+    cur: Option<Value>,
+    updated: bool,
+    timer_set: bool,
+    id: TimerId,
+    eid: ExprId,
+    invalid: bool,
+}
+
+impl<C: Ctx, E: Clone> Register<C, E> for AfterIdle {
+    fn register(ctx: &mut ExecCtx<C, E>) {
+        let f: InitFn<C, E> = Arc::new(|ctx, from, _, eid| match from {
+            [timeout, cur] => {
+                let mut t = AfterIdle {
+                    timeout: timeout.current(ctx),
+                    cur: cur.current(ctx),
+                    updated: false,
+                    timer_set: false,
+                    id: TimerId::new(),
+                    eid,
+                    invalid: false,
+                };
+                t.maybe_set_timer(ctx);
+                Box::new(t)
+            }
+            _ => Box::new(AfterIdle {
+                timeout: None,
+                cur: None,
+                updated: false,
+                timer_set: false,
+                id: TimerId::new(),
+                eid,
+                invalid: true,
+            }),
+        });
+        ctx.functions.insert("after_idle".into(), f);
+        ctx.signatures.insert(
+            "after_idle".into(),
+            Signature::new(2, Some(2), &[Kind::Any], Kind::Any),
+        );
+        ctx.user.register_fn("after_idle".into(), Path::root());
+    }
+}
+
+impl<C: Ctx, E: Clone> Apply<C, E> for AfterIdle {
+    fn current(&self, _ctx: &mut ExecCtx<C, E>) -> Option<Value> {
+        self.usage()
+    }
+
+    fn update(
+        &mut self,
+        ctx: &mut ExecCtx<C, E>,
+        from: &mut [Node<C, E>],
+        event: &Event<E>,
+    ) -> Option<Value> {
+        match from {
+            [timeout, cur] => {
+                if let Some(timeout) = timeout.update(ctx, event) {
+                    self.timeout = Some(timeout);
+                    self.maybe_set_timer(ctx);
+                }
+                if let Some(cur) = cur.update(ctx, event) {
+                    self.updated = true;
+                    self.cur = Some(cur);
+                    self.maybe_set_timer(ctx);
+                }
+                match event {
+                    Event::Variable(_, _, _)
+                    | Event::Netidx(_, _)
+                    | Event::Rpc(_, _)
+                    | Event::User(_) => self.usage(),
+                    Event::Timer(id) => {
+                        if id != &self.id {
+                            self.usage()
+                        } else {
+                            self.timer_set = false;
+                            if self.updated {
+                                self.maybe_set_timer(ctx);
+                                self.usage()
+                            } else {
+                                self.cur.clone()
+                            }
+                        }
+                    }
+                }
+            }
+            exprs => {
+                let mut up = false;
+                self.invalid = true;
+                for expr in exprs {
+                    up |= expr.update(ctx, event).is_some();
+                }
+                if up {
+                    self.usage()
+                } else {
+                    None
+                }
+            }
+        }
+    }
+}
+
+impl AfterIdle {
+    fn maybe_set_timer<C: Ctx, E>(&mut self, ctx: &mut ExecCtx<C, E>) {
+        use std::time::Duration;
+        if !self.invalid && !self.timer_set {
+            match (&self.timeout, &self.cur) {
+                (Some(timeout), Some(_)) => match timeout.clone().cast_to::<f64>() {
+                    Err(_) => {
+                        self.invalid = true;
+                    }
+                    Ok(timeout) => {
+                        self.invalid = false;
+                        self.updated = false;
+                        self.timer_set = true;
+                        ctx.user.set_timer(
+                            self.id,
+                            Duration::from_secs_f64(timeout),
+                            self.eid,
+                        );
+                    }
+                },
+                (_, _) => (),
+            }
+        }
+    }
+
+    fn usage(&self) -> Option<Value> {
+        if self.invalid {
+            Some(Value::Error(Chars::from(
+                "after_idle(timeout: f64, v: any): expected two arguments",
+            )))
+        } else {
+            None
+        }
+    }
+}
+
+pub(crate) struct Timer {
+    id: TimerId,
+    eid: ExprId,
+    timeout: Option<Value>,
+    repeat: Option<Value>,
+    timer_set: bool,
+    invalid: bool,
+}
+
+impl<C: Ctx, E: Clone> Register<C, E> for Timer {
+    fn register(ctx: &mut ExecCtx<C, E>) {
+        let f: InitFn<C, E> = Arc::new(|ctx, from, _, eid| match from {
+            [timeout, repeat] => {
+                let mut t = Self {
+                    id: TimerId::new(),
+                    eid,
+                    timeout: timeout.current(ctx),
+                    repeat: match repeat.current(ctx) {
+                        Some(Value::False) => Some(1.into()),
+                        v => v,
+                    },
+                    timer_set: false,
+                    invalid: false,
+                };
+                t.maybe_set_timer(ctx);
+                Box::new(t)
+            }
+            _ => Box::new(Self {
+                id: TimerId::new(),
+                eid,
+                timeout: None,
+                repeat: None,
+                timer_set: false,
+                invalid: true,
+            }),
+        });
+        ctx.functions.insert("timer".into(), f);
+        ctx.signatures
+            .insert("timer".into(), Signature::new(2, Some(2), &[Kind::Any], Kind::Any));
+        ctx.user.register_fn("timer".into(), Path::root());
+    }
+}
+
+impl<C: Ctx, E: Clone> Apply<C, E> for Timer {
+    fn current(&self, _ctx: &mut ExecCtx<C, E>) -> Option<Value> {
+        self.usage()
+    }
+
+    fn update(
+        &mut self,
+        ctx: &mut ExecCtx<C, E>,
+        from: &mut [Node<C, E>],
+        event: &Event<E>,
+    ) -> Option<Value> {
+        match from {
+            [timeout, repeat] => {
+                if let Some(timeout) = timeout.update(ctx, event) {
+                    self.timeout = Some(timeout);
+                    self.maybe_set_timer(ctx);
+                }
+                if let Some(repeat) = repeat.update(ctx, event) {
+                    self.repeat = Some(repeat);
+                    self.maybe_set_timer(ctx);
+                }
+                match event {
+                    Event::Variable(_, _, _)
+                    | Event::Netidx(_, _)
+                    | Event::Rpc(_, _)
+                    | Event::User(_) => self.usage(),
+                    Event::Timer(id) => {
+                        if id != &self.id {
+                            self.usage()
+                        } else {
+                            self.timer_set = false;
+                            self.maybe_set_timer(ctx);
+                            Some(Value::Null)
+                        }
+                    }
+                }
+            }
+            exprs => {
+                let mut up = false;
+                self.invalid = true;
+                for expr in exprs {
+                    up |= expr.update(ctx, event).is_some();
+                }
+                if up {
+                    self.usage()
+                } else {
+                    None
+                }
+            }
+        }
+    }
+}
+
+impl Timer {
+    fn maybe_set_timer<C: Ctx, E>(&mut self, ctx: &mut ExecCtx<C, E>) {
+        use std::time::Duration;
+        if !self.invalid && !self.timer_set {
+            match (&self.timeout, &self.repeat) {
+                (None, _) | (_, None) => (),
+                (Some(timeout), Some(repeat)) => {
+                    let timeout = timeout.clone().cast_to::<f64>();
+                    let repeat = match repeat {
+                        Value::Null => Ok(None),
+                        Value::True => Ok(None),
+                        Value::False => Ok(Some(0)),
+                        v => v.clone().cast_to::<u64>().map(|v| Some(v)),
+                    };
+                    match (timeout, repeat) {
+                        (Ok(timeout), Ok(repeat)) => {
+                            let go = match repeat {
+                                None => true,
+                                Some(n) => {
+                                    if n == 0 {
+                                        false
+                                    } else {
+                                        self.repeat = Some((n - 1).into());
+                                        true
+                                    }
+                                }
+                            };
+                            if go {
+                                self.timer_set = true;
+                                let d = Duration::from_secs_f64(timeout);
+                                ctx.user.set_timer(self.id, d, self.eid);
+                            }
+                        }
+                        (_, _) => {
+                            self.invalid = true;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn usage(&self) -> Option<Value> {
+        if self.invalid {
+            Some(Value::Error(Chars::from(
+                "timer(timeout: f64, repeat): expected two arguments",
+            )))
+        } else {
+            None
+        }
+    }
+}
+
+pub(crate) struct Now {
+    id: TimerId,
+    eid: ExprId,
+    interval: Option<Value>,
+    timer_set: bool,
+    invalid: bool,
+}
+
+impl<C: Ctx, E: Clone> Register<C, E> for Now {
+    fn register(ctx: &mut ExecCtx<C, E>) {
+        let f: InitFn<C, E> = Arc::new(|ctx, from, _, eid| match from {
+            [] => Box::new(Self {
+                id: TimerId::new(),
+                eid,
+                interval: None,
+                timer_set: false,
+                invalid: false,
+            }),
+            [interval] => {
+                let mut t = Self {
+                    id: TimerId::new(),
+                    eid,
+                    interval: interval.current(ctx),
+                    timer_set: false,
+                    invalid: false,
+                };
+                t.maybe_set_timer(ctx);
+                Box::new(t)
+            }
+            _ => Box::new(Self {
+                id: TimerId::new(),
+                eid,
+                interval: None,
+                timer_set: false,
+                invalid: true,
+            }),
+        });
+        ctx.functions.insert("now".into(), f);
+        ctx.signatures.insert(
+            "now".into(),
+            Signature::new(0, Some(1), &[Kind::Any], Kind::DateTime),
+        );
+        ctx.user.register_fn("now".into(), Path::root());
+    }
+}
+
+impl<C: Ctx, E: Clone> Apply<C, E> for Now {
+    fn current(&self, _ctx: &mut ExecCtx<C, E>) -> Option<Value> {
+        if self.invalid {
+            self.usage()
+        } else {
+            Some(Value::DateTime(Utc::now()))
+        }
+    }
+
+    fn update(
+        &mut self,
+        ctx: &mut ExecCtx<C, E>,
+        from: &mut [Node<C, E>],
+        event: &Event<E>,
+    ) -> Option<Value> {
+        match from {
+            [] => (),
+            [interval] => {
+                if let Some(interval) = interval.update(ctx, event) {
+                    if self.interval.as_ref() != Some(&interval) {
+                        // timers can't be cancelled, so take a new id
+                        // and ignore the pending one when it fires
+                        self.id = TimerId::new();
+                        self.timer_set = false;
+                    }
+                    self.interval = Some(interval);
+                    self.invalid = false;
+                    self.maybe_set_timer(ctx);
+                }
+            }
+            exprs => {
+                let mut up = false;
+                for expr in exprs {
+                    up |= expr.update(ctx, event).is_some();
+                }
+                return if up { self.usage() } else { None };
+            }
+        }
+        match event {
+            Event::Timer(id) if id == &self.id => {
+                self.timer_set = false;
+                self.maybe_set_timer(ctx);
+                Some(Value::DateTime(Utc::now()))
+            }
+            Event::Variable(_, _, _)
+            | Event::Netidx(_, _)
+            | Event::Rpc(_, _)
+            | Event::Timer(_)
+            | Event::User(_) => self.usage(),
+        }
+    }
+}
+
+impl Now {
+    fn maybe_set_timer<C: Ctx, E>(&mut self, ctx: &mut ExecCtx<C, E>) {
+        if !self.invalid && !self.timer_set {
+            if let Some(interval) = &self.interval {
+                match interval.clone().cast_to::<Duration>() {
+                    Ok(d) if d > Duration::ZERO => {
+                        self.timer_set = true;
+                        ctx.user.set_timer(self.id, d, self.eid);
+                    }
+                    Ok(_) | Err(_) => {
+                        self.invalid = true;
+                    }
+                }
+            }
+        }
+    }
+
+    fn usage(&self) -> Option<Value> {
+        if self.invalid {
+            Some(Value::Error(Chars::from(
+                "now([interval]): expected interval to be a positive duration",
+            )))
+        } else {
+            None
+        }
+    }
+}
+
+// A time zone argument, utc, local, or a fixed offset from utc,
+// e.g. +05:00
+#[derive(Debug, Clone, Copy)]
+enum Zone {
+    Utc,
+    Local,
+    Fixed(FixedOffset),
+}
+
+impl Zone {
+    fn parse(v: &Value) -> Option<Self> {
+        match v {
+            Value::String(s) => match s.trim() {
+                "utc" | "UTC" => Some(Zone::Utc),
+                "local" => Some(Zone::Local),
+                s => {
+                    let sign = match s.chars().next()? {
+                        '+' => 1,
+                        '-' => -1,
+                        _ => return None,
+                    };
+                    let hm = s[1..].replace(':', "");
+                    if hm.len() != 4 || !hm.chars().all(|c| c.is_ascii_digit()) {
+                        return None;
+                    }
+                    let h = hm[0..2].parse::<i32>().ok()?;
+                    let m = hm[2..4].parse::<i32>().ok()?;
+                    FixedOffset::east_opt(sign * (h * 3600 + m * 60)).map(Zone::Fixed)
+                }
+            },
+            _ => None,
+        }
+    }
+
+    // the time zone argument at position i, utc if it's missing, None
+    // if it hasn't been computed yet
+    fn arg(from: &CachedVals, i: usize) -> Option<Result<Self, Value>> {
+        match from.0.get(i) {
+            None => Some(Ok(Zone::Utc)),
+            Some(None) => None,
+            Some(Some(v)) => Some(Zone::parse(v).ok_or_else(|| {
+                Value::Error(Chars::from(format!(
+                    "invalid time zone {}, expected utc, local, or an offset e.g. +05:00",
+                    v
+                )))
+            })),
+        }
+    }
+
+    fn to_local(&self, ts: &DateTime<Utc>) -> NaiveDateTime {
+        match self {
+            Zone::Utc => ts.naive_utc(),
+            Zone::Local => ts.with_timezone(&Local).naive_local(),
+            Zone::Fixed(o) => ts.with_timezone(o).naive_local(),
+        }
+    }
+
+    fn from_local(&self, ts: &NaiveDateTime) -> Option<DateTime<Utc>> {
+        match self {
+            Zone::Utc => Some(Utc.from_utc_datetime(ts)),
+            Zone::Local => {
+                Local.from_local_datetime(ts).earliest().map(|ts| ts.with_timezone(&Utc))
+            }
+            Zone::Fixed(o) => {
+                o.from_local_datetime(ts).earliest().map(|ts| ts.with_timezone(&Utc))
+            }
+        }
+    }
+
+    fn format(&self, ts: &DateTime<Utc>, items: &[Item]) -> String {
+        match self {
+            Zone::Utc => ts.format_with_items(items.iter()).to_string(),
+            Zone::Local => {
+                ts.with_timezone(&Local).format_with_items(items.iter()).to_string()
+            }
+            Zone::Fixed(o) => {
+                ts.with_timezone(o).format_with_items(items.iter()).to_string()
+            }
+        }
+    }
+}
+
+/// format_time(ts, fmt, [tz]), format a datetime with a strftime
+/// style format string in time zone tz, utc by default.
+pub struct FormatTimeEv;
+
+impl CachedCurEval for FormatTimeEv {
+    fn eval(from: &CachedVals) -> Option<Value> {
+        let zone = match &*from.0 {
+            [_, _] | [_, _, _] => match Zone::arg(from, 2)? {
+                Ok(zone) => zone,
+                Err(e) => return Some(e),
+            },
+            _ => {
+                return Some(Value::Error(Chars::from(
+                    "format_time(ts, fmt, [tz]): expected 2 or 3 arguments",
+                )))
+            }
+        };
+        match (&from.0[0], &from.0[1]) {
+            (None, _) | (_, None) => None,
+            (Some(ts), Some(Value::String(fmt))) => {
+                let ts = match ts.clone().cast_to::<DateTime<Utc>>() {
+                    Ok(ts) => ts,
+                    Err(_) => {
+                        return Some(Value::Error(Chars::from(format!(
+                            "format_time: expected a datetime, not {}",
+                            ts
+                        ))))
+                    }
+                };
+                let items = StrftimeItems::new(fmt).collect::<Vec<_>>();
+                if items.iter().any(|i| matches!(i, Item::Error)) {
+                    Some(Value::Error(Chars::from(format!(
+                        "format_time: invalid format string {}",
+                        fmt
+                    ))))
+                } else {
+                    Some(Value::String(Chars::from(zone.format(&ts, &items))))
+                }
+            }
+            (Some(_), Some(fmt)) => Some(Value::Error(Chars::from(format!(
+                "format_time: expected a format string, not {}",
+                fmt
+            )))),
+        }
+    }
+
+    fn name() -> &'static str {
+        "format_time"
+    }
+
+    fn signature() -> Signature {
+        Signature::new(2, Some(3), &[Kind::Any, Kind::String, Kind::String], Kind::String)
+    }
+}
+
+pub type FormatTime = CachedCur<FormatTimeEv>;
+
+/// parse_time(s, [fmt], [tz]), parse a datetime from an rfc3339
+/// string, or with a strftime style format string. If the format has
+/// no utc offset the time is in time zone tz, utc by default.
+pub struct ParseTimeEv;
+
+impl CachedCurEval for ParseTimeEv {
+    fn eval(from: &CachedVals) -> Option<Value> {
+        let zone = match &*from.0 {
+            [_] | [_, _] | [_, _, _] => match Zone::arg(from, 2)? {
+                Ok(zone) => zone,
+                Err(e) => return Some(e),
+            },
+            _ => {
+                return Some(Value::Error(Chars::from(
+                    "parse_time(s, [fmt], [tz]): expected 1 to 3 arguments",
+                )))
+            }
+        };
+        let s = match &from.0[0] {
+            None => return None,
+            Some(Value::String(s)) => s,
+            Some(v) => {
+                return Some(Value::Error(Chars::from(format!(
+                    "parse_time: expected a string, not {}",
+                    v
+                ))))
+            }
+        };
+        let ts = match from.0.get(1) {
+            None => s.parse::<DateTime<Utc>>().ok(),
+            Some(None) => return None,
+            Some(Some(Value::String(fmt))) => DateTime::parse_from_str(s, fmt)
+                .map(|ts| ts.with_timezone(&Utc))
+                .ok()
+                .or_else(|| {
+                    let ts =
+                        NaiveDateTime::parse_from_str(s, fmt).ok().or_else(|| {
+                            NaiveDate::parse_from_str(s, fmt)
+                                .ok()
+                                .and_then(|d| d.and_hms_opt(0, 0, 0))
+                        })?;
+                    zone.from_local(&ts)
+                }),
+            Some(Some(fmt)) => {
+                return Some(Value::Error(Chars::from(format!(
+                    "parse_time: expected a format string, not {}",
+                    fmt
+                ))))
+            }
+        };
+        match ts {
+            Some(ts) => Some(Value::DateTime(ts)),
+            None => {
+                Some(Value::Error(Chars::from(format!("parse_time: can't parse {}", s))))
+            }
+        }
+    }
+
+    fn name() -> &'static str {
+        "parse_time"
+    }
+
+    fn signature() -> Signature {
+        Signature::new(1, Some(3), &[Kind::String], Kind::DateTime)
+    }
+}
+
+pub type ParseTime = CachedCur<ParseTimeEv>;
+
+// a duration argument, either a duration, or a number of seconds,
+// which may be negative
+fn signed_duration(v: &Value) -> Option<ChronoDuration> {
+    match v {
+        Value::Duration(d) => ChronoDuration::from_std(*d).ok(),
+        v => {
+            let secs = v.clone().cast_to::<f64>().ok()?;
+            if secs.is_finite() {
+                Some(ChronoDuration::nanoseconds((secs * 1e9) as i64))
+            } else {
+                None
+            }
+        }
+    }
+}
+
+/// add_duration(ts, d), the datetime d after ts. d is a duration or
+/// a number of seconds, a negative number of seconds is before ts.
+pub struct AddDurationEv;
+
+impl CachedCurEval for AddDurationEv {
+    fn eval(from: &CachedVals) -> Option<Value> {
+        match &*from.0 {
+            [Some(ts), Some(d)] => {
+                let ts = ts.clone().cast_to::<DateTime<Utc>>().ok();
+                match (ts, signed_duration(d)) {
+                    (Some(ts), Some(d)) => match ts.checked_add_signed(d) {
+                        Some(ts) => Some(Value::DateTime(ts)),
+                        None => Some(Value::Error(Chars::from(
+                            "add_duration: datetime out of range",
+                        ))),
+                    },
+                    (_, _) => Some(Value::Error(Chars::from(
+                        "add_duration(ts, d): expected a datetime and a duration",
+                    ))),
+                }
+            }
+            [None, _] | [_, None] => None,
+            _ => Some(Value::Error(Chars::from(
+                "add_duration(ts, d): expected 2 arguments",
+            ))),
+        }
+    }
+
+    fn name() -> &'static str {
+        "add_duration"
+    }
+
+    fn signature() -> Signature {
+        Signature::new(2, Some(2), &[Kind::DateTime, Kind::Any], Kind::DateTime)
+    }
+}
+
+pub type AddDuration = CachedCur<AddDurationEv>;
+
+/// diff(a, b), the duration from b to a, which are both datetimes or
+/// both durations. The order matters, durations can't be negative, so
+/// it is an error if b is after a. diff(now(interval), ts) is the age
+/// of ts.
+pub struct DiffEv;
+
+impl CachedCurEval for DiffEv {
+    fn eval(from: &CachedVals) -> Option<Value> {
+        let negative = || {
+            Value::Error(Chars::from(
+                "diff(a, b): b is after a, and durations can't be negative",
+            ))
+        };
+        match &*from.0 {
+            [Some(Value::Duration(a)), Some(Value::Duration(b))] => {
+                Some(a.checked_sub(*b).map(Value::Duration).unwrap_or_else(negative))
+            }
+            [Some(a), Some(b)] => {
+                let a = a.clone().cast_to::<DateTime<Utc>>();
+                let b = b.clone().cast_to::<DateTime<Utc>>();
+                match (a, b) {
+                    (Ok(a), Ok(b)) => Some(
+                        a.signed_duration_since(b)
+                            .to_std()
+                            .map(Value::Duration)
+                            .unwrap_or_else(|_| negative()),
+                    ),
+                    (_, _) => Some(Value::Error(Chars::from(
+                        "diff(a, b): expected two datetimes or two durations",
+                    ))),
+                }
+            }
+            [None, _] | [_, None] => None,
+            _ => Some(Value::Error(Chars::from("diff(a, b): expected 2 arguments"))),
+        }
+    }
+
+    fn name() -> &'static str {
+        "diff"
+    }
+
+    fn signature() -> Signature {
+        Signature::new(2, Some(2), &[Kind::Any], Kind::Duration)
+    }
+}
+
+pub type Diff = CachedCur<DiffEv>;
+
+/// truncate(ts, unit, [tz]), truncate a datetime to the start of the
+/// year, month, day, hour, minute, or second it is in, in time zone
+/// tz, utc by default.
+pub struct TruncateEv;
+
+impl CachedCurEval for TruncateEv {
+    fn eval(from: &CachedVals) -> Option<Value> {
+        let zone = match &*from.0 {
+            [_, _] | [_, _, _] => match Zone::arg(from, 2)? {
+                Ok(zone) => zone,
+                Err(e) => return Some(e),
+            },
+            _ => {
+                return Some(Value::Error(Chars::from(
+                    "truncate(ts, unit, [tz]): expected 2 or 3 arguments",
+                )))
+            }
+        };
+        let (ts, unit) = match (&from.0[0], &from.0[1]) {
+            (None, _) | (_, None) => return None,
+            (Some(ts), Some(Value::String(unit))) => {
+                match ts.clone().cast_to::<DateTime<Utc>>() {
+                    Ok(ts) => (ts, unit),
+                    Err(_) => {
+                        return Some(Value::Error(Chars::from(format!(
+                            "truncate: expected a datetime, not {}",
+                            ts
+                        ))))
+                    }
+                }
+            }
+            (Some(_), Some(unit)) => {
+                return Some(Value::Error(Chars::from(format!(
+                    "truncate: expected a unit, not {}",
+                    unit
+                ))))
+            }
+        };
+        let local = zone.to_local(&ts);
+        let (d, t) = (local.date(), local.time());
+        let midnight = |d: NaiveDate| d.and_hms_opt(0, 0, 0);
+        let local = match &**unit {
+            "year" => NaiveDate::from_ymd_opt(d.year(), 1, 1).and_then(midnight),
+            "month" => NaiveDate::from_ymd_opt(d.year(), d.month(), 1).and_then(midnight),
+            "day" => midnight(d),
+            "hour" => d.and_hms_opt(t.hour(), 0, 0),
+            "minute" => d.and_hms_opt(t.hour(), t.minute(), 0),
+            "second" => d.and_hms_opt(t.hour(), t.minute(), t.second()),
+            unit => {
+                return Some(Value::Error(Chars::from(format!(
+                    "truncate: invalid unit {}, expected year, month, day, hour, minute, or second",
+                    unit
+                ))))
+            }
+        };
+        match local.and_then(|local| zone.from_local(&local)) {
+            Some(ts) => Some(Value::DateTime(ts)),
+            None => Some(Value::Error(Chars::from(format!(
+                "truncate: the start of the {} doesn't exist in {:?}",
+                unit, zone
+            )))),
+        }
+    }
+
+    fn name() -> &'static str {
+        "truncate"
+    }
+
+    fn signature() -> Signature {
+        Signature::new(
+            2,
+            Some(3),
+            &[Kind::Any, Kind::String, Kind::String],
+            Kind::DateTime,
+        )
+    }
+}
+
+pub type Truncate = CachedCur<TruncateEv>;
+
+/// age(ts), the duration since ts, zero if ts is in the future. age
+/// is computed when ts changes, use diff(now(interval), ts) for an
+/// age that counts up.
+pub struct AgeEv;
+
+impl CachedCurEval for AgeEv {
+    fn eval(from: &CachedVals) -> Option<Value> {
+        match &*from.0 {
+            [None] => None,
+            [Some(ts)] => match ts.clone().cast_to::<DateTime<Utc>>() {
+                Ok(ts) => Some(Value::Duration(
+                    Utc::now()
+                        .signed_duration_since(ts)
+                        .to_std()
+                        .unwrap_or(Duration::ZERO),
+                )),
+                Err(_) => Some(Value::Error(Chars::from(format!(
+                    "age(ts): expected a datetime, not {}",
+                    ts
+                )))),
+            },
+            _ => Some(Value::Error(Chars::from("age(ts): expected 1 argument"))),
+        }
+    }
+
+    fn name() -> &'static str {
+        "age"
+    }
+
+    fn signature() -> Signature {
+        Signature::new(1, Some(1), &[Kind::Any], Kind::Duration)
+    }
+}
+
+pub type Age = CachedCur<AgeEv>;
+
+pub trait RegexEval {
+    fn name() -> &'static str;
+    fn signature() -> Signature;
+    fn eval(re: &Regex, args: &[Option<Value>]) -> Option<Value>;
+}
+
+/// A function whose first argument is a regex. The regex is compiled
+/// when the pattern changes, and the compiled regex is kept for as
+/// long as the node lives.
+pub struct CachedRegex<T: RegexEval + Send + Sync> {
+    cached: CachedVals,
+    re: Option<(Chars, Result<Regex, Value>)>,
+    current: Option<Value>,
+    t: PhantomData<T>,
+}
+
+impl<T: RegexEval + Send + Sync> CachedRegex<T> {
+    fn eval(&mut self) -> Option<Value> {
+        match &self.cached.0[..] {
+            [] => Some(Value::Error(Chars::from(format!(
+                "{}: expected a regex and arguments",
+                T::name()
+            )))),
+            [None, ..] => None,
+            [Some(Value::String(pat)), args @ ..] => {
+                match &self.re {
+                    Some((p, _)) if p == pat => (),
+                    Some(_) | None => {
+                        let re = Regex::new(pat).map_err(|e| {
+                            Value::Error(Chars::from(format!("{}: {}", T::name(), e)))
+                        });
+                        self.re = Some((pat.clone(), re))
+                    }
+                }
+                match &self.re {
+                    Some((_, Ok(re))) => T::eval(re, args),
+                    Some((_, Err(e))) => Some(e.clone()),
+                    None => unreachable!(),
+                }
+            }
+            [Some(v), ..] => Some(Value::Error(Chars::from(format!(
+                "{}: expected a regex string, not {}",
+                T::name(),
+                v
+            )))),
+        }
+    }
+}
+
+impl<C: Ctx, E: Clone, T: RegexEval + Send + Sync + 'static> Register<C, E>
+    for CachedRegex<T>
+{
+    fn register(ctx: &mut ExecCtx<C, E>) {
+        let f: InitFn<C, E> = Arc::new(|ctx, from, _, _| {
+            let mut t = CachedRegex::<T> {
+                cached: CachedVals::new(from, ctx),
+                re: None,
+                current: None,
+                t: PhantomData,
+            };
+            t.current = t.eval();
+            Box::new(t)
+        });
+        ctx.functions.insert(T::name().into(), f);
+        ctx.signatures.insert(T::name().into(), T::signature());
+        ctx.user.register_fn(T::name().into(), Path::root());
+    }
+}
+
+impl<C: Ctx, E: Clone, T: RegexEval + Send + Sync + 'static> Apply<C, E>
+    for CachedRegex<T>
+{
+    fn current(&self, _ctx: &mut ExecCtx<C, E>) -> Option<Value> {
+        self.current.clone()
+    }
+
+    fn update(
+        &mut self,
+        ctx: &mut ExecCtx<C, E>,
+        from: &mut [Node<C, E>],
+        event: &Event<E>,
+    ) -> Option<Value> {
+        if !self.cached.update(ctx, from, event) {
+            None
+        } else {
+            let cur = self.eval();
+            self.current = cur.clone();
+            cur
+        }
+    }
+}
+
+pub struct RegexMatchEv;
+
+impl RegexEval for RegexMatchEv {
+    fn name() -> &'static str {
+        "regex_match"
+    }
+
+    fn signature() -> Signature {
+        Signature::new(2, Some(2), &[Kind::String], Kind::Bool)
+    }
+
+    fn eval(re: &Regex, args: &[Option<Value>]) -> Option<Value> {
+        match args {
+            [None] => None,
+            [Some(Value::String(s))] => {
+                Some(if re.is_match(s) { Value::True } else { Value::False })
+            }
+            _ => Some(Value::Error(Chars::from(
+                "regex_match(re, s): expected a regex and a string",
+            ))),
+        }
+    }
+}
+
+pub type RegexMatch = CachedRegex<RegexMatchEv>;
+
+/// regex_captures(re, s), an array with an element for each match of
+/// re in s, each an array of the capture groups of the match, the
+/// whole match first. Groups that didn't participate in the match are
+/// null.
+pub struct RegexCapturesEv;
+
+impl RegexEval for RegexCapturesEv {
+    fn name() -> &'static str {
+        "regex_captures"
+    }
+
+    fn signature() -> Signature {
+        Signature::new(2, Some(2), &[Kind::String], Kind::Array)
+    }
+
+    fn eval(re: &Regex, args: &[Option<Value>]) -> Option<Value> {
+        match args {
+            [None] => None,
+            [Some(Value::String(s))] => {
+                let matches = re
+                    .captures_iter(s)
+                    .map(|caps| {
+                        let groups = caps
+                            .iter()
+                            .map(|m| match m {
+                                None => Value::Null,
+                                Some(m) => {
+                                    Value::String(Chars::from(String::from(m.as_str())))
+                                }
+                            })
+                            .collect::<Vec<_>>();
+                        Value::Array(Arc::from(groups))
+                    })
+                    .collect::<Vec<_>>();
+                Some(Value::Array(Arc::from(matches)))
+            }
+            _ => Some(Value::Error(Chars::from(
+                "regex_captures(re, s): expected a regex and a string",
+            ))),
+        }
+    }
+}
+
+pub type RegexCaptures = CachedRegex<RegexCapturesEv>;
+
+/// regex_replace(re, rep, s), replace every match of re in s with
+/// rep, which may refer to capture groups as $1, $name, etc.
+pub struct RegexReplaceEv;
+
+impl RegexEval for RegexReplaceEv {
+    fn name() -> &'static str {
+        "regex_replace"
+    }
+
+    fn signature() -> Signature {
+        Signature::new(3, Some(3), &[Kind::String], Kind::String)
+    }
+
+    fn eval(re: &Regex, args: &[Option<Value>]) -> Option<Value> {
+        match args {
+            [None, _] | [_, None] => None,
+            [Some(Value::String(rep)), Some(Value::String(s))] => {
+                Some(Value::String(Chars::from(String::from(re.replace_all(s, &**rep)))))
+            }
+            _ => Some(Value::Error(Chars::from(
+                "regex_replace(re, rep, s): expected a regex and two strings",
+            ))),
+        }
+    }
+}
+
+pub type RegexReplace = CachedRegex<RegexReplaceEv>;
+
+/// split(sep, s), the array of the parts of s separated by sep. An
+/// empty sep splits s into it's characters.
+pub struct SplitEv;
+
+impl CachedCurEval for SplitEv {
+    fn eval(from: &CachedVals) -> Option<Value> {
+        match &*from.0 {
+            [Some(Value::String(sep)), Some(Value::String(s))] if sep.is_empty() => {
+                let parts = s
+                    .chars()
+                    .map(|c| Value::String(Chars::from(String::from(c))))
+                    .collect::<Vec<_>>();
+                Some(Value::Array(Arc::from(parts)))
+            }
+            [Some(Value::String(sep)), Some(Value::String(s))] => {
+                let parts = s
+                    .split(&**sep)
+                    .map(|p| Value::String(Chars::from(String::from(p))))
+                    .collect::<Vec<_>>();
+                Some(Value::Array(Arc::from(parts)))
+            }
+            [None, _] | [_, None] => None,
+            _ => Some(Value::Error(Chars::from("split(sep, s): expected 2 strings"))),
+        }
+    }
+
+    fn name() -> &'static str {
+        "split"
+    }
+
+    fn signature() -> Signature {
+        Signature::new(2, Some(2), &[Kind::String], Kind::Array)
+    }
+}
+
+pub type Split = CachedCur<SplitEv>;
+
+pub struct ToUpperEv;
+
+impl CachedCurEval for ToUpperEv {
+    fn eval(from: &CachedVals) -> Option<Value> {
+        match &*from.0 {
+            [Some(Value::String(s))] => {
+                Some(Value::String(Chars::from(s.to_uppercase())))
+            }
+            [None] => None,
+            _ => Some(Value::Error(Chars::from("to_upper(s): expected 1 string"))),
+        }
+    }
+
+    fn name() -> &'static str {
+        "to_upper"
+    }
+
+    fn signature() -> Signature {
+        Signature::new(1, Some(1), &[Kind::String], Kind::String)
+    }
+}
+
+pub type ToUpper = CachedCur<ToUpperEv>;
+
+pub struct ToLowerEv;
+
+impl CachedCurEval for ToLowerEv {
+    fn eval(from: &CachedVals) -> Option<Value> {
+        match &*from.0 {
+            [Some(Value::String(s))] => {
+                Some(Value::String(Chars::from(s.to_lowercase())))
+            }
+            [None] => None,
+            _ => Some(Value::Error(Chars::from("to_lower(s): expected 1 string"))),
+        }
+    }
+
+    fn name() -> &'static str {
+        "to_lower"
+    }
+
+    fn signature() -> Signature {
+        Signature::new(1, Some(1), &[Kind::String], Kind::String)
+    }
+}
+
+pub type ToLower = CachedCur<ToLowerEv>;
+
+/// pad(s, width, [align], [fill]), pad s with fill, a space by
+/// default, to at least width characters. align is left, the
+/// default, right, or center.
+pub struct PadEv;
+
+impl CachedCurEval for PadEv {
+    fn eval(from: &CachedVals) -> Option<Value> {
+        let usage = || {
+            Some(Value::Error(Chars::from(
+                "pad(s, width, [align], [fill]): expected a string, a width, \
+                 left, right, or center, and a fill character",
+            )))
+        };
+        match from.0.len() {
+            2..=4 => (),
+            _ => return usage(),
+        }
+        if from.0.iter().any(|v| v.is_none()) {
+            return None;
+        }
+        let arg = |i: usize| from.0.get(i).and_then(|v| v.as_ref());
+        let s = match arg(0) {
+            Some(Value::String(s)) => s,
+            _ => return usage(),
+        };
+        let width = match arg(1).map(|w| w.clone().cast_to::<u64>()) {
+            Some(Ok(w)) => w as usize,
+            _ => return usage(),
+        };
+        let align = match arg(2) {
+            None => "left",
+            Some(Value::String(a)) if &**a == "left" => "left",
+            Some(Value::String(a)) if &**a == "right" => "right",
+            Some(Value::String(a)) if &**a == "center" => "center",
+            Some(_) => return usage(),
+        };
+        let fill = match arg(3) {
+            None => ' ',
+            Some(Value::String(f)) if f.chars().count() == 1 => f.chars().next().unwrap(),
+            Some(_) => return usage(),
+        };
+        let len = s.chars().count();
+        if len >= width {
+            return Some(Value::String(s.clone()));
+        }
+        let n = width - len;
+        let (before, after) = match align {
+            "left" => (0, n),
+            "right" => (n, 0),
+            _ => (n / 2, n - n / 2),
+        };
+        let mut res = String::with_capacity(s.len() + n * fill.len_utf8());
+        res.extend(iter::repeat(fill).take(before));
+        res.push_str(s);
+        res.extend(iter::repeat(fill).take(after));
+        Some(Value::String(Chars::from(res)))
+    }
+
+    fn name() -> &'static str {
+        "pad"
+    }
+
+    fn signature() -> Signature {
+        Signature::new(
+            2,
+            Some(4),
+            &[Kind::String, Kind::Number, Kind::String, Kind::String],
+            Kind::String,
+        )
+    }
+}
+
+pub type Pad = CachedCur<PadEv>;
+
+/// substring(s, start, [end]), the characters of s from start up to,
+/// but not including, end, or the end of s. Indexes are clamped to
+/// the length of s.
+pub struct SubstringEv;
+
+impl CachedCurEval for SubstringEv {
+    fn eval(from: &CachedVals) -> Option<Value> {
+        let usage = || {
+            Some(Value::Error(Chars::from(
+                "substring(s, start, [end]): expected a string and positive indexes",
+            )))
+        };
+        let (s, start, end) = match &*from.0 {
+            [Some(s), Some(start)] => (s, start, None),
+            [Some(s), Some(start), Some(end)] => (s, start, Some(end)),
+            [None, _] | [_, None] | [None, _, _] | [_, None, _] | [_, _, None] => {
+                return None
+            }
+            _ => return usage(),
+        };
+        let s = match s {
+            Value::String(s) => s,
+            _ => return usage(),
+        };
+        let len = s.chars().count();
+        let start = match start.clone().cast_to::<u64>() {
+            Ok(i) => (i as usize).min(len),
+            Err(_) => return usage(),
+        };
+        let end = match end {
+            None | Some(Value::Null) => len,
+            Some(e) => match e.clone().cast_to::<u64>() {
+                Ok(i) => (i as usize).min(len).max(start),
+                Err(_) => return usage(),
+            },
+        };
+        let sub = s.chars().skip(start).take(end - start).collect::<String>();
+        Some(Value::String(Chars::from(sub)))
+    }
+
+    fn name() -> &'static str {
+        "substring"
+    }
+
+    fn signature() -> Signature {
+        Signature::new(2, Some(3), &[Kind::String, Kind::Any], Kind::String)
+    }
+}
+
+pub type Substring = CachedCur<SubstringEv>;
+
+// insert a comma between each group of 3 digits in the integer part
+// of a formatted number
+fn thousands(s: &str) -> String {
+    let (sign, s) = match s.strip_prefix('-') {
+        Some(s) => ("-", s),
+        None => ("", s),
+    };
+    let (int, frac) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s, ""),
+    };
+    let mut res = String::from(sign);
+    for (i, c) in int.chars().enumerate() {
+        if i > 0 && (int.len() - i) % 3 == 0 {
+            res.push(',');
+        }
+        res.push(c);
+    }
+    res.push_str(frac);
+    res
+}
+
+// format v according to spec, [,][.precision]
+fn format_arg(v: &Value, spec: &str) -> Result<String, String> {
+    let (sep, prec) = match spec.strip_prefix(',') {
+        Some(spec) => (true, spec),
+        None => (false, spec),
+    };
+    let prec = match prec {
+        "" => None,
+        p => match p.strip_prefix('.').and_then(|p| p.parse::<usize>().ok()) {
+            Some(p) => Some(p),
+            None => return Err(format!("invalid format spec {}", spec)),
+        },
+    };
+    if (sep || prec.is_some()) && !v.number() {
+        return Err(format!("{} is not a number", v));
+    }
+    let s = match (v, prec) {
+        (v, None) => v.to_string_naked(),
+        (Value::Decimal(d), Some(p)) => format!("{:.*}", p, d),
+        // not through f64, which can't represent every 64 bit integer
+        (v, Some(p)) if Typ::get(v).integer() => {
+            let mut s = v.to_string_naked();
+            if p > 0 {
+                s.push('.');
+                s.extend(iter::repeat('0').take(p));
+            }
+            s
+        }
+        (v, Some(p)) => match v.clone().cast_to::<f64>() {
+            Ok(f) => format!("{:.*}", p, f),
+            Err(_) => return Err(format!("{} is not a number", v)),
+        },
+    };
+    Ok(if sep { thousands(&s) } else { s })
+}
+
+/// format(fmt, args...), replace each {} in fmt with the next
+/// argument. A placeholder may have a spec, {:,} separates the
+/// thousands of a number with commas, {:.2} formats a number with 2
+/// digits after the decimal point, and {:,.2} does both. {{ and }}
+/// are literal braces.
+pub struct FormatEv;
+
+impl CachedCurEval for FormatEv {
+    fn eval(from: &CachedVals) -> Option<Value> {
+        if from.0.iter().any(|v| v.is_none()) {
+            return None;
+        }
+        let err = |e: String| Some(Value::Error(Chars::from(format!("format: {}", e))));
+        let (fmt, args) = match &*from.0 {
+            [Some(Value::String(fmt)), args @ ..] => (fmt, args),
+            _ => return err(String::from("expected a format string and arguments")),
+        };
+        let mut args = args.iter().map(|v| v.as_ref().unwrap());
+        let mut res = String::new();
+        let mut chars = fmt.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.as_str().starts_with('{') => {
+                    chars.next();
+                    res.push('{')
+                }
+                '}' if chars.as_str().starts_with('}') => {
+                    chars.next();
+                    res.push('}')
+                }
+                '{' => {
+                    let rest = chars.as_str();
+                    let spec = match rest.find('}') {
+                        Some(i) => &rest[..i],
+                        None => return err(String::from("unclosed {")),
+                    };
+                    let spec = match spec {
+                        "" => "",
+                        s => match s.strip_prefix(':') {
+                            Some(s) => s,
+                            None => return err(format!("invalid placeholder {{{}}}", s)),
+                        },
+                    };
+                    let v = match args.next() {
+                        Some(v) => v,
+                        None => return err(String::from("not enough arguments")),
+                    };
+                    match format_arg(v, spec) {
+                        Ok(s) => res.push_str(&s),
+                        Err(e) => return err(e),
+                    }
+                    chars = rest[rest.find('}').unwrap() + 1..].chars();
+                }
+                '}' => return err(String::from("unmatched }")),
+                c => res.push(c),
+            }
+        }
+        if args.next().is_some() {
+            return err(String::from("too many arguments"));
+        }
+        Some(Value::String(Chars::from(res)))
+    }
+
+    fn name() -> &'static str {
+        "format"
+    }
+
+    fn signature() -> Signature {
+        Signature::new(1, None, &[Kind::String, Kind::Any], Kind::String)
+    }
+}
+
+pub type Format = CachedCur<FormatEv>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::Harness;
+
+    fn ints(a: &[i64]) -> Value {
+        Value::from(a.iter().map(|i| Value::I64(*i)).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn array_functions() {
+        let mut h = Harness::new();
+        let cases = [
+            ("map([1, 2, 3], |x| x * 2)", ints(&[2, 4, 6])),
+            ("filter_array([1, 2, 3, 4], |x| x > 2)", ints(&[3, 4])),
+            ("fold([1, 2, 3], 10, |acc, x| acc + x)", Value::I64(16)),
+            ("fold([], 10, |acc, x| acc + x)", Value::I64(10)),
+            ("sort_by([3, 1, 2], |x| 0 - x)", ints(&[3, 2, 1])),
+            (
+                "group_by([1, 2, 3, 4], |x| x > 2)",
+                Value::from(vec![
+                    Value::from(vec![Value::False, ints(&[1, 2])]),
+                    Value::from(vec![Value::True, ints(&[3, 4])]),
+                ]),
+            ),
+            ("zip([1, 2, 3], [4, 5])", Value::from(vec![ints(&[1, 4]), ints(&[2, 5])])),
+            ("flatten([[1, 2], 3, [], [4]])", ints(&[1, 2, 3, 4])),
+            ("slice([1, 2, 3, 4], 1, 3)", ints(&[2, 3])),
+            ("slice([1, 2, 3, 4], 2)", ints(&[3, 4])),
+            ("slice([1, 2, 3, 4], 3, 1)", ints(&[])),
+            ("slice([1, 2, 3, 4], 7)", ints(&[])),
+            ("len([1, 2, 3])", Value::I64(3)),
+            ("len(\"héllo\")", Value::I64(5)),
+        ];
+        for (src, expected) in cases {
+            let e = h.compile(src).unwrap();
+            assert_eq!(h.values(e).last(), Some(&expected), "{}", src);
+        }
+        let e = h.compile("map(1, |x| x)").unwrap();
+        assert_eq!(
+            h.values(e),
+            &[Value::Error(Chars::from("map: expected an array, not 1"))]
+        );
+    }
+
+    #[test]
+    fn sort_by_total_order() {
+        let mut h = Harness::new();
+        let e = h.compile(r#"sort_by([3, "b", 1.5, null, [1, 2], 2, "a", [1]], |x| x)"#);
+        let expected = Value::from(vec![
+            Value::Null,
+            Value::I64(2),
+            Value::I64(3),
+            Value::F64(1.5),
+            Value::from("a"),
+            Value::from("b"),
+            ints(&[1]),
+            ints(&[1, 2]),
+        ]);
+        assert_eq!(h.values(e.unwrap()).last(), Some(&expected));
+        let keys = [
+            Value::F64(f64::NAN),
+            Value::F64(1.),
+            Value::F64(-f64::NAN),
+            Value::F64(f64::NEG_INFINITY),
+            Value::I64(0),
+            Value::U64(0),
+            Value::True,
+            Value::False,
+        ];
+        let mut sorted = keys.to_vec();
+        sorted.sort_by(SortByEv::cmp);
+        assert_eq!(
+            sorted.iter().map(|v| v.to_string()).collect::<Vec<_>>(),
+            [
+                Value::False,
+                Value::True,
+                Value::U64(0),
+                Value::I64(0),
+                Value::F64(-f64::NAN),
+                Value::F64(f64::NEG_INFINITY),
+                Value::F64(1.),
+                Value::F64(f64::NAN),
+            ]
+            .iter()
+            .map(|v| v.to_string())
+            .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn array_function_updates() {
+        let mut h = Harness::new();
+        let m = h.compile(r#"map(load("/a"), |x| x + load("/k"))"#).unwrap();
+        let f = h.compile(r#"fold(load("/a"), 0, |acc, x| acc + x)"#).unwrap();
+        h.set("/k", Value::I64(10));
+        h.set("/a", ints(&[1, 2, 3]));
+        assert_eq!(h.values(m).last(), Some(&ints(&[11, 12, 13])));
+        assert_eq!(h.values(f).last(), Some(&Value::I64(6)));
+        h.set("/k", Value::I64(20));
+        assert_eq!(h.values(m).last(), Some(&ints(&[21, 22, 23])));
+        let scopes = h.ctx.variables.len();
+        for i in 0..10 {
+            h.set("/a", ints(&[i, i + 1]));
+            h.set("/a", ints(&[i, i + 1, i + 2]));
+        }
+        assert_eq!(h.values(m).last(), Some(&ints(&[29, 30, 31])));
+        assert_eq!(h.values(f).last(), Some(&Value::I64(30)));
+        assert_eq!(h.ctx.variables.len(), scopes);
+    }
+
+    #[test]
+    fn match_values() {
+        let mut h = Harness::new();
+        let e = h
+            .compile(
+                r#"match load("/v") {
+                    i64(n) if n > 0 => "positive",
+                    0 => "zero",
+                    [x, ..rest] => rest,
+                    error(e) => e,
+                    _ => "other"
+                }"#,
+            )
+            .unwrap();
+        h.set("/v", Value::I64(5));
+        h.set("/v", Value::I64(-3));
+        h.set("/v", Value::I64(0));
+        h.set("/v", Value::from(vec![Value::I64(1), Value::I64(2), Value::I64(3)]));
+        assert_eq!(
+            h.take_values(e),
+            vec![
+                Value::from("#LOST"),
+                Value::from("positive"),
+                Value::from("other"),
+                Value::from("zero"),
+                Value::from(vec![Value::I64(2), Value::I64(3)]),
+            ]
+        );
+        let e = h.compile(r#"match 1.5 { i64(n) => n }"#).unwrap();
+        assert_eq!(
+            h.values(e),
+            &[Value::Error(Chars::from("match: no pattern matches 1.5"))]
+        );
+    }
+
+    #[test]
+    fn try_catch() {
+        let mut h = Harness::new();
+        let t = h.compile(r#"try(load("/v"), -1)"#).unwrap();
+        let c = h.compile(r#"catch(load("/v"), |e| "failed: [e]")"#).unwrap();
+        assert_eq!(h.values(t), &[Value::I64(-1)]);
+        assert_eq!(h.values(c).last(), Some(&Value::from("failed: #LOST")));
+        h.set("/v", Value::I64(42));
+        assert_eq!(h.values(t).last(), Some(&Value::I64(42)));
+        assert_eq!(h.values(c).last(), Some(&Value::I64(42)));
+        h.set("/v", Value::Error(Chars::from("boom")));
+        assert_eq!(h.values(t).last(), Some(&Value::I64(-1)));
+        assert_eq!(h.values(c).last(), Some(&Value::from("failed: boom")));
+    }
+
+    #[test]
+    fn lambdas() {
+        let mut h = Harness::new();
+        let err = |s: &str| Value::Error(Chars::from(String::from(s)));
+        let e = h.compile("{ let f <- |x, y| x + y; f(1, 2) }").unwrap();
+        assert_eq!(h.values(e).last(), Some(&Value::I64(3)));
+        // a closure sees the variables of the scope it was defined in
+        let e = h.compile(r#"{ let k <- load("/k"); let f <- |x| x * k; f(2) }"#);
+        let e = e.unwrap();
+        h.set("/k", Value::I64(3));
+        assert_eq!(h.values(e).last(), Some(&Value::I64(6)));
+        h.set("/k", Value::I64(4));
+        assert_eq!(h.values(e).last(), Some(&Value::I64(8)));
+        // a new argument runs the body again
+        let e = h.compile(r#"{ let f <- |x| x + 1; f(load("/x")) }"#).unwrap();
+        h.take_values(e);
+        h.set("/x", Value::I64(1));
+        assert_eq!(h.take_values(e).last(), Some(&Value::I64(2)));
+        h.set("/x", Value::I64(10));
+        assert_eq!(h.take_values(e).last(), Some(&Value::I64(11)));
+        let e = h.compile("{ let f <- |x| f(x); f(1) }").unwrap();
+        assert_eq!(h.values(e), &[err("f: recursive functions are not supported")]);
+        let e = h.compile("{ let f <- |x, y| x; f(1) }").unwrap();
+        assert_eq!(h.values(e), &[err("f: expected 2 arguments")]);
+    }
+
+    #[test]
+    fn function_arguments() {
+        let mut h = Harness::new();
+        let cases = [
+            ("{ let double <- |x| x * 2; map([1, 2], double) }", ints(&[2, 4])),
+            ("map([[1, 2], [3]], len)", ints(&[2, 1])),
+            ("{ let double <- |x| x * 2; map([1, 2], \"double\") }", ints(&[2, 4])),
+            (
+                "{ let double <- |x| x * 2; let g <- \"double\"; map([1, 2], g) }",
+                ints(&[2, 4]),
+            ),
+            ("map([1, 2], nope)", Value::Error(Chars::from("unknown function nope"))),
+        ];
+        for (src, expected) in cases {
+            let e = h.compile(src).unwrap();
+            assert_eq!(h.values(e).last(), Some(&expected), "{}", src);
+        }
+    }
+
+    fn strs(a: &[&str]) -> Value {
+        Value::from(a.iter().map(|s| Value::from(String::from(*s))).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn regexes() {
+        let mut h = Harness::new();
+        let m = h.compile(r#"regex_match(load("/re"), "abc")"#).unwrap();
+        h.set("/re", Value::from("a.c"));
+        assert_eq!(h.values(m).last(), Some(&Value::True));
+        // a new pattern replaces the compiled regex
+        h.set("/re", Value::from("^b"));
+        assert_eq!(h.values(m).last(), Some(&Value::False));
+        h.set("/re", Value::from("(b"));
+        match h.values(m).last() {
+            Some(Value::Error(e)) => {
+                assert!(e.starts_with("regex_match: regex parse error"), "{}", e)
+            }
+            v => panic!("expected a compile error, not {:?}", v),
+        }
+        h.set("/re", Value::from("b"));
+        assert_eq!(h.values(m).last(), Some(&Value::True));
+        let cases = [
+            (r#"regex_match("^a+$", "aaa")"#, Value::True),
+            (
+                r#"regex_match("a", 42)"#,
+                Value::Error(Chars::from(
+                    "regex_match(re, s): expected a regex and a string",
+                )),
+            ),
+            (
+                r#"regex_captures("(a)|(b)", "ab")"#,
+                Value::from(vec![
+                    Value::from(vec![Value::from("a"), Value::from("a"), Value::Null]),
+                    Value::from(vec![Value::from("b"), Value::Null, Value::from("b")]),
+                ]),
+            ),
+            (r#"regex_captures("x", "ab")"#, Value::from(Vec::<Value>::new())),
+            (r#"regex_replace("(o+)", "<$1>", "foo boo")"#, Value::from("f<oo> b<oo>")),
+            (r#"regex_replace("(?P<x>o+)", "$x$x", "fo")"#, Value::from("foo")),
+        ];
+        for (src, expected) in cases {
+            let e = h.compile(src).unwrap();
+            assert_eq!(h.values(e).last(), Some(&expected), "{}", src);
+        }
+    }
+
+    #[test]
+    fn strings() {
+        let mut h = Harness::new();
+        let cases = [
+            (r#"split(",", "a,b,,c")"#, strs(&["a", "b", "", "c"])),
+            (r#"split(",", "")"#, strs(&[""])),
+            (r#"split("", "héj")"#, strs(&["h", "é", "j"])),
+            (r#"split("", "")"#, strs(&[])),
+            (r#"pad("ab", 5)"#, Value::from("ab   ")),
+            (r#"pad("ab", 5, "right", "*")"#, Value::from("***ab")),
+            (r#"pad("ab", 5, "center")"#, Value::from(" ab  ")),
+            (r#"pad("abcdef", 3)"#, Value::from("abcdef")),
+            (r#"substring("héllo", 1, 3)"#, Value::from("él")),
+            (r#"substring("abc", 1)"#, Value::from("bc")),
+            (r#"substring("abc", 2, 1)"#, Value::from("")),
+            (r#"substring("abc", 5)"#, Value::from("")),
+        ];
+        for (src, expected) in cases {
+            let e = h.compile(src).unwrap();
+            assert_eq!(h.values(e).last(), Some(&expected), "{}", src);
+        }
+    }
+
+    #[test]
+    fn format_specs() {
+        let fmt = |f: &str, args: &[Value]| {
+            let mut from = vec![Some(Value::from(String::from(f)))];
+            from.extend(args.iter().cloned().map(Some));
+            FormatEv::eval(&CachedVals(from)).unwrap()
+        };
+        let s = |s: &str| Value::from(String::from(s));
+        let err = |s: &str| Value::Error(Chars::from(format!("format: {}", s)));
+        let d = Value::Decimal(rust_decimal::Decimal::new(12345678, 3));
+        let cases = [
+            ("{} and {}", vec![Value::I64(1), s("x")], s("1 and x")),
+            ("{{}} {}", vec![Value::I64(1)], s("{} 1")),
+            ("{:,}", vec![Value::I64(-1234567)], s("-1,234,567")),
+            ("{:,}", vec![Value::I64(-100)], s("-100")),
+            ("{:,}", vec![Value::U64(u64::MAX)], s("18,446,744,073,709,551,615")),
+            ("{:,}", vec![Value::F64(-1234.5)], s("-1,234.5")),
+            ("{:,}", vec![Value::F64(-0.25)], s("-0.25")),
+            ("{:.2}", vec![Value::F64(3.14159)], s("3.14")),
+            ("{:.2}", vec![Value::I64(42)], s("42.00")),
+            ("{:.0}", vec![Value::I64((1 << 53) + 1)], s("9007199254740993")),
+            ("{:.1}", vec![Value::U64(u64::MAX)], s("18446744073709551615.0")),
+            ("{:,.2}", vec![Value::F64(-1234567.891)], s("-1,234,567.89")),
+            ("{:,.2}", vec![Value::I64(-1000)], s("-1,000.00")),
+            ("{:,.2}", vec![d], s("12,345.68")),
+            ("{:x}", vec![Value::I64(1)], err("invalid format spec x")),
+            ("{:.}", vec![Value::I64(1)], err("invalid format spec .")),
+            ("{:,}", vec![Value::True], err("true is not a number")),
+            ("{x}", vec![Value::I64(1)], err("invalid placeholder {x}")),
+            ("{", vec![], err("unclosed {")),
+            ("}", vec![], err("unmatched }")),
+            ("{} {}", vec![Value::I64(1)], err("not enough arguments")),
+            ("{}", vec![Value::I64(1), Value::I64(2)], err("too many arguments")),
+        ];
+        for (f, args, expected) in cases {
+            assert_eq!(fmt(f, &args), expected, "{}", f);
+        }
+    }
+
+    #[test]
+    fn now_interval() {
+        let mut h = Harness::new();
+        let n = h.compile(r#"now(load("/i"))"#).unwrap();
+        let c = h.compile(r#"count(now(load("/i")))"#).unwrap();
+        h.set("/i", Value::I64(1));
+        h.take_values(c);
+        h.advance(Duration::from_millis(2500));
+        assert_eq!(h.take_values(c).len(), 2);
+        assert!(matches!(h.values(n).last(), Some(Value::DateTime(_))));
+        // the timer set for the old interval doesn't tick
+        h.set("/i", Value::I64(10));
+        h.advance(Duration::from_secs(5));
+        assert_eq!(h.take_values(c).len(), 0);
+        h.advance(Duration::from_secs(5));
+        assert_eq!(h.take_values(c).len(), 1);
+        h.set("/i", Value::I64(0));
+        assert_eq!(
+            h.values(n).last(),
+            Some(&Value::Error(Chars::from(
+                "now([interval]): expected interval to be a positive duration"
+            )))
+        );
+        h.take_values(c);
+        h.advance(Duration::from_secs(60));
+        assert_eq!(h.take_values(c).len(), 0);
+    }
+
+    #[track_caller]
+    fn check_all(h: &mut Harness, cases: &[(String, Value)]) {
+        for (src, expected) in cases {
+            let e = h.compile(src).unwrap();
+            assert_eq!(h.values(e).last(), Some(expected), "{}", src);
+        }
+    }
+
+    fn utc(y: i32, mo: u32, d: u32, h: u32, mi: u32, s: u32) -> Value {
+        Value::DateTime(Utc.with_ymd_and_hms(y, mo, d, h, mi, s).unwrap())
+    }
+
+    #[test]
+    fn datetimes() {
+        let mut h = Harness::new();
+        let err = |s: &str| Value::Error(Chars::from(String::from(s)));
+        let t = r#"parse_time("2024-01-15T12:34:56Z")"#;
+        let noon = r#"parse_time("2024-01-15T12:00:00Z")"#;
+        let fmt = r#""%Y-%m-%d %H:%M:%S""#;
+        let ofs = "%Y-%m-%d %H:%M %z";
+        let bad_zone = |z: &str| {
+            err(&format!(
+                "invalid time zone {}, expected utc, local, or an offset e.g. +05:00",
+                z
+            ))
+        };
+        let bad_unit = "truncate: invalid unit week, expected year, month, day, hour, \
+                        minute, or second";
+        check_all(
+            &mut h,
+            &[
+                (t.into(), utc(2024, 1, 15, 12, 34, 56)),
+                (format!("format_time({t}, {fmt})"), Value::from("2024-01-15 12:34:56")),
+                (
+                    format!(r#"format_time({t}, {fmt}, "+05:30")"#),
+                    Value::from("2024-01-15 18:04:56"),
+                ),
+                (
+                    format!(r#"format_time({t}, {fmt}, "-0800")"#),
+                    Value::from("2024-01-15 04:34:56"),
+                ),
+                (
+                    format!(r#"parse_time(format_time({t}, "%+"))"#),
+                    utc(2024, 1, 15, 12, 34, 56),
+                ),
+                (
+                    format!(
+                        r#"parse_time(format_time({t}, {fmt}, "-02:00"), {fmt}, "-0200")"#
+                    ),
+                    utc(2024, 1, 15, 12, 34, 56),
+                ),
+                // an offset in the string wins over the tz argument
+                (
+                    format!(r#"parse_time("2024-01-15 18:04 +0530", "{ofs}", "-08:00")"#),
+                    utc(2024, 1, 15, 12, 34, 0),
+                ),
+                (
+                    r#"parse_time("2024-01-15", "%Y-%m-%d", "-05:00")"#.into(),
+                    utc(2024, 1, 15, 5, 0, 0),
+                ),
+                (format!("add_duration({t}, -90)"), utc(2024, 1, 15, 12, 33, 26)),
+                (
+                    format!("add_duration({t}, duration:1.s)"),
+                    utc(2024, 1, 15, 12, 34, 57),
+                ),
+                (
+                    format!(r#"truncate({t}, "hour", "+05:30")"#),
+                    utc(2024, 1, 15, 12, 30, 0),
+                ),
+                (format!(r#"truncate({t}, "month")"#), utc(2024, 1, 1, 0, 0, 0)),
+                (
+                    format!("diff({t}, {noon})"),
+                    Value::Duration(Duration::from_secs(34 * 60 + 56)),
+                ),
+                (
+                    format!("diff({noon}, {t})"),
+                    err("diff(a, b): b is after a, and durations can't be negative"),
+                ),
+                (
+                    format!("age(add_duration({t}, 1000000000))"),
+                    Value::Duration(Duration::ZERO),
+                ),
+                (
+                    format!(r#"format_time({t}, "%Q")"#),
+                    err("format_time: invalid format string %Q"),
+                ),
+                (format!(r#"format_time({t}, {fmt}, "mars")"#), bad_zone("mars")),
+                (format!(r#"truncate({t}, "day", "+25:00")"#), bad_zone("+25:00")),
+                (
+                    r#"parse_time("yesterday")"#.into(),
+                    err("parse_time: can't parse yesterday"),
+                ),
+                (
+                    r#"parse_time("15/01/2024", "%Y-%m-%d")"#.into(),
+                    err("parse_time: can't parse 15/01/2024"),
+                ),
+                (format!(r#"truncate({t}, "week")"#), err(bad_unit)),
+            ],
+        );
+    }
+
+    // This is the only test that uses the local time zone. chrono
+    // caches it per thread, and each test runs on it's own thread.
+    #[test]
+    fn daylight_saving_time() {
+        std::env::set_var("TZ", "EST5EDT,M3.2.0,M11.1.0");
+        let mut h = Harness::new();
+        let local = |s: &str| format!(r#"parse_time("{s}", "%Y-%m-%d %H:%M", "local")"#);
+        let fmt =
+            |ts: &str| format!(r#"format_time(parse_time("{ts}"), "%H:%M %z", "local")"#);
+        check_all(
+            &mut h,
+            &[
+                (fmt("2024-03-10T06:59:00Z"), Value::from("01:59 -0500")),
+                (fmt("2024-03-10T07:00:00Z"), Value::from("03:00 -0400")),
+                // skipped when the clocks go forward
+                (
+                    local("2024-03-10 02:30"),
+                    Value::Error(Chars::from("parse_time: can't parse 2024-03-10 02:30")),
+                ),
+                // ambiguous when the clocks go back, the earliest wins
+                (local("2024-11-03 01:30"), utc(2024, 11, 3, 5, 30, 0)),
+                (
+                    r#"truncate(parse_time("2024-03-10T12:00:00Z"), "day", "local")"#
+                        .into(),
+                    utc(2024, 3, 10, 5, 0, 0),
+                ),
+                (
+                    format!(
+                        "diff({}, {})",
+                        local("2024-03-10 04:00"),
+                        local("2024-03-10 01:00")
+                    ),
+                    Value::Duration(Duration::from_secs(2 * 3600)),
+                ),
+            ],
+        );
+    }
 }
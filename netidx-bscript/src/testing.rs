@@ -0,0 +1,410 @@
+//! A deterministic harness for testing bscript expressions without a
+//! netidx cluster.
+//!
+//! [`Harness`] compiles expressions against an [`ExecCtx`] backed by
+//! [`TestCtx`], a mock [`Ctx`] that never touches the network. Tests
+//! declare the values of fake subscriptions with [`Harness::set`],
+//! move a virtual clock forward with [`Harness::advance`] to fire
+//! timers, stub rpcs with [`Harness::stub_rpc`], and then assert on
+//! the sequence of values each compiled expression produced.
+//!
+//! ```
+//! use netidx::subscriber::Value;
+//! use netidx_bscript::testing::Harness;
+//!
+//! let mut h = Harness::new();
+//! let e = h.compile("load(\"/foo\") + 1").unwrap();
+//! h.set("/foo", Value::I64(41));
+//! assert_eq!(h.values(e).last(), Some(&Value::I64(42)));
+//! ```
+//!
+//! A subscription that has no declared value behaves like a real
+//! subscription that isn't established yet, `load` produces `#LOST`.
+//! Subscriptions are detached `Dval`s, so nothing ever touches the
+//! network, and writes are recorded, see [`Harness::writes`]. Only
+//! timers run on the virtual clock, functions such as `now` still
+//! read the wall clock.
+use crate::{
+    expr::{Expr, ExprId},
+    vm::{self, Ctx, Event, ExecCtx, Node, RpcCallId, TimerId},
+};
+use anyhow::Result;
+use fxhash::FxHashMap;
+use netidx::{
+    chars::Chars,
+    path::Path,
+    subscriber::{Dval, UpdatesFlags, Value},
+};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    time::Duration,
+};
+
+/// The most events a single call to process will handle before
+/// deciding the expressions under test will never settle.
+const MAX_EVENTS: usize = 100_000;
+
+/// A stubbed rpc, called with the arguments of each call, returns
+/// the reply.
+pub type RpcStub = Box<dyn FnMut(&[(Chars, Value)]) -> Value>;
+
+/// A mock `Ctx` that records what the expressions under test ask for,
+/// and queues the events they would eventually receive.
+#[derive(Default)]
+pub struct TestCtx {
+    subs: FxHashMap<Path, (Dval, usize)>,
+    values: FxHashMap<Path, Value>,
+    pending: VecDeque<Event<()>>,
+    now: Duration,
+    seq: u64,
+    timers: BTreeMap<(Duration, u64), TimerId>,
+    rpcs: FxHashMap<Path, RpcStub>,
+    calls: Vec<(Path, Vec<(Chars, Value)>)>,
+    writes: Vec<(Path, Value)>,
+    files: FxHashMap<String, Chars>,
+}
+
+impl Ctx for TestCtx {
+    fn clear(&mut self) {}
+
+    fn durable_subscribe(
+        &mut self,
+        flags: UpdatesFlags,
+        path: Path,
+        _ref_by: ExprId,
+    ) -> Dval {
+        // like a real subscriber, subscribing to the same path again
+        // returns the same dval
+        let (dv, n) =
+            self.subs.entry(path.clone()).or_insert_with(|| (Dval::detached(), 0));
+        *n += 1;
+        let dv = dv.clone();
+        if flags.contains(UpdatesFlags::BEGIN_WITH_LAST) {
+            if let Some(v) = self.values.get(&path) {
+                self.pending.push_back(Event::Netidx(dv.id(), v.clone()));
+            }
+        }
+        dv
+    }
+
+    fn unsubscribe(&mut self, path: Path, _dv: Dval, _ref_by: ExprId) {
+        if let Some((_, n)) = self.subs.get_mut(&path) {
+            *n -= 1;
+            if *n == 0 {
+                self.subs.remove(&path);
+            }
+        }
+    }
+
+    fn ref_var(&mut self, _name: Chars, _scope: Path, _ref_by: ExprId) {}
+
+    fn unref_var(&mut self, _name: Chars, _scope: Path, _ref_by: ExprId) {}
+
+    fn register_fn(&mut self, _name: Chars, _scope: Path) {}
+
+    fn set_var(
+        &mut self,
+        variables: &mut FxHashMap<Path, FxHashMap<Chars, Value>>,
+        local: bool,
+        scope: Path,
+        name: Chars,
+        value: Value,
+    ) {
+        vm::store_var(variables, local, &scope, &name, value.clone());
+        self.pending.push_back(Event::Variable(scope, name, value));
+    }
+
+    fn call_rpc(
+        &mut self,
+        name: Path,
+        args: Vec<(Chars, Value)>,
+        _ref_by: ExprId,
+        id: RpcCallId,
+    ) {
+        let reply = match self.rpcs.get_mut(&name) {
+            Some(f) => f(&args),
+            None => Value::Error(Chars::from(format!("no such rpc {}", name))),
+        };
+        self.calls.push((name, args));
+        self.pending.push_back(Event::Rpc(id, reply));
+    }
+
+    fn set_timer(&mut self, id: TimerId, timeout: Duration, _ref_by: ExprId) {
+        self.seq += 1;
+        self.timers.insert((self.now + timeout, self.seq), id);
+    }
+
+    fn write(&mut self, path: &Path, _dv: &Dval, value: Value, _ref_by: ExprId) {
+        self.writes.push((path.clone(), value));
+    }
+
+    fn load_file(&mut self, path: &str) -> Result<Chars> {
+        match self.files.get(path) {
+            Some(src) => Ok(src.clone()),
+            None => anyhow::bail!("no such file {}", path),
+        }
+    }
+}
+
+/// Compiles expressions against a `TestCtx`, delivers the events they
+/// generate, and records every value they produce.
+pub struct Harness {
+    pub ctx: ExecCtx<TestCtx, ()>,
+    nodes: Vec<(ExprId, Node<TestCtx, ()>, Vec<Value>)>,
+}
+
+impl Default for Harness {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Harness {
+    /// Create a harness with the standard library loaded
+    pub fn new() -> Self {
+        Harness { ctx: ExecCtx::new(TestCtx::default()), nodes: vec![] }
+    }
+
+    /// Parse and compile `src`, record its initial value, if any, and
+    /// deliver any events it generated. The returned id names the
+    /// expression in `values` and `take_values`.
+    pub fn compile(&mut self, src: &str) -> Result<ExprId> {
+        let expr = src.parse::<Expr>()?;
+        let id = expr.id;
+        let node = Node::compile(&mut self.ctx, Path::root(), expr);
+        let values = node.current(&mut self.ctx).into_iter().collect();
+        self.nodes.push((id, node, values));
+        self.process();
+        Ok(id)
+    }
+
+    /// Declare the value of the subscription `path`, and deliver it
+    /// to everything currently subscribed to it. Subscriptions created
+    /// later will start with this value.
+    pub fn set(&mut self, path: &str, value: Value) {
+        let path = Path::from(String::from(path));
+        let user = &mut self.ctx.user;
+        if let Some((dv, _)) = user.subs.get(&path) {
+            user.pending.push_back(Event::Netidx(dv.id(), value.clone()));
+        }
+        user.values.insert(path, value);
+        self.process();
+    }
+
+    /// Deliver an arbitrary event, e.g. a `User` event
+    pub fn event(&mut self, event: Event<()>) {
+        self.ctx.user.pending.push_back(event);
+        self.process();
+    }
+
+    /// Move the virtual clock forward by `by`, firing every timer
+    /// that comes due along the way, in order.
+    pub fn advance(&mut self, by: Duration) {
+        let until = self.ctx.user.now + by;
+        loop {
+            let due = match self.ctx.user.timers.first_key_value() {
+                Some((k, _)) if k.0 <= until => *k,
+                Some(_) | None => break,
+            };
+            let id = self.ctx.user.timers.remove(&due).unwrap();
+            self.ctx.user.now = due.0;
+            self.ctx.user.pending.push_back(Event::Timer(id));
+            self.process();
+        }
+        self.ctx.user.now = until;
+    }
+
+    /// The time on the virtual clock, the harness starts at zero
+    pub fn now(&self) -> Duration {
+        self.ctx.user.now
+    }
+
+    /// Reply to calls to the rpc `name` with `f`. Calls to rpcs that
+    /// aren't stubbed fail.
+    pub fn stub_rpc<F>(&mut self, name: &str, f: F)
+    where
+        F: FnMut(&[(Chars, Value)]) -> Value + 'static,
+    {
+        self.ctx.user.rpcs.insert(Path::from(String::from(name)), Box::new(f));
+    }
+
+    /// Every rpc call made so far, in order
+    pub fn rpc_calls(&self) -> &[(Path, Vec<(Chars, Value)>)] {
+        &self.ctx.user.calls
+    }
+
+    /// Make `src` importable as `file:path`
+    pub fn add_file(&mut self, path: &str, src: &str) {
+        self.ctx.user.files.insert(String::from(path), Chars::from(String::from(src)));
+    }
+
+    /// Every value written to a subscription so far, in order
+    pub fn writes(&self) -> &[(Path, Value)] {
+        &self.ctx.user.writes
+    }
+
+    /// True if anything is currently subscribed to `path`
+    pub fn subscribed(&self, path: &str) -> bool {
+        self.ctx.user.subs.contains_key(path)
+    }
+
+    /// Every value the expression `id` has produced so far
+    pub fn values(&self, id: ExprId) -> &[Value] {
+        self.nodes
+            .iter()
+            .find(|(i, _, _)| *i == id)
+            .map(|(_, _, v)| &v[..])
+            .unwrap_or(&[])
+    }
+
+    /// Like `values`, but also forget them, so the next call only
+    /// returns what was produced in between.
+    pub fn take_values(&mut self, id: ExprId) -> Vec<Value> {
+        self.nodes
+            .iter_mut()
+            .find(|(i, _, _)| *i == id)
+            .map(|(_, _, v)| std::mem::take(v))
+            .unwrap_or_default()
+    }
+
+    /// Deliver queued events to every expression until there are no
+    /// more. Panics if the expressions never settle.
+    pub fn process(&mut self) {
+        let mut n = 0;
+        while let Some(event) = self.ctx.user.pending.pop_front() {
+            n += 1;
+            if n > MAX_EVENTS {
+                panic!("more than {} events, the expressions never settle", MAX_EVENTS)
+            }
+            for (_, node, values) in self.nodes.iter_mut() {
+                if let Some(v) = node.update(&mut self.ctx, &event) {
+                    values.push(v);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn subscriptions() {
+        let mut h = Harness::new();
+        let e = h.compile("load(\"/foo\")").unwrap();
+        assert_eq!(h.values(e), &[Value::Error(Chars::from("#LOST"))]);
+        assert!(h.subscribed("/foo"));
+        let e = h.compile("load(\"/foo\") * 2").unwrap();
+        h.take_values(e);
+        h.set("/foo", Value::I64(1));
+        h.set("/foo", Value::I64(2));
+        assert_eq!(h.take_values(e), vec![Value::I64(2), Value::I64(4)]);
+        h.set("/bar", Value::I64(3));
+        let e = h.compile("load(\"/bar\")").unwrap();
+        assert_eq!(h.values(e).last(), Some(&Value::I64(3)));
+    }
+
+    #[test]
+    fn writes() {
+        let mut h = Harness::new();
+        h.compile("store(\"/a\", 42)").unwrap();
+        assert_eq!(h.writes(), &[(Path::from("/a"), Value::I64(42))]);
+        h.compile("store(\"/b\", filter_err(load(\"/in\")))").unwrap();
+        h.set("/in", Value::I64(1));
+        h.set("/in", Value::I64(2));
+        assert_eq!(
+            &h.writes()[1..],
+            &[(Path::from("/b"), Value::I64(1)), (Path::from("/b"), Value::I64(2))]
+        );
+        assert!(h.subscribed("/b"));
+    }
+
+    #[test]
+    fn variables() {
+        let mut h = Harness::new();
+        let e = h.compile("{ let x <- load(\"/foo\"); x + 1 }").unwrap();
+        h.set("/foo", Value::I64(1));
+        assert_eq!(h.values(e).last(), Some(&Value::I64(2)));
+    }
+
+    #[test]
+    fn timers() {
+        let mut h = Harness::new();
+        let e = h.compile("count(timer(1.0, 3))").unwrap();
+        h.take_values(e);
+        h.advance(Duration::from_millis(500));
+        assert_eq!(h.take_values(e), vec![]);
+        h.advance(Duration::from_millis(2000));
+        assert_eq!(h.take_values(e), vec![Value::U64(1), Value::U64(2)]);
+        h.advance(Duration::from_secs(10));
+        assert_eq!(h.take_values(e), vec![Value::U64(3)]);
+        assert_eq!(h.now(), Duration::from_millis(12500));
+    }
+
+    #[test]
+    fn rpcs() {
+        let mut h = Harness::new();
+        h.stub_rpc("/double", |args| match args {
+            [(_, Value::I64(i))] => Value::I64(i * 2),
+            _ => Value::Error(Chars::from("bad args")),
+        });
+        let e =
+            h.compile("call(filter_err(load(\"/go\")), \"/double\", \"x\", 21)").unwrap();
+        assert_eq!(h.take_values(e), vec![]);
+        h.set("/go", Value::Null);
+        assert_eq!(h.take_values(e), vec![Value::I64(42)]);
+        assert_eq!(
+            h.rpc_calls(),
+            &[(Path::from("/double"), vec![(Chars::from("x"), Value::I64(21))])]
+        );
+        let e = h.compile("call(null, \"/missing\")").unwrap();
+        assert_eq!(h.values(e), &[Value::Error(Chars::from("no such rpc /missing"))]);
+    }
+
+    #[test]
+    fn imports() {
+        let mut h = Harness::new();
+        h.add_file("m.bs", "{ let base <- 10; let f <- |x| x + base }");
+        let e = h.compile("{ import \"file:m.bs\" as m; m::base + 1 }").unwrap();
+        assert_eq!(h.values(e).last(), Some(&Value::I64(11)));
+        let e = h.compile("{ import \"file:m.bs\" as m; map([1, 2], \"m::f\") }");
+        let expected = Value::from(vec![Value::I64(11), Value::I64(12)]);
+        assert_eq!(h.values(e.unwrap()).last(), Some(&expected));
+        // the module's names are only visible qualified
+        let e = h.compile("{ import \"file:m.bs\" as m; f(1) }").unwrap();
+        assert_eq!(h.values(e), &[Value::Error(Chars::from("unknown function f"))]);
+        let e = h.compile("{ import \"file:m.bs\" as m; n::f(1) }").unwrap();
+        assert_eq!(h.values(e), &[Value::Error(Chars::from("unknown function n::f"))]);
+        let e = h.compile("{ import \"/m\" as m; m::f(1) }").unwrap();
+        h.set("/m", Value::from("let f <- |x| x + 1"));
+        assert_eq!(h.take_values(e).last(), Some(&Value::I64(2)));
+        h.set("/m", Value::from("let f <- |x| x + 2"));
+        assert_eq!(h.take_values(e).last(), Some(&Value::I64(3)));
+        let e = h.compile("{ import \"/v\" as v; v::x * 2 }").unwrap();
+        h.set("/v", Value::from("{ let x <- load(\"/x\"); let y <- 0 }"));
+        h.set("/x", Value::I64(4));
+        assert_eq!(h.take_values(e).last(), Some(&Value::I64(8)));
+        h.set("/x", Value::I64(5));
+        assert_eq!(h.take_values(e).last(), Some(&Value::I64(10)));
+        // a new version of a module replaces the old definitions
+        h.set("/v", Value::from("let y <- 1"));
+        assert!(h.ctx.lookup_var(&Path::root(), &Chars::from("v::x")).is_none());
+        assert_eq!(
+            h.ctx.lookup_var(&Path::root(), &Chars::from("v::y")).map(|(_, v)| v),
+            Some(&Value::I64(1))
+        );
+        // and deletes the old nodes, which drops their subscriptions
+        let e = h.compile("{ import \"/w\" as w; w::x + load(\"/b\") }").unwrap();
+        h.set("/w", Value::from("let x <- load(\"/wx\")"));
+        assert!(h.subscribed("/wx") && h.subscribed("/b"));
+        h.set("/w", Value::from("let x <- 1"));
+        assert!(!h.subscribed("/wx") && h.subscribed("/b"));
+        h.set("/b", Value::I64(2));
+        assert_eq!(h.values(e).last(), Some(&Value::I64(3)));
+        // a file is only read the first time it's imported
+        h.add_file("m.bs", "let base <- 20");
+        let e = h.compile("{ import \"file:m.bs\" as m; m::base + 1 }").unwrap();
+        assert_eq!(h.values(e).last(), Some(&Value::I64(11)));
+    }
+}
@@ -1,8 +1,10 @@
 pub use crate::stdfn::{RpcCallId, TimerId};
 use crate::{
+    check::Signature,
     expr::{Expr, ExprId, ExprKind},
-    stdfn,
+    math, stdfn,
 };
+use anyhow::{bail, Result};
 use arcstr::ArcStr;
 use chrono::prelude::*;
 use fxhash::{FxBuildHasher, FxHashMap};
@@ -130,6 +132,11 @@ pub trait Apply<C: Ctx, E> {
         from: &mut [Node<C, E>],
         event: &Event<E>,
     ) -> Option<Value>;
+
+    /// Remove anything the function bound or subscribed to in `ctx`.
+    /// Called when the function is about to be dropped while the
+    /// program keeps running, see `Node::delete`.
+    fn delete(&mut self, _ctx: &mut ExecCtx<C, E>) {}
 }
 
 pub trait Ctx {
@@ -166,6 +173,19 @@ pub trait Ctx {
 
     /// arrange to have a Timer event delivered after timeout
     fn set_timer(&mut self, id: TimerId, timeout: Duration, ref_by: ExprId);
+
+    /// Write `value` to `path` through `dv`, a subscription held by
+    /// `ref_by`. Contexts that need to control what expressions may
+    /// write can override this, the default just writes.
+    fn write(&mut self, _path: &Path, dv: &Dval, value: Value, _ref_by: ExprId) {
+        dv.write(value);
+    }
+
+    /// Read the source of a module imported from a file. Contexts
+    /// that can't read files, which is the default, should fail.
+    fn load_file(&mut self, path: &str) -> Result<Chars> {
+        bail!("can't import {}, files can't be imported here", path)
+    }
 }
 
 pub fn store_var(
@@ -205,30 +225,112 @@ pub fn store_var(
 
 pub struct ExecCtx<C: Ctx + 'static, E: 'static> {
     pub functions: FxHashMap<String, InitFn<C, E>>,
+    /// the signatures of the built in functions, used by the checker
+    pub signatures: FxHashMap<String, Signature>,
+    /// user defined functions, by the scope they are bound in
+    pub lambdas: FxHashMap<Path, FxHashMap<Chars, InitFn<C, E>>>,
     pub variables: FxHashMap<Path, FxHashMap<Chars, Value>>,
+    /// the source and parsed form of imported modules, by path
+    pub modules: FxHashMap<Chars, (Chars, Expr)>,
+    /// the modules being compiled, outermost first
+    pub importing: Vec<Chars>,
     pub dbg_ctx: DbgCtx<E>,
     pub user: C,
 }
 
+/// Split a name qualified by a module, `module::name`, into the
+/// module and the name.
+pub fn split_qualified(name: &str) -> (Option<&str>, &str) {
+    match name.split_once("::") {
+        Some((module, name)) => (Some(module), name),
+        None => (None, name),
+    }
+}
+
+/// The scope below which a binding of `name` in `scope` is visible,
+/// or None if `name` can't refer to a binding in `scope`. A plain name
+/// is visible below the scope it is bound in, a qualified name below
+/// the scope it's module was imported in.
+pub fn visible_below<'a>(scope: &'a str, name: &str) -> Option<&'a str> {
+    match split_qualified(name) {
+        (None, _) => Some(scope),
+        (Some(module), _) if Path::basename(scope) == Some(module) => {
+            Some(Path::dirname(scope).unwrap_or("/"))
+        }
+        (Some(_), _) => None,
+    }
+}
+
+// find the closest binding of name visible from scope
+fn lookup<'a, T>(
+    bindings: &'a FxHashMap<Path, FxHashMap<Chars, T>>,
+    scope: &Path,
+    name: &str,
+) -> Option<(&'a Path, &'a T)> {
+    let (module, name) = split_qualified(name);
+    let mut iter = Path::dirnames(scope);
+    loop {
+        let scope = iter.next_back()?;
+        let found = match module {
+            None => bindings.get_key_value(scope),
+            Some(module) => {
+                let scope = Path::from(ArcStr::from(scope)).append(module);
+                bindings.get_key_value(&*scope)
+            }
+        };
+        if let Some((scope, bound)) = found {
+            if let Some(v) = bound.get(name) {
+                break Some((scope, v));
+            }
+        }
+    }
+}
+
 impl<C: Ctx, E: Clone> ExecCtx<C, E> {
+    /// Find the variable `name` referenced from `scope`. A name
+    /// qualified by a module, `module::name`, is only looked up in
+    /// the closest scope that module was imported in.
     pub fn lookup_var(&self, scope: &Path, name: &Chars) -> Option<(&Path, &Value)> {
-        let mut iter = Path::dirnames(scope);
-        loop {
-            match iter.next_back() {
-                Some(scope) => {
-                    if let Some((scope, vars)) = self.variables.get_key_value(scope) {
-                        if let Some(var) = vars.get(name) {
-                            break Some((scope, var));
-                        }
-                    }
-                }
-                None => break None,
+        lookup(&self.variables, scope, name)
+    }
+
+    fn lookup_lambda(&self, scope: &Path, name: &str) -> Option<(&Path, &InitFn<C, E>)> {
+        lookup(&self.lambdas, scope, name)
+    }
+
+    /// Find the function `name` called from `scope`. User defined
+    /// functions shadow built in functions of the same name.
+    pub fn lookup_fn(&self, scope: &Path, name: &str) -> Option<InitFn<C, E>> {
+        self.lookup_lambda(scope, name)
+            .map(|(_, f)| f)
+            .or_else(|| self.functions.get(name))
+            .map(Arc::clone)
+    }
+
+    /// Bind the user defined function `name`. Like variables, a local
+    /// function is bound in `scope`, otherwise it replaces the
+    /// closest definition visible from `scope`, or is bound at the
+    /// root if there isn't one.
+    pub fn define_fn(&mut self, local: bool, scope: &Path, name: Chars, f: InitFn<C, E>) {
+        let scope = if local {
+            scope.clone()
+        } else {
+            match self.lookup_lambda(scope, &name) {
+                Some((scope, _)) => scope.clone(),
+                None => Path::root(),
             }
-        }
+        };
+        self.user.register_fn(name.clone(), scope.clone());
+        self.lambdas
+            .entry(scope)
+            .or_insert_with(|| HashMap::with_hasher(FxBuildHasher::default()))
+            .insert(name, f);
     }
 
     pub fn clear(&mut self) {
         self.variables.clear();
+        self.lambdas.clear();
+        self.modules.clear();
         self.dbg_ctx.clear();
         self.user.clear();
     }
@@ -236,7 +338,11 @@ impl<C: Ctx, E: Clone> ExecCtx<C, E> {
     pub fn no_std(user: C) -> Self {
         ExecCtx {
             functions: HashMap::with_hasher(FxBuildHasher::default()),
+            signatures: HashMap::with_hasher(FxBuildHasher::default()),
+            lambdas: HashMap::with_hasher(FxBuildHasher::default()),
             variables: HashMap::with_hasher(FxBuildHasher::default()),
+            modules: HashMap::with_hasher(FxBuildHasher::default()),
+            importing: vec![],
             dbg_ctx: DbgCtx::new(),
             user,
         }
@@ -244,52 +350,107 @@ impl<C: Ctx, E: Clone> ExecCtx<C, E> {
 
     pub fn new(user: C) -> Self {
         let mut t = ExecCtx::no_std(user);
+        stdfn::AddDuration::register(&mut t);
         stdfn::AfterIdle::register(&mut t);
+        stdfn::Age::register(&mut t);
         stdfn::All::register(&mut t);
         stdfn::And::register(&mut t);
         stdfn::Any::register(&mut t);
         stdfn::Array::register(&mut t);
         stdfn::Basename::register(&mut t);
         stdfn::Cast::register(&mut t);
+        stdfn::Catch::register(&mut t);
         stdfn::Cmp::register(&mut t);
         stdfn::Contains::register(&mut t);
         stdfn::Count::register(&mut t);
         stdfn::Dirname::register(&mut t);
+        stdfn::Diff::register(&mut t);
         stdfn::Divide::register(&mut t);
         stdfn::Do::register(&mut t);
         stdfn::EndsWith::register(&mut t);
         stdfn::Eval::register(&mut t);
+        stdfn::FilterArray::register(&mut t);
         stdfn::FilterErr::register(&mut t);
         stdfn::Filter::register(&mut t);
+        stdfn::Flatten::register(&mut t);
+        stdfn::Fold::register(&mut t);
+        stdfn::Format::register(&mut t);
+        stdfn::FormatTime::register(&mut t);
         stdfn::Get::register(&mut t);
+        stdfn::GroupBy::register(&mut t);
         stdfn::If::register(&mut t);
+        stdfn::Import::register(&mut t);
         stdfn::Index::register(&mut t);
         stdfn::Isa::register(&mut t);
         stdfn::IsErr::register(&mut t);
+        stdfn::Len::register(&mut t);
         stdfn::Load::register(&mut t);
+        stdfn::Map::register(&mut t);
+        stdfn::Match::register(&mut t);
         stdfn::Max::register(&mut t);
         stdfn::Mean::register(&mut t);
         stdfn::Min::register(&mut t);
         stdfn::Not::register(&mut t);
+        stdfn::Now::register(&mut t);
         stdfn::Once::register(&mut t);
         stdfn::Or::register(&mut t);
+        stdfn::Pad::register(&mut t);
+        stdfn::ParseTime::register(&mut t);
         stdfn::Product::register(&mut t);
+        stdfn::RegexCaptures::register(&mut t);
+        stdfn::RegexMatch::register(&mut t);
+        stdfn::RegexReplace::register(&mut t);
         stdfn::Replace::register(&mut t);
         stdfn::RpcCall::register(&mut t);
         stdfn::Sample::register(&mut t);
         stdfn::Set::register(&mut t);
+        stdfn::Slice::register(&mut t);
+        stdfn::SortBy::register(&mut t);
+        stdfn::Split::register(&mut t);
         stdfn::StartsWith::register(&mut t);
         stdfn::Store::register(&mut t);
         stdfn::StringConcat::register(&mut t);
         stdfn::StringJoin::register(&mut t);
         stdfn::StripPrefix::register(&mut t);
         stdfn::StripSuffix::register(&mut t);
+        stdfn::Substring::register(&mut t);
         stdfn::Sum::register(&mut t);
         stdfn::Timer::register(&mut t);
+        stdfn::ToLower::register(&mut t);
+        stdfn::ToUpper::register(&mut t);
         stdfn::TrimEnd::register(&mut t);
         stdfn::Trim::register(&mut t);
         stdfn::TrimStart::register(&mut t);
+        stdfn::Truncate::register(&mut t);
+        stdfn::Try::register(&mut t);
         stdfn::Uniq::register(&mut t);
+        stdfn::Zip::register(&mut t);
+        math::Abs::register(&mut t);
+        math::Acos::register(&mut t);
+        math::Add::register(&mut t);
+        math::Asin::register(&mut t);
+        math::Atan::register(&mut t);
+        math::Ceil::register(&mut t);
+        math::Clamp::register(&mut t);
+        math::Cos::register(&mut t);
+        math::Div::register(&mut t);
+        math::Eq::register(&mut t);
+        math::Exp::register(&mut t);
+        math::Floor::register(&mut t);
+        math::Gt::register(&mut t);
+        math::Gte::register(&mut t);
+        math::Log::register(&mut t);
+        math::Lt::register(&mut t);
+        math::Lte::register(&mut t);
+        math::Mod::register(&mut t);
+        math::Mul::register(&mut t);
+        math::Ne::register(&mut t);
+        math::Pow::register(&mut t);
+        math::Round::register(&mut t);
+        math::Sin::register(&mut t);
+        math::Sqrt::register(&mut t);
+        math::Sub::register(&mut t);
+        math::Tan::register(&mut t);
         t
     }
 }
@@ -297,6 +458,9 @@ impl<C: Ctx, E: Clone> ExecCtx<C, E> {
 pub enum Node<C: Ctx, E> {
     Error(Expr, Value),
     Constant(Expr, Value),
+    /// A lambda expression, it has no value, and is compiled when
+    /// it's called.
+    Lambda(Expr, InitFn<C, E>),
     Apply {
         spec: Expr,
         args: Vec<Node<C, E>>,
@@ -307,9 +471,10 @@ pub enum Node<C: Ctx, E> {
 impl<C: Ctx, E> fmt::Display for Node<C, E> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            Node::Error(s, _) | Node::Constant(s, _) | Node::Apply { spec: s, .. } => {
-                write!(f, "{}", s)
-            }
+            Node::Error(s, _)
+            | Node::Constant(s, _)
+            | Node::Lambda(s, _)
+            | Node::Apply { spec: s, .. } => write!(f, "{}", s),
         }
     }
 }
@@ -325,6 +490,40 @@ impl<C: Ctx, E: Clone> Node<C, E> {
             Expr { kind: ExprKind::Constant(v), id: _ } => {
                 Node::Constant(spec.clone(), v.clone())
             }
+            Expr { kind: ExprKind::Apply { .. }, id: _ } if spec.is_lambda() => {
+                match stdfn::Lambda::init(Chars::from("lambda"), &spec, scope) {
+                    Ok(init) => Node::Lambda(spec, init),
+                    Err(e) => Node::Error(spec, e),
+                }
+            }
+            Expr { kind: ExprKind::Apply { args, function }, id: _ }
+                if (function == "let" || function == "set")
+                    && args.len() == 2
+                    && args[0].is_fn()
+                    && args[1].is_lambda() =>
+            {
+                // a named function definition
+                let name = match &args[0].kind {
+                    ExprKind::Constant(Value::String(c)) => c.clone(),
+                    _ => unreachable!(),
+                };
+                match stdfn::Lambda::init(name.clone(), &args[1], scope.clone()) {
+                    Err(e) => Node::Error(spec, e),
+                    Ok(init) => {
+                        let local = function == "let";
+                        ctx.define_fn(local, &scope, name, Arc::clone(&init));
+                        Node::Lambda(spec, init)
+                    }
+                }
+            }
+            Expr { kind: ExprKind::Apply { args, function }, id }
+                if function == "do" && stdfn::needs_desugar(args) =>
+            {
+                // the expressions after an import are compiled by it
+                let args = stdfn::desugar_imports(args);
+                let kind = ExprKind::Apply { function: function.clone(), args };
+                Node::compile_int(ctx, Expr { id: *id, kind }, scope, top_id)
+            }
             Expr { kind: ExprKind::Apply { args, function }, id } => {
                 let scope = if function == "do" && id != &top_id {
                     scope.append(&format!("do{:?}", id))
@@ -337,7 +536,7 @@ impl<C: Ctx, E: Clone> Node<C, E> {
                         Node::compile_int(ctx, spec.clone(), scope.clone(), top_id)
                     })
                     .collect();
-                match ctx.functions.get(function).map(Arc::clone) {
+                match ctx.lookup_fn(&scope, function) {
                     None => {
                         let e = Value::Error(Chars::from(format!(
                             "unknown function {}",
@@ -363,6 +562,7 @@ impl<C: Ctx, E: Clone> Node<C, E> {
         let (id, res) = match self {
             Node::Error(spec, v) => (spec.id, Some(v.clone())),
             Node::Constant(spec, v) => (spec.id, Some(v.clone())),
+            Node::Lambda(spec, _) => (spec.id, None),
             Node::Apply { spec, function, .. } => (spec.id, function.current(ctx)),
         };
         if ctx.dbg_ctx.trace {
@@ -373,9 +573,23 @@ impl<C: Ctx, E: Clone> Node<C, E> {
         res
     }
 
+    /// Call `Apply::delete` on every function in the node, before
+    /// dropping it while the program keeps running.
+    pub fn delete(&mut self, ctx: &mut ExecCtx<C, E>) {
+        match self {
+            Node::Error(_, _) | Node::Constant(_, _) | Node::Lambda(_, _) => (),
+            Node::Apply { args, function, .. } => {
+                for n in args {
+                    n.delete(ctx)
+                }
+                function.delete(ctx)
+            }
+        }
+    }
+
     pub fn update(&mut self, ctx: &mut ExecCtx<C, E>, event: &Event<E>) -> Option<Value> {
         match self {
-            Node::Error(_, _) | Node::Constant(_, _) => None,
+            Node::Error(_, _) | Node::Constant(_, _) | Node::Lambda(_, _) => None,
             Node::Apply { spec, args, function } => {
                 let res = function.update(ctx, args, event);
                 if ctx.dbg_ctx.trace {
@@ -0,0 +1,129 @@
+use crate::storage::Tree;
+use anyhow::Result;
+use arcstr::ArcStr;
+use netidx::{
+    chars::Chars, pack::Pack, path::Path, protocol::resolver::UserInfo, subscriber::Value,
+};
+use netidx_derive::Pack;
+use serde_derive::{Deserialize, Serialize};
+use std::str;
+
+/// The kinds of change an acl controls
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    /// write data values
+    Data,
+    /// set formulas and on write handlers
+    Formula,
+    /// create and delete paths, sheets, and tables, lock and unlock,
+    /// add and remove roots, restore, and change acls
+    Structure,
+}
+
+/// Who may change a subtree. Each list holds user and group names,
+/// "*" matches everyone, including anonymous users. A path is
+/// governed by the acl set on it's closest parent (or itself), a path
+/// with no acl above it can be changed by anyone who can write to the
+/// container, except that only a container admin may set the first
+/// acl on it. Operations on a whole subtree must be allowed by every
+/// acl in the subtree.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Pack)]
+pub struct Acl {
+    pub data: Vec<Chars>,
+    pub formula: Vec<Chars>,
+    pub structure: Vec<Chars>,
+}
+
+/// return true if `user` is, or is a member of, one of `names`
+pub(super) fn is_member<S: AsRef<str>>(names: &[S], user: Option<&UserInfo>) -> bool {
+    names.iter().map(|n| n.as_ref()).any(|n| {
+        n == "*"
+            || user
+                .map(|u| {
+                    &*u.name == n
+                        || &*u.primary_group == n
+                        || u.groups.iter().any(|g| &**g == n)
+                })
+                .unwrap_or(false)
+    })
+}
+
+impl Acl {
+    fn names(&self, perm: Permission) -> &[Chars] {
+        match perm {
+            Permission::Data => &self.data,
+            Permission::Formula => &self.formula,
+            Permission::Structure => &self.structure,
+        }
+    }
+
+    /// return true if `user` has permission `perm`
+    pub fn allows(&self, perm: Permission, user: Option<&UserInfo>) -> bool {
+        is_member(self.names(perm), user)
+    }
+
+    /// return true if every name `self` gives `perm` is also given
+    /// `other_perm` by `other`. Group membership isn't known here, so
+    /// names are compared literally, which may deny a name that a
+    /// group in `other` would allow, but never the reverse.
+    pub(super) fn within(
+        &self,
+        perm: Permission,
+        other: &Acl,
+        other_perm: Permission,
+    ) -> bool {
+        let other = other.names(other_perm);
+        other.iter().any(|n| &**n == "*")
+            || self.names(perm).iter().all(|n| other.contains(n))
+    }
+
+    pub(super) fn to_value(&self, path: &Path) -> Value {
+        let names = |l: &Vec<Chars>| {
+            Value::from(l.iter().map(|n| Value::from(n.clone())).collect::<Vec<_>>())
+        };
+        Value::from(vec![
+            Value::from(path.clone()),
+            names(&self.data),
+            names(&self.formula),
+            names(&self.structure),
+        ])
+    }
+}
+
+/// the acls set strictly below `path`, and the paths they are set on
+pub(super) fn below(
+    acls: &Tree,
+    path: &Path,
+) -> impl Iterator<Item = Result<(Path, Acl)>> + 'static {
+    let path = path.clone();
+    acls.scan_prefix(path.as_bytes()).filter_map(move |r| {
+        let acl = || -> Result<Option<(Path, Acl)>> {
+            let (k, v) = r?;
+            let k = str::from_utf8(&k)?;
+            if k == &*path || !Path::is_parent(&path, k) {
+                Ok(None)
+            } else {
+                Ok(Some((Path::from(ArcStr::from(k)), Acl::decode(&mut &*v)?)))
+            }
+        };
+        acl().transpose()
+    })
+}
+
+/// find the acl governing `path`, and the path it is set on
+pub(super) fn lookup(acls: &Tree, path: &Path) -> Result<Option<(Path, Acl)>> {
+    let mut iter = acls.range(..=path.as_bytes());
+    loop {
+        match iter.next_back() {
+            None => break Ok(None),
+            Some(r) => {
+                let (k, v) = r?;
+                let k = str::from_utf8(&k)?;
+                if Path::is_parent(k, &path) {
+                    let acl = Acl::decode(&mut &*v)?;
+                    break Ok(Some((Path::from(ArcStr::from(k)), acl)));
+                }
+            }
+        }
+    }
+}
@@ -1,33 +1,44 @@
-use super::Params;
+use super::{
+    acl::{self, Acl, Permission},
+    dump::{self, DumpFormat, DumpWriter},
+    history::{History, HistoryEntry, Writer},
+    import::{self, Import, ImportFormat},
+    schema::{self, ColumnSchema},
+    storage::{self, Batch, Batches, IVec, Iter, Storage, Tree},
+    ttl::{self, Expires, Expiry, Ttl},
+    Params,
+};
 use anyhow::{anyhow, bail, Result};
 use arcstr::ArcStr;
-use bytes::{Buf, BufMut};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use chrono::prelude::*;
 use futures::{
     channel::{
-        mpsc::{unbounded, UnboundedReceiver, UnboundedSender},
+        mpsc::{self, unbounded, UnboundedReceiver, UnboundedSender},
         oneshot,
     },
     prelude::*,
     select_biased,
 };
+use log::{error, warn};
 use netidx::{
     chars::Chars,
     pack::{Pack, PackError},
     path::Path,
     pool::{Pool, Pooled},
+    protocol::resolver::UserInfo,
     publisher::{Publisher, SendResult, UpdateBatch, Val},
     subscriber::Value,
     utils::{BatchItem, Batched},
 };
+use netidx_derive::Pack;
 use netidx_protocols::rpc::server::RpcReply;
 use parking_lot::Mutex;
-use sled;
 use std::{
     cmp::{max, min},
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, HashMap, HashSet},
     fmt::Write,
-    path::PathBuf,
-    str,
+    io, mem, str,
     sync::{
         atomic::{AtomicUsize, Ordering},
         Arc,
@@ -40,6 +51,7 @@ pub enum Sendable {
     Packed(Arc<Mutex<Value>>),
     Rpc(RpcReply),
     Write(SendResult),
+    Shared(Arc<SharedReply>),
 }
 
 impl Sendable {
@@ -60,12 +72,41 @@ impl Sendable {
             Sendable::Write(reply) => {
                 reply.send(v);
             }
+            Sendable::Shared(reply) => {
+                let mut inner = reply.0.lock();
+                match &inner.1 {
+                    Value::Error(_) => (),
+                    _ => {
+                        inner.1 = v;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// A reply shared by several operations. It is sent when the last
+/// operation holding it is done, and it is the first error any of
+/// them returned, or else the last value.
+pub struct SharedReply(Mutex<(Option<Sendable>, Value)>);
+
+impl SharedReply {
+    pub fn new(reply: Sendable) -> Arc<Self> {
+        Arc::new(SharedReply(Mutex::new((Some(reply), Value::Null))))
+    }
+}
+
+impl Drop for SharedReply {
+    fn drop(&mut self) {
+        let (reply, v) = mem::replace(self.0.get_mut(), (None, Value::Null));
+        if let Some(reply) = reply {
+            reply.send(v)
         }
     }
 }
 
 pub type Reply = Option<Sendable>;
-type Txns = Vec<(TxnOp, Reply)>;
+type Txns = Vec<(TxnOp, Writer, Reply)>;
 
 lazy_static! {
     static ref BUF: Pool<Vec<u8>> = Pool::new(8, 16384);
@@ -90,6 +131,9 @@ pub(super) struct Update {
     pub(super) unlocked: Pooled<Vec<Path>>,
     pub(super) added_roots: Pooled<Vec<Path>>,
     pub(super) removed_roots: Pooled<Vec<Path>>,
+    pub(super) schemas: Pooled<Vec<Path>>,
+    /// the effects to send to replicas, and who caused them
+    pub(super) replicate: Vec<(TxnOp, Writer)>,
 }
 
 impl Update {
@@ -102,6 +146,8 @@ impl Update {
             unlocked: PATHS.take(),
             added_roots: PATHS.take(),
             removed_roots: PATHS.take(),
+            schemas: PATHS.take(),
+            replicate: Vec::new(),
         }
     }
 
@@ -113,6 +159,8 @@ impl Update {
         self.unlocked.extend(other.unlocked.drain(..));
         self.added_roots.extend(other.added_roots.drain(..));
         self.removed_roots.extend(other.removed_roots.drain(..));
+        self.schemas.extend(other.schemas.drain(..));
+        self.replicate.extend(other.replicate.drain(..));
     }
 
     fn merge(mut self, other: Update) -> Update {
@@ -121,6 +169,7 @@ impl Update {
     }
 }
 
+#[derive(Clone, Copy)]
 pub enum DatumKind {
     Data,
     Formula,
@@ -139,7 +188,7 @@ impl DatumKind {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Datum {
     Data(Value),
     Formula(Value, Value),
@@ -188,18 +237,38 @@ impl Pack for Datum {
     }
 }
 
-fn lookup_value<P: AsRef<[u8]>>(tree: &sled::Tree, path: P) -> Result<Option<Datum>> {
+fn lookup_value<P: AsRef<[u8]>>(tree: &Tree, path: P) -> Result<Option<Datum>> {
     match tree.get(path.as_ref())? {
         None => Ok(None),
         Some(v) => Ok(Some(Datum::decode(&mut &*v)?)),
     }
 }
 
-fn iter_paths(tree: &sled::Tree) -> impl Iterator<Item = Result<Path>> + 'static {
+fn iter_paths(tree: &Tree) -> impl Iterator<Item = Result<Path>> + 'static {
     tree.iter().keys().map(|res| Ok(Path::from(ArcStr::from(str::from_utf8(&res?)?))))
 }
 
-enum TxnOp {
+/// An operation in an atomic transaction
+#[derive(Debug, Clone, Pack)]
+pub enum AtomicOp {
+    SetData(Path, Value),
+    /// set the formula and the on write formula
+    SetFormula(Path, Value, Value),
+    Remove(Path),
+}
+
+impl AtomicOp {
+    pub fn path(&self) -> &Path {
+        match self {
+            AtomicOp::SetData(p, _) => p,
+            AtomicOp::SetFormula(p, _, _) => p,
+            AtomicOp::Remove(p) => p,
+        }
+    }
+}
+
+#[derive(Pack)]
+pub(super) enum TxnOp {
     Remove(Path),
     SetData(bool, Path, Value),
     SetFormula(Path, Value),
@@ -255,7 +324,31 @@ enum TxnOp {
     AddRoot(Path),
     DelRoot(Path),
     RemoveSubtree(Path),
-    Flush(oneshot::Sender<()>),
+    RestoreSubtree(Path, DateTime<Utc>),
+    Atomic {
+        preconditions: Vec<(Path, Value)>,
+        ops: Vec<AtomicOp>,
+    },
+    SetDataIf {
+        path: Path,
+        value: Value,
+        version: u64,
+    },
+    SetVersions {
+        clear: bool,
+        versions: Vec<(Path, u64)>,
+    },
+    SetAcl(Path, Acl),
+    RemoveAcl(Path),
+    SetTableSchema(Path, Vec<ColumnSchema>),
+    SetTtl(Path, Ttl),
+    RemoveTtl(Path),
+    Expire {
+        path: Path,
+        version: u64,
+    },
+    SetDatum(Path, Datum),
+    Flush(#[pack(skip)] Option<oneshot::Sender<()>>),
 }
 
 impl TxnOp {
@@ -279,38 +372,137 @@ impl TxnOp {
             SetLocked(p) => p.clone(),
             SetUnlocked(p) => p.clone(),
             RemoveSubtree(p) => p.clone(),
+            RestoreSubtree(p, _) => p.clone(),
+            Atomic { .. } => Path::root(),
+            SetDataIf { path, .. } => path.clone(),
+            SetVersions { .. } => Path::root(),
+            SetAcl(p, _) => p.clone(),
+            RemoveAcl(p) => p.clone(),
+            SetTableSchema(p, _) => p.clone(),
+            SetTtl(p, _) => p.clone(),
+            RemoveTtl(p) => p.clone(),
+            Expire { path, .. } => path.clone(),
+            SetDatum(p, _) => p.clone(),
             AddRoot(p) => p.clone(),
             DelRoot(p) => p.clone(),
             Flush(_) => Path::root(),
         }
     }
+
+    /// A copy of the op if it is sent to replicas as is. These ops
+    /// only change metadata that isn't reflected in an `Update`. The
+    /// effects of every other op are sent instead of the op.
+    fn passthrough(&self) -> Option<TxnOp> {
+        use TxnOp::*;
+        match self {
+            SetAcl(p, acl) => Some(SetAcl(p.clone(), acl.clone())),
+            RemoveAcl(p) => Some(RemoveAcl(p.clone())),
+            SetTtl(p, ttl) => Some(SetTtl(p.clone(), *ttl)),
+            RemoveTtl(p) => Some(RemoveTtl(p.clone())),
+            SetVersions { clear, versions } => {
+                Some(SetVersions { clear: *clear, versions: versions.clone() })
+            }
+            _ => None,
+        }
+    }
 }
 
-pub struct Txn(Pooled<Txns>);
+pub struct Txn {
+    ops: Pooled<Txns>,
+    user: Writer,
+    timestamp: Option<DateTime<Utc>>,
+}
 
 impl Txn {
     pub fn new() -> Self {
-        Self(TXNS.take())
+        Self { ops: TXNS.take(), user: None, timestamp: None }
     }
 
     pub fn dirty(&self) -> bool {
-        self.0.len() > 0
+        self.ops.len() > 0
+    }
+
+    /// Operations added after this call will be attributed to `user`
+    /// in the history, until it is called again. Set it back to
+    /// `None` when the user's operations are done, so that operations
+    /// the container adds on it's own behalf aren't attributed to
+    /// them.
+    pub fn set_user(&mut self, user: Option<UserInfo>) {
+        self.user = user.map(Arc::new);
+    }
+
+    /// commit the transaction as of `timestamp` instead of now
+    #[cfg(test)]
+    pub(super) fn set_timestamp(&mut self, timestamp: DateTime<Utc>) {
+        self.timestamp = Some(timestamp);
+    }
+
+    fn push(&mut self, op: TxnOp, reply: Reply) {
+        self.ops.push((op, self.user.clone(), reply))
+    }
+
+    /// fail every operation in the transaction without committing it
+    pub(super) fn reject(mut self, msg: &'static str) {
+        for (_, _, reply) in self.ops.drain(..) {
+            send_reply(reply, Err(anyhow!(msg)))
+        }
+    }
+
+    // replicas are sent the effects of a committed transaction, not
+    // it's ops, so they end up in the same state as the primary even
+    // when the ops depend on things only the primary has, e.g. it's
+    // history.
+    fn encode_for_replicas(
+        timestamp: DateTime<Utc>,
+        effects: &[(TxnOp, Writer)],
+    ) -> Result<Option<Bytes>> {
+        if effects.is_empty() {
+            return Ok(None);
+        }
+        let mut buf = BytesMut::new();
+        timestamp.encode(&mut buf)?;
+        for (op, user) in effects {
+            op.encode(&mut buf)?;
+            match user {
+                None => buf.put_u8(0),
+                Some(user) => {
+                    buf.put_u8(1);
+                    user.encode(&mut buf)?
+                }
+            }
+        }
+        Ok(Some(buf.freeze()))
+    }
+
+    pub(super) fn decode_from_primary(mut buf: Bytes) -> Result<Self> {
+        let mut txn = Txn::new();
+        txn.timestamp = Some(<DateTime<Utc> as Pack>::decode(&mut buf)?);
+        while buf.has_remaining() {
+            let op = TxnOp::decode(&mut buf)?;
+            let user = match <u8 as Pack>::decode(&mut buf)? {
+                0 => None,
+                1 => Some(Arc::new(UserInfo::decode(&mut buf)?)),
+                _ => bail!("invalid replicated user"),
+            };
+            txn.ops.push((op, user, None))
+        }
+        Ok(txn)
     }
 
     pub fn remove(&mut self, path: Path, reply: Reply) {
-        self.0.push((TxnOp::Remove(path), reply))
+        self.push(TxnOp::Remove(path), reply)
     }
 
     pub fn set_data(&mut self, update: bool, path: Path, value: Value, reply: Reply) {
-        self.0.push((TxnOp::SetData(update, path, value), reply))
+        self.push(TxnOp::SetData(update, path, value), reply)
     }
 
     pub fn set_formula(&mut self, path: Path, value: Value, reply: Reply) {
-        self.0.push((TxnOp::SetFormula(path, value), reply))
+        self.push(TxnOp::SetFormula(path, value), reply)
     }
 
     pub fn set_on_write(&mut self, path: Path, value: Value, reply: Reply) {
-        self.0.push((TxnOp::SetOnWrite(path, value), reply))
+        self.push(TxnOp::SetOnWrite(path, value), reply)
     }
 
     pub fn create_sheet(
@@ -323,26 +515,26 @@ impl Txn {
         lock: bool,
         reply: Reply,
     ) {
-        self.0.push((
+        self.push(
             TxnOp::CreateSheet { base, rows, cols, max_rows, max_columns, lock },
             reply,
-        ))
+        )
     }
 
     pub fn add_sheet_columns(&mut self, base: Path, cols: usize, reply: Reply) {
-        self.0.push((TxnOp::AddSheetColumns { base, cols }, reply))
+        self.push(TxnOp::AddSheetColumns { base, cols }, reply)
     }
 
     pub fn add_sheet_rows(&mut self, base: Path, rows: usize, reply: Reply) {
-        self.0.push((TxnOp::AddSheetRows { base, rows }, reply))
+        self.push(TxnOp::AddSheetRows { base, rows }, reply)
     }
 
     pub fn del_sheet_columns(&mut self, base: Path, cols: usize, reply: Reply) {
-        self.0.push((TxnOp::DelSheetColumns { base, cols }, reply))
+        self.push(TxnOp::DelSheetColumns { base, cols }, reply)
     }
 
     pub fn del_sheet_rows(&mut self, base: Path, rows: usize, reply: Reply) {
-        self.0.push((TxnOp::DelSheetRows { base, rows }, reply))
+        self.push(TxnOp::DelSheetRows { base, rows }, reply)
     }
 
     pub fn create_table(
@@ -353,47 +545,327 @@ impl Txn {
         lock: bool,
         reply: Reply,
     ) {
-        self.0.push((TxnOp::CreateTable { base, rows, cols, lock }, reply))
+        self.push(TxnOp::CreateTable { base, rows, cols, lock }, reply)
+    }
+
+    /// Set the column schemas of the table at `base`, an empty
+    /// schema removes it. Only later writes are checked against the
+    /// schema, existing values are left alone.
+    pub fn set_table_schema(
+        &mut self,
+        base: Path,
+        schema: Vec<ColumnSchema>,
+        reply: Reply,
+    ) {
+        self.push(TxnOp::SetTableSchema(base, schema), reply)
     }
 
     pub fn add_table_columns(&mut self, base: Path, cols: Vec<Chars>, reply: Reply) {
-        self.0.push((TxnOp::AddTableColumns { base, cols }, reply))
+        self.push(TxnOp::AddTableColumns { base, cols }, reply)
     }
 
     pub fn add_table_rows(&mut self, base: Path, rows: Vec<Chars>, reply: Reply) {
-        self.0.push((TxnOp::AddTableRows { base, rows }, reply))
+        self.push(TxnOp::AddTableRows { base, rows }, reply)
     }
 
     pub fn del_table_columns(&mut self, base: Path, cols: Vec<Chars>, reply: Reply) {
-        self.0.push((TxnOp::DelTableColumns { base, cols }, reply))
+        self.push(TxnOp::DelTableColumns { base, cols }, reply)
     }
 
     pub fn del_table_rows(&mut self, base: Path, rows: Vec<Chars>, reply: Reply) {
-        self.0.push((TxnOp::DelTableRows { base, rows }, reply))
+        self.push(TxnOp::DelTableRows { base, rows }, reply)
     }
 
     pub fn set_locked(&mut self, path: Path, reply: Reply) {
-        self.0.push((TxnOp::SetLocked(path), reply))
+        self.push(TxnOp::SetLocked(path), reply)
     }
 
     pub fn set_unlocked(&mut self, path: Path, reply: Reply) {
-        self.0.push((TxnOp::SetUnlocked(path), reply))
+        self.push(TxnOp::SetUnlocked(path), reply)
+    }
+
+    /// Set the acl governing the subtree rooted at `path`, replacing
+    /// any acl already set on `path`.
+    pub fn set_acl(&mut self, path: Path, acl: Acl, reply: Reply) {
+        self.push(TxnOp::SetAcl(path, acl), reply)
+    }
+
+    /// Remove the acl set on `path`, the subtree will be governed
+    /// by the acl of it's closest parent, if any.
+    pub fn remove_acl(&mut self, path: Path, reply: Reply) {
+        self.push(TxnOp::RemoveAcl(path), reply)
+    }
+
+    /// Set the ttl governing the subtree rooted at `path`, replacing
+    /// any ttl already set on `path`. Paths already in the subtree
+    /// expire if they aren't written for the duration of the ttl.
+    pub fn set_ttl(&mut self, path: Path, ttl: Ttl, reply: Reply) {
+        self.push(TxnOp::SetTtl(path, ttl), reply)
+    }
+
+    /// Remove the ttl set on `path`, the subtree will be governed by
+    /// the ttl of it's closest parent, if any.
+    pub fn remove_ttl(&mut self, path: Path, reply: Reply) {
+        self.push(TxnOp::RemoveTtl(path), reply)
+    }
+
+    /// Expire `path`, unless it has changed since `version`
+    pub(super) fn expire(&mut self, path: Path, version: u64) {
+        self.push(TxnOp::Expire { path, version }, None)
     }
 
     pub fn add_root(&mut self, path: Path, reply: Reply) {
-        self.0.push((TxnOp::AddRoot(path), reply));
+        self.push(TxnOp::AddRoot(path), reply);
     }
 
     pub fn del_root(&mut self, path: Path, reply: Reply) {
-        self.0.push((TxnOp::DelRoot(path), reply));
+        self.push(TxnOp::DelRoot(path), reply);
     }
 
     pub fn remove_subtree(&mut self, path: Path, reply: Reply) {
-        self.0.push((TxnOp::RemoveSubtree(path), reply))
+        self.push(TxnOp::RemoveSubtree(path), reply)
+    }
+
+    /// Restore the subtree rooted at `path` to it's state at
+    /// `timestamp`. This requires history to be enabled, and paths
+    /// with no history are left alone.
+    pub fn restore_subtree(
+        &mut self,
+        path: Path,
+        timestamp: DateTime<Utc>,
+        reply: Reply,
+    ) {
+        self.push(TxnOp::RestoreSubtree(path, timestamp), reply)
+    }
+
+    /// Apply `ops` all or nothing, if the current value of every path
+    /// in `preconditions` is equal to the specified value. A path
+    /// with no data has the value null, and the `.formula` and
+    /// `.on-write` children of a formula path have the value of it's
+    /// formula and on write formula. The value of a formula is
+    /// computed outside the db, so a precondition on it always
+    /// fails, the container checks those itself. If a precondition
+    /// fails then the reply is an error naming it, and nothing is
+    /// applied. The versions, expiry and history of the changed paths
+    /// are updated with the ops, all or nothing.
+    pub fn atomic(
+        &mut self,
+        preconditions: Vec<(Path, Value)>,
+        ops: Vec<AtomicOp>,
+        reply: Reply,
+    ) {
+        self.push(TxnOp::Atomic { preconditions, ops }, reply)
+    }
+
+    /// Set the data of `path` only if it's version is `version`,
+    /// otherwise the reply is an error. A path that was never
+    /// written is at version 0.
+    pub fn set_data_if(&mut self, path: Path, value: Value, version: u64, reply: Reply) {
+        self.push(TxnOp::SetDataIf { path, value, version }, reply)
+    }
+
+    /// Set the versions of paths, replicas use this to take the
+    /// versions of the primary. If `clear` is true then all the
+    /// existing versions are removed first.
+    pub(super) fn set_versions(&mut self, clear: bool, versions: Vec<(Path, u64)>) {
+        self.push(TxnOp::SetVersions { clear, versions }, None)
+    }
+}
+
+fn get_version(versions: &Tree, path: &Path) -> Result<u64> {
+    match versions.get(path.as_bytes())? {
+        None => Ok(0),
+        Some(v) => Ok(u64::decode(&mut &*v)?),
+    }
+}
+
+/// increment the version of every path changed by `up`
+fn bump_versions(versions: &Tree, batches: &mut Batches, up: &Update) -> Result<()> {
+    let mut paths = HashSet::new();
+    let changed = up.data.iter().chain(up.formula.iter()).chain(up.on_write.iter());
+    let mut val = BUF.take();
+    for (path, _) in changed {
+        if paths.insert(path) {
+            val.clear();
+            (get_version(versions, path)? + 1).encode(&mut *val)?;
+            batches.insert(versions, path.as_bytes(), &**val);
+        }
+    }
+    Ok(())
+}
+
+fn set_versions(versions: &Tree, clear: bool, set: Vec<(Path, u64)>) -> Result<()> {
+    if clear {
+        versions.clear()?;
+    }
+    let mut batch = Batch::default();
+    let mut val = BUF.take();
+    for (path, version) in set {
+        val.clear();
+        version.encode(&mut *val)?;
+        batch.insert(path.as_bytes(), &**val);
+    }
+    versions.apply_batch(batch)
+}
+
+/// stage the expiry of every path changed in `up` that is governed
+/// by a ttl, and unschedule the rest
+fn refresh_expiry(
+    data: &Tree,
+    ttls: &Tree,
+    expires: &Expires,
+    batches: &mut Batches,
+    up: &Update,
+    now: DateTime<Utc>,
+) -> Result<()> {
+    if ttls.is_empty()? && expires.is_empty()? {
+        return Ok(());
+    }
+    let mut paths = HashSet::new();
+    for (path, _) in up.data.iter().chain(up.formula.iter()) {
+        if paths.insert(path) {
+            let datum = match batches.get(data, path.as_bytes())? {
+                None => None,
+                Some(v) => Some(Datum::decode(&mut &*v)?),
+            };
+            let live = match datum {
+                None | Some(Datum::Deleted) | Some(Datum::Data(Value::Null)) => false,
+                Some(Datum::Data(_)) | Some(Datum::Formula(_, _)) => true,
+            };
+            match ttl::lookup(ttls, path)? {
+                Some((_, ttl)) if live => {
+                    let deadline = now + chrono::Duration::from_std(ttl.duration)?;
+                    expires.stage_schedule(batches, path, deadline)?
+                }
+                Some(_) | None => expires.stage_unschedule(batches, path)?,
+            }
+        }
+    }
+    Ok(())
+}
+
+fn set_ttl(
+    data: &Tree,
+    ttls: &Tree,
+    expires: &Expires,
+    path: Path,
+    ttl: Ttl,
+    now: DateTime<Utc>,
+) -> Result<()> {
+    let mut val = BUF.take();
+    ttl.encode(&mut *val)?;
+    ttls.insert(path.as_bytes(), &**val)?;
+    let deadline = now + chrono::Duration::from_std(ttl.duration)?;
+    for r in data.scan_prefix(path.as_bytes()) {
+        let (k, v) = r?;
+        let k = str::from_utf8(&k)?;
+        if !Path::is_parent(&path, k) {
+            continue;
+        }
+        let live = match Datum::decode(&mut &*v)? {
+            Datum::Deleted | Datum::Data(Value::Null) => false,
+            Datum::Data(_) | Datum::Formula(_, _) => true,
+        };
+        if live {
+            match ttl::lookup(ttls, &Path::from(ArcStr::from(k)))? {
+                Some((governor, _)) if governor == path => {
+                    expires.schedule(k, deadline)?
+                }
+                Some(_) | None => (),
+            }
+        }
+    }
+    Ok(())
+}
+
+fn remove_ttl(
+    ttls: &Tree,
+    expires: &Expires,
+    path: Path,
+    now: DateTime<Utc>,
+) -> Result<()> {
+    ttls.remove(path.as_bytes())?;
+    for k in expires.subtree(&path)? {
+        match ttl::lookup(ttls, &k)? {
+            Some((_, ttl)) => {
+                expires.schedule(&k, now + chrono::Duration::from_std(ttl.duration)?)?
+            }
+            None => expires.unschedule(&k)?,
+        }
+    }
+    Ok(())
+}
+
+fn expire(
+    data: &Tree,
+    versions: &Tree,
+    ttls: &Tree,
+    expires: &Expires,
+    pending: &mut Update,
+    path: Path,
+    version: u64,
+) -> Result<()> {
+    if get_version(versions, &path)? != version {
+        // it was written after the expiry was scheduled
+        return Ok(());
+    }
+    match ttl::lookup(ttls, &path)? {
+        None => expires.unschedule(&path),
+        Some((_, Ttl { expiry: Expiry::Delete, .. })) => remove(data, pending, path),
+        Some((_, Ttl { expiry: Expiry::Null, .. })) => {
+            set_data(data, pending, true, path, Value::Null)
+        }
+    }
+}
+
+fn set_data_if(
+    data: &Tree,
+    versions: &Tree,
+    schemas: &Tree,
+    pending: &mut Update,
+    path: Path,
+    value: Value,
+    version: u64,
+) -> Result<()> {
+    let current = get_version(versions, &path)?;
+    if current != version {
+        bail!("{} has been modified, it is at version {} not {}", path, current, version)
+    }
+    set_checked_data(data, schemas, pending, true, path, value)
+}
+
+/// set data, if `path` is a table cell check the value against the
+/// table schema first
+fn set_checked_data(
+    data: &Tree,
+    schemas: &Tree,
+    pending: &mut Update,
+    update: bool,
+    path: Path,
+    value: Value,
+) -> Result<()> {
+    let value = schema::check(schemas, &path, value)?;
+    set_data(data, pending, update, path, value)
+}
+
+fn set_table_schema(
+    schemas: &Tree,
+    pending: &mut Update,
+    base: Path,
+    schema: Vec<ColumnSchema>,
+) -> Result<()> {
+    if schema.is_empty() {
+        schemas.remove(base.as_bytes())?;
+    } else {
+        let mut val = BUF.take();
+        schema.encode(&mut *val)?;
+        schemas.insert(base.as_bytes(), &**val)?;
     }
+    pending.schemas.push(base);
+    Ok(())
 }
 
-fn remove(data: &sled::Tree, pending: &mut Update, path: Path) -> Result<()> {
+fn remove(data: &Tree, pending: &mut Update, path: Path) -> Result<()> {
     let key = path.as_bytes();
     let mut val = BUF.take();
     Datum::Deleted.encode(&mut *val)?;
@@ -411,7 +883,7 @@ fn remove(data: &sled::Tree, pending: &mut Update, path: Path) -> Result<()> {
 }
 
 fn set_data(
-    data: &sled::Tree,
+    data: &Tree,
     pending: &mut Update,
     update: bool,
     path: Path,
@@ -437,7 +909,7 @@ fn set_data(
 }
 
 fn set_formula(
-    data: &sled::Tree,
+    data: &Tree,
     pending: &mut Update,
     path: Path,
     value: Value,
@@ -471,7 +943,7 @@ fn set_formula(
 }
 
 fn set_on_write(
-    data: &sled::Tree,
+    data: &Tree,
     pending: &mut Update,
     path: Path,
     value: Value,
@@ -514,8 +986,8 @@ fn merge_err(e0: Result<()>, e1: Result<()>) -> Result<()> {
 }
 
 fn create_sheet(
-    data: &sled::Tree,
-    locked: &sled::Tree,
+    data: &Tree,
+    locked: &Tree,
     pending: &mut Update,
     base: Path,
     rows: usize,
@@ -568,7 +1040,7 @@ struct SheetDescr {
 }
 
 impl SheetDescr {
-    fn new(data: &sled::Tree, base: &Path) -> Result<Self> {
+    fn new(data: &Tree, base: &Path) -> Result<Self> {
         let base_levels = Path::levels(base);
         let mut rows = PATHS.take();
         let mut max_col = 0;
@@ -610,7 +1082,7 @@ impl SheetDescr {
 }
 
 fn add_sheet_columns(
-    data: &sled::Tree,
+    data: &Tree,
     pending: &mut Update,
     base: Path,
     cols: usize,
@@ -656,7 +1128,7 @@ fn add_sheet_columns(
 }
 
 fn del_sheet_columns(
-    data: &sled::Tree,
+    data: &Tree,
     pending: &mut Update,
     base: Path,
     cols: usize,
@@ -691,7 +1163,7 @@ fn del_sheet_columns(
 }
 
 fn add_sheet_rows(
-    data: &sled::Tree,
+    data: &Tree,
     pending: &mut Update,
     base: Path,
     rows: usize,
@@ -771,7 +1243,7 @@ fn add_sheet_rows(
 }
 
 fn del_sheet_rows(
-    data: &sled::Tree,
+    data: &Tree,
     pending: &mut Update,
     base: Path,
     rows: usize,
@@ -799,8 +1271,9 @@ fn del_sheet_rows(
 }
 
 fn create_table(
-    data: &sled::Tree,
-    locked: &sled::Tree,
+    data: &Tree,
+    locked: &Tree,
+    schemas: &Tree,
     pending: &mut Update,
     base: Path,
     rows: Vec<Chars>,
@@ -812,6 +1285,7 @@ fn create_table(
         rows.into_iter().map(|c| Path::escape(&c).into_owned()).collect();
     let cols: Vec<String> =
         cols.into_iter().map(|c| Path::escape(&c).into_owned()).collect();
+    let schema = schema::lookup(schemas, &base)?;
     let (up, res) = rows
         .par_iter()
         .map(|row| cols.par_iter().map(move |col| (row, col)))
@@ -824,7 +1298,8 @@ fn create_table(
                 let path = base.append(buf.as_str());
                 let res = match data.contains_key(path.as_bytes()) {
                     Ok(false) => {
-                        let r = set_data(data, &mut pending, true, path, Value::Null);
+                        let v = schema::default(&schema, col);
+                        let r = set_data(data, &mut pending, true, path, v);
                         merge_err(r, res)
                     }
                     Ok(true) => res,
@@ -845,7 +1320,7 @@ fn create_table(
     res
 }
 
-fn table_rows(data: &sled::Tree, base: &Path) -> Result<Pooled<Vec<Path>>> {
+fn table_rows(data: &Tree, base: &Path) -> Result<Pooled<Vec<Path>>> {
     let base_levels = Path::levels(&base);
     let mut paths = PATHS.take();
     for r in data.scan_prefix(base.as_bytes()).keys() {
@@ -873,7 +1348,8 @@ fn table_rows(data: &sled::Tree, base: &Path) -> Result<Pooled<Vec<Path>>> {
 }
 
 fn add_table_columns(
-    data: &sled::Tree,
+    data: &Tree,
+    schemas: &Tree,
     pending: &mut Update,
     base: Path,
     cols: Vec<Chars>,
@@ -881,6 +1357,7 @@ fn add_table_columns(
     use rayon::prelude::*;
     let cols: Vec<String> =
         cols.into_iter().map(|c| Path::escape(&c).into_owned()).collect();
+    let schema = schema::lookup(schemas, &base)?;
     let (up, res) = table_rows(data, &base)?
         .par_drain(..)
         .fold(
@@ -890,7 +1367,8 @@ fn add_table_columns(
                     let path = row.append(col);
                     res = match data.contains_key(path.as_bytes()) {
                         Ok(false) => {
-                            let r = set_data(data, &mut pending, true, path, Value::Null);
+                            let v = schema::default(&schema, col);
+                            let r = set_data(data, &mut pending, true, path, v);
                             merge_err(r, res)
                         }
                         Ok(true) => res,
@@ -909,7 +1387,7 @@ fn add_table_columns(
 }
 
 fn del_table_columns(
-    data: &sled::Tree,
+    data: &Tree,
     pending: &mut Update,
     base: Path,
     cols: Vec<Chars>,
@@ -938,7 +1416,8 @@ fn del_table_columns(
 }
 
 fn add_table_rows(
-    data: &sled::Tree,
+    data: &Tree,
+    schemas: &Tree,
     pending: &mut Update,
     base: Path,
     rows: Vec<Chars>,
@@ -962,6 +1441,7 @@ fn add_table_rows(
         .into_iter()
         .filter_map(|k| str::from_utf8(&k).ok().map(String::from))
         .collect();
+    let schema = schema::lookup(schemas, &base)?;
     let (up, res) = rows
         .into_par_iter()
         .fold(
@@ -973,7 +1453,8 @@ fn add_table_rows(
                     let path = base.append(&buf);
                     res = match data.contains_key(path.as_bytes()) {
                         Ok(false) => {
-                            let r = set_data(data, &mut pending, true, path, Value::Null);
+                            let v = schema::default(&schema, col);
+                            let r = set_data(data, &mut pending, true, path, v);
                             merge_err(r, res)
                         }
                         Ok(true) => res,
@@ -993,7 +1474,7 @@ fn add_table_rows(
 }
 
 fn del_table_rows(
-    data: &sled::Tree,
+    data: &Tree,
     pending: &mut Update,
     base: Path,
     rows: Vec<Chars>,
@@ -1019,7 +1500,7 @@ fn del_table_rows(
     res
 }
 
-fn is_locked(locked: &sled::Tree, path: &Path, parent_only: bool) -> Result<bool> {
+fn is_locked(locked: &Tree, path: &Path, parent_only: bool) -> Result<bool> {
     let mut iter = if parent_only {
         locked.range(..path.as_bytes())
     } else {
@@ -1039,7 +1520,7 @@ fn is_locked(locked: &sled::Tree, path: &Path, parent_only: bool) -> Result<bool
     }
 }
 
-fn set_locked(locked: &sled::Tree, pending: &mut Update, path: Path) -> Result<()> {
+fn set_locked(locked: &Tree, pending: &mut Update, path: Path) -> Result<()> {
     if is_locked(locked, &path, true)? {
         locked.remove(path.as_bytes())?;
     } else {
@@ -1049,7 +1530,7 @@ fn set_locked(locked: &sled::Tree, pending: &mut Update, path: Path) -> Result<(
     Ok(())
 }
 
-fn set_unlocked(locked: &sled::Tree, pending: &mut Update, path: Path) -> Result<()> {
+fn set_unlocked(locked: &Tree, pending: &mut Update, path: Path) -> Result<()> {
     if !is_locked(locked, &path, true)? {
         locked.remove(path.as_bytes())?;
     } else {
@@ -1059,7 +1540,19 @@ fn set_unlocked(locked: &sled::Tree, pending: &mut Update, path: Path) -> Result
     Ok(())
 }
 
-fn remove_subtree(data: &sled::Tree, pending: &mut Update, path: Path) -> Result<()> {
+fn set_acl(acls: &Tree, path: Path, acl: Acl) -> Result<()> {
+    let mut val = BUF.take();
+    acl.encode(&mut *val)?;
+    acls.insert(path.as_bytes(), &**val)?;
+    Ok(())
+}
+
+fn remove_acl(acls: &Tree, path: Path) -> Result<()> {
+    acls.remove(path.as_bytes())?;
+    Ok(())
+}
+
+fn remove_subtree(data: &Tree, pending: &mut Update, path: Path) -> Result<()> {
     use rayon::prelude::*;
     let mut paths = PATHS.take();
     for res in data.scan_prefix(path.as_ref()).keys() {
@@ -1086,7 +1579,7 @@ fn remove_subtree(data: &sled::Tree, pending: &mut Update, path: Path) -> Result
     res
 }
 
-fn add_root(roots: &sled::Tree, pending: &mut Update, path: Path) -> Result<()> {
+fn add_root(roots: &Tree, pending: &mut Update, path: Path) -> Result<()> {
     let key = path.as_bytes();
     if let Some(r) = roots.range(..key).next_back() {
         let (prev, _) = r?;
@@ -1096,16 +1589,19 @@ fn add_root(roots: &sled::Tree, pending: &mut Update, path: Path) -> Result<()>
         }
     }
     if !roots.contains_key(key)? {
-        roots.insert(key, &[])?;
+        roots.insert(key, b"")?;
         pending.added_roots.push(path);
     }
     Ok(())
 }
 
 fn del_root(
-    data: &sled::Tree,
-    roots: &sled::Tree,
-    locked: &sled::Tree,
+    data: &Tree,
+    roots: &Tree,
+    locked: &Tree,
+    acls: &Tree,
+    schemas: &Tree,
+    ttls: &Tree,
     pending: &mut Update,
     path: Path,
 ) -> Result<()> {
@@ -1143,10 +1639,261 @@ fn del_root(
                 pending.unlocked.push(Path::from(ArcStr::from(k)));
             }
         }
+        for r in acls.scan_prefix(key).keys() {
+            let k = r?;
+            let k = str::from_utf8(&k)?;
+            if Path::is_parent(&path, k) {
+                acls.remove(&k)?;
+            }
+        }
+        for r in ttls.scan_prefix(key).keys() {
+            let k = r?;
+            let k = str::from_utf8(&k)?;
+            if Path::is_parent(&path, k) {
+                ttls.remove(&k)?;
+            }
+        }
+        for r in schemas.scan_prefix(key).keys() {
+            let k = r?;
+            let k = str::from_utf8(&k)?;
+            if Path::is_parent(&path, k) {
+                schemas.remove(&k)?;
+                pending.schemas.push(Path::from(ArcStr::from(k)));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn restore_subtree(
+    data: &Tree,
+    history: Option<&History>,
+    pending: &mut Update,
+    base: Path,
+    timestamp: DateTime<Utc>,
+) -> Result<()> {
+    let history = history.ok_or_else(|| anyhow!("history is not enabled"))?;
+    for (path, datum) in history.as_of(&base, timestamp)? {
+        let current = lookup_value(data, &*path)?.unwrap_or(Datum::Deleted);
+        if current != datum {
+            set_datum(data, pending, path, datum)?
+        }
     }
     Ok(())
 }
 
+/// set `path` to `datum` without checking the schema
+fn set_datum(data: &Tree, pending: &mut Update, path: Path, datum: Datum) -> Result<()> {
+    match datum {
+        Datum::Deleted => remove(data, pending, path),
+        Datum::Data(v) => set_data(data, pending, true, path, v),
+        Datum::Formula(f, w) => {
+            set_formula(data, pending, path.clone(), f)?;
+            set_on_write(data, pending, path, w)
+        }
+    }
+}
+
+/// The value a precondition on `path` is checked against. The
+/// `.formula` and `.on-write` children of a formula path are it's
+/// formula and on write formula. The value of a formula is computed
+/// outside the db, so it is None.
+fn precondition_value(data: &Tree, path: &Path) -> Result<Option<Value>> {
+    match (Path::dirname(path), Path::basename(path)) {
+        (Some(base), Some(name)) if name == ".formula" || name == ".on-write" => {
+            if let Some(Datum::Formula(f, w)) = lookup_value(data, base)? {
+                return Ok(Some(if name == ".formula" { f } else { w }));
+            }
+        }
+        (_, _) => (),
+    }
+    Ok(match lookup_value(data, &**path)? {
+        None | Some(Datum::Deleted) => Some(Value::Null),
+        Some(Datum::Data(v)) => Some(v),
+        Some(Datum::Formula(_, _)) => None,
+    })
+}
+
+/// stage the writes of `ops` in `batches` if every precondition holds
+fn atomic(
+    data: &Tree,
+    schemas: &Tree,
+    batches: &mut Batches,
+    pending: &mut Update,
+    preconditions: Vec<(Path, Value)>,
+    ops: Vec<AtomicOp>,
+) -> Result<()> {
+    for (path, expected) in preconditions.iter() {
+        match precondition_value(data, path)? {
+            None => bail!("precondition failed, {} is a formula", path),
+            Some(current) if &current != expected => {
+                bail!("precondition failed, {} is {}", path, current)
+            }
+            Some(_) => (),
+        }
+    }
+    let mut up = Update::new();
+    let mut val = BUF.take();
+    for op in ops {
+        let path = op.path().clone();
+        // later ops see the effects of earlier ones
+        let prev = match batches.get(data, path.as_bytes())? {
+            None => DatumKind::Deleted,
+            Some(v) => DatumKind::decode(&mut &*v),
+        };
+        val.clear();
+        match op {
+            AtomicOp::SetData(_, v) => {
+                let v = schema::check(schemas, &path, v)?;
+                Datum::Data(v.clone()).encode(&mut *val)?;
+                let up_kind = match prev {
+                    DatumKind::Data => UpdateKind::Updated(v),
+                    _ => UpdateKind::Inserted(v),
+                };
+                up.data.push((path.clone(), up_kind));
+            }
+            AtomicOp::SetFormula(_, f, w) => {
+                Datum::Formula(f.clone(), w.clone()).encode(&mut *val)?;
+                let (f, w) = match prev {
+                    DatumKind::Formula => {
+                        (UpdateKind::Updated(f), UpdateKind::Updated(w))
+                    }
+                    _ => (UpdateKind::Inserted(f), UpdateKind::Inserted(w)),
+                };
+                up.formula.push((path.clone(), f));
+                up.on_write.push((path.clone(), w));
+            }
+            AtomicOp::Remove(_) => {
+                Datum::Deleted.encode(&mut *val)?;
+                match prev {
+                    DatumKind::Data => up.data.push((path.clone(), UpdateKind::Deleted)),
+                    DatumKind::Formula => {
+                        up.formula.push((path.clone(), UpdateKind::Deleted));
+                        up.on_write.push((path.clone(), UpdateKind::Deleted));
+                    }
+                    DatumKind::Deleted | DatumKind::Invalid => (),
+                }
+            }
+        }
+        batches.insert(data, path.as_bytes(), &**val);
+    }
+    pending.merge_from(up);
+    Ok(())
+}
+
+/// The ops that reproduce the effects of `up` on a replica. Data and
+/// formulas are sent as their new state, so replicas don't need the
+/// state the change was computed from.
+fn replica_effects(
+    data: &Tree,
+    locked: &Tree,
+    versions: &Tree,
+    schemas: &Tree,
+    up: &Update,
+) -> Result<Vec<TxnOp>> {
+    let mut ops = Vec::new();
+    for path in up.added_roots.iter() {
+        ops.push(TxnOp::AddRoot(path.clone()))
+    }
+    for path in up.schemas.iter() {
+        let schema = match schemas.get(path.as_bytes())? {
+            None => vec![],
+            Some(v) => <Vec<ColumnSchema> as Pack>::decode(&mut &*v)?,
+        };
+        ops.push(TxnOp::SetTableSchema(path.clone(), schema))
+    }
+    let mut paths = HashSet::new();
+    for path in up.locked.iter().chain(up.unlocked.iter()) {
+        if paths.insert(path) {
+            if is_locked(locked, path, false)? {
+                ops.push(TxnOp::SetLocked(path.clone()))
+            } else {
+                ops.push(TxnOp::SetUnlocked(path.clone()))
+            }
+        }
+    }
+    let mut paths = HashSet::new();
+    let mut set = Vec::new();
+    let changed = up.data.iter().chain(up.formula.iter()).chain(up.on_write.iter());
+    for (path, _) in changed {
+        if paths.insert(path) {
+            let datum = lookup_value(data, &**path)?.unwrap_or(Datum::Deleted);
+            ops.push(TxnOp::SetDatum(path.clone(), datum));
+            set.push((path.clone(), get_version(versions, path)?));
+        }
+    }
+    if !set.is_empty() {
+        ops.push(TxnOp::SetVersions { clear: false, versions: set })
+    }
+    for path in up.removed_roots.iter() {
+        ops.push(TxnOp::DelRoot(path.clone()))
+    }
+    Ok(ops)
+}
+
+/// Run `f`, then update the versions, expiry and history of the paths
+/// it changed. When there are replicas `replicate` holds the op to
+/// pass through to them, if any, and the effects of `f` are queued
+/// for them in `pending`.
+fn with_history<F: FnOnce(&mut Update, &mut Batches) -> Result<()>>(
+    db: &Storage,
+    data: &Tree,
+    locked: &Tree,
+    versions: &Tree,
+    schemas: &Tree,
+    ttls: &Tree,
+    expires: &Expires,
+    history: Option<&History>,
+    writer: &Writer,
+    timestamp: DateTime<Utc>,
+    replicate: Option<Option<TxnOp>>,
+    pending: &mut Update,
+    f: F,
+) -> Result<()> {
+    let mut up = Update::new();
+    let mut batches = Batches::default();
+    let res = f(&mut up, &mut batches);
+    let effects = |up: &Update, batches: &mut Batches| -> Result<()> {
+        bump_versions(versions, batches, up)?;
+        refresh_expiry(data, ttls, expires, batches, up, timestamp)?;
+        if let Some(history) = history {
+            history.record(data, batches, writer, timestamp, up)?
+        }
+        Ok(())
+    };
+    let res = if batches.is_empty() {
+        // f wrote it's changes itself, so their effects are written
+        // even if it failed part way
+        if let Err(e) = effects(&up, &mut batches).and_then(|()| batches.apply(db)) {
+            error!("failed to update versions, expiry, or history {}", e)
+        }
+        res
+    } else {
+        // f staged it's changes, they are written with their effects
+        // all or nothing
+        let res = res
+            .and_then(|()| effects(&up, &mut batches))
+            .and_then(|()| batches.apply(db));
+        if res.is_err() {
+            up = Update::new();
+        }
+        res
+    };
+    if let Some(passthrough) = replicate {
+        if let (Some(op), Ok(())) = (passthrough, &res) {
+            up.replicate.push((op, writer.clone()))
+        }
+        match replica_effects(data, locked, versions, schemas, &up) {
+            Err(e) => error!("failed to compute effects for replicas {}", e),
+            Ok(ops) => {
+                up.replicate.extend(ops.into_iter().map(|op| (op, writer.clone())))
+            }
+        }
+    }
+    pending.merge_from(up);
+    res
+}
+
 fn send_reply(reply: Reply, r: Result<()>) {
     match (r, reply) {
         (Ok(()), Some(reply)) => {
@@ -1161,123 +1908,230 @@ fn send_reply(reply: Reply, r: Result<()>) {
 }
 
 fn commit_complex(
-    data: &sled::Tree,
-    locked: &sled::Tree,
-    roots: &sled::Tree,
+    db: &Storage,
+    data: &Tree,
+    locked: &Tree,
+    roots: &Tree,
+    acls: &Tree,
+    versions: &Tree,
+    schemas: &Tree,
+    ttls: &Tree,
+    expires: &Expires,
+    history: Option<&History>,
+    now: DateTime<Utc>,
+    replicate: bool,
     mut txn: Txn,
 ) -> Update {
     let mut pending = Update::new();
-    for (op, reply) in txn.0.drain(..) {
-        let r = match op {
-            TxnOp::CreateSheet { base, rows, cols, max_rows, max_columns, lock } => {
-                create_sheet(
-                    &data,
-                    &locked,
-                    &mut pending,
-                    base,
-                    rows,
-                    cols,
-                    max_rows,
-                    max_columns,
-                    lock,
-                )
-            }
-            TxnOp::AddSheetColumns { base, cols } => {
-                add_sheet_columns(&data, &mut pending, base, cols)
-            }
-            TxnOp::AddSheetRows { base, rows } => {
-                add_sheet_rows(&data, &mut pending, base, rows)
-            }
-            TxnOp::DelSheetColumns { base, cols } => {
-                del_sheet_columns(&data, &mut pending, base, cols)
-            }
-            TxnOp::DelSheetRows { base, rows } => {
-                del_sheet_rows(&data, &mut pending, base, rows)
-            }
-            TxnOp::CreateTable { base, rows, cols, lock } => {
-                create_table(&data, &locked, &mut pending, base, rows, cols, lock)
-            }
-            TxnOp::AddTableColumns { base, cols } => {
-                add_table_columns(&data, &mut pending, base, cols)
-            }
-            TxnOp::AddTableRows { base, rows } => {
-                add_table_rows(&data, &mut pending, base, rows)
-            }
-            TxnOp::DelTableColumns { base, cols } => {
-                del_table_columns(&data, &mut pending, base, cols)
-            }
-            TxnOp::DelTableRows { base, rows } => {
-                del_table_rows(&data, &mut pending, base, rows)
-            }
-            TxnOp::Remove(path) => remove(&data, &mut pending, path),
-            TxnOp::RemoveSubtree(path) => remove_subtree(&data, &mut pending, path),
-            TxnOp::SetData(update, path, value) => {
-                set_data(&data, &mut pending, update, path, value)
-            }
-            TxnOp::SetFormula(path, value) => {
-                set_formula(&data, &mut pending, path, value)
-            }
-            TxnOp::SetOnWrite(path, value) => {
-                set_on_write(&data, &mut pending, path, value)
-            }
-            TxnOp::SetLocked(path) => set_locked(&locked, &mut pending, path),
-            TxnOp::SetUnlocked(path) => set_unlocked(&locked, &mut pending, path),
-            TxnOp::AddRoot(path) => add_root(&roots, &mut pending, path),
-            TxnOp::DelRoot(path) => del_root(&data, &roots, &locked, &mut pending, path),
-            TxnOp::Flush(finished) => {
-                let _: Result<_, _> = data.flush();
-                let _: Result<_, _> = locked.flush();
-                let _: Result<_, _> = finished.send(());
-                Ok(())
-            }
-        };
+    for (op, writer, reply) in txn.ops.drain(..) {
+        let replicate = if replicate { Some(op.passthrough()) } else { None };
+        let r = with_history(
+            db,
+            data,
+            locked,
+            versions,
+            schemas,
+            ttls,
+            expires,
+            history,
+            &writer,
+            now,
+            replicate,
+            &mut pending,
+            |pending, batches| match op {
+                TxnOp::CreateSheet { base, rows, cols, max_rows, max_columns, lock } => {
+                    create_sheet(
+                        &data,
+                        &locked,
+                        pending,
+                        base,
+                        rows,
+                        cols,
+                        max_rows,
+                        max_columns,
+                        lock,
+                    )
+                }
+                TxnOp::AddSheetColumns { base, cols } => {
+                    add_sheet_columns(&data, pending, base, cols)
+                }
+                TxnOp::AddSheetRows { base, rows } => {
+                    add_sheet_rows(&data, pending, base, rows)
+                }
+                TxnOp::DelSheetColumns { base, cols } => {
+                    del_sheet_columns(&data, pending, base, cols)
+                }
+                TxnOp::DelSheetRows { base, rows } => {
+                    del_sheet_rows(&data, pending, base, rows)
+                }
+                TxnOp::CreateTable { base, rows, cols, lock } => {
+                    create_table(&data, &locked, schemas, pending, base, rows, cols, lock)
+                }
+                TxnOp::AddTableColumns { base, cols } => {
+                    add_table_columns(&data, schemas, pending, base, cols)
+                }
+                TxnOp::AddTableRows { base, rows } => {
+                    add_table_rows(&data, schemas, pending, base, rows)
+                }
+                TxnOp::DelTableColumns { base, cols } => {
+                    del_table_columns(&data, pending, base, cols)
+                }
+                TxnOp::DelTableRows { base, rows } => {
+                    del_table_rows(&data, pending, base, rows)
+                }
+                TxnOp::Remove(path) => remove(&data, pending, path),
+                TxnOp::RemoveSubtree(path) => remove_subtree(&data, pending, path),
+                TxnOp::RestoreSubtree(path, ts) => {
+                    restore_subtree(&data, history, pending, path, ts)
+                }
+                TxnOp::Atomic { preconditions, ops } => {
+                    atomic(&data, schemas, batches, pending, preconditions, ops)
+                }
+                TxnOp::SetDataIf { path, value, version } => {
+                    set_data_if(&data, versions, schemas, pending, path, value, version)
+                }
+                TxnOp::SetVersions { clear, versions: set } => {
+                    set_versions(versions, clear, set)
+                }
+                TxnOp::SetData(update, path, value) => {
+                    set_checked_data(&data, schemas, pending, update, path, value)
+                }
+                TxnOp::SetFormula(path, value) => {
+                    set_formula(&data, pending, path, value)
+                }
+                TxnOp::SetOnWrite(path, value) => {
+                    set_on_write(&data, pending, path, value)
+                }
+                TxnOp::SetLocked(path) => set_locked(&locked, pending, path),
+                TxnOp::SetUnlocked(path) => set_unlocked(&locked, pending, path),
+                TxnOp::AddRoot(path) => add_root(&roots, pending, path),
+                TxnOp::DelRoot(path) => {
+                    del_root(&data, &roots, &locked, acls, schemas, ttls, pending, path)
+                }
+                TxnOp::SetAcl(path, acl) => set_acl(acls, path, acl),
+                TxnOp::RemoveAcl(path) => remove_acl(acls, path),
+                TxnOp::SetTableSchema(base, schema) => {
+                    set_table_schema(schemas, pending, base, schema)
+                }
+                TxnOp::SetTtl(path, ttl) => set_ttl(data, ttls, expires, path, ttl, now),
+                TxnOp::RemoveTtl(path) => remove_ttl(ttls, expires, path, now),
+                TxnOp::Expire { path, version } => {
+                    expire(data, versions, ttls, expires, pending, path, version)
+                }
+                TxnOp::SetDatum(path, datum) => set_datum(data, pending, path, datum),
+                TxnOp::Flush(finished) => {
+                    let _: Result<_, _> = data.flush();
+                    let _: Result<_, _> = locked.flush();
+                    let _: Result<_, _> = versions.flush();
+                    let _: Result<_, _> = acls.flush();
+                    let _: Result<_, _> = schemas.flush();
+                    let _: Result<_, _> = ttls.flush();
+                    let _: Result<_, _> = expires.flush();
+                    if let Some(history) = history {
+                        let _: Result<_> = history.flush();
+                    }
+                    if let Some(finished) = finished {
+                        let _: Result<_, _> = finished.send(());
+                    }
+                    Ok(())
+                }
+            },
+        );
         send_reply(reply, r);
     }
     pending
 }
 
-fn commit_simple(data: &sled::Tree, locked: &sled::Tree, mut txn: Txn) -> Update {
+fn commit_simple(
+    db: &Storage,
+    data: &Tree,
+    locked: &Tree,
+    acls: &Tree,
+    versions: &Tree,
+    schemas: &Tree,
+    ttls: &Tree,
+    expires: &Expires,
+    history: Option<&History>,
+    now: DateTime<Utc>,
+    replicate: bool,
+    mut txn: Txn,
+) -> Update {
     use rayon::prelude::*;
     let mut by_path = BYPATH.take();
-    for (op, reply) in txn.0.drain(..) {
-        by_path.entry(op.path()).or_insert_with(|| STXNS.take()).push((op, reply));
+    for (op, writer, reply) in txn.ops.drain(..) {
+        by_path
+            .entry(op.path())
+            .or_insert_with(|| STXNS.take())
+            .push((op, writer, reply));
     }
     by_path
         .par_drain()
         .fold(
             || Update::new(),
             |mut pending, (_, mut ops)| {
-                for (op, reply) in ops.drain(..) {
-                    let r = match op {
-                        TxnOp::SetData(update, path, value) => {
-                            set_data(data, &mut pending, update, path, value)
-                        }
-                        TxnOp::Remove(path) => remove(data, &mut pending, path),
-                        TxnOp::SetFormula(path, value) => {
-                            set_formula(data, &mut pending, path, value)
-                        }
-                        TxnOp::SetOnWrite(path, value) => {
-                            set_on_write(data, &mut pending, path, value)
-                        }
-                        TxnOp::SetLocked(path) => set_locked(locked, &mut pending, path),
-                        TxnOp::SetUnlocked(path) => {
-                            set_unlocked(locked, &mut pending, path)
-                        }
-                        TxnOp::CreateSheet { .. }
-                        | TxnOp::AddSheetColumns { .. }
-                        | TxnOp::AddSheetRows { .. }
-                        | TxnOp::DelSheetColumns { .. }
-                        | TxnOp::DelSheetRows { .. }
-                        | TxnOp::CreateTable { .. }
-                        | TxnOp::AddTableColumns { .. }
-                        | TxnOp::AddTableRows { .. }
-                        | TxnOp::DelTableColumns { .. }
-                        | TxnOp::DelTableRows { .. }
-                        | TxnOp::RemoveSubtree { .. }
-                        | TxnOp::AddRoot(_)
-                        | TxnOp::DelRoot(_)
-                        | TxnOp::Flush(_) => unreachable!(),
-                    };
+                for (op, writer, reply) in ops.drain(..) {
+                    let replicate = if replicate { Some(op.passthrough()) } else { None };
+                    let r = with_history(
+                        db,
+                        data,
+                        locked,
+                        versions,
+                        schemas,
+                        ttls,
+                        expires,
+                        history,
+                        &writer,
+                        now,
+                        replicate,
+                        &mut pending,
+                        |pending, _| match op {
+                            TxnOp::SetData(update, path, value) => set_checked_data(
+                                data, schemas, pending, update, path, value,
+                            ),
+                            TxnOp::Remove(path) => remove(data, pending, path),
+                            TxnOp::SetFormula(path, value) => {
+                                set_formula(data, pending, path, value)
+                            }
+                            TxnOp::SetOnWrite(path, value) => {
+                                set_on_write(data, pending, path, value)
+                            }
+                            TxnOp::SetLocked(path) => set_locked(locked, pending, path),
+                            TxnOp::SetUnlocked(path) => {
+                                set_unlocked(locked, pending, path)
+                            }
+                            TxnOp::SetDataIf { path, value, version } => set_data_if(
+                                data, versions, schemas, pending, path, value, version,
+                            ),
+                            TxnOp::SetAcl(path, acl) => set_acl(acls, path, acl),
+                            TxnOp::RemoveAcl(path) => remove_acl(acls, path),
+                            TxnOp::Expire { path, version } => expire(
+                                data, versions, ttls, expires, pending, path, version,
+                            ),
+                            TxnOp::SetDatum(path, datum) => {
+                                set_datum(data, pending, path, datum)
+                            }
+                            TxnOp::CreateSheet { .. }
+                            | TxnOp::AddSheetColumns { .. }
+                            | TxnOp::AddSheetRows { .. }
+                            | TxnOp::DelSheetColumns { .. }
+                            | TxnOp::DelSheetRows { .. }
+                            | TxnOp::CreateTable { .. }
+                            | TxnOp::AddTableColumns { .. }
+                            | TxnOp::AddTableRows { .. }
+                            | TxnOp::DelTableColumns { .. }
+                            | TxnOp::DelTableRows { .. }
+                            | TxnOp::RemoveSubtree { .. }
+                            | TxnOp::RestoreSubtree { .. }
+                            | TxnOp::Atomic { .. }
+                            | TxnOp::SetVersions { .. }
+                            | TxnOp::SetTableSchema(_, _)
+                            | TxnOp::SetTtl(_, _)
+                            | TxnOp::RemoveTtl(_)
+                            | TxnOp::AddRoot(_)
+                            | TxnOp::DelRoot(_)
+                            | TxnOp::Flush(_) => unreachable!(),
+                        },
+                    );
                     send_reply(reply, r)
                 }
                 pending
@@ -1362,7 +2216,7 @@ impl Stats {
     }
 }
 
-async fn background_delete_task(data: sled::Tree, stats: Option<Arc<Stats>>) {
+async fn background_delete_task(data: Tree, stats: Option<Arc<Stats>>) {
     if let Some(stats) = &stats {
         stats.set_deleting(true);
     }
@@ -1371,8 +2225,7 @@ async fn background_delete_task(data: sled::Tree, stats: Option<Arc<Stats>>) {
             if let Ok((k, v)) = r {
                 if let DatumKind::Deleted = DatumKind::decode(&mut &*v) {
                     // CR estokes: log these errors
-                    let _: Result<_, _> =
-                        data.compare_and_swap(k, Some(v), None::<sled::IVec>);
+                    let _: Result<_, _> = data.compare_and_swap(k, Some(v), None::<IVec>);
                 }
             }
         }
@@ -1382,15 +2235,144 @@ async fn background_delete_task(data: sled::Tree, stats: Option<Arc<Stats>>) {
     }
 }
 
+/// A message from the primary to a replica. A new replica is sent a
+/// snapshot of the primary's db, followed by every transaction
+/// committed after the snapshot was taken, in commit order.
+#[derive(Clone, Pack)]
+pub(super) enum ReplMsg {
+    SnapshotStart,
+    Roots(Vec<Path>),
+    Locked(Vec<(Path, bool)>),
+    Acls(Vec<(Path, Acl)>),
+    Ttls(Vec<(Path, Ttl)>),
+    Data(Vec<(Path, Datum)>),
+    Schemas(Vec<(Path, Vec<ColumnSchema>)>),
+    Versions { clear: bool, versions: Vec<(Path, u64)> },
+    SnapshotEnd,
+    Txn(Bytes),
+}
+
+const SNAPSHOT_CHUNK: usize = 10_000;
+
+/// The most transactions a replica may fall behind the primary. A
+/// replica that falls further behind is disconnected, and resyncs
+/// from a new snapshot when it reconnects.
+pub(super) const MAX_REPLICA_LAG: usize = 10_000;
+
+fn snapshot(
+    data: &Tree,
+    locked: &Tree,
+    roots: &Tree,
+    acls: &Tree,
+    ttls: &Tree,
+    schemas: &Tree,
+    versions: &Tree,
+) -> Result<Vec<ReplMsg>> {
+    let mut msgs = vec![ReplMsg::SnapshotStart];
+    let roots = iter_paths(roots).collect::<Result<Vec<_>>>()?;
+    msgs.push(ReplMsg::Roots(roots));
+    let mut chunk = Vec::new();
+    for r in locked.iter() {
+        let (k, v) = r?;
+        chunk.push((Path::from(ArcStr::from(str::from_utf8(&k)?)), &*v == &[1u8]));
+        if chunk.len() >= SNAPSHOT_CHUNK {
+            msgs.push(ReplMsg::Locked(mem::take(&mut chunk)));
+        }
+    }
+    msgs.push(ReplMsg::Locked(chunk));
+    let mut chunk = Vec::new();
+    for r in acls.iter() {
+        let (k, v) = r?;
+        chunk.push((
+            Path::from(ArcStr::from(str::from_utf8(&k)?)),
+            Acl::decode(&mut &*v)?,
+        ));
+        if chunk.len() >= SNAPSHOT_CHUNK {
+            msgs.push(ReplMsg::Acls(mem::take(&mut chunk)));
+        }
+    }
+    msgs.push(ReplMsg::Acls(chunk));
+    let mut chunk = Vec::new();
+    for r in ttls.iter() {
+        let (k, v) = r?;
+        chunk.push((
+            Path::from(ArcStr::from(str::from_utf8(&k)?)),
+            Ttl::decode(&mut &*v)?,
+        ));
+        if chunk.len() >= SNAPSHOT_CHUNK {
+            msgs.push(ReplMsg::Ttls(mem::take(&mut chunk)));
+        }
+    }
+    msgs.push(ReplMsg::Ttls(chunk));
+    let mut chunk = Vec::new();
+    for r in data.iter() {
+        let (k, v) = r?;
+        match Datum::decode(&mut &*v)? {
+            Datum::Deleted => (),
+            datum => {
+                chunk.push((Path::from(ArcStr::from(str::from_utf8(&k)?)), datum));
+                if chunk.len() >= SNAPSHOT_CHUNK {
+                    msgs.push(ReplMsg::Data(mem::take(&mut chunk)));
+                }
+            }
+        }
+    }
+    msgs.push(ReplMsg::Data(chunk));
+    // schemas come after the data, so data written before a schema
+    // was set isn't rejected by the replica
+    let mut chunk = Vec::new();
+    for r in schemas.iter() {
+        let (k, v) = r?;
+        let schema = <Vec<ColumnSchema> as Pack>::decode(&mut &*v)?;
+        chunk.push((Path::from(ArcStr::from(str::from_utf8(&k)?)), schema));
+        if chunk.len() >= SNAPSHOT_CHUNK {
+            msgs.push(ReplMsg::Schemas(mem::take(&mut chunk)));
+        }
+    }
+    msgs.push(ReplMsg::Schemas(chunk));
+    // the versions come last, so they replace the versions the
+    // replica assigns while applying the data
+    let mut clear = true;
+    let mut chunk = Vec::new();
+    for r in versions.iter() {
+        let (k, v) = r?;
+        chunk.push((
+            Path::from(ArcStr::from(str::from_utf8(&k)?)),
+            u64::decode(&mut &*v)?,
+        ));
+        if chunk.len() >= SNAPSHOT_CHUNK {
+            let versions = mem::take(&mut chunk);
+            msgs.push(ReplMsg::Versions { clear, versions });
+            clear = false;
+        }
+    }
+    msgs.push(ReplMsg::Versions { clear, versions: chunk });
+    msgs.push(ReplMsg::SnapshotEnd);
+    Ok(msgs)
+}
+
+// how often to discard history older than the retention period (seconds)
+const HISTORY_PRUNE_INTERVAL: i64 = 60;
+
 async fn commit_txns_task(
     stats: Option<Arc<Stats>>,
-    data: sled::Tree,
-    locked: sled::Tree,
-    roots: sled::Tree,
+    db: Storage,
+    data: Tree,
+    locked: Tree,
+    roots: Tree,
+    acls: Tree,
+    versions: Tree,
+    schemas: Tree,
+    ttls: Tree,
+    expires: Expires,
+    history: Option<History>,
     incoming: UnboundedReceiver<Txn>,
     outgoing: UnboundedSender<Update>,
+    new_replicas: UnboundedReceiver<mpsc::Sender<Vec<ReplMsg>>>,
 ) {
     let mut incoming = incoming.fuse();
+    let mut new_replicas = new_replicas.fuse();
+    let mut replicas: Vec<mpsc::Sender<Vec<ReplMsg>>> = Vec::new();
     async fn wait_delete_task(jh: &mut Option<task::JoinHandle<()>>) {
         match jh {
             Some(jh) => {
@@ -1402,17 +2384,34 @@ async fn commit_txns_task(
     }
     let mut delete_task: Option<task::JoinHandle<()>> = None;
     let mut delete_required = false;
+    let mut pruned: Option<DateTime<Utc>> = None;
     loop {
         select_biased! {
             () = wait_delete_task(&mut delete_task).fuse() => {
                 delete_task = None;
             }
+            mut replica = new_replicas.select_next_some() => {
+                // nothing is committed while the snapshot is taken, so
+                // the replica will see a consistent stream
+                let r = task::block_in_place(|| {
+                    snapshot(&data, &locked, &roots, &acls, &ttls, &schemas, &versions)
+                });
+                match r {
+                    Err(e) => error!("failed to take a snapshot for replica {}", e),
+                    // the snapshot is a single item, so it always fits
+                    Ok(msgs) => {
+                        if replica.try_send(msgs).is_ok() {
+                            replicas.push(replica)
+                        }
+                    }
+                }
+            }
             txn = incoming.select_next_some() => {
                 if let Some(stats) = &stats {
                     stats.dec_queued();
                 }
                 let (simple, delete) =
-                    txn.0.iter().fold((true, false), |(simple, delete), op| match &op.0 {
+                    txn.ops.iter().fold((true, false), |(simple, delete), op| match &op.0 {
                         TxnOp::CreateSheet { .. }
                         | TxnOp::AddSheetColumns { .. }
                         | TxnOp::AddSheetRows { .. }
@@ -1420,26 +2419,71 @@ async fn commit_txns_task(
                         | TxnOp::AddTableColumns { .. }
                         | TxnOp::AddTableRows { .. }
                         | TxnOp::AddRoot(_)
+                        | TxnOp::SetVersions { .. }
+                        | TxnOp::SetTableSchema(_, _)
+                        | TxnOp::SetTtl(_, _)
+                        | TxnOp::RemoveTtl(_)
                         | TxnOp::Flush(_) => (false, delete),
                         TxnOp::RemoveSubtree { .. }
+                        | TxnOp::RestoreSubtree { .. }
+                        | TxnOp::Atomic { .. }
                         | TxnOp::DelTableColumns { .. }
                         | TxnOp::DelTableRows { .. }
                         | TxnOp::DelSheetColumns { .. }
                         | TxnOp::DelSheetRows { .. }
                         | TxnOp::DelRoot(_) => (false, true),
-                        TxnOp::Remove(_) => (simple, true),
+                        TxnOp::Remove(_)
+                        | TxnOp::Expire { .. }
+                        | TxnOp::SetDatum(_, _) => (simple, true),
                         TxnOp::SetData(_, _, _)
                         | TxnOp::SetFormula(_, _)
                         | TxnOp::SetOnWrite(_, _)
+                        | TxnOp::SetDataIf { .. }
+                        | TxnOp::SetAcl(_, _)
+                        | TxnOp::RemoveAcl(_)
                         | TxnOp::SetLocked(_)
                         | TxnOp::SetUnlocked(_) => (simple, delete),
                     });
                 delete_required |= delete;
-                let pending = if simple {
-                    task::block_in_place(|| commit_simple(&data, &locked, txn))
+                let now = txn.timestamp.unwrap_or_else(Utc::now);
+                let replicate = !replicas.is_empty();
+                let history = history.as_ref();
+                // sqlite would otherwise commit every write separately
+                let mut pending = if simple {
+                    task::block_in_place(|| {
+                        storage::grouped(&db, || {
+                            commit_simple(
+                                &db, &data, &locked, &acls, &versions, &schemas, &ttls,
+                                &expires, history, now, replicate, txn,
+                            )
+                        })
+                    })
                 } else {
-                    task::block_in_place(|| commit_complex(&data, &locked, &roots, txn))
+                    task::block_in_place(|| {
+                        storage::grouped(&db, || {
+                            commit_complex(
+                                &db, &data, &locked, &roots, &acls, &versions, &schemas,
+                                &ttls, &expires, history, now, replicate, txn,
+                            )
+                        })
+                    })
                 };
+                let effects = mem::take(&mut pending.replicate);
+                match Txn::encode_for_replicas(now, &effects) {
+                    Ok(None) => (),
+                    Ok(Some(b)) => {
+                        let m = ReplMsg::Txn(b);
+                        replicas.retain_mut(|r| match r.try_send(vec![m.clone()]) {
+                            Ok(()) => true,
+                            Err(e) if e.is_full() => {
+                                warn!("replica fell too far behind, disconnecting it");
+                                false
+                            }
+                            Err(_) => false,
+                        })
+                    }
+                    Err(e) => error!("failed to encode txn for replicas {}", e),
+                }
                 if let Some(stats) = &stats {
                     stats.set_busy(false);
                 }
@@ -1452,6 +2496,15 @@ async fn commit_txns_task(
                     delete_required = false;
                     delete_task = Some(task::spawn(job));
                 }
+                if let Some(history) = history {
+                    let interval = chrono::Duration::seconds(HISTORY_PRUNE_INTERVAL);
+                    if pruned.map(|t| now - t >= interval).unwrap_or(true) {
+                        pruned = Some(now);
+                        if let Err(e) = task::block_in_place(|| history.prune(now)) {
+                            error!("failed to prune history {}", e)
+                        }
+                    }
+                }
             }
             complete => break,
         }
@@ -1460,11 +2513,18 @@ async fn commit_txns_task(
 
 #[derive(Clone)]
 pub struct Db {
-    db: sled::Db,
-    data: sled::Tree,
-    locked: sled::Tree,
-    roots: sled::Tree,
+    db: Storage,
+    data: Tree,
+    locked: Tree,
+    roots: Tree,
+    acls: Tree,
+    versions: Tree,
+    schemas: Tree,
+    ttls: Tree,
+    expires: Expires,
+    history: Option<History>,
     submit_txn: UnboundedSender<Txn>,
+    add_replica: UnboundedSender<mpsc::Sender<Vec<ReplMsg>>>,
     stats: Option<Arc<Stats>>,
 }
 
@@ -1478,36 +2538,87 @@ impl Db {
             None => None,
             Some(p) => Some(Stats::new(publisher, p)?),
         };
-        let path = cfg
-            .db
-            .as_ref()
-            .map(PathBuf::from)
-            .or_else(Params::default_db_path)
-            .ok_or_else(|| {
-                anyhow!("db dir not specified and no default could be determined")
-            })?;
-        let db = sled::Config::default()
-            .cache_capacity(cfg.cache_size.unwrap_or(16 * 1024 * 1024))
-            .path(&path)
-            .open()?;
+        Self::open_with_stats(cfg, stats)
+    }
+
+    /// Open the db without a container, e.g. to dump or load it
+    /// offline. The db can't be open in a running container at the
+    /// same time.
+    pub fn open(cfg: &Params) -> Result<Self> {
+        let (db, mut updates) = Self::open_with_stats(cfg, None)?;
+        task::spawn(async move { while let Some(_) = updates.next().await {} });
+        Ok(db)
+    }
+
+    fn open_with_stats(
+        cfg: &Params,
+        stats: Option<Arc<Stats>>,
+    ) -> Result<(Self, UnboundedReceiver<Update>)> {
+        let db = storage::open(cfg)?;
         let data = db.open_tree("data")?;
         let locked = db.open_tree("locked")?;
         let roots = db.open_tree("roots")?;
+        let acls = db.open_tree("acls")?;
+        let versions = db.open_tree("versions")?;
+        let schemas = db.open_tree("schemas")?;
+        let ttls = db.open_tree("ttls")?;
+        let expires = Expires::new(db.open_tree("expires")?, db.open_tree("deadlines")?)?;
+        let history = if cfg.history {
+            let retention =
+                cfg.history_retention.map(|s| chrono::Duration::seconds(s as i64));
+            Some(History::new(&db, retention)?)
+        } else {
+            None
+        };
         let (tx_incoming, rx_incoming) = unbounded();
         let (tx_outgoing, rx_outgoing) = unbounded();
+        let (add_replica, new_replicas) = unbounded();
         task::spawn(commit_txns_task(
             stats.clone(),
+            db.clone(),
             data.clone(),
             locked.clone(),
             roots.clone(),
+            acls.clone(),
+            versions.clone(),
+            schemas.clone(),
+            ttls.clone(),
+            expires.clone(),
+            history.clone(),
             rx_incoming,
             tx_outgoing,
+            new_replicas,
         ));
-        Ok((Db { db, data, locked, roots, submit_txn: tx_incoming, stats }, rx_outgoing))
-    }
-
-    pub fn open_tree(&self, name: &str) -> Result<sled::Tree> {
-        if name == "data" || name == "locked" || name == "roots" {
+        let t = Db {
+            db,
+            data,
+            locked,
+            roots,
+            acls,
+            versions,
+            schemas,
+            ttls,
+            expires,
+            history,
+            submit_txn: tx_incoming,
+            add_replica,
+            stats,
+        };
+        Ok((t, rx_outgoing))
+    }
+
+    pub fn open_tree(&self, name: &str) -> Result<Tree> {
+        if name == "data"
+            || name == "locked"
+            || name == "roots"
+            || name == "acls"
+            || name == "versions"
+            || name == "schemas"
+            || name == "ttls"
+            || name == "expires"
+            || name == "deadlines"
+            || name == "history"
+        {
             bail!("tree name reserved")
         }
         Ok(self.db.open_tree(name)?)
@@ -1520,10 +2631,74 @@ impl Db {
         let _: Result<_, _> = self.submit_txn.unbounded_send(txn);
     }
 
+    /// Start replicating to a new replica. The returned channel will
+    /// receive a snapshot of the db as one item, followed by every
+    /// committed transaction, one per item. The channel is closed if
+    /// more than `MAX_REPLICA_LAG` transactions are waiting in it.
+    pub(super) fn replicate(&self) -> mpsc::Receiver<Vec<ReplMsg>> {
+        let (tx, rx) = mpsc::channel(MAX_REPLICA_LAG);
+        let _: Result<_, _> = self.add_replica.unbounded_send(tx);
+        rx
+    }
+
+    /// Write a consistent snapshot of the db to `out` in the
+    /// specified format. Commits wait while the snapshot is taken, but
+    /// the container keeps publishing, and writing the snapshot out
+    /// happens concurrently with new commits.
+    pub async fn dump(&self, format: DumpFormat, out: impl io::Write) -> Result<()> {
+        let mut snapshot = self.replicate();
+        let mut out = DumpWriter::new(format, out);
+        while let Some(msgs) = snapshot.next().await {
+            for m in msgs {
+                if task::block_in_place(|| out.write_msg(m))? {
+                    return Ok(());
+                }
+            }
+        }
+        bail!("snapshot ended unexpectedly")
+    }
+
+    /// Load a dump in the specified format, adding it's roots,
+    /// locked flags, data, and formulas to the db in a single
+    /// transaction. Return when the transaction is committed.
+    pub async fn load(&self, format: DumpFormat, input: impl io::Read) -> Result<()> {
+        let txn = task::block_in_place(|| dump::read(format, input))?;
+        self.commit(txn);
+        self.flush_async().await
+    }
+
+    /// Import a table in the specified format to `base`, creating
+    /// the table, or adding any missing rows and columns to it, and
+    /// setting the imported cells in a single transaction. Return
+    /// when the transaction is committed.
+    pub async fn import(
+        &self,
+        base: Path,
+        format: ImportFormat,
+        input: &str,
+    ) -> Result<()> {
+        let table = Import::parse(format, input)?;
+        let res = Arc::new(Mutex::new(Value::Null));
+        let mut txn = Txn::new();
+        table.add_to_txn(&mut txn, base, Some(Sendable::Packed(res.clone())));
+        self.commit(txn);
+        self.flush_async().await?;
+        match mem::replace(&mut *res.lock(), Value::Null) {
+            Value::Error(e) => bail!("{}", e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Render the table or sheet at `base` as csv, in the format
+    /// read by `import`
+    pub fn export(&self, base: &Path) -> Result<String> {
+        import::export_csv(self, base)
+    }
+
     pub(super) async fn flush_async(&self) -> Result<()> {
         let (tx, rx) = oneshot::channel();
         let mut txn = Txn::new();
-        txn.0.push((TxnOp::Flush(tx), None));
+        txn.push(TxnOp::Flush(Some(tx)), None);
         self.commit(txn);
         let _: Result<_, _> = rx.await;
         Ok(())
@@ -1667,8 +2842,8 @@ impl Db {
     }
 
     fn decode_iter(
-        iter: sled::Iter,
-    ) -> impl Iterator<Item = Result<(Path, DatumKind, sled::IVec)>> + 'static {
+        iter: Iter,
+    ) -> impl Iterator<Item = Result<(Path, DatumKind, IVec)>> + 'static {
         iter.map(|res| {
             let (key, val) = res?;
             let path = Path::from(ArcStr::from(str::from_utf8(&key)?));
@@ -1679,14 +2854,14 @@ impl Db {
 
     pub fn iter(
         &self,
-    ) -> impl Iterator<Item = Result<(Path, DatumKind, sled::IVec)>> + 'static {
+    ) -> impl Iterator<Item = Result<(Path, DatumKind, IVec)>> + 'static {
         Self::decode_iter(self.data.iter())
     }
 
     pub fn iter_prefix(
         &self,
         prefix: Path,
-    ) -> impl Iterator<Item = Result<(Path, DatumKind, sled::IVec)>> + 'static {
+    ) -> impl Iterator<Item = Result<(Path, DatumKind, IVec)>> + 'static {
         Self::decode_iter(self.data.scan_prefix(&*prefix))
     }
 
@@ -1708,10 +2883,159 @@ impl Db {
         iter_paths(&self.roots)
     }
 
+    /// The acl governing `path`, and the path it is set on, if any
+    pub fn acl(&self, path: &Path) -> Result<Option<(Path, Acl)>> {
+        acl::lookup(&self.acls, path)
+    }
+
+    /// Fail if the acl governing `path` does not give `user` the
+    /// permission `perm`
+    pub fn check_acl(
+        &self,
+        user: Option<&UserInfo>,
+        path: &Path,
+        perm: Permission,
+    ) -> Result<()> {
+        match self.acl(path)? {
+            None => Ok(()),
+            Some((_, acl)) if acl.allows(perm, user) => Ok(()),
+            Some((base, _)) => bail!("permission denied by the acl of {}", base),
+        }
+    }
+
+    /// Fail unless the acl governing `path`, and every acl set below
+    /// it, give `user` the permission `perm`
+    pub fn check_subtree_acl(
+        &self,
+        user: Option<&UserInfo>,
+        path: &Path,
+        perm: Permission,
+    ) -> Result<()> {
+        self.check_acl(user, path, perm)?;
+        for r in acl::below(&self.acls, path) {
+            let (base, acl) = r?;
+            if !acl.allows(perm, user) {
+                bail!("permission denied by the acl of {}", base)
+            }
+        }
+        Ok(())
+    }
+
+    /// Fail if the formula or on write handler at `formula` may not
+    /// write data to `path`. Formulas write as the container, so
+    /// they may only write where everyone who may change them could
+    /// write themselves.
+    pub fn check_formula_acl(&self, formula: &Path, path: &Path) -> Result<()> {
+        let (base, acl) = match self.acl(path)? {
+            None => return Ok(()),
+            Some(acl) => acl,
+        };
+        let allowed = match self.acl(formula)? {
+            None => acl.data.iter().any(|n| &**n == "*"),
+            Some((_, src)) => src.within(Permission::Formula, &acl, Permission::Data),
+        };
+        if allowed {
+            Ok(())
+        } else {
+            bail!("{} may not write to {}, denied by the acl of {}", formula, path, base)
+        }
+    }
+
+    /// The column schemas of the table at `base`, if it has any
+    pub fn table_schema(&self, base: &Path) -> Result<Option<Vec<ColumnSchema>>> {
+        schema::lookup(&self.schemas, base)
+    }
+
+    /// All the tables with a schema, and their schemas
+    pub fn table_schemas(
+        &self,
+    ) -> impl Iterator<Item = Result<(Path, Vec<ColumnSchema>)>> + 'static {
+        self.schemas.iter().map(|r| {
+            let (k, v) = r?;
+            let path = Path::from(ArcStr::from(str::from_utf8(&k)?));
+            Ok((path, <Vec<ColumnSchema> as Pack>::decode(&mut &*v)?))
+        })
+    }
+
+    /// All the acls, and the paths they are set on
+    pub fn acls(&self) -> impl Iterator<Item = Result<(Path, Acl)>> + 'static {
+        self.acls.iter().map(|r| {
+            let (k, v) = r?;
+            let path = Path::from(ArcStr::from(str::from_utf8(&k)?));
+            Ok((path, Acl::decode(&mut &*v)?))
+        })
+    }
+
+    /// The ttl governing `path`, and the path it is set on, if any
+    pub fn ttl(&self, path: &Path) -> Result<Option<(Path, Ttl)>> {
+        ttl::lookup(&self.ttls, path)
+    }
+
+    pub fn ttls(&self) -> impl Iterator<Item = Result<(Path, Ttl)>> + 'static {
+        self.ttls.iter().map(|r| {
+            let (k, v) = r?;
+            let path = Path::from(ArcStr::from(str::from_utf8(&k)?));
+            Ok((path, Ttl::decode(&mut &*v)?))
+        })
+    }
+
+    /// The paths that expired at or before `now`, and their current
+    /// versions, in the order they expired.
+    pub(super) fn expired(&self, now: DateTime<Utc>) -> Result<Vec<(Path, u64)>> {
+        self.expires
+            .due(&now)
+            .map(|r| {
+                let path = r?;
+                let version = get_version(&self.versions, &path)?;
+                Ok((path, version))
+            })
+            .collect()
+    }
+
+    /// The history of `path` in commit order. Each entry is the state
+    /// of the path after a committed change. Fails if history is not
+    /// enabled.
+    pub fn history(&self, path: &Path) -> Result<Vec<HistoryEntry>> {
+        match &self.history {
+            None => bail!("history is not enabled"),
+            Some(history) => history.path(path),
+        }
+    }
+
+    /// The state of the subtree rooted at `base` as of `timestamp`,
+    /// according to the history. Paths that did not exist at
+    /// `timestamp` are omitted, as are paths with no history. Fails if
+    /// history is not enabled.
+    pub fn as_of(
+        &self,
+        base: &Path,
+        timestamp: DateTime<Utc>,
+    ) -> Result<BTreeMap<Path, Datum>> {
+        match &self.history {
+            None => bail!("history is not enabled"),
+            Some(history) => {
+                let mut res = history.as_of(base, timestamp)?;
+                res.retain(|_, d| d != &Datum::Deleted);
+                Ok(res)
+            }
+        }
+    }
+
+    /// The version of `path`, it is incremented every time the path
+    /// is changed, and is 0 if the path was never written. Replicas
+    /// take the versions of the primary.
+    pub fn version(&self, path: &Path) -> Result<u64> {
+        get_version(&self.versions, path)
+    }
+
     pub fn clear(&self) -> Result<()> {
-        self.db.clear()?;
         self.data.clear()?;
         self.locked.clear()?;
+        self.versions.clear()?;
+        self.acls.clear()?;
+        self.schemas.clear()?;
+        self.ttls.clear()?;
+        self.expires.clear()?;
         Ok(self.roots.clear()?)
     }
 }
@@ -0,0 +1,174 @@
+use crate::{
+    acl::Acl,
+    db::{Datum, ReplMsg, Txn},
+    schema::ColumnSchema,
+    ttl::Ttl,
+};
+use anyhow::{bail, Error, Result};
+use bytes::{Buf, BytesMut};
+use netidx::{pack::Pack, path::Path, subscriber::Value};
+use netidx_derive::Pack;
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    str::FromStr,
+};
+
+/// The format of a container dump
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DumpFormat {
+    /// one json record per line, easy to inspect and to process with
+    /// other tools
+    Json,
+    /// netidx pack encoded records, compact and fast to load
+    Packed,
+}
+
+impl FromStr for DumpFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "json" => Ok(DumpFormat::Json),
+            "packed" => Ok(DumpFormat::Packed),
+            s => bail!("invalid dump format {}, expected json or packed", s),
+        }
+    }
+}
+
+/// One record of a dump. A dump contains all the roots, followed by
+/// all the locked flags, followed by all the acls, followed by all the
+/// ttls, followed by all the data and formulas, followed by all the
+/// table schemas.
+#[derive(Debug, Clone, Serialize, Deserialize, Pack)]
+pub enum DumpRecord {
+    Root(Path),
+    Locked(Path, bool),
+    Acl(Path, Acl),
+    Data(Path, Value),
+    Formula(Path, Value, Value),
+    Schema(Path, Vec<ColumnSchema>),
+    Ttl(Path, Ttl),
+}
+
+impl DumpRecord {
+    fn add_to_txn(self, txn: &mut Txn) {
+        match self {
+            DumpRecord::Root(path) => txn.add_root(path, None),
+            DumpRecord::Locked(path, true) => txn.set_locked(path, None),
+            DumpRecord::Locked(path, false) => txn.set_unlocked(path, None),
+            DumpRecord::Acl(path, acl) => txn.set_acl(path, acl, None),
+            DumpRecord::Data(path, value) => txn.set_data(true, path, value, None),
+            DumpRecord::Formula(path, formula, on_write) => {
+                txn.set_formula(path.clone(), formula, None);
+                txn.set_on_write(path, on_write, None);
+            }
+            DumpRecord::Schema(base, schema) => txn.set_table_schema(base, schema, None),
+            DumpRecord::Ttl(path, ttl) => txn.set_ttl(path, ttl, None),
+        }
+    }
+}
+
+pub(super) struct DumpWriter<W> {
+    format: DumpFormat,
+    out: W,
+    buf: BytesMut,
+}
+
+impl<W: Write> DumpWriter<W> {
+    pub(super) fn new(format: DumpFormat, out: W) -> Self {
+        Self { format, out, buf: BytesMut::new() }
+    }
+
+    fn write(&mut self, rec: &DumpRecord) -> Result<()> {
+        match self.format {
+            DumpFormat::Json => {
+                serde_json::to_writer(&mut self.out, rec)?;
+                self.out.write_all(b"\n")?
+            }
+            DumpFormat::Packed => {
+                self.buf.clear();
+                rec.encode(&mut self.buf)?;
+                self.out.write_all(&self.buf)?
+            }
+        }
+        Ok(())
+    }
+
+    /// write a snapshot message, return true if it was the end of the
+    /// snapshot.
+    pub(super) fn write_msg(&mut self, m: ReplMsg) -> Result<bool> {
+        match m {
+            ReplMsg::SnapshotStart => (),
+            ReplMsg::Roots(roots) => {
+                for path in roots {
+                    self.write(&DumpRecord::Root(path))?
+                }
+            }
+            ReplMsg::Locked(locked) => {
+                for (path, locked) in locked {
+                    self.write(&DumpRecord::Locked(path, locked))?
+                }
+            }
+            ReplMsg::Acls(acls) => {
+                for (path, acl) in acls {
+                    self.write(&DumpRecord::Acl(path, acl))?
+                }
+            }
+            ReplMsg::Ttls(ttls) => {
+                for (path, ttl) in ttls {
+                    self.write(&DumpRecord::Ttl(path, ttl))?
+                }
+            }
+            ReplMsg::Data(data) => {
+                for (path, datum) in data {
+                    match datum {
+                        Datum::Deleted => (),
+                        Datum::Data(v) => self.write(&DumpRecord::Data(path, v))?,
+                        Datum::Formula(f, w) => {
+                            self.write(&DumpRecord::Formula(path, f, w))?
+                        }
+                    }
+                }
+            }
+            ReplMsg::Schemas(schemas) => {
+                for (base, schema) in schemas {
+                    self.write(&DumpRecord::Schema(base, schema))?
+                }
+            }
+            // versions are local to a db, a loaded dump starts over
+            ReplMsg::Versions { .. } => (),
+            ReplMsg::SnapshotEnd => {
+                self.out.flush()?;
+                return Ok(true);
+            }
+            ReplMsg::Txn(_) => bail!("unexpected transaction in snapshot"),
+        }
+        Ok(false)
+    }
+}
+
+/// read a dump from `input` into a single transaction
+pub(super) fn read(format: DumpFormat, input: impl Read) -> Result<Txn> {
+    let mut txn = Txn::new();
+    match format {
+        DumpFormat::Json => {
+            for line in BufReader::new(input).lines() {
+                let line = line?;
+                if !line.trim().is_empty() {
+                    serde_json::from_str::<DumpRecord>(&line)?.add_to_txn(&mut txn)
+                }
+            }
+        }
+        DumpFormat::Packed => {
+            let mut input = input;
+            let mut buf = Vec::new();
+            input.read_to_end(&mut buf)?;
+            let mut buf = &buf[..];
+            while buf.has_remaining() {
+                DumpRecord::decode(&mut buf)?.add_to_txn(&mut txn)
+            }
+        }
+    }
+    Ok(txn)
+}
@@ -0,0 +1,226 @@
+use crate::{
+    db::{Datum, Update},
+    storage::{self, Batches, IVec, Storage, Tree},
+};
+use anyhow::{bail, Result};
+use arcstr::ArcStr;
+use chrono::{prelude::*, Duration};
+use netidx::{
+    pack::Pack, path::Path, pool::Pool, protocol::resolver::UserInfo, subscriber::Value,
+};
+use std::{
+    collections::{BTreeMap, HashSet},
+    str,
+    sync::Arc,
+};
+
+/// The user who wrote a transaction operation, if known
+pub type Writer = Option<Arc<UserInfo>>;
+
+lazy_static! {
+    static ref BUF: Pool<Vec<u8>> = Pool::new(8, 16384);
+}
+
+// history keys are the path, a zero byte, and a big endian sequence
+// number, so the history of a path is stored in commit order, and
+// the history of a subtree can be found with a prefix scan.
+const SEQ_LEN: usize = 9;
+
+/// One committed change to a path, the datum is the state of the path
+/// after the change was committed.
+#[derive(Debug, Clone)]
+pub struct HistoryEntry {
+    pub path: Path,
+    pub timestamp: DateTime<Utc>,
+    pub user: Option<UserInfo>,
+    pub datum: Datum,
+}
+
+impl HistoryEntry {
+    fn decode(key: &[u8], mut val: &[u8]) -> Result<Self> {
+        if key.len() < SEQ_LEN {
+            bail!("invalid history key")
+        }
+        let path = str::from_utf8(&key[..key.len() - SEQ_LEN])?;
+        let path = Path::from(ArcStr::from(path));
+        let timestamp = <DateTime<Utc> as Pack>::decode(&mut val)?;
+        let user = <Option<UserInfo> as Pack>::decode(&mut val)?;
+        let datum = Datum::decode(&mut val)?;
+        Ok(HistoryEntry { path, timestamp, user, datum })
+    }
+
+    /// the entry as a netidx value [timestamp, user, kind, ..]. Where
+    /// kind is one of "data", "formula", or "deleted", followed by
+    /// the value for data, or the formula and on write formula for
+    /// formulas.
+    pub fn to_value(&self) -> Value {
+        let user = match &self.user {
+            None => Value::Null,
+            Some(u) => Value::from(u.name.clone()),
+        };
+        let mut v = vec![Value::from(self.timestamp), user];
+        datum_to_value(&self.datum, &mut v);
+        Value::from(v)
+    }
+}
+
+pub(super) fn datum_to_value(datum: &Datum, v: &mut Vec<Value>) {
+    match datum {
+        Datum::Data(d) => {
+            v.push(Value::from("data"));
+            v.push(d.clone());
+        }
+        Datum::Formula(f, w) => {
+            v.push(Value::from("formula"));
+            v.push(f.clone());
+            v.push(w.clone());
+        }
+        Datum::Deleted => v.push(Value::from("deleted")),
+    }
+}
+
+#[derive(Clone)]
+pub(super) struct History {
+    db: Storage,
+    tree: Tree,
+    retention: Option<Duration>,
+}
+
+impl History {
+    /// open the history, if `retention` is specified history older
+    /// than it is discarded by `prune`.
+    pub(super) fn new(db: &Storage, retention: Option<Duration>) -> Result<Self> {
+        Ok(History { db: db.clone(), tree: db.open_tree("history")?, retention })
+    }
+
+    /// The oldest time the history can restore, if it is limited
+    fn horizon(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        self.retention.map(|r| now - r)
+    }
+
+    /// stage the history of every path changed by `up` in `batches`,
+    /// which hold the writes that made the changes, if they aren't
+    /// already committed to `data`.
+    pub(super) fn record(
+        &self,
+        data: &Tree,
+        batches: &mut Batches,
+        writer: &Writer,
+        timestamp: DateTime<Utc>,
+        up: &Update,
+    ) -> Result<()> {
+        let mut user = BUF.take();
+        writer.as_ref().map(|u| UserInfo::clone(u)).encode(&mut *user)?;
+        let mut paths = HashSet::new();
+        let changed = up.data.iter().chain(up.formula.iter()).chain(up.on_write.iter());
+        for (path, _) in changed {
+            if !paths.insert(path) {
+                continue;
+            }
+            let datum = match batches.get(data, path.as_bytes())? {
+                None => Datum::Deleted,
+                Some(v) => Datum::decode(&mut &*v)?,
+            };
+            let mut key = BUF.take();
+            key.extend_from_slice(path.as_bytes());
+            key.push(0);
+            key.extend_from_slice(&self.db.generate_id()?.to_be_bytes());
+            let mut val = BUF.take();
+            timestamp.encode(&mut *val)?;
+            val.extend_from_slice(&user);
+            datum.encode(&mut *val)?;
+            batches.insert(&self.tree, &**key, &**val);
+        }
+        Ok(())
+    }
+
+    /// Discard the entries older than the retention period, except
+    /// the last one of each path, which is still the state of the
+    /// path at the start of the period. If that is a delete it is
+    /// discarded too, paths with no history as of a time don't exist.
+    pub(super) fn prune(&self, now: DateTime<Utc>) -> Result<()> {
+        let horizon = match self.horizon(now) {
+            None => return Ok(()),
+            Some(horizon) => horizon,
+        };
+        let mut remove: Vec<IVec> = Vec::new();
+        // the last entry before the horizon of the current path
+        let mut last: Option<(Path, IVec, bool)> = None;
+        for r in self.tree.iter() {
+            let (k, v) = r?;
+            let ent = HistoryEntry::decode(&k, &v)?;
+            if let Some((path, key, deleted)) = last.take() {
+                if path != ent.path {
+                    if deleted {
+                        remove.push(key)
+                    }
+                } else if ent.timestamp < horizon {
+                    remove.push(key)
+                } else {
+                    last = Some((path, key, deleted))
+                }
+            }
+            if ent.timestamp < horizon {
+                last = Some((ent.path, k, ent.datum == Datum::Deleted))
+            }
+        }
+        if let Some((_, key, true)) = last {
+            remove.push(key)
+        }
+        storage::grouped(&self.db, || -> Result<()> {
+            for k in remove {
+                self.tree.remove(&k)?;
+            }
+            Ok(())
+        })
+    }
+
+    pub(super) fn flush(&self) -> Result<()> {
+        self.tree.flush()?;
+        Ok(())
+    }
+
+    /// the history of `path` in commit order
+    pub(super) fn path(&self, path: &Path) -> Result<Vec<HistoryEntry>> {
+        let mut prefix = BUF.take();
+        prefix.extend_from_slice(path.as_bytes());
+        prefix.push(0);
+        self.tree
+            .scan_prefix(&**prefix)
+            .map(|r| {
+                let (k, v) = r?;
+                HistoryEntry::decode(&k, &v)
+            })
+            .collect()
+    }
+
+    /// The state of every path in the subtree rooted at `base` that
+    /// has any history as of `timestamp`. Paths that were first
+    /// written after `timestamp` are `Datum::Deleted`. Fails if
+    /// `timestamp` is older than the retention period.
+    pub(super) fn as_of(
+        &self,
+        base: &Path,
+        timestamp: DateTime<Utc>,
+    ) -> Result<BTreeMap<Path, Datum>> {
+        if let Some(horizon) = self.horizon(Utc::now()) {
+            if timestamp < horizon {
+                bail!("history before {} has been discarded", horizon)
+            }
+        }
+        let mut res: BTreeMap<Path, Datum> = BTreeMap::new();
+        for r in self.tree.scan_prefix(base.as_bytes()) {
+            let (k, v) = r?;
+            let ent = HistoryEntry::decode(&k, &v)?;
+            if !Path::is_parent(base, &ent.path) {
+                continue;
+            }
+            if ent.timestamp <= timestamp {
+                res.insert(ent.path, ent.datum);
+            } else if !res.contains_key(&ent.path) {
+                res.insert(ent.path, Datum::Deleted);
+            }
+        }
+        Ok(res)
+    }
+}
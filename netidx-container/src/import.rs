@@ -0,0 +1,300 @@
+use crate::db::{Datum, DatumKind, Db, Reply, Sendable, SharedReply, Txn};
+use anyhow::{bail, Context, Error, Result};
+use netidx::{chars::Chars, pack::Pack, path::Path, subscriber::Value};
+use std::{
+    collections::{HashMap, HashSet},
+    mem,
+    str::FromStr,
+    sync::Arc,
+};
+
+/// The format of a table import
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImportFormat {
+    /// A header row naming the columns, followed by one row per
+    /// table row. The first field of each row is the row name, the
+    /// name of the first column is ignored. Fields are parsed as
+    /// netidx values, e.g. `42`, `true`, `u32:42`, or `"42"`, and are
+    /// strings if they don't parse. Empty fields are left alone,
+    /// unless they are quoted, in which case they are empty strings.
+    Csv,
+    /// An object of rows, each an object of columns, e.g. `{"row0":
+    /// {"col0": 42, "col1": "foo"}}`. Plain json values are mapped to
+    /// the closest netidx type, objects are interpreted as serialized
+    /// netidx values, e.g. `{"type": "U32", "value": 42}`.
+    Json,
+}
+
+impl FromStr for ImportFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim() {
+            "csv" => Ok(ImportFormat::Csv),
+            "json" => Ok(ImportFormat::Json),
+            s => bail!("invalid import format {}, expected csv or json", s),
+        }
+    }
+}
+
+/// A table read from an import
+pub struct Import {
+    pub rows: Vec<Chars>,
+    pub columns: Vec<Chars>,
+    /// the row index, column index, and value of each cell
+    pub cells: Vec<(usize, usize, Value)>,
+}
+
+// split csv input into records of (field, quoted). Fields may be
+// quoted with ", and "" in a quoted field is a literal ".
+fn parse_csv(input: &str) -> Result<Vec<Vec<(String, bool)>>> {
+    let mut records = vec![];
+    let mut record = vec![];
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut in_quotes = false;
+    let mut chars = input.chars().peekable();
+    while let Some(c) = chars.next() {
+        if in_quotes {
+            match c {
+                '"' if chars.peek() == Some(&'"') => {
+                    chars.next();
+                    field.push('"')
+                }
+                '"' => in_quotes = false,
+                c => field.push(c),
+            }
+        } else {
+            match c {
+                '"' => {
+                    quoted = true;
+                    in_quotes = true
+                }
+                ',' => record.push((mem::take(&mut field), mem::take(&mut quoted))),
+                '\r' if chars.peek() == Some(&'\n') => (),
+                '\n' => {
+                    record.push((mem::take(&mut field), mem::take(&mut quoted)));
+                    records.push(mem::take(&mut record))
+                }
+                c => field.push(c),
+            }
+        }
+    }
+    if in_quotes {
+        bail!("unterminated quoted field")
+    }
+    if quoted || !field.is_empty() || !record.is_empty() {
+        record.push((field, quoted));
+        records.push(record)
+    }
+    // skip blank lines
+    records.retain(|r| r.len() > 1 || r.iter().any(|(f, q)| *q || !f.is_empty()));
+    Ok(records)
+}
+
+fn value_from_json(v: serde_json::Value) -> Result<Value> {
+    use serde_json::Value as J;
+    match v {
+        J::Null => Ok(Value::Null),
+        J::Bool(true) => Ok(Value::True),
+        J::Bool(false) => Ok(Value::False),
+        J::Number(n) => match (n.as_i64(), n.as_u64(), n.as_f64()) {
+            (Some(i), _, _) => Ok(Value::I64(i)),
+            (None, Some(u), _) => Ok(Value::U64(u)),
+            (None, None, Some(f)) => Ok(Value::F64(f)),
+            (None, None, None) => bail!("unrepresentable number {}", n),
+        },
+        J::String(s) => Ok(Value::String(Chars::from(s))),
+        J::Array(elts) => {
+            let elts =
+                elts.into_iter().map(value_from_json).collect::<Result<Vec<_>>>()?;
+            Ok(Value::Array(Arc::from(elts)))
+        }
+        v @ J::Object(_) => serde_json::from_value::<Value>(v)
+            .context("objects must be serialized netidx values"),
+    }
+}
+
+impl Import {
+    pub fn parse(format: ImportFormat, input: &str) -> Result<Self> {
+        match format {
+            ImportFormat::Csv => Self::from_csv(input),
+            ImportFormat::Json => Self::from_json(input),
+        }
+    }
+
+    fn from_csv(input: &str) -> Result<Self> {
+        let mut records = parse_csv(input)?.into_iter();
+        let columns = match records.next() {
+            None => bail!("missing header row"),
+            Some(header) => header
+                .into_iter()
+                .skip(1)
+                .map(|(name, _)| Chars::from(name))
+                .collect::<Vec<_>>(),
+        };
+        let mut rows = vec![];
+        let mut cells = vec![];
+        for (i, record) in records.enumerate() {
+            if record.len() > columns.len() + 1 {
+                bail!("row {} has more fields than the header", i + 1)
+            }
+            let mut fields = record.into_iter();
+            let (name, _) = fields.next().unwrap();
+            if name.is_empty() {
+                bail!("row {} has no name", i + 1)
+            }
+            for (col, (field, quoted)) in fields.enumerate() {
+                let v = if field.is_empty() {
+                    if !quoted {
+                        continue;
+                    }
+                    Value::String(Chars::from(field))
+                } else {
+                    match field.parse::<Value>() {
+                        Ok(v) => v,
+                        Err(_) => Value::String(Chars::from(field)),
+                    }
+                };
+                cells.push((rows.len(), col, v))
+            }
+            rows.push(Chars::from(name));
+        }
+        Ok(Import { rows, columns, cells })
+    }
+
+    fn from_json(input: &str) -> Result<Self> {
+        use serde_json::Value as J;
+        let table = match serde_json::from_str::<J>(input).context("parsing json")? {
+            J::Object(table) => table,
+            _ => bail!("expected an object of rows"),
+        };
+        let mut rows = vec![];
+        let mut columns = vec![];
+        let mut column_index: HashMap<String, usize> = HashMap::new();
+        let mut cells = vec![];
+        for (name, row) in table {
+            let row = match row {
+                J::Object(row) => row,
+                _ => bail!("row {} is not an object of columns", name),
+            };
+            for (col, v) in row {
+                let col = *column_index.entry(col).or_insert_with_key(|col| {
+                    columns.push(Chars::from(col.clone()));
+                    columns.len() - 1
+                });
+                let v = value_from_json(v).with_context(|| format!("row {}", name))?;
+                cells.push((rows.len(), col, v))
+            }
+            rows.push(Chars::from(name));
+        }
+        Ok(Import { rows, columns, cells })
+    }
+
+    /// Add the operations to import the table at `base` to `txn`. The
+    /// table is created if it doesn't exist, otherwise missing rows
+    /// and columns are added to it. `reply` is sent once every
+    /// operation has been committed, it is the first error, if any.
+    pub fn add_to_txn(self, txn: &mut Txn, base: Path, reply: Reply) {
+        let reply = reply.map(SharedReply::new);
+        let r = || reply.as_ref().map(|r| Sendable::Shared(r.clone()));
+        txn.create_table(
+            base.clone(),
+            self.rows.clone(),
+            self.columns.clone(),
+            false,
+            r(),
+        );
+        txn.add_table_columns(base.clone(), self.columns.clone(), r());
+        txn.add_table_rows(base.clone(), self.rows.clone(), r());
+        for (row, col, v) in self.cells {
+            let path = base
+                .append(&*Path::escape(&self.rows[row]))
+                .append(&*Path::escape(&self.columns[col]));
+            txn.set_data(true, path, v, r())
+        }
+    }
+}
+
+fn csv_field(out: &mut String, s: &str, quote: bool) {
+    if quote || s.contains(&[',', '"', '\r', '\n'][..]) {
+        out.push('"');
+        out.push_str(&s.replace('"', "\"\""));
+        out.push('"');
+    } else {
+        out.push_str(s)
+    }
+}
+
+// strings that would parse as another value are written as netidx
+// string literals, so they are imported as strings again
+fn csv_value(out: &mut String, v: &Value) {
+    match v {
+        Value::Null => (),
+        Value::String(s) if s.is_empty() => csv_field(out, "", true),
+        Value::String(s) if s.parse::<Value>().is_ok() => {
+            csv_field(out, &v.to_string(), false)
+        }
+        Value::String(s) => csv_field(out, s, false),
+        Value::I64(_) | Value::True | Value::False => {
+            csv_field(out, &v.to_string_naked(), false)
+        }
+        v => csv_field(out, &v.to_string(), false),
+    }
+}
+
+/// Render the table or sheet at `base` as csv, in the same format
+/// `ImportFormat::Csv` reads. Formula cells are written empty.
+pub(super) fn export_csv(db: &Db, base: &Path) -> Result<String> {
+    let base_levels = Path::levels(base);
+    let mut rows: Vec<(String, HashMap<String, Value>)> = vec![];
+    let mut columns: Vec<String> = vec![];
+    let mut known: HashSet<String> = HashSet::new();
+    for r in db.iter_prefix(base.clone()) {
+        let (path, kind, raw) = r?;
+        if !Path::is_parent(base, &path) || Path::levels(&path) != base_levels + 2 {
+            continue;
+        }
+        let row = Path::basename(Path::dirname(&path).unwrap_or("/")).unwrap_or("");
+        let col = Path::basename(&path).unwrap_or("");
+        let v = match kind {
+            DatumKind::Data => match Datum::decode(&mut &*raw)? {
+                Datum::Data(v) => v,
+                Datum::Formula(_, _) | Datum::Deleted => unreachable!(),
+            },
+            DatumKind::Formula => Value::Null,
+            DatumKind::Deleted | DatumKind::Invalid => continue,
+        };
+        if !known.contains(col) {
+            known.insert(String::from(col));
+            columns.push(String::from(col));
+        }
+        match rows.last_mut() {
+            Some((name, cells)) if *name == row => {
+                cells.insert(String::from(col), v);
+            }
+            _ => {
+                let mut cells = HashMap::new();
+                cells.insert(String::from(col), v);
+                rows.push((String::from(row), cells))
+            }
+        }
+    }
+    let mut out = String::new();
+    for col in &columns {
+        out.push(',');
+        csv_field(&mut out, &Path::unescape(col), false);
+    }
+    out.push('\n');
+    for (row, cells) in &rows {
+        csv_field(&mut out, &Path::unescape(row), false);
+        for col in &columns {
+            out.push(',');
+            if let Some(v) = cells.get(col) {
+                csv_value(&mut out, v)
+            }
+        }
+        out.push('\n');
+    }
+    Ok(out)
+}
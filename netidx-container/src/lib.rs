@@ -3,13 +3,26 @@ extern crate lazy_static;
 #[macro_use]
 extern crate netidx_protocols;
 
+mod acl;
 mod db;
+mod dump;
+mod history;
+mod import;
+mod replication;
 mod rpcs;
+mod schema;
 mod stats;
+mod storage;
+#[cfg(test)]
+mod test;
+mod ttl;
 
+pub use acl::{Acl, Permission};
 use anyhow::{anyhow, bail, Result};
 use arcstr::ArcStr;
-pub use db::{Datum, DatumKind, Db, Reply, Sendable, Txn};
+use chrono::{DateTime, Utc};
+pub use db::{AtomicOp, Datum, DatumKind, Db, Reply, Sendable, SharedReply, Txn};
+pub use dump::{DumpFormat, DumpRecord};
 use futures::{
     self,
     channel::{mpsc, oneshot},
@@ -19,13 +32,15 @@ use futures::{
     stream::FusedStream,
 };
 use fxhash::{FxBuildHasher, FxHashMap, FxHashSet};
-use log::{error, info};
+pub use import::{Import, ImportFormat};
+use log::{error, info, warn};
 use netidx::{
     chars::Chars,
     config::Config,
     pack::Pack,
     path::Path,
     pool::{Pool, Pooled},
+    protocol::resolver::UserInfo,
     publisher::{
         BindCfg, DefaultHandle, Event as PEvent, Id, PublishFlags, Publisher,
         PublisherBuilder, UpdateBatch, Val, WriteRequest,
@@ -40,7 +55,9 @@ use netidx_bscript::{
 };
 use netidx_protocols::rpc;
 use parking_lot::Mutex;
+use replication::{Replication, Role};
 use rpcs::{RpcRequest, RpcRequestKind};
+pub use schema::ColumnSchema;
 use stats::Stats;
 use std::{
     collections::{
@@ -62,6 +79,7 @@ use tokio::{
     task,
     time::{self, Instant},
 };
+pub use ttl::{Expiry, Ttl};
 
 use crate::rpcs::RpcApi;
 
@@ -82,10 +100,41 @@ pub struct Params {
     pub api_path: Option<Path>,
     #[structopt(long = "db", help = "the db file")]
     pub db: Option<String>,
+    #[structopt(
+        long = "storage",
+        help = "the storage backend, sled, memory, or sqlite",
+        default_value = "sled"
+    )]
+    pub storage: StorageKind,
     #[structopt(long = "cache-size", help = "db page cache size in bytes")]
     pub cache_size: Option<u64>,
     #[structopt(long = "sparse", help = "don't even advertise the contents of the db")]
     pub sparse: bool,
+    #[structopt(
+        long = "history",
+        help = "record the history of every change, enables point in time restore"
+    )]
+    pub history: bool,
+    #[structopt(
+        long = "history-retention",
+        help = "discard history older than this many seconds, default keep it forever"
+    )]
+    pub history_retention: Option<u64>,
+    #[structopt(
+        long = "replicate",
+        help = "replicate with the other containers using this netidx base path"
+    )]
+    pub replicate: Option<Path>,
+    #[structopt(
+        long = "hidden",
+        help = "as a replica, don't publish the db until promoted to primary"
+    )]
+    pub hidden: bool,
+    #[structopt(
+        long = "admin",
+        help = "a user or group that may set the first acl on a path, may be repeated"
+    )]
+    pub admin: Vec<String>,
 }
 
 impl Params {
@@ -211,6 +260,7 @@ struct Lc {
     ref_updates: Pooled<Vec<(Path, Value)>>,
     by_id: FxHashMap<Id, Published>,
     by_path: HashMap<Path, Published>,
+    formulas: FxHashMap<ExprId, Path>,
     events: mpsc::UnboundedSender<LcEvent>,
 }
 
@@ -267,11 +317,13 @@ impl Lc {
             ref_updates: REFS.take(),
             by_id: HashMap::default(),
             by_path: HashMap::new(),
+            formulas: HashMap::default(),
             events,
         }
     }
 
     fn unref(&mut self, expr_id: ExprId) {
+        self.formulas.remove(&expr_id);
         if let Some(refs) = self.forward_refs.remove(&expr_id) {
             for path in refs.refs {
                 remove_eid_from_map(&mut self.refs, path, &expr_id);
@@ -418,6 +470,22 @@ impl Ctx for Lc {
         self.forward_refs.entry(ref_by).or_insert_with(Refs::new).timers.insert(id);
         let _: Result<_, _> = self.events.unbounded_send(LcEvent::Timer(id, timeout));
     }
+
+    // formulas write through the container's own subscriber, so the
+    // writes would be checked against the container's acl
+    // permissions, not those of the people who can change the formula
+    fn write(&mut self, path: &Path, dv: &Dval, value: Value, ref_by: ExprId) {
+        let res = match self.formulas.get(&ref_by) {
+            None => Err(anyhow!("write from an unknown formula")),
+            Some(formula) => self.db.check_formula_acl(formula, path),
+        };
+        match res {
+            Ok(()) => {
+                dv.write(value);
+            }
+            Err(e) => warn!("formula write to {} denied {}", path, e),
+        }
+    }
 }
 
 struct Ref {
@@ -804,6 +872,7 @@ struct ContainerInner {
     api_path: Option<Path>,
     stats: Option<Stats>,
     locked: BTreeMap<Path, bool>,
+    schemas: FxHashMap<Path, Val>,
     ctx: ExecCtx<Lc, UserEv>,
     compiled: FxHashMap<ExprId, Compiled>,
     sub_updates: mpsc::Receiver<Pooled<Vec<(SubId, Event)>>>,
@@ -820,6 +889,12 @@ struct ContainerInner {
     >,
     timer: OptTimer,
     timers: BTreeMap<time::Instant, TimerId>,
+    replica: Option<Replica>,
+}
+
+struct Replica {
+    hidden: bool,
+    promoted: oneshot::Receiver<()>,
 }
 
 impl ContainerInner {
@@ -831,23 +906,45 @@ impl ContainerInner {
             .build()
             .await?;
         publisher.events(publish_events_tx);
-        let (db, db_updates) =
-            Db::new(&params, publisher.clone(), params.api_path.clone())?;
         let subscriber = Subscriber::new(cfg, auth)?;
+        let replication = match params.replicate.as_ref() {
+            None => None,
+            Some(base) => {
+                let mut r =
+                    Replication::new(&publisher, &subscriber, base.clone()).await?;
+                let primary = r.should_be_primary().await?;
+                Some((r, primary))
+            }
+        };
+        let primary = replication.as_ref().map(|(_, p)| *p).unwrap_or(true);
+        // a replica that is promoted will not publish db stats
+        let db_stats = if primary { params.api_path.clone() } else { None };
+        let (db, db_updates) = Db::new(&params, publisher.clone(), db_stats)?;
+        let replica = match replication {
+            None => None,
+            Some((r, primary)) => match r.start(db.clone(), primary) {
+                Role::Primary => None,
+                Role::Replica(promoted) => {
+                    Some(Replica { hidden: params.hidden, promoted })
+                }
+            },
+        };
         let (sub_updates_tx, sub_updates) = mpsc::channel(3);
         let (write_updates_tx, write_updates_rx) = mpsc::channel(3);
         let (bs_tx, bs_rx) = mpsc::unbounded();
         let (api_path, api) = match params.api_path.as_ref() {
-            None => (None, None),
-            Some(api_path) => {
+            Some(api_path) if replica.is_none() => {
                 let api_path = api_path.append("rpcs");
                 let api = rpcs::RpcApi::new(&publisher, &api_path)?;
                 (Some(api_path), Some(api))
             }
+            Some(_) | None => (None, None),
         };
         let stats = match params.api_path.as_ref() {
-            None => None,
-            Some(p) => Some(Stats::new(publisher.clone(), p.clone())),
+            Some(p) if replica.is_none() => {
+                Some(Stats::new(publisher.clone(), p.clone()))
+            }
+            Some(_) | None => None,
         };
         let mut ctx =
             ExecCtx::new(Lc::new(db, subscriber, publisher, sub_updates_tx, bs_tx));
@@ -859,6 +956,7 @@ impl ContainerInner {
             api_path,
             stats,
             locked: BTreeMap::new(),
+            schemas: HashMap::with_hasher(FxBuildHasher::default()),
             roots: Roots(BTreeMap::new()),
             ctx,
             sub_updates,
@@ -872,9 +970,37 @@ impl ContainerInner {
             compiled: HashMap::with_hasher(FxBuildHasher::default()),
             timer: OptTimer { timer: None },
             timers: BTreeMap::new(),
+            replica,
         })
     }
 
+    fn hidden(&self) -> bool {
+        self.replica.as_ref().map(|r| r.hidden).unwrap_or(false)
+    }
+
+    /// This replica was promoted to primary, start accepting writes,
+    /// and publish the api.
+    async fn promote(&mut self) -> Result<()> {
+        if let Some(replica) = self.replica.take() {
+            if replica.hidden {
+                self.init().await?;
+            }
+            if let Some(api_path) = self.params.api_path.as_ref() {
+                let rpcs_path = api_path.append("rpcs");
+                self.api = Some(rpcs::RpcApi::new(&self.ctx.user.publisher, &rpcs_path)?);
+                self.api_path = Some(rpcs_path);
+                let mut stats =
+                    Stats::new(self.ctx.user.publisher.clone(), api_path.clone());
+                let mut batch = self.ctx.user.publisher.start_batch();
+                let _ = stats.set_roots(&mut batch, &self.roots);
+                let _ = stats.set_locked(&mut batch, &self.locked);
+                self.stats = Some(stats);
+                batch.commit(self.params.timeout.map(Duration::from_secs)).await;
+            }
+        }
+        Ok(())
+    }
+
     fn get_root(&self, path: &Path) -> Option<(&Path, &DefaultHandle)> {
         match self
             .roots
@@ -924,6 +1050,8 @@ impl ContainerInner {
         let on_write_expr_id =
             on_write_expr.as_ref().map(|e| e.id).unwrap_or_else(|_| ExprId::new());
         self.ctx.user.current_path = path.clone();
+        self.ctx.user.formulas.insert(expr_id, path.clone());
+        self.ctx.user.formulas.insert(on_write_expr_id, path.clone());
         let scope = Path::dirname(&path)
             .map(|s| Path::from(String::from(s)))
             .unwrap_or_else(Path::root);
@@ -1033,9 +1161,35 @@ impl ContainerInner {
                 DatumKind::Deleted | DatumKind::Invalid => (),
             }
         }
+        for res in self.ctx.user.db.table_schemas() {
+            let (base, _) = res?;
+            let _: Result<()> = self.publish_schema(&mut batch, base);
+        }
         Ok(batch.commit(self.params.timeout.map(Duration::from_secs)).await)
     }
 
+    /// publish the schema of the table at `base` as `base/.schema`,
+    /// or stop publishing it if the table no longer has a schema
+    fn publish_schema(&mut self, batch: &mut UpdateBatch, base: Path) -> Result<()> {
+        match self.ctx.user.db.table_schema(&base)? {
+            None => {
+                self.schemas.remove(&base);
+            }
+            Some(schema) => {
+                let v = schema::schema_to_value(&schema);
+                match self.schemas.get(&base) {
+                    Some(val) => val.update(batch, v),
+                    None => {
+                        let path = base.append(".schema");
+                        let val = self.ctx.user.publisher.publish(path, v)?;
+                        self.schemas.insert(base, val);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
     fn update_expr_ids(
         &mut self,
         batch: &mut UpdateBatch,
@@ -1148,6 +1302,7 @@ impl ContainerInner {
             Ok(expr) => {
                 *expr_id = expr.id;
                 self.ctx.user.current_path = fifo.data_path.clone();
+                self.ctx.user.formulas.insert(*expr_id, fifo.data_path.clone());
                 let scope = Path::dirname(&fifo.data_path)
                     .map(|s| Path::from(String::from(s)))
                     .unwrap_or_else(Path::root);
@@ -1184,6 +1339,7 @@ impl ContainerInner {
             Ok(expr) => {
                 *expr_id = expr.id;
                 self.ctx.user.current_path = fifo.data_path.clone();
+                self.ctx.user.formulas.insert(*expr_id, fifo.data_path.clone());
                 let scope = Path::dirname(&fifo.data_path)
                     .map(|s| Path::from(String::from(s)))
                     .unwrap_or_else(Path::root);
@@ -1208,18 +1364,35 @@ impl ContainerInner {
         for req in writes.drain(..) {
             let reply = req.send_result.map(Sendable::Write);
             refs.clear();
+            let user = self.ctx.user.publisher.user(&req.client);
+            let db = &self.ctx.user.db;
+            macro_rules! or_deny {
+                ($path:expr, $perm:expr) => {
+                    if let Err(e) = db.check_acl(user.as_ref(), $path, $perm) {
+                        if let Some(reply) = reply {
+                            reply.send(Value::Error(Chars::from(format!("{}", e))));
+                        }
+                        continue;
+                    }
+                };
+            }
+            txn.set_user(user.clone());
             match self.ctx.user.by_id.get(&req.id) {
                 None => (), // CR estokes: log
                 Some(Published::Data(p)) => {
+                    or_deny!(&p.path, Permission::Data);
                     txn.set_data(true, p.path.clone(), req.value, reply);
                 }
                 Some(Published::Formula(fifo)) => {
                     let fifo = fifo.clone();
                     if fifo.src.id() == req.id {
+                        or_deny!(&fifo.data_path, Permission::Formula);
                         txn.set_formula(fifo.data_path.clone(), req.value, reply);
                     } else if fifo.on_write.id() == req.id {
+                        or_deny!(&fifo.data_path, Permission::Formula);
                         txn.set_on_write(fifo.data_path.clone(), req.value, reply);
                     } else if fifo.data.id() == req.id {
+                        or_deny!(&fifo.data_path, Permission::Data);
                         if let Some(Compiled::OnWrite(node)) =
                             self.compiled.get_mut(&fifo.on_write_expr_id.lock())
                         {
@@ -1231,6 +1404,7 @@ impl ContainerInner {
                 }
             }
         }
+        txn.set_user(None);
     }
 
     fn is_locked_gen(&self, path: &Path, parent_only: bool) -> bool {
@@ -1263,7 +1437,10 @@ impl ContainerInner {
             }
             Ok(path) => {
                 let name = Path::basename(&path);
-                if name != Some(".formula") && name != Some(".on-write") {
+                if name != Some(".formula")
+                    && name != Some(".on-write")
+                    && name != Some(".schema")
+                {
                     match self.ctx.user.db.lookup(path.as_ref()) {
                         Ok(Some(Datum::Data(v))) => {
                             let _: Result<()> = self.publish_data(path, v);
@@ -1276,7 +1453,7 @@ impl ContainerInner {
                                 .as_ref()
                                 .map(|p| Path::is_parent(p, &path))
                                 .unwrap_or(false);
-                            if !locked && !api {
+                            if !locked && !api && self.replica.is_none() {
                                 let _: Result<()> = self.publish_data(path, Value::Null);
                             };
                         }
@@ -1510,92 +1687,323 @@ impl ContainerInner {
         txn.create_table(path, rows, columns, lock, reply);
     }
 
+    fn history(&self, path: Path) -> Result<Value> {
+        let path = self.check_path(path)?;
+        let history = self.ctx.user.db.history(&path)?;
+        Ok(Value::from(history.iter().map(|h| h.to_value()).collect::<Vec<_>>()))
+    }
+
+    fn as_of(&self, path: Path, ts: DateTime<Utc>) -> Result<Value> {
+        let path = self.check_path(path)?;
+        let state = self.ctx.user.db.as_of(&path, ts)?;
+        let state = state.into_iter().map(|(path, datum)| {
+            let mut v = vec![Value::from(path)];
+            history::datum_to_value(&datum, &mut v);
+            Value::from(v)
+        });
+        Ok(Value::from(state.collect::<Vec<_>>()))
+    }
+
+    fn restore_subtree(
+        &mut self,
+        txn: &mut Txn,
+        path: Path,
+        ts: DateTime<Utc>,
+        reply: Reply,
+    ) {
+        let path = or_reply!(reply, self.check_path(path));
+        txn.restore_subtree(path, ts, reply);
+    }
+
+    fn transaction(
+        &mut self,
+        txn: &mut Txn,
+        preconditions: Vec<(Path, Value)>,
+        ops: Vec<AtomicOp>,
+        reply: Reply,
+    ) {
+        let paths =
+            preconditions.iter().map(|(p, _)| p).chain(ops.iter().map(|op| op.path()));
+        for path in paths {
+            or_reply!(reply, self.check_path(path.clone()));
+        }
+        // the db doesn't know the values of formulas, so preconditions
+        // on them are checked against their published values now
+        let mut checked = Vec::with_capacity(preconditions.len());
+        for (path, expected) in preconditions {
+            match self.ctx.user.by_path.get(&path) {
+                Some(Published::Formula(fifo)) if fifo.data_path == path => {
+                    let current = self
+                        .ctx
+                        .user
+                        .publisher
+                        .current(&fifo.data.id())
+                        .unwrap_or(Value::Null);
+                    if current != expected {
+                        if let Some(reply) = reply {
+                            let e = format!(
+                                "precondition failed, {} is {}",
+                                path, current
+                            );
+                            reply.send(Value::Error(Chars::from(e)));
+                        }
+                        return;
+                    }
+                }
+                Some(_) | None => checked.push((path, expected)),
+            }
+        }
+        txn.atomic(checked, ops, reply);
+    }
+
+    fn set_acl(&mut self, txn: &mut Txn, path: Path, acl: Acl, reply: Reply) {
+        let path = or_reply!(reply, self.check_path(path));
+        txn.set_acl(path, acl, reply);
+    }
+
+    fn remove_acl(&mut self, txn: &mut Txn, path: Path, reply: Reply) {
+        let path = or_reply!(reply, self.check_path(path));
+        txn.remove_acl(path, reply);
+    }
+
+    fn list_acls(&self) -> Result<Value> {
+        let acls = self
+            .ctx
+            .user
+            .db
+            .acls()
+            .map(|r| r.map(|(path, acl)| acl.to_value(&path)))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Value::from(acls))
+    }
+
+    fn set_table_schema(
+        &mut self,
+        txn: &mut Txn,
+        path: Path,
+        schema: Vec<ColumnSchema>,
+        reply: Reply,
+    ) {
+        let path = or_reply!(reply, self.check_path(path));
+        txn.set_table_schema(path, schema, reply);
+    }
+
+    fn import(&mut self, txn: &mut Txn, path: Path, import: Import, reply: Reply) {
+        let path = or_reply!(reply, self.check_path(path));
+        import.add_to_txn(txn, path, reply);
+    }
+
+    fn export(&self, path: Path) -> Result<Value> {
+        let path = self.check_path(path)?;
+        Ok(Value::from(self.ctx.user.db.export(&path)?))
+    }
+
+    fn set_ttl(&mut self, txn: &mut Txn, path: Path, ttl: Ttl, reply: Reply) {
+        let path = or_reply!(reply, self.check_path(path));
+        txn.set_ttl(path, ttl, reply);
+    }
+
+    fn remove_ttl(&mut self, txn: &mut Txn, path: Path, reply: Reply) {
+        let path = or_reply!(reply, self.check_path(path));
+        txn.remove_ttl(path, reply);
+    }
+
+    fn list_ttls(&self) -> Result<Value> {
+        let ttls = self
+            .ctx
+            .user
+            .db
+            .ttls()
+            .map(|r| r.map(|(path, ttl)| ttl.to_value(&path)))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Value::from(ttls))
+    }
+
+    /// queue the expiry of every path whose ttl has run out
+    fn process_expiry(&mut self, txn: &mut Txn) {
+        match self.ctx.user.db.expired(Utc::now()) {
+            Err(e) => error!("failed to find expired paths {}", e),
+            Ok(expired) => {
+                txn.set_user(None);
+                for (path, version) in expired {
+                    txn.expire(path, version)
+                }
+            }
+        }
+    }
+
+    fn version(&self, path: Path) -> Result<Value> {
+        let path = self.check_path(path)?;
+        Ok(Value::from(self.ctx.user.db.version(&path)?))
+    }
+
+    fn set_data_if(
+        &mut self,
+        txn: &mut Txn,
+        path: Path,
+        value: Value,
+        version: u64,
+        reply: Reply,
+    ) {
+        let path = or_reply!(reply, self.check_path(path));
+        txn.set_data_if(path, value, version, reply);
+    }
+
     fn process_rpc_requests(&mut self, txn: &mut Txn, reqs: &mut Vec<RpcRequest>) {
-        let mut process_non_packed = |reply: Sendable, req: RpcRequestKind| match req {
-            RpcRequestKind::Delete(path) => self.delete_path(txn, path, Some(reply)),
-            RpcRequestKind::DeleteSubtree(path) => {
-                self.delete_subtree(txn, path, Some(reply))
-            }
-            RpcRequestKind::LockSubtree(path) => {
-                self.lock_subtree(txn, path, Some(reply))
-            }
-            RpcRequestKind::UnlockSubtree(path) => {
-                self.unlock_subtree(txn, path, Some(reply))
-            }
-            RpcRequestKind::SetData { path, value } => {
-                self.set_data(txn, path, value, Some(reply))
-            }
-            RpcRequestKind::SetFormula { path, formula, on_write } => {
-                self.set_formula(txn, path, formula, on_write, Some(reply))
-            }
-            RpcRequestKind::CreateSheet {
-                path,
-                rows,
-                columns,
-                max_rows,
-                max_columns,
-                lock,
-            } => self.create_sheet(
-                txn,
-                path,
-                rows,
-                columns,
-                max_rows,
-                max_columns,
-                lock,
-                Some(reply),
-            ),
-            RpcRequestKind::AddSheetRows(path, rows) => {
-                txn.add_sheet_rows(path, rows, Some(reply));
-            }
-            RpcRequestKind::AddSheetCols(path, cols) => {
-                txn.add_sheet_columns(path, cols, Some(reply));
-            }
-            RpcRequestKind::DelSheetRows(path, rows) => {
-                txn.del_sheet_rows(path, rows, Some(reply));
-            }
-            RpcRequestKind::DelSheetCols(path, cols) => {
-                txn.del_sheet_columns(path, cols, Some(reply));
-            }
-            RpcRequestKind::CreateTable { path, rows, columns, lock } => {
-                self.create_table(txn, path, rows, columns, lock, Some(reply))
-            }
-            RpcRequestKind::AddTableRows(path, rows) => {
-                txn.add_table_rows(path, rows, Some(reply));
-            }
-            RpcRequestKind::AddTableCols(path, cols) => {
-                txn.add_table_columns(path, cols, Some(reply));
-            }
-            RpcRequestKind::DelTableRows(path, rows) => {
-                txn.del_table_rows(path, rows, Some(reply));
-            }
-            RpcRequestKind::DelTableCols(path, cols) => {
-                txn.del_table_columns(path, cols, Some(reply));
-            }
-            RpcRequestKind::AddRoot(path) => {
-                txn.add_root(path, Some(reply));
-            }
-            RpcRequestKind::DelRoot(path) => {
-                txn.del_root(path, Some(reply));
-            }
-            RpcRequestKind::Packed(_) => unreachable!(),
+        let publisher = self.ctx.user.publisher.clone();
+        let db = self.ctx.user.db.clone();
+        let admin = self.params.admin.clone();
+        let check_acls = |user: Option<&UserInfo>, req: &RpcRequestKind| {
+            req.check_acls(&db, &admin, user)
         };
+        let mut process_non_packed =
+            |txn: &mut Txn, reply: Sendable, req: RpcRequestKind| match req {
+                RpcRequestKind::Delete(path) => self.delete_path(txn, path, Some(reply)),
+                RpcRequestKind::DeleteSubtree(path) => {
+                    self.delete_subtree(txn, path, Some(reply))
+                }
+                RpcRequestKind::LockSubtree(path) => {
+                    self.lock_subtree(txn, path, Some(reply))
+                }
+                RpcRequestKind::UnlockSubtree(path) => {
+                    self.unlock_subtree(txn, path, Some(reply))
+                }
+                RpcRequestKind::SetData { path, value } => {
+                    self.set_data(txn, path, value, Some(reply))
+                }
+                RpcRequestKind::SetFormula { path, formula, on_write } => {
+                    self.set_formula(txn, path, formula, on_write, Some(reply))
+                }
+                RpcRequestKind::CreateSheet {
+                    path,
+                    rows,
+                    columns,
+                    max_rows,
+                    max_columns,
+                    lock,
+                } => self.create_sheet(
+                    txn,
+                    path,
+                    rows,
+                    columns,
+                    max_rows,
+                    max_columns,
+                    lock,
+                    Some(reply),
+                ),
+                RpcRequestKind::AddSheetRows(path, rows) => {
+                    txn.add_sheet_rows(path, rows, Some(reply));
+                }
+                RpcRequestKind::AddSheetCols(path, cols) => {
+                    txn.add_sheet_columns(path, cols, Some(reply));
+                }
+                RpcRequestKind::DelSheetRows(path, rows) => {
+                    txn.del_sheet_rows(path, rows, Some(reply));
+                }
+                RpcRequestKind::DelSheetCols(path, cols) => {
+                    txn.del_sheet_columns(path, cols, Some(reply));
+                }
+                RpcRequestKind::CreateTable { path, rows, columns, lock } => {
+                    self.create_table(txn, path, rows, columns, lock, Some(reply))
+                }
+                RpcRequestKind::AddTableRows(path, rows) => {
+                    txn.add_table_rows(path, rows, Some(reply));
+                }
+                RpcRequestKind::AddTableCols(path, cols) => {
+                    txn.add_table_columns(path, cols, Some(reply));
+                }
+                RpcRequestKind::DelTableRows(path, rows) => {
+                    txn.del_table_rows(path, rows, Some(reply));
+                }
+                RpcRequestKind::DelTableCols(path, cols) => {
+                    txn.del_table_columns(path, cols, Some(reply));
+                }
+                RpcRequestKind::AddRoot(path) => {
+                    txn.add_root(path, Some(reply));
+                }
+                RpcRequestKind::DelRoot(path) => {
+                    txn.del_root(path, Some(reply));
+                }
+                RpcRequestKind::History(path) => match self.history(path) {
+                    Ok(v) => reply.send(v),
+                    Err(e) => reply.send(Value::Error(Chars::from(format!("{}", e)))),
+                },
+                RpcRequestKind::AsOf(path, ts) => match self.as_of(path, ts) {
+                    Ok(v) => reply.send(v),
+                    Err(e) => reply.send(Value::Error(Chars::from(format!("{}", e)))),
+                },
+                RpcRequestKind::RestoreSubtree(path, ts) => {
+                    self.restore_subtree(txn, path, ts, Some(reply))
+                }
+                RpcRequestKind::Transaction { preconditions, ops } => {
+                    self.transaction(txn, preconditions, ops, Some(reply))
+                }
+                RpcRequestKind::Version(path) => match self.version(path) {
+                    Ok(v) => reply.send(v),
+                    Err(e) => reply.send(Value::Error(Chars::from(format!("{}", e)))),
+                },
+                RpcRequestKind::SetDataIf { path, value, version } => {
+                    self.set_data_if(txn, path, value, version, Some(reply))
+                }
+                RpcRequestKind::SetAcl(path, acl) => {
+                    self.set_acl(txn, path, acl, Some(reply))
+                }
+                RpcRequestKind::RemoveAcl(path) => {
+                    self.remove_acl(txn, path, Some(reply))
+                }
+                RpcRequestKind::ListAcls => match self.list_acls() {
+                    Ok(v) => reply.send(v),
+                    Err(e) => reply.send(Value::Error(Chars::from(format!("{}", e)))),
+                },
+                RpcRequestKind::SetTableSchema(path, schema) => {
+                    self.set_table_schema(txn, path, schema, Some(reply))
+                }
+                RpcRequestKind::Import(path, import) => {
+                    self.import(txn, path, import, Some(reply))
+                }
+                RpcRequestKind::Export(path) => match self.export(path) {
+                    Ok(v) => reply.send(v),
+                    Err(e) => reply.send(Value::Error(Chars::from(format!("{}", e)))),
+                },
+                RpcRequestKind::SetTtl(path, ttl) => {
+                    self.set_ttl(txn, path, ttl, Some(reply))
+                }
+                RpcRequestKind::RemoveTtl(path) => {
+                    self.remove_ttl(txn, path, Some(reply))
+                }
+                RpcRequestKind::ListTtls => match self.list_ttls() {
+                    Ok(v) => reply.send(v),
+                    Err(e) => reply.send(Value::Error(Chars::from(format!("{}", e)))),
+                },
+                RpcRequestKind::Packed(_) => unreachable!(),
+            };
         for mut req in reqs.drain(..) {
+            let user = publisher.user(&req.client);
+            txn.set_user(user.clone());
             match req.kind {
                 RpcRequestKind::Packed(reqs) => {
                     let res = Arc::new(Mutex::new(Value::Null));
                     for req in reqs {
                         let reply = Sendable::Packed(res.clone());
-                        process_non_packed(reply, req)
+                        match check_acls(user.as_ref(), &req) {
+                            Ok(()) => process_non_packed(txn, reply, req),
+                            Err(e) => {
+                                reply.send(Value::Error(Chars::from(format!("{}", e))))
+                            }
+                        }
                     }
                     req.reply.send(mem::replace(&mut *res.lock(), Value::Null));
                 }
                 k => {
                     let reply = Sendable::Rpc(req.reply);
-                    process_non_packed(reply, k)
+                    match check_acls(user.as_ref(), &k) {
+                        Ok(()) => process_non_packed(txn, reply, k),
+                        Err(e) => reply.send(Value::Error(Chars::from(format!("{}", e)))),
+                    }
                 }
             }
         }
+        txn.set_user(None);
     }
 
     fn remove_deleted_published(&mut self, batch: &mut UpdateBatch, path: &Path) {
@@ -1669,6 +2077,9 @@ impl ContainerInner {
             roots = true;
             self.roots.remove(&path);
         }
+        for base in update.schemas.drain(..) {
+            let _: Result<()> = self.publish_schema(batch, base);
+        }
         for (path, value) in update.data.drain(..) {
             match value {
                 UpdateKind::Updated(v) => {
@@ -1795,6 +2206,7 @@ impl ContainerInner {
 
     async fn run(mut self, mut cmd: mpsc::UnboundedReceiver<ToInner>) -> Result<()> {
         let mut gc_rpcs = time::interval(Duration::from_secs(60));
+        let mut expire = time::interval(Duration::from_secs(1));
         let mut rpcbatch = Vec::new();
         let mut batch = self.ctx.user.publisher.start_batch();
         let mut txn = Txn::new();
@@ -1804,6 +2216,15 @@ impl ContainerInner {
                 None => future::pending().await,
             }
         }
+        async fn promoted(replica: &mut Option<Replica>) {
+            match replica {
+                Some(r) => match (&mut r.promoted).await {
+                    Ok(()) => (),
+                    Err(_) => future::pending().await,
+                },
+                None => future::pending().await,
+            }
+        }
         loop {
             select_biased! {
                 r = self.publish_events.select_next_some() => {
@@ -1828,8 +2249,20 @@ impl ContainerInner {
                 _ = gc_rpcs.tick().fuse() => {
                     self.gc_rpcs();
                 },
+                _ = expire.tick().fuse() => {
+                    // replicas expire paths when the primary does
+                    if self.replica.is_none() {
+                        self.process_expiry(&mut txn);
+                    }
+                },
                 u = self.db_updates.select_next_some() => {
-                    self.process_update(&mut batch, u);
+                    if !self.hidden() {
+                        self.process_update(&mut batch, u);
+                    }
+                },
+                () = promoted(&mut self.replica).fuse() => {
+                    info!("promoted to primary");
+                    self.promote().await?
                 },
                 c = cmd.select_next_some() => {
                     self.process_command(&mut txn, c);
@@ -1840,7 +2273,12 @@ impl ContainerInner {
                 complete => break,
             }
             if txn.dirty() {
-                self.ctx.user.db.commit(mem::replace(&mut txn, Txn::new()));
+                let t = mem::replace(&mut txn, Txn::new());
+                if self.replica.is_some() {
+                    t.reject("read only replica")
+                } else {
+                    self.ctx.user.db.commit(t)
+                }
             }
             if batch.len() > 0 {
                 let timeout = self.params.timeout.map(Duration::from_secs);
@@ -1875,7 +2313,9 @@ impl Container {
     ) -> Result<Container> {
         let (w, r) = mpsc::unbounded();
         let mut c = ContainerInner::new(cfg, auth, params).await?;
-        c.init().await?;
+        if !c.hidden() {
+            c.init().await?;
+        }
         task::spawn(async move {
             match c.run(r).await {
                 Err(e) => error!("container stopped with error {}", e),
@@ -0,0 +1,255 @@
+use crate::db::{Datum, Db, ReplMsg, Txn};
+use anyhow::Result;
+use futures::{
+    channel::{mpsc, oneshot},
+    prelude::*,
+};
+use log::{error, info, warn};
+use netidx::{
+    path::Path,
+    publisher::Publisher,
+    subscriber::Subscriber,
+    utils::{BatchItem, Batched},
+};
+use netidx_protocols::{cluster::Cluster, pack_channel};
+use std::{mem, time::Duration};
+use tokio::{task, time};
+
+/// The role of a container that is replicating.
+pub(super) enum Role {
+    Primary,
+    /// A read only replica, the receiver will be triggered if the
+    /// replica is promoted to primary.
+    Replica(oneshot::Receiver<()>),
+}
+
+/// Replication between a set of containers sharing a base path. All
+/// the members join a cluster at `base/cluster`, and the primary
+/// publishes a channel at `base/primary`. Each replica connects to
+/// the primary, receives a snapshot of it's db, and then every
+/// transaction the primary commits, in commit order. A replica that
+/// falls too far behind is disconnected, and resyncs from a new
+/// snapshot when it reconnects. When the primary disappears the
+/// member the cluster elects as primary is promoted.
+pub(super) struct Replication {
+    base: Path,
+    publisher: Publisher,
+    subscriber: Subscriber,
+    cluster: Cluster<()>,
+}
+
+impl Replication {
+    pub(super) async fn new(
+        publisher: &Publisher,
+        subscriber: &Subscriber,
+        base: Path,
+    ) -> Result<Self> {
+        let cluster =
+            Cluster::new(publisher, subscriber.clone(), base.append("cluster"), 0)
+                .await?;
+        Ok(Self {
+            base,
+            publisher: publisher.clone(),
+            subscriber: subscriber.clone(),
+            cluster,
+        })
+    }
+
+    /// Return true if there is no primary, and the cluster elects us
+    /// to become the primary.
+    pub(super) async fn should_be_primary(&mut self) -> Result<bool> {
+        self.cluster.poll_members().await?;
+        let primary = self.base.append("primary");
+        let published = self.subscriber.resolver().list(self.base.clone()).await?;
+        Ok(!published.iter().any(|p| p == &primary) && self.cluster.primary())
+    }
+
+    /// Start replicating `db` in the specified role. If `primary` is
+    /// false, then `db` will follow the primary until it is
+    /// promoted.
+    pub(super) fn start(self, db: Db, primary: bool) -> Role {
+        if primary {
+            task::spawn(async move {
+                if let Err(e) = self.serve(db).await {
+                    error!("replication primary stopped with error {}", e)
+                }
+            });
+            Role::Primary
+        } else {
+            let (tx, rx) = oneshot::channel();
+            task::spawn(async move {
+                if let Err(e) = self.follow_primary(db, tx).await {
+                    error!("replication stopped with error {}", e)
+                }
+            });
+            Role::Replica(rx)
+        }
+    }
+
+    async fn serve(self, db: Db) -> Result<()> {
+        let path = self.base.append("primary");
+        let mut listener =
+            pack_channel::server::Listener::new(&self.publisher, None, path).await?;
+        loop {
+            let con = listener.accept().await?;
+            let msgs = db.replicate();
+            task::spawn(async move {
+                match forward(con, msgs).await {
+                    Ok(()) => info!("replica disconnected"),
+                    Err(e) => info!("replica disconnected {}", e),
+                }
+            });
+        }
+    }
+
+    async fn follow_primary(
+        mut self,
+        db: Db,
+        promoted: oneshot::Sender<()>,
+    ) -> Result<()> {
+        loop {
+            match self.should_be_primary().await {
+                Err(e) => warn!("failed to check the primary {}", e),
+                Ok(false) => (),
+                Ok(true) => {
+                    info!("promoted to primary");
+                    let _: Result<_, _> = promoted.send(());
+                    break self.serve(db).await;
+                }
+            }
+            if let Err(e) = self.follow(&db).await {
+                warn!("replication from primary failed {}", e)
+            }
+            time::sleep(Duration::from_secs(1)).await
+        }
+    }
+
+    async fn follow(&self, db: &Db) -> Result<()> {
+        let path = self.base.append("primary");
+        let con =
+            pack_channel::client::Connection::connect(&self.subscriber, path).await?;
+        let mut snapshot: Option<Txn> = None;
+        loop {
+            let mut res = Ok(());
+            con.recv(|m: ReplMsg| {
+                res = apply(db, &mut snapshot, m);
+                res.is_ok()
+            })
+            .await?;
+            res?
+        }
+    }
+}
+
+async fn forward(
+    con: pack_channel::server::Connection,
+    msgs: mpsc::Receiver<Vec<ReplMsg>>,
+) -> Result<()> {
+    let mut msgs = Batched::new(msgs, 10_000);
+    let mut batch = con.start_batch();
+    while let Some(m) = msgs.next().await {
+        match m {
+            BatchItem::InBatch(msgs) => {
+                for m in msgs {
+                    batch.queue(&m)?
+                }
+            }
+            BatchItem::EndBatch => {
+                con.send(mem::replace(&mut batch, con.start_batch())).await?
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Apply a message from the primary. The snapshot is accumulated in
+/// a single transaction, so readers of the replica never see a
+/// partial snapshot.
+pub(super) fn apply(db: &Db, snapshot: &mut Option<Txn>, m: ReplMsg) -> Result<()> {
+    match m {
+        ReplMsg::SnapshotStart => {
+            // the snapshot replaces everything the replica had
+            let mut txn = Txn::new();
+            for r in db.roots() {
+                let root = r?;
+                txn.remove_subtree(root.clone(), None);
+                txn.del_root(root, None);
+            }
+            for r in db.acls() {
+                txn.remove_acl(r?.0, None)
+            }
+            for r in db.ttls() {
+                txn.remove_ttl(r?.0, None)
+            }
+            for r in db.table_schemas() {
+                txn.set_table_schema(r?.0, vec![], None)
+            }
+            *snapshot = Some(txn);
+        }
+        ReplMsg::Roots(roots) => {
+            if let Some(txn) = snapshot {
+                for root in roots {
+                    txn.add_root(root, None);
+                }
+            }
+        }
+        ReplMsg::Locked(locked) => {
+            if let Some(txn) = snapshot {
+                for (path, locked) in locked {
+                    if locked {
+                        txn.set_locked(path, None)
+                    } else {
+                        txn.set_unlocked(path, None)
+                    }
+                }
+            }
+        }
+        ReplMsg::Acls(acls) => {
+            if let Some(txn) = snapshot {
+                for (path, acl) in acls {
+                    txn.set_acl(path, acl, None)
+                }
+            }
+        }
+        ReplMsg::Ttls(ttls) => {
+            if let Some(txn) = snapshot {
+                for (path, ttl) in ttls {
+                    txn.set_ttl(path, ttl, None)
+                }
+            }
+        }
+        ReplMsg::Data(data) => {
+            if let Some(txn) = snapshot {
+                for (path, datum) in data {
+                    match datum {
+                        Datum::Deleted => (),
+                        Datum::Data(v) => txn.set_data(true, path, v, None),
+                        Datum::Formula(f, w) => {
+                            txn.set_formula(path.clone(), f, None);
+                            txn.set_on_write(path, w, None);
+                        }
+                    }
+                }
+            }
+        }
+        ReplMsg::Schemas(schemas) => {
+            if let Some(txn) = snapshot {
+                for (base, schema) in schemas {
+                    txn.set_table_schema(base, schema, None)
+                }
+            }
+        }
+        ReplMsg::Versions { clear, versions } => {
+            if let Some(txn) = snapshot {
+                txn.set_versions(clear, versions)
+            }
+        }
+        ReplMsg::SnapshotEnd => {
+            if let Some(txn) = snapshot.take() {
+                db.commit(txn)
+            }
+        }
+        ReplMsg::Txn(b) => db.commit(Txn::decode_from_primary(b)?),
+    }
+    Ok(())
+}
@@ -1,10 +1,24 @@
-use anyhow::Result;
+use crate::{
+    acl::{self, Acl, Permission},
+    db::{AtomicOp, Db},
+    import::{Import, ImportFormat},
+    schema::ColumnSchema,
+    ttl::{Expiry, Ttl},
+};
+use anyhow::{bail, Result};
 use arcstr::ArcStr;
+use chrono::prelude::*;
 use futures::channel::mpsc;
 use netidx::{
-    chars::Chars, path::Path, publisher::Publisher, subscriber::Value, utils::Batched,
+    chars::Chars,
+    path::Path,
+    protocol::resolver::UserInfo,
+    publisher::{ClId, Publisher},
+    subscriber::Value,
+    utils::Batched,
 };
 use netidx_protocols::rpc::server::{ArgSpec, Proc, RpcCall, RpcReply};
+use std::time::Duration;
 
 pub(super) enum RpcRequestKind {
     Delete(Path),
@@ -44,11 +58,152 @@ pub(super) enum RpcRequestKind {
     DelTableCols(Path, Vec<Chars>),
     AddRoot(Path),
     DelRoot(Path),
+    History(Path),
+    AsOf(Path, DateTime<Utc>),
+    RestoreSubtree(Path, DateTime<Utc>),
+    Transaction {
+        preconditions: Vec<(Path, Value)>,
+        ops: Vec<AtomicOp>,
+    },
+    Version(Path),
+    SetDataIf {
+        path: Path,
+        value: Value,
+        version: u64,
+    },
+    SetAcl(Path, Acl),
+    RemoveAcl(Path),
+    ListAcls,
+    SetTableSchema(Path, Vec<ColumnSchema>),
+    Import(Path, Import),
+    Export(Path),
+    SetTtl(Path, Ttl),
+    RemoveTtl(Path),
+    ListTtls,
     Packed(Vec<Self>),
 }
 
+impl RpcRequestKind {
+    /// The paths this request changes, and the permission it needs
+    /// on each of them.
+    pub(super) fn permissions(&self) -> Vec<(&Path, Permission)> {
+        use RpcRequestKind::*;
+        match self {
+            SetData { path, .. } | SetDataIf { path, .. } => {
+                vec![(path, Permission::Data)]
+            }
+            SetFormula { path, .. } => vec![(path, Permission::Formula)],
+            Delete(path)
+            | DeleteSubtree(path)
+            | LockSubtree(path)
+            | UnlockSubtree(path)
+            | CreateSheet { path, .. }
+            | AddSheetRows(path, _)
+            | AddSheetCols(path, _)
+            | DelSheetRows(path, _)
+            | DelSheetCols(path, _)
+            | CreateTable { path, .. }
+            | AddTableRows(path, _)
+            | AddTableCols(path, _)
+            | DelTableRows(path, _)
+            | DelTableCols(path, _)
+            | AddRoot(path)
+            | DelRoot(path)
+            | RestoreSubtree(path, _)
+            | SetAcl(path, _)
+            | RemoveAcl(path)
+            | SetTableSchema(path, _)
+            | SetTtl(path, _)
+            | RemoveTtl(path) => vec![(path, Permission::Structure)],
+            Import(path, _) => {
+                vec![(path, Permission::Structure), (path, Permission::Data)]
+            }
+            Transaction { ops, .. } => ops
+                .iter()
+                .map(|op| match op {
+                    AtomicOp::SetData(path, _) => (path, Permission::Data),
+                    AtomicOp::SetFormula(path, _, _) => (path, Permission::Formula),
+                    AtomicOp::Remove(path) => (path, Permission::Structure),
+                })
+                .collect(),
+            History(_)
+            | AsOf(_, _)
+            | Version(_)
+            | ListAcls
+            | Export(_)
+            | ListTtls
+            | Packed(_) => vec![],
+        }
+    }
+
+    /// Fail if `user` may not make this request. `admin` are the
+    /// users and groups that may set the first acl on a path.
+    pub(super) fn check_acls(
+        &self,
+        db: &Db,
+        admin: &[String],
+        user: Option<&UserInfo>,
+    ) -> Result<()> {
+        if let RpcRequestKind::SetAcl(path, _) = self {
+            if db.acl(path)?.is_none() && !acl::is_member(admin, user) {
+                bail!("only an admin may set the first acl on {}", path)
+            }
+        }
+        self.permissions().into_iter().try_for_each(|(path, perm)| {
+            if self.subtree() {
+                db.check_subtree_acl(user, path, perm)
+            } else {
+                db.check_acl(user, path, perm)
+            }
+        })
+    }
+
+    /// True if the request changes everything under it's paths, in
+    /// which case every acl below them must allow it too.
+    fn subtree(&self) -> bool {
+        use RpcRequestKind::*;
+        match self {
+            DeleteSubtree(_)
+            | LockSubtree(_)
+            | UnlockSubtree(_)
+            | CreateSheet { .. }
+            | AddSheetRows(_, _)
+            | AddSheetCols(_, _)
+            | DelSheetRows(_, _)
+            | DelSheetCols(_, _)
+            | CreateTable { .. }
+            | AddTableRows(_, _)
+            | AddTableCols(_, _)
+            | DelTableRows(_, _)
+            | DelTableCols(_, _)
+            | DelRoot(_)
+            | RestoreSubtree(_, _)
+            | SetTableSchema(_, _)
+            | Import(_, _)
+            | SetTtl(_, _)
+            | RemoveTtl(_) => true,
+            Delete(_)
+            | SetData { .. }
+            | SetFormula { .. }
+            | AddRoot(_)
+            | History(_)
+            | AsOf(_, _)
+            | Transaction { .. }
+            | Version(_)
+            | SetDataIf { .. }
+            | SetAcl(_, _)
+            | RemoveAcl(_)
+            | ListAcls
+            | Export(_)
+            | ListTtls
+            | Packed(_) => false,
+        }
+    }
+}
+
 pub(super) struct RpcRequest {
     pub(super) kind: RpcRequestKind,
+    pub(super) client: ClId,
     pub(super) reply: RpcReply,
 }
 
@@ -71,6 +226,21 @@ pub(super) struct RpcApi {
     _del_table_cols: Proc,
     _add_root: Proc,
     _del_root: Proc,
+    _history: Proc,
+    _as_of: Proc,
+    _restore_subtree: Proc,
+    _transaction: Proc,
+    _version: Proc,
+    _set_data_if: Proc,
+    _set_acl: Proc,
+    _remove_acl: Proc,
+    _list_acls: Proc,
+    _set_table_schema: Proc,
+    _import: Proc,
+    _export: Proc,
+    _set_ttl: Proc,
+    _remove_ttl: Proc,
+    _list_ttls: Proc,
     pub(super) rx: Batched<mpsc::Receiver<RpcRequest>>,
 }
 
@@ -108,6 +278,23 @@ impl RpcApi {
             start_del_table_cols_rpc(&publisher, &base_path, tx.clone())?;
         let _add_root = start_add_root_rpc(&publisher, &base_path, tx.clone())?;
         let _del_root = start_del_root_rpc(&publisher, &base_path, tx.clone())?;
+        let _history = start_history_rpc(&publisher, &base_path, tx.clone())?;
+        let _as_of = start_as_of_rpc(&publisher, &base_path, tx.clone())?;
+        let _restore_subtree =
+            start_restore_subtree_rpc(&publisher, &base_path, tx.clone())?;
+        let _transaction = start_transaction_rpc(&publisher, &base_path, tx.clone())?;
+        let _version = start_version_rpc(&publisher, &base_path, tx.clone())?;
+        let _set_data_if = start_set_data_if_rpc(&publisher, &base_path, tx.clone())?;
+        let _set_acl = start_set_acl_rpc(&publisher, &base_path, tx.clone())?;
+        let _remove_acl = start_remove_acl_rpc(&publisher, &base_path, tx.clone())?;
+        let _list_acls = start_list_acls_rpc(&publisher, &base_path, tx.clone())?;
+        let _set_table_schema =
+            start_set_table_schema_rpc(&publisher, &base_path, tx.clone())?;
+        let _import = start_import_rpc(&publisher, &base_path, tx.clone())?;
+        let _export = start_export_rpc(&publisher, &base_path, tx.clone())?;
+        let _set_ttl = start_set_ttl_rpc(&publisher, &base_path, tx.clone())?;
+        let _remove_ttl = start_remove_ttl_rpc(&publisher, &base_path, tx.clone())?;
+        let _list_ttls = start_list_ttls_rpc(&publisher, &base_path, tx.clone())?;
         Ok(RpcApi {
             _delete_path_rpc,
             _delete_subtree_rpc,
@@ -127,6 +314,21 @@ impl RpcApi {
             _del_table_cols,
             _add_root,
             _del_root,
+            _history,
+            _as_of,
+            _restore_subtree,
+            _transaction,
+            _version,
+            _set_data_if,
+            _set_acl,
+            _remove_acl,
+            _list_acls,
+            _set_table_schema,
+            _import,
+            _export,
+            _set_ttl,
+            _remove_ttl,
+            _list_ttls,
             rx: Batched::new(rx, 1_000_000),
         })
     }
@@ -145,10 +347,18 @@ fn start_path_arg_rpc(
         if path.len() == 0 {
             rpc_err!(c.reply, "expected at least 1 path")
         } else if path.len() == 1 {
-            Some(RpcRequest { kind: f(path.pop().unwrap()), reply: c.reply })
+            Some(RpcRequest {
+                kind: f(path.pop().unwrap()),
+                client: c.client,
+                reply: c.reply,
+            })
         } else {
             let reqs = path.into_iter().map(f).collect();
-            Some(RpcRequest { kind: RpcRequestKind::Packed(reqs), reply: c.reply })
+            Some(RpcRequest {
+                kind: RpcRequestKind::Packed(reqs),
+                client: c.client,
+                reply: c.reply,
+            })
         }
     };
     define_rpc!(
@@ -267,6 +477,7 @@ pub(super) fn start_set_data_rpc(
             rpc_err!(c.reply, "expected at least 1 path")
         } else if path.len() == 1 {
             Some(RpcRequest {
+                client: c.client,
                 reply: c.reply,
                 kind: RpcRequestKind::SetData { path: path.pop().unwrap(), value },
             })
@@ -275,7 +486,11 @@ pub(super) fn start_set_data_rpc(
                 .into_iter()
                 .map(|path| RpcRequestKind::SetData { path, value: value.clone() })
                 .collect();
-            Some(RpcRequest { reply: c.reply, kind: RpcRequestKind::Packed(reqs) })
+            Some(RpcRequest {
+                client: c.client,
+                reply: c.reply,
+                kind: RpcRequestKind::Packed(reqs),
+            })
         }
     }
     define_rpc!(
@@ -305,7 +520,7 @@ pub(super) fn start_set_formula_rpc(
         } else if path.len() == 1 {
             let path = path.pop().unwrap();
             let kind = RpcRequestKind::SetFormula { path, formula, on_write };
-            Some(RpcRequest { reply: c.reply, kind })
+            Some(RpcRequest { client: c.client, reply: c.reply, kind })
         } else {
             let reqs = path
                 .into_iter()
@@ -315,7 +530,11 @@ pub(super) fn start_set_formula_rpc(
                     on_write: on_write.clone(),
                 })
                 .collect();
-            Some(RpcRequest { reply: c.reply, kind: RpcRequestKind::Packed(reqs) })
+            Some(RpcRequest {
+                client: c.client,
+                reply: c.reply,
+                kind: RpcRequestKind::Packed(reqs),
+            })
         }
     }
     define_rpc!(
@@ -356,7 +575,7 @@ pub(super) fn start_create_sheet_rpc(
             max_columns,
             lock,
         };
-        Some(RpcRequest { reply: c.reply, kind })
+        Some(RpcRequest { client: c.client, reply: c.reply, kind })
     }
     define_rpc!(
         publisher,
@@ -380,7 +599,7 @@ pub(super) fn start_add_sheet_rows_rpc(
 ) -> Result<Proc> {
     fn map(c: RpcCall, path: Path, rows: usize) -> Option<RpcRequest> {
         let kind = RpcRequestKind::AddSheetRows(path, rows);
-        Some(RpcRequest { reply: c.reply, kind })
+        Some(RpcRequest { client: c.client, reply: c.reply, kind })
     }
     define_rpc!(
         publisher,
@@ -400,7 +619,7 @@ pub(super) fn start_add_sheet_cols_rpc(
 ) -> Result<Proc> {
     fn map(c: RpcCall, path: Path, columns: usize) -> Option<RpcRequest> {
         let kind = RpcRequestKind::AddSheetCols(path, columns);
-        Some(RpcRequest { kind, reply: c.reply })
+        Some(RpcRequest { kind, client: c.client, reply: c.reply })
     }
     define_rpc!(
         publisher,
@@ -420,7 +639,7 @@ pub(super) fn start_del_sheet_rows_rpc(
 ) -> Result<Proc> {
     fn map(c: RpcCall, path: Path, rows: usize) -> Option<RpcRequest> {
         let kind = RpcRequestKind::DelSheetRows(path, rows);
-        Some(RpcRequest { kind, reply: c.reply })
+        Some(RpcRequest { kind, client: c.client, reply: c.reply })
     }
     define_rpc!(
         publisher,
@@ -440,7 +659,7 @@ pub(super) fn start_del_sheet_cols_rpc(
 ) -> Result<Proc> {
     fn map(c: RpcCall, path: Path, columns: usize) -> Option<RpcRequest> {
         let kind = RpcRequestKind::DelSheetCols(path, columns);
-        Some(RpcRequest { kind, reply: c.reply })
+        Some(RpcRequest { kind, client: c.client, reply: c.reply })
     }
     define_rpc!(
         publisher,
@@ -466,7 +685,7 @@ pub(super) fn start_create_table_rpc(
         lock: bool,
     ) -> Option<RpcRequest> {
         let kind = RpcRequestKind::CreateTable { path, rows, columns, lock };
-        Some(RpcRequest { kind, reply: c.reply })
+        Some(RpcRequest { kind, client: c.client, reply: c.reply })
     }
     define_rpc!(
         publisher,
@@ -488,7 +707,7 @@ pub(super) fn start_add_table_rows_rpc(
 ) -> Result<Proc> {
     fn map(c: RpcCall, path: Path, rows: Vec<Chars>) -> Option<RpcRequest> {
         let kind = RpcRequestKind::AddTableRows(path, rows);
-        Some(RpcRequest { kind, reply: c.reply })
+        Some(RpcRequest { kind, client: c.client, reply: c.reply })
     }
     define_rpc!(
         publisher,
@@ -508,7 +727,7 @@ pub(super) fn start_add_table_cols_rpc(
 ) -> Result<Proc> {
     fn map(c: RpcCall, path: Path, columns: Vec<Chars>) -> Option<RpcRequest> {
         let kind = RpcRequestKind::AddTableCols(path, columns);
-        Some(RpcRequest { reply: c.reply, kind })
+        Some(RpcRequest { client: c.client, reply: c.reply, kind })
     }
     define_rpc!(
         publisher,
@@ -528,7 +747,7 @@ pub(super) fn start_del_table_rows_rpc(
 ) -> Result<Proc> {
     fn map(c: RpcCall, path: Path, rows: Vec<Chars>) -> Option<RpcRequest> {
         let kind = RpcRequestKind::DelTableRows(path, rows);
-        Some(RpcRequest { reply: c.reply, kind })
+        Some(RpcRequest { client: c.client, reply: c.reply, kind })
     }
     define_rpc!(
         publisher,
@@ -548,7 +767,7 @@ pub(super) fn start_del_table_cols_rpc(
 ) -> Result<Proc> {
     fn map(c: RpcCall, path: Path, columns: Vec<Chars>) -> Option<RpcRequest> {
         let kind = RpcRequestKind::DelTableCols(path, columns);
-        Some(RpcRequest { reply: c.reply, kind })
+        Some(RpcRequest { client: c.client, reply: c.reply, kind })
     }
     define_rpc!(
         publisher,
@@ -560,3 +779,354 @@ pub(super) fn start_del_table_cols_rpc(
         columns: Vec<Chars> = Value::Null; "the columns to delete"
     )
 }
+
+pub(super) fn start_history_rpc(
+    publisher: &Publisher,
+    base_path: &Path,
+    tx: mpsc::Sender<RpcRequest>,
+) -> Result<Proc> {
+    fn map(c: RpcCall, path: Path) -> Option<RpcRequest> {
+        let kind = RpcRequestKind::History(path);
+        Some(RpcRequest { kind, client: c.client, reply: c.reply })
+    }
+    define_rpc!(
+        publisher,
+        base_path.append("history"),
+        "list the changes to a path, oldest first, as [timestamp, user, kind, ..]",
+        map,
+        Some(tx),
+        path: Path = Value::Null; "the path"
+    )
+}
+
+pub(super) fn start_as_of_rpc(
+    publisher: &Publisher,
+    base_path: &Path,
+    tx: mpsc::Sender<RpcRequest>,
+) -> Result<Proc> {
+    fn map(c: RpcCall, path: Path, time: DateTime<Utc>) -> Option<RpcRequest> {
+        let kind = RpcRequestKind::AsOf(path, time);
+        Some(RpcRequest { kind, client: c.client, reply: c.reply })
+    }
+    define_rpc!(
+        publisher,
+        base_path.append("read-as-of"),
+        "read a subtree as it was at a point in time, as [[path, kind, ..], ..]",
+        map,
+        Some(tx),
+        path: Path = Value::Null; "the subtree to read",
+        time: DateTime<Utc> = Value::Null; "the point in time"
+    )
+}
+
+pub(super) fn start_restore_subtree_rpc(
+    publisher: &Publisher,
+    base_path: &Path,
+    tx: mpsc::Sender<RpcRequest>,
+) -> Result<Proc> {
+    fn map(c: RpcCall, path: Path, time: DateTime<Utc>) -> Option<RpcRequest> {
+        let kind = RpcRequestKind::RestoreSubtree(path, time);
+        Some(RpcRequest { kind, client: c.client, reply: c.reply })
+    }
+    define_rpc!(
+        publisher,
+        base_path.append("restore-subtree"),
+        "restore a subtree to it's state at a point in time",
+        map,
+        Some(tx),
+        path: Path = Value::Null; "the subtree to restore",
+        time: DateTime<Utc> = Value::Null; "the point in time"
+    )
+}
+
+fn parse_atomic_op(op: Value) -> Result<AtomicOp> {
+    match op {
+        Value::Array(a) => match &a[..] {
+            [Value::String(op), path] if &**op == "delete" => {
+                Ok(AtomicOp::Remove(path.clone().cast_to::<Path>()?))
+            }
+            [Value::String(op), path, value] if &**op == "set-data" => {
+                Ok(AtomicOp::SetData(path.clone().cast_to::<Path>()?, value.clone()))
+            }
+            [Value::String(op), path, formula, on_write] if &**op == "set-formula" => {
+                let path = path.clone().cast_to::<Path>()?;
+                Ok(AtomicOp::SetFormula(path, formula.clone(), on_write.clone()))
+            }
+            _ => bail!("invalid operation {}", Value::Array(a.clone())),
+        },
+        op => bail!("invalid operation {}", op),
+    }
+}
+
+pub(super) fn start_transaction_rpc(
+    publisher: &Publisher,
+    base_path: &Path,
+    tx: mpsc::Sender<RpcRequest>,
+) -> Result<Proc> {
+    fn map(
+        mut c: RpcCall,
+        preconditions: Vec<(Path, Value)>,
+        ops: Vec<Value>,
+    ) -> Option<RpcRequest> {
+        let ops = match ops.into_iter().map(parse_atomic_op).collect::<Result<_>>() {
+            Ok(ops) => ops,
+            Err(e) => rpc_err!(c.reply, format!("{}", e)),
+        };
+        let kind = RpcRequestKind::Transaction { preconditions, ops };
+        Some(RpcRequest { kind, client: c.client, reply: c.reply })
+    }
+    define_rpc!(
+        publisher,
+        base_path.append("transaction"),
+        "apply a list of operations all or nothing, if every precondition holds",
+        map,
+        Some(tx),
+        preconditions: Vec<(Path, Value)> = Vec::<Value>::new(); "[[path, value], ..], null matches a path with no data",
+        ops: Vec<Value> = Vec::<Value>::new(); "[[\"set-data\", path, value] | [\"set-formula\", path, formula, on-write] | [\"delete\", path], ..]"
+    )
+}
+
+pub(super) fn start_version_rpc(
+    publisher: &Publisher,
+    base_path: &Path,
+    tx: mpsc::Sender<RpcRequest>,
+) -> Result<Proc> {
+    fn map(c: RpcCall, path: Path) -> Option<RpcRequest> {
+        let kind = RpcRequestKind::Version(path);
+        Some(RpcRequest { kind, client: c.client, reply: c.reply })
+    }
+    define_rpc!(
+        publisher,
+        base_path.append("version"),
+        "get the version of a path, it is incremented every time the path changes",
+        map,
+        Some(tx),
+        path: Path = Value::Null; "the path"
+    )
+}
+
+pub(super) fn start_set_data_if_rpc(
+    publisher: &Publisher,
+    base_path: &Path,
+    tx: mpsc::Sender<RpcRequest>,
+) -> Result<Proc> {
+    fn map(c: RpcCall, path: Path, value: Value, version: u64) -> Option<RpcRequest> {
+        let kind = RpcRequestKind::SetDataIf { path, value, version };
+        Some(RpcRequest { kind, client: c.client, reply: c.reply })
+    }
+    define_rpc!(
+        publisher,
+        base_path.append("set-data-if"),
+        "set the data of a path, if it hasn't changed since the specified version",
+        map,
+        Some(tx),
+        path: Path = Value::Null; "the path to set",
+        value: Value = Value::Null; "the value",
+        version: u64 = Value::Null; "the expected version of the path"
+    )
+}
+
+pub(super) fn start_set_acl_rpc(
+    publisher: &Publisher,
+    base_path: &Path,
+    tx: mpsc::Sender<RpcRequest>,
+) -> Result<Proc> {
+    fn map(
+        c: RpcCall,
+        path: Path,
+        data: Vec<Chars>,
+        formula: Vec<Chars>,
+        structure: Vec<Chars>,
+    ) -> Option<RpcRequest> {
+        let kind = RpcRequestKind::SetAcl(path, Acl { data, formula, structure });
+        Some(RpcRequest { kind, client: c.client, reply: c.reply })
+    }
+    define_rpc!(
+        publisher,
+        base_path.append("set-acl"),
+        "set the acl of a subtree, each list holds user and group names, * matches everyone",
+        map,
+        Some(tx),
+        path: Path = Value::Null; "the subtree",
+        data: Vec<Chars> = Vec::<Value>::new(); "who may write data",
+        formula: Vec<Chars> = Vec::<Value>::new(); "who may set formulas and on write handlers",
+        structure: Vec<Chars> = Vec::<Value>::new(); "who may create, delete, lock, and restore, and change acls"
+    )
+}
+
+pub(super) fn start_remove_acl_rpc(
+    publisher: &Publisher,
+    base_path: &Path,
+    tx: mpsc::Sender<RpcRequest>,
+) -> Result<Proc> {
+    fn map(c: RpcCall, path: Path) -> Option<RpcRequest> {
+        let kind = RpcRequestKind::RemoveAcl(path);
+        Some(RpcRequest { kind, client: c.client, reply: c.reply })
+    }
+    define_rpc!(
+        publisher,
+        base_path.append("remove-acl"),
+        "remove the acl of a subtree",
+        map,
+        Some(tx),
+        path: Path = Value::Null; "the subtree"
+    )
+}
+
+pub(super) fn start_list_acls_rpc(
+    publisher: &Publisher,
+    base_path: &Path,
+    tx: mpsc::Sender<RpcRequest>,
+) -> Result<Proc> {
+    fn map(c: RpcCall) -> Option<RpcRequest> {
+        let kind = RpcRequestKind::ListAcls;
+        Some(RpcRequest { kind, client: c.client, reply: c.reply })
+    }
+    define_rpc!(
+        publisher,
+        base_path.append("list-acls"),
+        "list the acls as [[path, data, formula, structure], ..]",
+        map,
+        Some(tx),
+    )
+}
+
+pub(super) fn start_set_table_schema_rpc(
+    publisher: &Publisher,
+    base_path: &Path,
+    tx: mpsc::Sender<RpcRequest>,
+) -> Result<Proc> {
+    fn map(mut c: RpcCall, path: Path, columns: Vec<Value>) -> Option<RpcRequest> {
+        let columns = match columns
+            .into_iter()
+            .map(ColumnSchema::from_value)
+            .collect::<Result<_>>()
+        {
+            Ok(columns) => columns,
+            Err(e) => rpc_err!(c.reply, format!("{}", e)),
+        };
+        let kind = RpcRequestKind::SetTableSchema(path, columns);
+        Some(RpcRequest { kind, client: c.client, reply: c.reply })
+    }
+    define_rpc!(
+        publisher,
+        base_path.append("set-table-schema"),
+        "set the column schemas of a table, an empty list removes the schema",
+        map,
+        Some(tx),
+        path: Path = Value::Null; "the table",
+        columns: Vec<Value> = Vec::<Value>::new(); "[[name, type | null, nullable, [allowed, ..] | null, default], ..]"
+    )
+}
+
+pub(super) fn start_import_rpc(
+    publisher: &Publisher,
+    base_path: &Path,
+    tx: mpsc::Sender<RpcRequest>,
+) -> Result<Proc> {
+    fn map(mut c: RpcCall, path: Path, format: Chars, data: Chars) -> Option<RpcRequest> {
+        let import =
+            match format.parse::<ImportFormat>().and_then(|f| Import::parse(f, &data)) {
+                Ok(import) => import,
+                Err(e) => rpc_err!(c.reply, format!("{}", e)),
+            };
+        let kind = RpcRequestKind::Import(path, import);
+        Some(RpcRequest { kind, client: c.client, reply: c.reply })
+    }
+    define_rpc!(
+        publisher,
+        base_path.append("import"),
+        "import csv or json into a table, creating it or adding missing rows and columns",
+        map,
+        Some(tx),
+        path: Path = Value::Null; "the table",
+        format: Chars = "csv"; "the format of data, csv or json",
+        data: Chars = Value::Null; "the csv or json to import"
+    )
+}
+
+pub(super) fn start_export_rpc(
+    publisher: &Publisher,
+    base_path: &Path,
+    tx: mpsc::Sender<RpcRequest>,
+) -> Result<Proc> {
+    fn map(c: RpcCall, path: Path) -> Option<RpcRequest> {
+        let kind = RpcRequestKind::Export(path);
+        Some(RpcRequest { kind, client: c.client, reply: c.reply })
+    }
+    define_rpc!(
+        publisher,
+        base_path.append("export"),
+        "render a table or sheet as csv",
+        map,
+        Some(tx),
+        path: Path = Value::Null; "the table or sheet"
+    )
+}
+
+pub(super) fn start_set_ttl_rpc(
+    publisher: &Publisher,
+    base_path: &Path,
+    tx: mpsc::Sender<RpcRequest>,
+) -> Result<Proc> {
+    fn map(
+        mut c: RpcCall,
+        path: Path,
+        duration: Duration,
+        expiry: Chars,
+    ) -> Option<RpcRequest> {
+        let expiry = match expiry.parse::<Expiry>() {
+            Ok(expiry) => expiry,
+            Err(e) => rpc_err!(c.reply, format!("{}", e)),
+        };
+        let kind = RpcRequestKind::SetTtl(path, Ttl { duration, expiry });
+        Some(RpcRequest { kind, client: c.client, reply: c.reply })
+    }
+    define_rpc!(
+        publisher,
+        base_path.append("set-ttl"),
+        "set the time to live of a subtree, paths that aren't written for the duration expire",
+        map,
+        Some(tx),
+        path: Path = Value::Null; "the subtree",
+        duration: Duration = Value::Null; "how long paths live after they are written",
+        expiry: Chars = "delete"; "what happens to expired paths, delete or null"
+    )
+}
+
+pub(super) fn start_remove_ttl_rpc(
+    publisher: &Publisher,
+    base_path: &Path,
+    tx: mpsc::Sender<RpcRequest>,
+) -> Result<Proc> {
+    fn map(c: RpcCall, path: Path) -> Option<RpcRequest> {
+        let kind = RpcRequestKind::RemoveTtl(path);
+        Some(RpcRequest { kind, client: c.client, reply: c.reply })
+    }
+    define_rpc!(
+        publisher,
+        base_path.append("remove-ttl"),
+        "remove the time to live of a subtree",
+        map,
+        Some(tx),
+        path: Path = Value::Null; "the subtree"
+    )
+}
+
+pub(super) fn start_list_ttls_rpc(
+    publisher: &Publisher,
+    base_path: &Path,
+    tx: mpsc::Sender<RpcRequest>,
+) -> Result<Proc> {
+    fn map(c: RpcCall) -> Option<RpcRequest> {
+        let kind = RpcRequestKind::ListTtls;
+        Some(RpcRequest { kind, client: c.client, reply: c.reply })
+    }
+    define_rpc!(
+        publisher,
+        base_path.append("list-ttls"),
+        "list the time to live settings as [[path, duration, expiry], ..]",
+        map,
+        Some(tx),
+    )
+}
@@ -0,0 +1,137 @@
+use crate::storage::Tree;
+use anyhow::{anyhow, bail, Result};
+use netidx::{
+    chars::Chars,
+    pack::Pack,
+    path::Path,
+    subscriber::{Typ, Value},
+};
+use netidx_derive::Pack;
+use serde_derive::{Deserialize, Serialize};
+
+/// The schema of one table column. Values written to a cell in the
+/// column are cast to `typ` and checked against `allowed`, and the
+/// write fails if they don't fit. Columns that aren't in the schema
+/// of their table accept any value.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Pack)]
+pub struct ColumnSchema {
+    pub name: Chars,
+    /// the type of the column, any type if None
+    pub typ: Option<Typ>,
+    /// may cells be null
+    pub nullable: bool,
+    /// if not empty, the only values cells may have
+    pub allowed: Vec<Value>,
+    /// the value of new cells in the column
+    pub default: Value,
+}
+
+impl ColumnSchema {
+    /// check that `v` may be written to this column, and return it
+    /// cast to the column type.
+    pub fn check(&self, v: Value) -> Result<Value> {
+        let v = match (self.typ, v) {
+            (_, Value::Null) if self.nullable => return Ok(Value::Null),
+            (_, Value::Null) => bail!("column {} may not be null", self.name),
+            (None, v) => v,
+            (Some(typ), v) => match v.clone().cast(typ) {
+                Some(v) => v,
+                None => bail!("column {} expects a {}, not {}", self.name, typ, v),
+            },
+        };
+        if !self.allowed.is_empty() && !self.allowed.contains(&v) {
+            bail!("{} is not an allowed value of column {}", v, self.name)
+        }
+        Ok(v)
+    }
+
+    /// [name, type, nullable, [allowed, ..], default], where type is
+    /// a type name or null
+    pub fn to_value(&self) -> Value {
+        Value::from(vec![
+            Value::from(self.name.clone()),
+            self.typ.map(|t| Value::from(t.name())).unwrap_or(Value::Null),
+            Value::from(self.nullable),
+            Value::from(self.allowed.clone()),
+            self.default.clone(),
+        ])
+    }
+
+    pub fn from_value(v: Value) -> Result<Self> {
+        match v {
+            Value::Array(a) => match &a[..] {
+                [name, typ, nullable, allowed, default] => {
+                    let name = name.clone().cast_to::<Chars>()?;
+                    let typ = match typ {
+                        Value::Null => None,
+                        t => Some(t.clone().cast_to::<Chars>()?.parse::<Typ>()?),
+                    };
+                    let nullable = nullable.clone().cast_to::<bool>()?;
+                    let allowed = match allowed {
+                        Value::Null => vec![],
+                        a => a.clone().cast_to::<Vec<Value>>()?,
+                    };
+                    let mut schema = ColumnSchema {
+                        name,
+                        typ,
+                        nullable,
+                        allowed: vec![],
+                        default: Value::Null,
+                    };
+                    schema.allowed = allowed
+                        .into_iter()
+                        .map(|v| schema.check(v))
+                        .collect::<Result<Vec<_>>>()?;
+                    if default != &Value::Null {
+                        schema.default = schema.check(default.clone())?;
+                    }
+                    Ok(schema)
+                }
+                _ => bail!("invalid column schema {}", Value::Array(a.clone())),
+            },
+            v => bail!("invalid column schema {}", v),
+        }
+    }
+}
+
+pub(super) fn schema_to_value(schema: &[ColumnSchema]) -> Value {
+    Value::from(schema.iter().map(|c| c.to_value()).collect::<Vec<_>>())
+}
+
+/// the schema of the table at `base`
+pub(super) fn lookup(schemas: &Tree, base: &Path) -> Result<Option<Vec<ColumnSchema>>> {
+    match schemas.get(base.as_bytes())? {
+        None => Ok(None),
+        Some(v) => Ok(Some(Pack::decode(&mut &*v)?)),
+    }
+}
+
+/// the default value of new cells in column `col` (escaped) of the
+/// table described by `schema`
+pub(super) fn default(schema: &Option<Vec<ColumnSchema>>, col: &str) -> Value {
+    schema
+        .as_ref()
+        .and_then(|s| s.iter().find(|c| Path::escape(&c.name) == col))
+        .map(|c| c.default.clone())
+        .unwrap_or(Value::Null)
+}
+
+/// if `path` is a cell of a table with a schema, check that `v` may
+/// be written to it, and return it cast to the column type.
+pub(super) fn check(schemas: &Tree, path: &Path, v: Value) -> Result<Value> {
+    let base = Path::dirname(path).and_then(|p| Path::dirname(p));
+    let col = Path::basename(path);
+    match (base, col) {
+        (Some(base), Some(col)) => match schemas.get(base.as_bytes())? {
+            None => Ok(v),
+            Some(s) => {
+                let schema: Vec<ColumnSchema> = Pack::decode(&mut &*s)?;
+                match schema.iter().find(|c| Path::escape(&c.name) == col) {
+                    None => Ok(v),
+                    Some(c) => c.check(v).map_err(|e| anyhow!("{}: {}", path, e)),
+                }
+            }
+        },
+        (_, _) => Ok(v),
+    }
+}
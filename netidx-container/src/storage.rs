@@ -0,0 +1,971 @@
+use crate::Params;
+use anyhow::{anyhow, bail, Error, Result};
+use log::error;
+use parking_lot::{Mutex, RwLock};
+use sled::transaction::{ConflictableTransactionError, TransactionError, Transactional};
+pub use sled::IVec;
+use std::{
+    any::Any,
+    collections::{BTreeMap, HashMap, VecDeque},
+    ops::{Bound, RangeBounds},
+    path::PathBuf,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+/// The storage backend of a container
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageKind {
+    /// a sled db directory, the default
+    Sled,
+    /// an in memory db that is lost when the container stops, for
+    /// tests and ephemeral scratch spaces
+    Memory,
+    /// a sqlite db file, requires the sqlite feature
+    Sqlite,
+}
+
+impl Default for StorageKind {
+    fn default() -> Self {
+        StorageKind::Sled
+    }
+}
+
+impl FromStr for StorageKind {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "sled" => Ok(StorageKind::Sled),
+            "memory" => Ok(StorageKind::Memory),
+            "sqlite" => Ok(StorageKind::Sqlite),
+            s => bail!("invalid storage {}, expected sled, memory, or sqlite", s),
+        }
+    }
+}
+
+/// An iterator over the (key, value) pairs of a tree in key order
+pub struct Iter(Box<dyn DoubleEndedIterator<Item = Result<(IVec, IVec)>> + Send>);
+
+impl Iter {
+    pub fn new<I>(iter: I) -> Self
+    where
+        I: DoubleEndedIterator<Item = Result<(IVec, IVec)>> + Send + 'static,
+    {
+        Iter(Box::new(iter))
+    }
+
+    pub fn keys(self) -> impl DoubleEndedIterator<Item = Result<IVec>> + Send + 'static {
+        self.map(|r| r.map(|(k, _)| k))
+    }
+
+    pub fn values(
+        self,
+    ) -> impl DoubleEndedIterator<Item = Result<IVec>> + Send + 'static {
+        self.map(|r| r.map(|(_, v)| v))
+    }
+}
+
+impl Iterator for Iter {
+    type Item = Result<(IVec, IVec)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next()
+    }
+}
+
+impl DoubleEndedIterator for Iter {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.0.next_back()
+    }
+}
+
+fn bound_ref(b: &Bound<IVec>) -> Bound<&[u8]> {
+    match b {
+        Bound::Included(k) => Bound::Included(&**k),
+        Bound::Excluded(k) => Bound::Excluded(&**k),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+fn bound_owned(b: Bound<&[u8]>) -> Bound<IVec> {
+    match b {
+        Bound::Included(k) => Bound::Included(IVec::from(k)),
+        Bound::Excluded(k) => Bound::Excluded(IVec::from(k)),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+/// return true if no key can be in the range from `start` to `end`
+fn range_is_empty(start: Bound<&[u8]>, end: Bound<&[u8]>) -> bool {
+    match (start, end) {
+        (Bound::Included(s), Bound::Included(e)) => s > e,
+        (Bound::Included(s), Bound::Excluded(e))
+        | (Bound::Excluded(s), Bound::Included(e))
+        | (Bound::Excluded(s), Bound::Excluded(e)) => s >= e,
+        (Bound::Unbounded, _) | (_, Bound::Unbounded) => false,
+    }
+}
+
+const CHUNK: usize = 256;
+
+/// A range that is read lazily, `CHUNK` keys at a time, from either
+/// end. `fetch(start, end, reverse)` reads at most `CHUNK` keys from
+/// the range, the first ones, or the last ones in reverse order if
+/// `reverse` is true. Keys that have been read narrow the range, so
+/// a key is never returned twice.
+struct Chunks<F> {
+    fetch: F,
+    start: Bound<IVec>,
+    end: Bound<IVec>,
+    front: VecDeque<(IVec, IVec)>,
+    // in reverse key order
+    back: VecDeque<(IVec, IVec)>,
+    // everything left in the range is in front or back
+    done: bool,
+}
+
+impl<F> Chunks<F>
+where
+    F: FnMut(Bound<&[u8]>, Bound<&[u8]>, bool) -> Result<Vec<(IVec, IVec)>>
+        + Send
+        + 'static,
+{
+    fn new(start: Bound<&[u8]>, end: Bound<&[u8]>, fetch: F) -> Iter {
+        Iter::new(Chunks {
+            fetch,
+            start: bound_owned(start),
+            end: bound_owned(end),
+            front: VecDeque::new(),
+            back: VecDeque::new(),
+            done: range_is_empty(start, end),
+        })
+    }
+
+    fn fill(&mut self, reverse: bool) -> Result<()> {
+        if range_is_empty(bound_ref(&self.start), bound_ref(&self.end)) {
+            self.done = true;
+            return Ok(());
+        }
+        let chunk = (self.fetch)(bound_ref(&self.start), bound_ref(&self.end), reverse)?;
+        self.done = chunk.len() < CHUNK;
+        if let Some((k, _)) = chunk.last() {
+            if reverse {
+                self.end = Bound::Excluded(k.clone());
+            } else {
+                self.start = Bound::Excluded(k.clone());
+            }
+        }
+        if reverse {
+            self.back.extend(chunk)
+        } else {
+            self.front.extend(chunk)
+        }
+        Ok(())
+    }
+}
+
+impl<F> Iterator for Chunks<F>
+where
+    F: FnMut(Bound<&[u8]>, Bound<&[u8]>, bool) -> Result<Vec<(IVec, IVec)>>
+        + Send
+        + 'static,
+{
+    type Item = Result<(IVec, IVec)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.front.is_empty() && !self.done {
+            if let Err(e) = self.fill(false) {
+                self.done = true;
+                return Some(Err(e));
+            }
+        }
+        self.front.pop_front().or_else(|| self.back.pop_back()).map(Ok)
+    }
+}
+
+impl<F> DoubleEndedIterator for Chunks<F>
+where
+    F: FnMut(Bound<&[u8]>, Bound<&[u8]>, bool) -> Result<Vec<(IVec, IVec)>>
+        + Send
+        + 'static,
+{
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.back.is_empty() && !self.done {
+            if let Err(e) = self.fill(true) {
+                self.done = true;
+                return Some(Err(e));
+            }
+        }
+        self.back.pop_front().or_else(|| self.front.pop_back()).map(Ok)
+    }
+}
+
+/// A set of inserts and removes that are applied to a tree
+/// atomically. The last write to a key wins.
+#[derive(Debug, Clone, Default)]
+pub struct Batch(BTreeMap<IVec, Option<IVec>>);
+
+impl Batch {
+    pub fn insert<K: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, key: K, value: V) {
+        self.0.insert(IVec::from(key.as_ref()), Some(IVec::from(value.as_ref())));
+    }
+
+    pub fn remove<K: AsRef<[u8]>>(&mut self, key: K) {
+        self.0.insert(IVec::from(key.as_ref()), None);
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+}
+
+/// Batches of writes to several trees of the same storage, that are
+/// applied all or nothing. Reads through `get` see the writes.
+#[derive(Default)]
+pub struct Batches(Vec<(Tree, Batch)>);
+
+impl Batches {
+    fn batch(&mut self, tree: &Tree) -> &mut Batch {
+        match self.0.iter().position(|(t, _)| t.same(tree)) {
+            Some(i) => &mut self.0[i].1,
+            None => {
+                self.0.push((tree.clone(), Batch::default()));
+                &mut self.0.last_mut().unwrap().1
+            }
+        }
+    }
+
+    pub fn insert<K: AsRef<[u8]>, V: AsRef<[u8]>>(
+        &mut self,
+        tree: &Tree,
+        key: K,
+        value: V,
+    ) {
+        self.batch(tree).insert(key, value)
+    }
+
+    pub fn remove<K: AsRef<[u8]>>(&mut self, tree: &Tree, key: K) {
+        self.batch(tree).remove(key)
+    }
+
+    /// the value of `key` in `tree` after the batches are applied
+    pub fn get<K: AsRef<[u8]>>(&self, tree: &Tree, key: K) -> Result<Option<IVec>> {
+        let key = key.as_ref();
+        match self.0.iter().find(|(t, _)| t.same(tree)).and_then(|(_, b)| b.0.get(key)) {
+            Some(v) => Ok(v.clone()),
+            None => tree.get(key),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.iter().all(|(_, b)| b.len() == 0)
+    }
+
+    /// apply the batches all or nothing, the trees must belong to
+    /// `storage`
+    pub fn apply(self, storage: &Storage) -> Result<()> {
+        if self.is_empty() {
+            Ok(())
+        } else {
+            storage.apply_batches(self.0)
+        }
+    }
+}
+
+/// An ordered key value tree. Keys are ordered lexicographically by
+/// byte, as memcmp would order them.
+pub trait StorageTree: Send + Sync + 'static {
+    fn get(&self, key: &[u8]) -> Result<Option<IVec>>;
+
+    /// insert `value` at `key`, returning the previous value
+    fn insert(&self, key: &[u8], value: &[u8]) -> Result<Option<IVec>>;
+
+    /// remove `key`, returning the previous value
+    fn remove(&self, key: &[u8]) -> Result<Option<IVec>>;
+
+    fn contains_key(&self, key: &[u8]) -> Result<bool> {
+        Ok(self.get(key)?.is_some())
+    }
+
+    /// return true if the tree has no keys. This should not need to
+    /// read the tree.
+    fn is_empty(&self) -> Result<bool>;
+
+    /// If the current value of `key` is `old` then set it to `new`,
+    /// where None means absent. Return true if the swap happened.
+    fn compare_and_swap(
+        &self,
+        key: &[u8],
+        old: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<bool>;
+
+    fn range(&self, start: Bound<&[u8]>, end: Bound<&[u8]>) -> Iter;
+
+    fn apply_batch(&self, batch: Batch) -> Result<()>;
+
+    fn clear(&self) -> Result<()>;
+
+    fn flush(&self) -> Result<()>;
+
+    /// the tree as Any, so a backend can recognize it's own trees
+    fn as_any(&self) -> &dyn Any;
+}
+
+/// A storage backend for a container, a set of named trees
+pub trait ContainerStorage: Send + Sync + 'static {
+    fn open_tree(&self, name: &str) -> Result<Tree>;
+
+    /// generate a unique id that is greater than every id previously
+    /// generated by this db.
+    fn generate_id(&self) -> Result<u64>;
+
+    fn flush(&self) -> Result<()>;
+
+    /// Apply each batch to it's tree, all or nothing. The trees must
+    /// be distinct, and opened from this storage.
+    fn apply_batches(&self, batches: Vec<(Tree, Batch)>) -> Result<()>;
+
+    /// Start grouping writes to every tree, so they are committed
+    /// together by `end_group`, for backends where each write would
+    /// otherwise be a costly commit. Groups do not nest.
+    fn begin_group(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Commit the writes made since `begin_group`
+    fn end_group(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+pub type Storage = Arc<dyn ContainerStorage>;
+
+/// Run `f`, grouping the writes it makes. The writes are not rolled
+/// back if `f` fails.
+pub(super) fn grouped<R>(storage: &Storage, f: impl FnOnce() -> R) -> R {
+    let grouped = match storage.begin_group() {
+        Ok(()) => true,
+        Err(e) => {
+            error!("failed to group writes {}", e);
+            false
+        }
+    };
+    let r = f();
+    if grouped {
+        if let Err(e) = storage.end_group() {
+            error!("failed to commit grouped writes {}", e)
+        }
+    }
+    r
+}
+
+/// Open the storage backend specified by `cfg`
+pub(super) fn open(cfg: &Params) -> Result<Storage> {
+    let path = || {
+        cfg.db.as_ref().map(PathBuf::from).or_else(Params::default_db_path).ok_or_else(
+            || anyhow!("db dir not specified and no default could be determined"),
+        )
+    };
+    match cfg.storage {
+        StorageKind::Sled => {
+            let db = sled::Config::default()
+                .cache_capacity(cfg.cache_size.unwrap_or(16 * 1024 * 1024))
+                .path(&path()?)
+                .open()?;
+            Ok(Arc::new(db))
+        }
+        StorageKind::Memory => Ok(Arc::new(MemStorage::default())),
+        #[cfg(feature = "sqlite")]
+        StorageKind::Sqlite => Ok(Arc::new(sqlite::SqliteStorage::open(path()?)?)),
+        #[cfg(not(feature = "sqlite"))]
+        StorageKind::Sqlite => bail!("sqlite storage support was not compiled in"),
+    }
+}
+
+/// A handle to a tree in a storage backend
+#[derive(Clone)]
+pub struct Tree(Arc<dyn StorageTree>);
+
+fn bound<K: AsRef<[u8]>>(b: Bound<&K>) -> Bound<&[u8]> {
+    match b {
+        Bound::Included(k) => Bound::Included(k.as_ref()),
+        Bound::Excluded(k) => Bound::Excluded(k.as_ref()),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+// the smallest key that is greater than every key starting with
+// prefix, or None if there is no such key
+fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(b) = end.pop() {
+        if b < u8::MAX {
+            end.push(b + 1);
+            return Some(end);
+        }
+    }
+    None
+}
+
+impl Tree {
+    pub fn new<T: StorageTree>(tree: T) -> Self {
+        Tree(Arc::new(tree))
+    }
+
+    /// true if `self` and `other` are handles to the same tree
+    fn same(&self, other: &Tree) -> bool {
+        Arc::as_ptr(&self.0) as *const () == Arc::as_ptr(&other.0) as *const ()
+    }
+
+    /// the backend's tree, if it is a `T`
+    fn downcast<T: StorageTree>(&self) -> Result<&T> {
+        self.0.as_any().downcast_ref::<T>().ok_or_else(|| anyhow!("foreign tree"))
+    }
+
+    pub fn get<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<IVec>> {
+        self.0.get(key.as_ref())
+    }
+
+    pub fn insert<K, V>(&self, key: K, value: V) -> Result<Option<IVec>>
+    where
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        self.0.insert(key.as_ref(), value.as_ref())
+    }
+
+    pub fn remove<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<IVec>> {
+        self.0.remove(key.as_ref())
+    }
+
+    pub fn contains_key<K: AsRef<[u8]>>(&self, key: K) -> Result<bool> {
+        self.0.contains_key(key.as_ref())
+    }
+
+    pub fn is_empty(&self) -> Result<bool> {
+        self.0.is_empty()
+    }
+
+    pub fn compare_and_swap<K, O, N>(
+        &self,
+        key: K,
+        old: Option<O>,
+        new: Option<N>,
+    ) -> Result<bool>
+    where
+        K: AsRef<[u8]>,
+        O: AsRef<[u8]>,
+        N: AsRef<[u8]>,
+    {
+        let old = old.as_ref().map(|v| v.as_ref());
+        let new = new.as_ref().map(|v| v.as_ref());
+        self.0.compare_and_swap(key.as_ref(), old, new)
+    }
+
+    pub fn range<K: AsRef<[u8]>, R: RangeBounds<K>>(&self, range: R) -> Iter {
+        self.0.range(bound(range.start_bound()), bound(range.end_bound()))
+    }
+
+    pub fn scan_prefix<K: AsRef<[u8]>>(&self, prefix: K) -> Iter {
+        let prefix = prefix.as_ref();
+        match prefix_end(prefix) {
+            None => self.0.range(Bound::Included(prefix), Bound::Unbounded),
+            Some(end) => self.0.range(Bound::Included(prefix), Bound::Excluded(&end)),
+        }
+    }
+
+    pub fn iter(&self) -> Iter {
+        self.0.range(Bound::Unbounded, Bound::Unbounded)
+    }
+
+    pub fn apply_batch(&self, batch: Batch) -> Result<()> {
+        self.0.apply_batch(batch)
+    }
+
+    pub fn clear(&self) -> Result<()> {
+        self.0.clear()
+    }
+
+    pub fn flush(&self) -> Result<()> {
+        self.0.flush()
+    }
+}
+
+impl StorageTree for sled::Tree {
+    fn get(&self, key: &[u8]) -> Result<Option<IVec>> {
+        Ok(sled::Tree::get(self, key)?)
+    }
+
+    fn insert(&self, key: &[u8], value: &[u8]) -> Result<Option<IVec>> {
+        Ok(sled::Tree::insert(self, key, value)?)
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<Option<IVec>> {
+        Ok(sled::Tree::remove(self, key)?)
+    }
+
+    fn contains_key(&self, key: &[u8]) -> Result<bool> {
+        Ok(sled::Tree::contains_key(self, key)?)
+    }
+
+    fn is_empty(&self) -> Result<bool> {
+        Ok(sled::Tree::is_empty(self))
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: &[u8],
+        old: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<bool> {
+        Ok(sled::Tree::compare_and_swap(self, key, old, new)?.is_ok())
+    }
+
+    fn range(&self, start: Bound<&[u8]>, end: Bound<&[u8]>) -> Iter {
+        Iter::new(sled::Tree::range(self, (start, end)).map(|r| Ok(r?)))
+    }
+
+    fn apply_batch(&self, batch: Batch) -> Result<()> {
+        Ok(sled::Tree::apply_batch(self, sled_batch(batch))?)
+    }
+
+    fn clear(&self) -> Result<()> {
+        Ok(sled::Tree::clear(self)?)
+    }
+
+    fn flush(&self) -> Result<()> {
+        sled::Tree::flush(self)?;
+        Ok(())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+fn sled_batch(batch: Batch) -> sled::Batch {
+    let mut b = sled::Batch::default();
+    for (k, v) in batch.0 {
+        match v {
+            Some(v) => b.insert(k, v),
+            None => b.remove(k),
+        }
+    }
+    b
+}
+
+impl ContainerStorage for sled::Db {
+    fn open_tree(&self, name: &str) -> Result<Tree> {
+        Ok(Tree::new(sled::Db::open_tree(self, name)?))
+    }
+
+    fn generate_id(&self) -> Result<u64> {
+        Ok(sled::Db::generate_id(self)?)
+    }
+
+    fn flush(&self) -> Result<()> {
+        sled::Db::flush(self)?;
+        Ok(())
+    }
+
+    fn apply_batches(&self, batches: Vec<(Tree, Batch)>) -> Result<()> {
+        let mut trees = Vec::with_capacity(batches.len());
+        let mut writes = Vec::with_capacity(batches.len());
+        for (tree, batch) in batches {
+            trees.push(tree.downcast::<sled::Tree>()?.clone());
+            writes.push(sled_batch(batch));
+        }
+        let r = trees[..].transaction(|trees| {
+            for (tree, batch) in trees.iter().zip(writes.iter()) {
+                tree.apply_batch(batch)?
+            }
+            Ok::<_, ConflictableTransactionError<()>>(())
+        });
+        match r {
+            Ok(()) => Ok(()),
+            Err(TransactionError::Abort(())) => bail!("transaction aborted"),
+            Err(TransactionError::Storage(e)) => Err(e.into()),
+        }
+    }
+}
+
+#[derive(Default)]
+struct MemTree(Arc<RwLock<BTreeMap<IVec, IVec>>>);
+
+impl StorageTree for MemTree {
+    fn get(&self, key: &[u8]) -> Result<Option<IVec>> {
+        Ok(self.0.read().get(key).cloned())
+    }
+
+    fn insert(&self, key: &[u8], value: &[u8]) -> Result<Option<IVec>> {
+        Ok(self.0.write().insert(IVec::from(key), IVec::from(value)))
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<Option<IVec>> {
+        Ok(self.0.write().remove(key))
+    }
+
+    fn is_empty(&self) -> Result<bool> {
+        Ok(self.0.read().is_empty())
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: &[u8],
+        old: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<bool> {
+        let mut tree = self.0.write();
+        if tree.get(key).map(|v| &**v) != old {
+            Ok(false)
+        } else {
+            match new {
+                None => tree.remove(key),
+                Some(v) => tree.insert(IVec::from(key), IVec::from(v)),
+            };
+            Ok(true)
+        }
+    }
+
+    // the range is read a chunk at a time, so the tree isn't locked
+    // while it is iterated
+    fn range(&self, start: Bound<&[u8]>, end: Bound<&[u8]>) -> Iter {
+        let tree = Arc::clone(&self.0);
+        Chunks::new(start, end, move |start, end, reverse| {
+            let tree = tree.read();
+            let range = tree.range::<[u8], _>((start, end));
+            let chunk = |(k, v): (&IVec, &IVec)| (k.clone(), v.clone());
+            Ok(if reverse {
+                range.rev().take(CHUNK).map(chunk).collect()
+            } else {
+                range.take(CHUNK).map(chunk).collect()
+            })
+        })
+    }
+
+    fn apply_batch(&self, batch: Batch) -> Result<()> {
+        let mut tree = self.0.write();
+        for (k, v) in batch.0 {
+            match v {
+                Some(v) => tree.insert(k, v),
+                None => tree.remove(&k),
+            };
+        }
+        Ok(())
+    }
+
+    fn clear(&self) -> Result<()> {
+        Ok(self.0.write().clear())
+    }
+
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+#[derive(Default)]
+struct MemStorage {
+    trees: Mutex<HashMap<String, Tree>>,
+    id: AtomicU64,
+}
+
+impl ContainerStorage for MemStorage {
+    fn open_tree(&self, name: &str) -> Result<Tree> {
+        let mut trees = self.trees.lock();
+        let tree = trees
+            .entry(String::from(name))
+            .or_insert_with(|| Tree::new(MemTree::default()));
+        Ok(tree.clone())
+    }
+
+    fn generate_id(&self) -> Result<u64> {
+        Ok(self.id.fetch_add(1, Ordering::Relaxed))
+    }
+
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+
+    // every tree is locked before any is written, so readers see all
+    // of the batches or none of them. Trees are locked in address
+    // order, so concurrent calls can't deadlock.
+    fn apply_batches(&self, batches: Vec<(Tree, Batch)>) -> Result<()> {
+        let mut batches = batches
+            .into_iter()
+            .map(|(tree, batch)| Ok((Arc::clone(&tree.downcast::<MemTree>()?.0), batch)))
+            .collect::<Result<Vec<_>>>()?;
+        batches.sort_by_key(|(tree, _)| Arc::as_ptr(tree) as usize);
+        let mut trees = batches.iter().map(|(tree, _)| tree.write()).collect::<Vec<_>>();
+        for (tree, (_, batch)) in trees.iter_mut().zip(batches.iter()) {
+            for (k, v) in batch.0.iter() {
+                match v {
+                    Some(v) => tree.insert(k.clone(), v.clone()),
+                    None => tree.remove(k),
+                };
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "sqlite")]
+mod sqlite {
+    use super::*;
+    use rusqlite::{params, Connection, OptionalExtension};
+    use std::path::Path;
+
+    // each tree is a table of (k, v) blobs, sqlite orders blobs with
+    // memcmp, so key order is the same as the other backends.
+    pub(super) struct SqliteTree {
+        con: Arc<Mutex<Connection>>,
+        table: String,
+    }
+
+    impl SqliteTree {
+        fn one(&self, sql: &str, key: &[u8]) -> Result<Option<IVec>> {
+            let con = self.con.lock();
+            let v = con
+                .prepare_cached(sql)?
+                .query_row(params![key], |r| r.get::<_, Vec<u8>>(0))
+                .optional()?;
+            Ok(v.map(IVec::from))
+        }
+
+        /// write `batch` with `con`, the connection of the tree
+        fn write(&self, con: &Connection, batch: Batch) -> Result<()> {
+            for (k, v) in batch.0 {
+                match v {
+                    None => con
+                        .prepare_cached(&format!(
+                            "DELETE FROM {} WHERE k = ?1",
+                            self.table
+                        ))?
+                        .execute(params![&*k])?,
+                    Some(v) => con
+                        .prepare_cached(&format!(
+                            "INSERT OR REPLACE INTO {} (k, v) VALUES (?1, ?2)",
+                            self.table
+                        ))?
+                        .execute(params![&*k, &*v])?,
+                };
+            }
+            Ok(())
+        }
+    }
+
+    impl StorageTree for SqliteTree {
+        fn get(&self, key: &[u8]) -> Result<Option<IVec>> {
+            self.one(&format!("SELECT v FROM {} WHERE k = ?1", self.table), key)
+        }
+
+        fn insert(&self, key: &[u8], value: &[u8]) -> Result<Option<IVec>> {
+            let mut con = self.con.lock();
+            let txn = con.savepoint()?;
+            let prev = txn
+                .prepare_cached(&format!("SELECT v FROM {} WHERE k = ?1", self.table))?
+                .query_row(params![key], |r| r.get::<_, Vec<u8>>(0))
+                .optional()?;
+            txn.prepare_cached(&format!(
+                "INSERT OR REPLACE INTO {} (k, v) VALUES (?1, ?2)",
+                self.table
+            ))?
+            .execute(params![key, value])?;
+            txn.commit()?;
+            Ok(prev.map(IVec::from))
+        }
+
+        fn remove(&self, key: &[u8]) -> Result<Option<IVec>> {
+            self.one(&format!("DELETE FROM {} WHERE k = ?1 RETURNING v", self.table), key)
+        }
+
+        fn is_empty(&self) -> Result<bool> {
+            let con = self.con.lock();
+            let row = con
+                .prepare_cached(&format!("SELECT 1 FROM {} LIMIT 1", self.table))?
+                .query_row([], |_| Ok(()))
+                .optional()?;
+            Ok(row.is_none())
+        }
+
+        fn compare_and_swap(
+            &self,
+            key: &[u8],
+            old: Option<&[u8]>,
+            new: Option<&[u8]>,
+        ) -> Result<bool> {
+            let mut con = self.con.lock();
+            let txn = con.savepoint()?;
+            let cur = txn
+                .prepare_cached(&format!("SELECT v FROM {} WHERE k = ?1", self.table))?
+                .query_row(params![key], |r| r.get::<_, Vec<u8>>(0))
+                .optional()?;
+            if cur.as_deref() != old {
+                return Ok(false);
+            }
+            match new {
+                None => txn
+                    .prepare_cached(&format!("DELETE FROM {} WHERE k = ?1", self.table))?
+                    .execute(params![key])?,
+                Some(v) => txn
+                    .prepare_cached(&format!(
+                        "INSERT OR REPLACE INTO {} (k, v) VALUES (?1, ?2)",
+                        self.table
+                    ))?
+                    .execute(params![key, v])?,
+            };
+            txn.commit()?;
+            Ok(true)
+        }
+
+        // the range is read a chunk at a time, so the connection isn't
+        // locked while it is iterated
+        fn range(&self, start: Bound<&[u8]>, end: Bound<&[u8]>) -> Iter {
+            let con = Arc::clone(&self.con);
+            let table = self.table.clone();
+            Chunks::new(start, end, move |start, end, reverse| {
+                let mut sql = format!("SELECT k, v FROM {} WHERE 1", table);
+                let mut args: Vec<&[u8]> = vec![];
+                match start {
+                    Bound::Unbounded => (),
+                    Bound::Included(k) => {
+                        args.push(k);
+                        sql.push_str(&format!(" AND k >= ?{}", args.len()))
+                    }
+                    Bound::Excluded(k) => {
+                        args.push(k);
+                        sql.push_str(&format!(" AND k > ?{}", args.len()))
+                    }
+                }
+                match end {
+                    Bound::Unbounded => (),
+                    Bound::Included(k) => {
+                        args.push(k);
+                        sql.push_str(&format!(" AND k <= ?{}", args.len()))
+                    }
+                    Bound::Excluded(k) => {
+                        args.push(k);
+                        sql.push_str(&format!(" AND k < ?{}", args.len()))
+                    }
+                }
+                let order = if reverse { "DESC" } else { "ASC" };
+                sql.push_str(&format!(" ORDER BY k {} LIMIT {}", order, CHUNK));
+                let con = con.lock();
+                let mut stmt = con.prepare_cached(&sql)?;
+                let rows = stmt.query_map(rusqlite::params_from_iter(args), |r| {
+                    Ok((r.get::<_, Vec<u8>>(0)?, r.get::<_, Vec<u8>>(1)?))
+                })?;
+                let chunk = rows
+                    .map(|r| {
+                        let (k, v) = r?;
+                        Ok((IVec::from(k), IVec::from(v)))
+                    })
+                    .collect::<Result<Vec<_>>>();
+                chunk
+            })
+        }
+
+        fn apply_batch(&self, batch: Batch) -> Result<()> {
+            let mut con = self.con.lock();
+            let txn = con.savepoint()?;
+            self.write(&txn, batch)?;
+            Ok(txn.commit()?)
+        }
+
+        fn clear(&self) -> Result<()> {
+            self.con.lock().execute(&format!("DELETE FROM {}", self.table), [])?;
+            Ok(())
+        }
+
+        fn flush(&self) -> Result<()> {
+            Ok(())
+        }
+
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+    }
+
+    pub(super) struct SqliteStorage(Arc<Mutex<Connection>>);
+
+    impl SqliteStorage {
+        pub(super) fn open(path: impl AsRef<Path>) -> Result<Self> {
+            let con = Connection::open(path)?;
+            con.execute_batch(
+                "PRAGMA journal_mode = WAL;
+                 PRAGMA synchronous = NORMAL;
+                 CREATE TABLE IF NOT EXISTS netidx_ids (id INTEGER NOT NULL);",
+            )?;
+            Ok(SqliteStorage(Arc::new(Mutex::new(con))))
+        }
+    }
+
+    impl ContainerStorage for SqliteStorage {
+        fn open_tree(&self, name: &str) -> Result<Tree> {
+            // tree names are chosen by the application, quote them so
+            // any name is a valid table name
+            let table = format!("\"tree_{}\"", name.replace('"', "\"\""));
+            self.0.lock().execute(
+                &format!(
+                    "CREATE TABLE IF NOT EXISTS {} \
+                     (k BLOB PRIMARY KEY, v BLOB NOT NULL) WITHOUT ROWID",
+                    table
+                ),
+                [],
+            )?;
+            Ok(Tree::new(SqliteTree { con: self.0.clone(), table }))
+        }
+
+        fn generate_id(&self) -> Result<u64> {
+            let mut con = self.0.lock();
+            let txn = con.savepoint()?;
+            let id = txn
+                .query_row("SELECT id FROM netidx_ids", [], |r| r.get::<_, i64>(0))
+                .optional()?;
+            let id = match id {
+                None => {
+                    txn.execute("INSERT INTO netidx_ids (id) VALUES (1)", [])?;
+                    0
+                }
+                Some(id) => {
+                    txn.execute("UPDATE netidx_ids SET id = id + 1", [])?;
+                    id
+                }
+            };
+            txn.commit()?;
+            Ok(id as u64)
+        }
+
+        fn flush(&self) -> Result<()> {
+            self.0.lock().execute_batch("PRAGMA wal_checkpoint(PASSIVE);")?;
+            Ok(())
+        }
+
+        fn apply_batches(&self, batches: Vec<(Tree, Batch)>) -> Result<()> {
+            let mut con = self.0.lock();
+            let txn = con.savepoint()?;
+            for (tree, batch) in batches {
+                tree.downcast::<SqliteTree>()?.write(&txn, batch)?
+            }
+            Ok(txn.commit()?)
+        }
+
+        // without a group every write is it's own transaction, and
+        // waits for the wal to be written
+        fn begin_group(&self) -> Result<()> {
+            Ok(self.0.lock().execute_batch("BEGIN")?)
+        }
+
+        fn end_group(&self) -> Result<()> {
+            Ok(self.0.lock().execute_batch("COMMIT")?)
+        }
+    }
+}
@@ -0,0 +1,1074 @@
+use crate::{
+    acl::Acl,
+    db::{AtomicOp, Datum, Db, ReplMsg, Reply, Sendable, Txn, MAX_REPLICA_LAG},
+    dump::DumpFormat,
+    history::HistoryEntry,
+    import::{self, Import, ImportFormat},
+    replication,
+    rpcs::RpcRequestKind,
+    schema::ColumnSchema,
+    storage::{self, Batch, Batches, IVec, Storage},
+    ttl::{Expiry, Ttl},
+    Params,
+};
+use anyhow::Result;
+use arcstr::ArcStr;
+use bytes::Bytes;
+use chrono::prelude::*;
+use futures::channel::mpsc;
+use netidx::{
+    chars::Chars,
+    path::Path,
+    protocol::resolver::UserInfo,
+    subscriber::{Typ, Value},
+};
+use parking_lot::Mutex;
+use std::{
+    fs,
+    ops::Bound,
+    path::PathBuf,
+    process,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use structopt::StructOpt;
+use tokio::time;
+
+pub(crate) fn params(args: &[&str]) -> Params {
+    Params::from_iter(["netidx-container"].iter().chain(args.iter()).copied())
+}
+
+/// open an in memory db with the container command line `args`
+pub(crate) fn memdb(args: &[&str]) -> Db {
+    let args = ["--storage", "memory"].iter().chain(args.iter()).copied();
+    Db::open(&params(&args.collect::<Vec<_>>())).expect("open db")
+}
+
+/// A directory that is removed when it is dropped
+pub(crate) struct TempDir(PathBuf);
+
+impl TempDir {
+    pub(crate) fn new() -> Self {
+        static N: AtomicUsize = AtomicUsize::new(0);
+        let mut path = std::env::temp_dir();
+        let n = N.fetch_add(1, Ordering::Relaxed);
+        path.push(format!("netidx-container-test-{}-{}", process::id(), n));
+        fs::create_dir_all(&path).expect("create temp dir");
+        TempDir(path)
+    }
+
+    pub(crate) fn path(&self, name: &str) -> String {
+        self.0.join(name).to_string_lossy().into_owned()
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _: Result<_, _> = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// commit `txn` and wait for it to be written
+pub(crate) async fn commit(db: &Db, txn: Txn) {
+    db.commit(txn);
+    db.flush_async().await.expect("flush")
+}
+
+/// a reply that keeps the result it is sent
+pub(crate) fn reply() -> (Reply, Arc<Mutex<Value>>) {
+    let res = Arc::new(Mutex::new(Value::Null));
+    (Some(Sendable::Packed(res.clone())), res)
+}
+
+fn user(name: &str, groups: &[&str]) -> UserInfo {
+    UserInfo {
+        name: ArcStr::from(name),
+        primary_group: ArcStr::from(name),
+        groups: groups.iter().map(|g| ArcStr::from(*g)).collect(),
+        resolver: "127.0.0.1:4564".parse().unwrap(),
+        token: Bytes::new(),
+    }
+}
+
+fn acl(data: &[&str], formula: &[&str], structure: &[&str]) -> Acl {
+    let names = |l: &[&str]| l.iter().map(|n| Chars::from(String::from(*n))).collect();
+    Acl { data: names(data), formula: names(formula), structure: names(structure) }
+}
+
+async fn acl_db() -> Db {
+    let db = memdb(&["--admin", "wheel"]);
+    let mut txn = Txn::new();
+    txn.set_acl(Path::from("/a"), acl(&["alice"], &["alice"], &["alice"]), None);
+    txn.set_acl(Path::from("/a/b"), acl(&["bob"], &["bob"], &["bob"]), None);
+    txn.set_acl(Path::from("/x"), acl(&["alice"], &["alice"], &["alice"]), None);
+    txn.set_acl(Path::from("/xy"), acl(&[], &[], &[]), None);
+    txn.set_acl(Path::from("/pub"), acl(&["*"], &[], &[]), None);
+    commit(&db, txn).await;
+    db
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn subtree_acls() {
+    let db = acl_db().await;
+    let alice = user("alice", &[]);
+    let bob = user("bob", &[]);
+    let check = |u: &UserInfo, req: RpcRequestKind| req.check_acls(&db, &[], Some(u));
+    let a = || Path::from("/a");
+    let import = || Import::parse(ImportFormat::Csv, "row,c\nr0,1\n").unwrap();
+    let ttl = Ttl { duration: Duration::from_secs(1), expiry: Expiry::Delete };
+    // /a/b has it's own acl that alice isn't in
+    assert!(check(&alice, RpcRequestKind::DeleteSubtree(a())).is_err());
+    assert!(check(&alice, RpcRequestKind::LockSubtree(a())).is_err());
+    assert!(check(&alice, RpcRequestKind::UnlockSubtree(a())).is_err());
+    assert!(check(&alice, RpcRequestKind::DelRoot(a())).is_err());
+    assert!(check(&alice, RpcRequestKind::RestoreSubtree(a(), Utc::now())).is_err());
+    assert!(check(&alice, RpcRequestKind::Import(a(), import())).is_err());
+    assert!(check(&alice, RpcRequestKind::SetTtl(a(), ttl)).is_err());
+    // single paths are governed by their closest acl only
+    assert!(check(&alice, RpcRequestKind::Delete(Path::from("/a/c"))).is_ok());
+    assert!(check(&alice, RpcRequestKind::DeleteSubtree(Path::from("/a/c"))).is_ok());
+    assert!(check(&bob, RpcRequestKind::DeleteSubtree(Path::from("/a/b"))).is_ok());
+    assert!(check(&bob, RpcRequestKind::Delete(Path::from("/a/c"))).is_err());
+    assert!(check(&bob, RpcRequestKind::SetTtl(Path::from("/a/b"), ttl)).is_ok());
+    // /xy is not below /x, but it is below /
+    assert!(check(&alice, RpcRequestKind::DeleteSubtree(Path::from("/x"))).is_ok());
+    assert!(check(&alice, RpcRequestKind::DeleteSubtree(Path::from("/"))).is_err());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn first_acl() {
+    let db = acl_db().await;
+    let admin = [String::from("wheel")];
+    let new = || acl(&["carol"], &["carol"], &["carol"]);
+    let check = |u: &UserInfo, path: &str| {
+        RpcRequestKind::SetAcl(Path::from(String::from(path)), new()).check_acls(
+            &db,
+            &admin,
+            Some(u),
+        )
+    };
+    let carol = user("carol", &[]);
+    let root = user("root", &["wheel"]);
+    // nobody may take over an ungoverned path, except an admin
+    assert!(check(&carol, "/c").is_err());
+    assert!(RpcRequestKind::SetAcl(Path::from("/c"), new())
+        .check_acls(&db, &admin, None)
+        .is_err());
+    assert!(check(&root, "/c").is_ok());
+    // under an existing acl structure permission is enough
+    assert!(check(&user("bob", &[]), "/a/b/c").is_ok());
+    assert!(check(&carol, "/a/b/c").is_err());
+    assert!(check(&root, "/a/b/c").is_err());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn formula_acls() {
+    let db = acl_db().await;
+    let p = |s: &'static str| Path::from(s);
+    // only alice may set formulas under /a, so they may write
+    // where alice may write
+    assert!(db.check_formula_acl(&p("/a/f"), &p("/a/x")).is_ok());
+    assert!(db.check_formula_acl(&p("/a/f"), &p("/a/b/x")).is_err());
+    assert!(db.check_formula_acl(&p("/a/b/f"), &p("/a/x")).is_err());
+    assert!(db.check_formula_acl(&p("/a/b/f"), &p("/a/b/x")).is_ok());
+    // anyone may set a formula on an ungoverned path
+    assert!(db.check_formula_acl(&p("/u/f"), &p("/a/x")).is_err());
+    assert!(db.check_formula_acl(&p("/u/f"), &p("/pub/x")).is_ok());
+    assert!(db.check_formula_acl(&p("/u/f"), &p("/v/x")).is_ok());
+    assert!(db.check_formula_acl(&p("/a/f"), &p("/pub/x")).is_ok());
+}
+
+fn paths(expired: Vec<(Path, u64)>) -> Vec<Path> {
+    expired.into_iter().map(|(p, _)| p).collect()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn ttl_expiry() {
+    let db = memdb(&[]);
+    let p = |s: &'static str| Path::from(s);
+    let later = || Utc::now() + chrono::Duration::seconds(11);
+    let set = |txn: &mut Txn, path: &'static str, v: Value| {
+        txn.set_data(true, p(path), v, None)
+    };
+    let mut txn = Txn::new();
+    let ttl = Ttl { duration: Duration::from_secs(10), expiry: Expiry::Delete };
+    txn.set_ttl(p("/t"), ttl, None);
+    set(&mut txn, "/t/a", Value::U64(1));
+    set(&mut txn, "/t/b", Value::Null);
+    set(&mut txn, "/u/c", Value::U64(1));
+    commit(&db, txn).await;
+    // null paths and paths with no ttl never expire
+    assert!(db.expired(Utc::now()).unwrap().is_empty());
+    assert_eq!(paths(db.expired(later()).unwrap()), vec![p("/t/a")]);
+    time::sleep(Duration::from_millis(10)).await;
+    let mut txn = Txn::new();
+    set(&mut txn, "/t/b", Value::U64(2));
+    commit(&db, txn).await;
+    assert_eq!(paths(db.expired(later()).unwrap()), vec![p("/t/a"), p("/t/b")]);
+    // writing a path pushes it's deadline back
+    time::sleep(Duration::from_millis(10)).await;
+    let mut txn = Txn::new();
+    set(&mut txn, "/t/a", Value::U64(3));
+    commit(&db, txn).await;
+    let expired = db.expired(later()).unwrap();
+    assert_eq!(paths(expired.clone()), vec![p("/t/b"), p("/t/a")]);
+    // an expiry scheduled before the last write does nothing
+    let mut txn = Txn::new();
+    for (path, version) in expired {
+        txn.expire(path, version - 1)
+    }
+    commit(&db, txn).await;
+    assert_eq!(db.lookup_value("/t/a"), Some(Value::U64(3)));
+    assert_eq!(db.lookup_value("/t/b"), Some(Value::U64(2)));
+    let mut txn = Txn::new();
+    for (path, version) in db.expired(later()).unwrap() {
+        txn.expire(path, version)
+    }
+    commit(&db, txn).await;
+    assert_eq!(db.lookup_value("/t/a"), None);
+    assert_eq!(db.lookup_value("/t/b"), None);
+    assert_eq!(db.lookup_value("/u/c"), Some(Value::U64(1)));
+    assert!(db.expired(later()).unwrap().is_empty());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn ttl_null_and_remove() {
+    let db = memdb(&[]);
+    let p = |s: &'static str| Path::from(s);
+    let later = || Utc::now() + chrono::Duration::seconds(2);
+    let mut txn = Txn::new();
+    let ttl = Ttl { duration: Duration::from_secs(1), expiry: Expiry::Null };
+    txn.set_ttl(p("/n"), ttl, None);
+    txn.set_data(true, p("/n/a"), Value::U64(1), None);
+    txn.set_data(true, p("/r/a"), Value::U64(1), None);
+    commit(&db, txn).await;
+    // a new ttl applies to the paths already under it
+    let mut txn = Txn::new();
+    let ttl = Ttl { duration: Duration::from_secs(1), expiry: Expiry::Delete };
+    txn.set_ttl(p("/r"), ttl, None);
+    commit(&db, txn).await;
+    assert_eq!(paths(db.expired(later()).unwrap()), vec![p("/n/a"), p("/r/a")]);
+    let mut txn = Txn::new();
+    txn.remove_ttl(p("/r"), None);
+    for (path, version) in db.expired(later()).unwrap() {
+        if path == p("/n/a") {
+            txn.expire(path, version)
+        }
+    }
+    commit(&db, txn).await;
+    assert_eq!(db.lookup_value("/n/a"), Some(Value::Null));
+    assert_eq!(db.lookup_value("/r/a"), Some(Value::U64(1)));
+    assert!(db.expired(later()).unwrap().is_empty());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn history_and_restore() {
+    let db = memdb(&["--history"]);
+    let p = |s: &'static str| Path::from(s);
+    let data = |h: &[HistoryEntry]| h.iter().map(|e| e.datum.clone()).collect::<Vec<_>>();
+    let mut txn = Txn::new();
+    txn.set_user(Some(user("alice", &[])));
+    txn.set_data(true, p("/h/a"), Value::U64(1), None);
+    txn.set_user(None);
+    txn.set_data(true, p("/h/b"), Value::U64(1), None);
+    commit(&db, txn).await;
+    time::sleep(Duration::from_millis(10)).await;
+    let before = Utc::now();
+    time::sleep(Duration::from_millis(10)).await;
+    let mut txn = Txn::new();
+    txn.set_data(true, p("/h/a"), Value::U64(2), None);
+    txn.remove(p("/h/b"), None);
+    txn.set_data(true, p("/h/c"), Value::U64(3), None);
+    txn.set_formula(p("/h/f"), Value::from("sum(1, 2)"), None);
+    commit(&db, txn).await;
+    let h = db.history(&p("/h/a")).unwrap();
+    assert_eq!(data(&h), vec![Datum::Data(Value::U64(1)), Datum::Data(Value::U64(2))]);
+    assert_eq!(h[0].user.as_ref().map(|u| &*u.name), Some("alice"));
+    assert!(h[1].user.is_none());
+    assert!(h[0].timestamp < before && h[1].timestamp > before);
+    let h = db.history(&p("/h/b")).unwrap();
+    assert_eq!(data(&h), vec![Datum::Data(Value::U64(1)), Datum::Deleted]);
+    assert!(h[0].user.is_none());
+    // paths that did not exist yet are left out
+    let state = db.as_of(&p("/h"), before).unwrap().into_iter().collect::<Vec<_>>();
+    assert_eq!(
+        state,
+        vec![
+            (p("/h/a"), Datum::Data(Value::U64(1))),
+            (p("/h/b"), Datum::Data(Value::U64(1)))
+        ]
+    );
+    let mut txn = Txn::new();
+    txn.restore_subtree(p("/h"), before, None);
+    commit(&db, txn).await;
+    assert_eq!(db.lookup_value("/h/a"), Some(Value::U64(1)));
+    assert_eq!(db.lookup_value("/h/b"), Some(Value::U64(1)));
+    assert_eq!(db.lookup_value("/h/c"), None);
+    assert!(matches!(db.lookup("/h/f").unwrap(), None | Some(Datum::Deleted)));
+    // the restore is part of the history
+    let h = db.history(&p("/h/c")).unwrap();
+    assert_eq!(data(&h), vec![Datum::Data(Value::U64(3)), Datum::Deleted]);
+    let db = memdb(&[]);
+    assert!(db.history(&p("/h/a")).is_err());
+    assert!(db.as_of(&p("/h"), before).is_err());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn history_retention() {
+    let db = memdb(&["--history", "--history-retention", "100"]);
+    let p = |s: &'static str| Path::from(s);
+    let now = Utc::now();
+    let ago = |secs| now - chrono::Duration::seconds(secs);
+    let data = |path| {
+        let h = db.history(&p(path)).unwrap();
+        h.into_iter().map(|e| e.datum).collect::<Vec<_>>()
+    };
+    for (secs, path, v) in [
+        (300, "/r/a", Some(Value::U64(1))),
+        (290, "/r/a", Some(Value::U64(2))),
+        (280, "/r/b", Some(Value::U64(1))),
+        (270, "/r/b", None),
+        (260, "/r/c", Some(Value::U64(1))),
+        (50, "/r/c", Some(Value::U64(2))),
+    ] {
+        let mut txn = Txn::new();
+        txn.set_timestamp(ago(secs));
+        match v {
+            Some(v) => txn.set_data(true, p(path), v, None),
+            None => txn.remove(p(path), None),
+        }
+        commit(&db, txn).await;
+    }
+    // the last write before the retention period is kept, unless it
+    // was a delete
+    assert_eq!(data("/r/a"), vec![Datum::Data(Value::U64(2))]);
+    assert_eq!(data("/r/b"), vec![]);
+    assert_eq!(
+        data("/r/c"),
+        vec![Datum::Data(Value::U64(1)), Datum::Data(Value::U64(2))]
+    );
+    let state = db.as_of(&p("/r"), ago(80)).unwrap().into_iter().collect::<Vec<_>>();
+    assert_eq!(
+        state,
+        vec![
+            (p("/r/a"), Datum::Data(Value::U64(2))),
+            (p("/r/c"), Datum::Data(Value::U64(1)))
+        ]
+    );
+    assert!(db.as_of(&p("/r"), ago(120)).is_err());
+}
+
+#[derive(Debug, PartialEq)]
+struct State {
+    roots: Vec<Path>,
+    locked: Vec<(Path, bool)>,
+    acls: Vec<(Path, Acl)>,
+    ttls: Vec<(Path, Ttl)>,
+    schemas: Vec<(Path, Vec<ColumnSchema>)>,
+    data: Vec<(Path, Datum, u64)>,
+}
+
+/// everything a replica must agree with it's primary about
+fn state(db: &Db) -> State {
+    let mut data = Vec::new();
+    for r in db.iter() {
+        let (path, _, _) = r.unwrap();
+        match db.lookup(&*path).unwrap() {
+            None | Some(Datum::Deleted) => (),
+            Some(datum) => {
+                let version = db.version(&path).unwrap();
+                data.push((path, datum, version))
+            }
+        }
+    }
+    State {
+        roots: db.roots().collect::<Result<_>>().unwrap(),
+        locked: db.locked().collect::<Result<_>>().unwrap(),
+        acls: db.acls().collect::<Result<_>>().unwrap(),
+        ttls: db.ttls().collect::<Result<_>>().unwrap(),
+        schemas: db.table_schemas().collect::<Result<_>>().unwrap(),
+        data,
+    }
+}
+
+/// apply every message the primary has sent so far to `replica`
+async fn sync(
+    primary: &Db,
+    msgs: &mut mpsc::Receiver<Vec<ReplMsg>>,
+    replica: &Db,
+    snapshot: &mut Option<Txn>,
+) {
+    primary.flush_async().await.expect("flush");
+    while let Ok(Some(msgs)) = msgs.try_next() {
+        for m in msgs {
+            replication::apply(replica, snapshot, m).expect("apply");
+        }
+    }
+    replica.flush_async().await.expect("flush")
+}
+
+fn schema(name: &str) -> Vec<ColumnSchema> {
+    vec![ColumnSchema {
+        name: Chars::from(String::from(name)),
+        typ: None,
+        nullable: true,
+        allowed: vec![],
+        default: Value::Null,
+    }]
+}
+
+async fn populate(db: &Db, base: &str, n: i64) {
+    let base = Path::from(ArcStr::from(base));
+    let ttl = Ttl { duration: Duration::from_secs(3600), expiry: Expiry::Delete };
+    let mut txn = Txn::new();
+    txn.add_root(base.clone(), None);
+    for i in 0..n {
+        txn.set_data(true, base.append(&format!("d{}", i)), Value::I64(i), None);
+    }
+    txn.set_formula(base.append("f"), Value::from("sum(1, 2)"), None);
+    txn.set_on_write(base.append("f"), Value::from("null"), None);
+    txn.set_locked(base.append("locked"), None);
+    txn.set_acl(base.append("acl"), acl(&["alice"], &[], &[]), None);
+    txn.set_ttl(base.append("ttl"), ttl, None);
+    txn.set_table_schema(base.append("table"), schema("c"), None);
+    commit(db, txn).await
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn replicate_snapshot() {
+    let primary = memdb(&[]);
+    populate(&primary, "/app", 10).await;
+    // the replica has it's own, stale, state that must not survive
+    let replica = memdb(&[]);
+    populate(&replica, "/stale", 3).await;
+    let mut txn = Txn::new();
+    txn.set_acl(Path::from("/elsewhere"), acl(&["bob"], &[], &[]), None);
+    commit(&replica, txn).await;
+    let mut msgs = primary.replicate();
+    let mut snapshot = None;
+    sync(&primary, &mut msgs, &replica, &mut snapshot).await;
+    assert!(snapshot.is_none());
+    assert_eq!(state(&primary), state(&replica));
+    assert_eq!(replica.acls().count(), 1);
+    assert_eq!(replica.ttls().count(), 1);
+    assert_eq!(replica.table_schemas().count(), 1);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn replicate_effects() {
+    // the replica doesn't keep history, so it can't replay a restore
+    let primary = memdb(&["--history"]);
+    let replica = memdb(&[]);
+    let mut msgs = primary.replicate();
+    let mut snapshot = None;
+    populate(&primary, "/app", 5).await;
+    sync(&primary, &mut msgs, &replica, &mut snapshot).await;
+    assert_eq!(state(&primary), state(&replica));
+    time::sleep(Duration::from_millis(10)).await;
+    let before = Utc::now();
+    time::sleep(Duration::from_millis(10)).await;
+    let mut txn = Txn::new();
+    txn.set_data(true, Path::from("/app/d0"), Value::from("changed"), None);
+    txn.remove(Path::from("/app/d1"), None);
+    txn.set_data(true, Path::from("/app/new"), Value::I64(42), None);
+    commit(&primary, txn).await;
+    let mut txn = Txn::new();
+    txn.set_unlocked(Path::from("/app/locked"), None);
+    txn.remove_acl(Path::from("/app/acl"), None);
+    txn.create_table(
+        Path::from("/app/table"),
+        vec![Chars::from("r0")],
+        vec![Chars::from("c")],
+        true,
+        None,
+    );
+    commit(&primary, txn).await;
+    sync(&primary, &mut msgs, &replica, &mut snapshot).await;
+    assert_eq!(state(&primary), state(&replica));
+    let mut txn = Txn::new();
+    txn.restore_subtree(Path::from("/app"), before, None);
+    commit(&primary, txn).await;
+    sync(&primary, &mut msgs, &replica, &mut snapshot).await;
+    assert_eq!(primary.lookup_value("/app/d0"), Some(Value::I64(0)));
+    assert_eq!(primary.lookup_value("/app/d1"), Some(Value::I64(1)));
+    assert_eq!(primary.lookup_value("/app/new"), None);
+    assert_eq!(state(&primary), state(&replica));
+    let mut txn = Txn::new();
+    txn.del_root(Path::from("/app"), None);
+    commit(&primary, txn).await;
+    sync(&primary, &mut msgs, &replica, &mut snapshot).await;
+    assert_eq!(state(&primary), state(&replica));
+    assert!(replica.roots().next().is_none());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn replicate_failover() {
+    let primary = memdb(&[]);
+    let replica = memdb(&[]);
+    let mut msgs = primary.replicate();
+    let mut snapshot = None;
+    populate(&primary, "/app", 5).await;
+    sync(&primary, &mut msgs, &replica, &mut snapshot).await;
+    // the primary goes away, the replica is promoted, takes writes,
+    // and a new replica follows it
+    drop(msgs);
+    let mut txn = Txn::new();
+    txn.set_data(true, Path::from("/app/d0"), Value::from("promoted"), None);
+    commit(&replica, txn).await;
+    let follower = memdb(&[]);
+    let mut msgs = replica.replicate();
+    let mut snapshot = None;
+    sync(&replica, &mut msgs, &follower, &mut snapshot).await;
+    assert_eq!(state(&replica), state(&follower));
+    let mut txn = Txn::new();
+    txn.set_data(true, Path::from("/app/d1"), Value::from("after"), None);
+    txn.set_data_if(Path::from("/app/d0"), Value::I64(0), 2, None);
+    commit(&replica, txn).await;
+    sync(&replica, &mut msgs, &follower, &mut snapshot).await;
+    assert_eq!(follower.lookup_value("/app/d0"), Some(Value::I64(0)));
+    assert_eq!(state(&replica), state(&follower));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn replicate_lagging() {
+    let primary = memdb(&[]);
+    populate(&primary, "/app", 5).await;
+    let mut msgs = primary.replicate();
+    for i in 1..=MAX_REPLICA_LAG + 10 {
+        let mut txn = Txn::new();
+        txn.set_data(true, Path::from("/app/d0"), Value::I64(i as i64), None);
+        primary.commit(txn);
+    }
+    primary.flush_async().await.expect("flush");
+    // a replica that doesn't keep up is disconnected, after the
+    // snapshot, and the transactions that fit in its channel
+    let mut received = 0;
+    loop {
+        match msgs.try_next() {
+            Ok(Some(_)) => received += 1,
+            Ok(None) => break,
+            Err(_) => panic!("the lagging replica is still connected"),
+        }
+    }
+    assert!(received > 1 && received <= MAX_REPLICA_LAG + 2);
+    // and it resyncs from a new snapshot
+    let replica = memdb(&[]);
+    let mut msgs = primary.replicate();
+    let mut snapshot = None;
+    sync(&primary, &mut msgs, &replica, &mut snapshot).await;
+    assert_eq!(state(&primary), state(&replica));
+}
+
+fn column(
+    name: &'static str,
+    typ: Option<Typ>,
+    nullable: bool,
+    allowed: Vec<Value>,
+    default: Value,
+) -> ColumnSchema {
+    ColumnSchema { name: Chars::from(name), typ, nullable, allowed, default }
+}
+
+/// `v` and it's type, since values of different numeric types compare equal
+fn typed(v: Option<Value>) -> Option<(Typ, Value)> {
+    v.map(|v| (Typ::get(&v), v))
+}
+
+#[test]
+fn schema_check() {
+    let n = column("n", Some(Typ::I64), false, vec![], Value::Null);
+    assert_eq!(typed(n.check(Value::U32(5)).ok()), Some((Typ::I64, Value::I64(5))));
+    assert_eq!(typed(n.check(Value::from("6")).ok()), Some((Typ::I64, Value::I64(6))));
+    assert!(n.check(Value::from("six")).is_err());
+    let e = n.check(Value::Null).unwrap_err();
+    assert_eq!(e.to_string(), "column n may not be null");
+    let s = column("s", Some(Typ::String), true, vec![Value::from("a")], Value::Null);
+    assert_eq!(s.check(Value::Null).unwrap(), Value::Null);
+    assert_eq!(s.check(Value::from("a")).unwrap(), Value::from("a"));
+    let e = s.check(Value::from("b")).unwrap_err();
+    assert_eq!(e.to_string(), "\"b\" is not an allowed value of column s");
+    // allowed values are compared after the cast
+    let i = column("i", Some(Typ::I64), false, vec![Value::I64(1)], Value::Null);
+    assert_eq!(typed(i.check(Value::U32(1)).ok()), Some((Typ::I64, Value::I64(1))));
+    assert!(i.check(Value::U32(2)).is_err());
+    // untyped columns pass values through unchanged
+    let any = column("any", None, true, vec![], Value::Null);
+    assert_eq!(typed(any.check(Value::U32(1)).ok()), Some((Typ::U32, Value::U32(1))));
+}
+
+#[test]
+fn schema_value() {
+    let cols = [
+        column("n", Some(Typ::F64), false, vec![], Value::F64(0.)),
+        column("s", None, true, vec![Value::from("a"), Value::from("b")], Value::Null),
+    ];
+    for c in cols.iter() {
+        assert_eq!(&ColumnSchema::from_value(c.to_value()).unwrap(), c);
+    }
+    // allowed values and the default are cast to the column type
+    let v = Value::from(vec![
+        Value::from("n"),
+        Value::from("i64"),
+        Value::False,
+        Value::from(vec![Value::U32(1), Value::U32(2)]),
+        Value::U32(2),
+    ]);
+    let c = ColumnSchema::from_value(v).unwrap();
+    assert_eq!(typed(Some(c.default)), Some((Typ::I64, Value::I64(2))));
+    assert!(c.allowed.iter().all(|v| Typ::get(v) == Typ::I64));
+    let invalid = |typ: &str, allowed: Vec<Value>, default: Value| {
+        let v = Value::from(vec![
+            Value::from("n"),
+            Value::from(String::from(typ)),
+            Value::False,
+            Value::from(allowed),
+            default,
+        ]);
+        assert!(ColumnSchema::from_value(v).is_err())
+    };
+    invalid("int", vec![], Value::Null);
+    invalid("i64", vec![Value::from("one")], Value::Null);
+    invalid("i64", vec![], Value::from("one"));
+    invalid("i64", vec![Value::I64(1)], Value::I64(2));
+    assert!(ColumnSchema::from_value(Value::from("n")).is_err());
+    assert!(ColumnSchema::from_value(Value::from(vec![Value::from("n")])).is_err());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn schema_defaults() {
+    let db = memdb(&[]);
+    let base = Path::from("/t");
+    let get = |path: &str| typed(db.lookup_value(path));
+    let mut cols = vec![
+        column("n", Some(Typ::I64), false, vec![], Value::I64(0)),
+        column("s", Some(Typ::String), true, vec![Value::from("a")], Value::from("a")),
+    ];
+    let mut txn = Txn::new();
+    txn.set_table_schema(base.clone(), cols.clone(), None);
+    let names = vec![Chars::from("n"), Chars::from("s"), Chars::from("x")];
+    txn.create_table(base.clone(), vec![Chars::from("r0")], names, false, None);
+    commit(&db, txn).await;
+    assert_eq!(db.table_schema(&base).unwrap(), Some(cols.clone()));
+    assert_eq!(get("/t/r0/n"), Some((Typ::I64, Value::I64(0))));
+    assert_eq!(get("/t/r0/s"), Some((Typ::String, Value::from("a"))));
+    assert_eq!(get("/t/r0/x"), Some((Typ::Null, Value::Null)));
+    // new rows and columns get the defaults of their columns
+    cols.push(column("m", Some(Typ::I64), true, vec![], Value::I64(7)));
+    let mut txn = Txn::new();
+    txn.set_table_schema(base.clone(), cols.clone(), None);
+    txn.add_table_rows(base.clone(), vec![Chars::from("r1")], None);
+    txn.add_table_columns(base.clone(), vec![Chars::from("m")], None);
+    commit(&db, txn).await;
+    assert_eq!(get("/t/r1/n"), Some((Typ::I64, Value::I64(0))));
+    assert_eq!(get("/t/r1/s"), Some((Typ::String, Value::from("a"))));
+    assert_eq!(get("/t/r1/x"), Some((Typ::Null, Value::Null)));
+    assert_eq!(get("/t/r0/m"), Some((Typ::I64, Value::I64(7))));
+    assert_eq!(get("/t/r1/m"), Some((Typ::I64, Value::I64(7))));
+    // writes are cast to the column type, and rejected if they don't fit
+    for (path, v) in [
+        ("/t/r0/n", Value::U32(5)),
+        ("/t/r0/n", Value::from("six")),
+        ("/t/r1/n", Value::Null),
+        ("/t/r0/s", Value::from("b")),
+        ("/t/r0/x", Value::U32(1)),
+    ] {
+        let mut txn = Txn::new();
+        txn.set_data(true, Path::from(path), v, None);
+        commit(&db, txn).await;
+    }
+    assert_eq!(get("/t/r0/n"), Some((Typ::I64, Value::I64(5))));
+    assert_eq!(get("/t/r1/n"), Some((Typ::I64, Value::I64(0))));
+    assert_eq!(get("/t/r0/s"), Some((Typ::String, Value::from("a"))));
+    assert_eq!(get("/t/r0/x"), Some((Typ::U32, Value::U32(1))));
+    // an empty schema removes it, and writes are no longer checked
+    let mut txn = Txn::new();
+    txn.set_table_schema(base.clone(), vec![], None);
+    txn.set_data(true, Path::from("/t/r0/n"), Value::from("six"), None);
+    commit(&db, txn).await;
+    assert_eq!(db.table_schema(&base).unwrap(), None);
+    assert_eq!(get("/t/r0/n"), Some((Typ::String, Value::from("six"))));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn dump_and_load() {
+    let db = memdb(&[]);
+    populate(&db, "/app", 10).await;
+    populate(&db, "/other", 2).await;
+    let mut txn = Txn::new();
+    txn.set_unlocked(Path::from("/app/locked/open"), None);
+    txn.set_data(true, Path::from("/app/null"), Value::Null, None);
+    txn.set_data(true, Path::from("/app/gone"), Value::U64(1), None);
+    txn.remove(Path::from("/app/gone"), None);
+    commit(&db, txn).await;
+    // versions are not part of a dump, a loaded db starts over
+    let unversioned = |db: &Db| {
+        let mut state = state(db);
+        for (_, _, version) in state.data.iter_mut() {
+            *version = 0
+        }
+        state
+    };
+    for format in [DumpFormat::Json, DumpFormat::Packed] {
+        let mut buf = Vec::new();
+        db.dump(format, &mut buf).await.unwrap();
+        let loaded = memdb(&[]);
+        loaded.load(format, &buf[..]).await.unwrap();
+        assert_eq!(unversioned(&loaded), unversioned(&db));
+        assert_eq!(loaded.roots().count(), 2);
+        assert_eq!(loaded.locked().count(), 3);
+        assert_eq!(loaded.lookup("/app/gone").unwrap(), None);
+    }
+}
+
+/// the cells of an import with their types
+fn cells(import: &Import) -> Vec<(usize, usize, Typ, Value)> {
+    let cells = import.cells.iter().cloned();
+    cells.map(|(row, col, v)| (row, col, Typ::get(&v), v)).collect()
+}
+
+fn names(names: &[Chars]) -> Vec<&str> {
+    names.iter().map(|n| &**n).collect()
+}
+
+#[test]
+fn import_csv() {
+    let input = concat!(
+        "name,a,\"b,c\",d\r\n",
+        "r0,42,\"x, \"\"y\"\"\",\n",
+        "\n",
+        "r1,\"\",u32:7,hello\n",
+        "\"r,2\",true,,\"\"\"42\"\"\""
+    );
+    let import = Import::parse(ImportFormat::Csv, input).unwrap();
+    assert_eq!(names(&import.columns), vec!["a", "b,c", "d"]);
+    assert_eq!(names(&import.rows), vec!["r0", "r1", "r,2"]);
+    // empty fields are left alone, quoted empty fields are empty
+    // strings, and fields that don't parse as values are strings
+    assert_eq!(
+        cells(&import),
+        vec![
+            (0, 0, Typ::I64, Value::I64(42)),
+            (0, 1, Typ::String, Value::from("x, \"y\"")),
+            (1, 0, Typ::String, Value::from("")),
+            (1, 1, Typ::U32, Value::U32(7)),
+            (1, 2, Typ::String, Value::from("hello")),
+            (2, 0, Typ::Bool, Value::True),
+            (2, 2, Typ::String, Value::from("42")),
+        ]
+    );
+    for input in ["", "name,a\nr0,\"1\n", "name,a\nr0,1,2\n", "name,a\n,1\n"] {
+        assert!(Import::parse(ImportFormat::Csv, input).is_err())
+    }
+}
+
+#[test]
+fn import_json() {
+    let input = r#"{
+        "r0": {"a": 42, "b": "x", "c": null, "d": [1, 2.5]},
+        "r1": {"b": true, "e": {"type": "U32", "value": 7}, "f": 18446744073709551615}
+    }"#;
+    let import = Import::parse(ImportFormat::Json, input).unwrap();
+    assert_eq!(names(&import.columns), vec!["a", "b", "c", "d", "e", "f"]);
+    assert_eq!(names(&import.rows), vec!["r0", "r1"]);
+    let array = Value::from(vec![Value::I64(1), Value::F64(2.5)]);
+    assert_eq!(
+        cells(&import),
+        vec![
+            (0, 0, Typ::I64, Value::I64(42)),
+            (0, 1, Typ::String, Value::from("x")),
+            (0, 2, Typ::Null, Value::Null),
+            (0, 3, Typ::Array, array),
+            (1, 1, Typ::Bool, Value::True),
+            (1, 4, Typ::U32, Value::U32(7)),
+            (1, 5, Typ::U64, Value::U64(u64::MAX)),
+        ]
+    );
+    for input in ["[]", r#"{"r0": 1}"#, r#"{"r0": {"a": {"b": 1}}}"#, "{"] {
+        assert!(Import::parse(ImportFormat::Json, input).is_err())
+    }
+}
+
+async fn import_table(db: &Db, csv: &str, base: &Path) {
+    let mut txn = Txn::new();
+    let import = Import::parse(ImportFormat::Csv, csv).unwrap();
+    import.add_to_txn(&mut txn, base.clone(), None);
+    commit(db, txn).await
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn export_csv() {
+    let db = memdb(&[]);
+    let (base, copy) = (Path::from("/t"), Path::from("/copy"));
+    // rows are exported in path order
+    let input = concat!(
+        ",a,b,c\n",
+        "\"r,0\",42,\"x, \"\"y\"\"\",\"\"\n",
+        "r1,u32:7,\"\"\"42\"\"\",\n",
+        "r2,hello,f64:1.5,true\n",
+    );
+    import_table(&db, input, &base).await;
+    let csv = import::export_csv(&db, &base).unwrap();
+    assert_eq!(csv, input);
+    // an export imports back to the same table
+    import_table(&db, &csv, &copy).await;
+    for r in db.iter_prefix(base.clone()) {
+        let (path, _, _) = r.unwrap();
+        let copied = copy.append(&path[base.len() + 1..]);
+        assert_eq!(typed(db.lookup_value(&*copied)), typed(db.lookup_value(&*path)));
+    }
+    assert_eq!(import::export_csv(&db, &copy).unwrap(), input);
+    // formula cells are empty
+    let mut txn = Txn::new();
+    txn.set_formula(base.append("r,0").append("a"), Value::from("sum(1, 2)"), None);
+    commit(&db, txn).await;
+    let csv = import::export_csv(&db, &base).unwrap();
+    assert_eq!(csv.lines().nth(1), Some("\"r,0\",,\"x, \"\"y\"\"\",\"\""));
+}
+
+fn keys(iter: impl Iterator<Item = Result<(IVec, IVec)>>) -> Vec<String> {
+    iter.map(|r| String::from_utf8(r.unwrap().0.to_vec()).unwrap()).collect()
+}
+
+fn check_storage(storage: Storage) {
+    let tree = storage.open_tree("test").unwrap();
+    let key = |i: usize| format!("/k/{:04}", i);
+    let val = |i: usize| (i as u64).to_be_bytes();
+    assert!(tree.is_empty().unwrap());
+    // more keys than are read at once, so ranges span several reads
+    let mut batch = Batch::default();
+    for i in 0..1000 {
+        batch.insert(key(i), val(i))
+    }
+    tree.apply_batch(batch).unwrap();
+    assert!(!tree.is_empty().unwrap());
+    assert_eq!(tree.get(key(42)).unwrap().as_deref(), Some(&val(42)[..]));
+    let all = (0..1000).map(key).collect::<Vec<_>>();
+    assert_eq!(keys(tree.iter()), all);
+    assert_eq!(keys(tree.iter().rev()), all.iter().rev().cloned().collect::<Vec<_>>());
+    assert_eq!(keys(tree.range(key(10)..key(20))), all[10..20].to_vec());
+    assert_eq!(keys(tree.range(key(10)..=key(20)).rev()).first(), Some(&key(20)));
+    let r = (Bound::Excluded(key(10)), Bound::Included(key(12)));
+    assert_eq!(keys(tree.range(r)), vec![key(11), key(12)]);
+    assert_eq!(keys(tree.range(key(20)..key(10))), Vec::<String>::new());
+    assert_eq!(keys(tree.range(key(10)..key(10))), Vec::<String>::new());
+    assert_eq!(keys(tree.scan_prefix("/k/00")), all[0..100].to_vec());
+    assert_eq!(keys(tree.scan_prefix("/j")), Vec::<String>::new());
+    // reading from both ends returns every key once
+    let mut iter = tree.iter();
+    let (mut front, mut back) = (vec![], vec![]);
+    loop {
+        match (iter.next(), iter.next_back(), iter.next_back()) {
+            (None, None, None) => break,
+            (f, b0, b1) => {
+                front.extend(f);
+                back.extend(b0);
+                back.extend(b1);
+            }
+        }
+    }
+    let mut both = keys(front.into_iter());
+    both.extend(keys(back.into_iter().rev()));
+    assert_eq!(both, all);
+    // point operations
+    assert_eq!(tree.insert(key(1), b"x").unwrap().as_deref(), Some(&val(1)[..]));
+    assert_eq!(tree.insert("/z", b"z").unwrap(), None);
+    assert!(tree.contains_key("/z").unwrap());
+    assert_eq!(tree.remove("/z").unwrap().as_deref(), Some(&b"z"[..]));
+    assert!(!tree.contains_key("/z").unwrap());
+    assert!(tree.compare_and_swap(key(1), Some(b"x"), Some(b"y")).unwrap());
+    assert!(!tree.compare_and_swap(key(1), Some(b"x"), Some(b"z")).unwrap());
+    assert!(!tree.compare_and_swap("/z", Some(b"x"), None::<&[u8]>).unwrap());
+    assert!(tree.compare_and_swap("/z", None::<&[u8]>, Some(b"z")).unwrap());
+    assert!(tree.compare_and_swap("/z", Some(b"z"), None::<&[u8]>).unwrap());
+    assert_eq!(tree.get(key(1)).unwrap().as_deref(), Some(&b"y"[..]));
+    let mut batch = Batch::default();
+    batch.remove(key(1));
+    batch.insert("/z", b"z");
+    tree.apply_batch(batch).unwrap();
+    assert_eq!(tree.get(key(1)).unwrap(), None);
+    assert_eq!(tree.get("/z").unwrap().as_deref(), Some(&b"z"[..]));
+    // grouped writes are visible, and other trees are separate
+    let other = storage.open_tree("other").unwrap();
+    storage::grouped(&storage, || {
+        other.insert("/a", b"a").unwrap();
+        tree.remove("/z").unwrap();
+    });
+    assert_eq!(keys(other.iter()), vec![String::from("/a")]);
+    assert_eq!(tree.get("/z").unwrap(), None);
+    // batches to several trees are applied together, and reads
+    // through them see their writes
+    let mut batches = Batches::default();
+    batches.insert(&tree, "/z", b"x");
+    batches.insert(&tree, "/z", b"z");
+    batches.remove(&other, "/a");
+    batches.insert(&other, "/b", b"b");
+    assert_eq!(batches.get(&tree, "/z").unwrap().as_deref(), Some(&b"z"[..]));
+    assert_eq!(batches.get(&other, "/a").unwrap(), None);
+    assert_eq!(batches.get(&tree, key(2)).unwrap().as_deref(), Some(&val(2)[..]));
+    assert_eq!(tree.get("/z").unwrap(), None);
+    assert!(other.contains_key("/a").unwrap());
+    batches.apply(&storage).unwrap();
+    assert_eq!(tree.get("/z").unwrap().as_deref(), Some(&b"z"[..]));
+    assert_eq!(keys(other.iter()), vec![String::from("/b")]);
+    assert!(storage.generate_id().unwrap() < storage.generate_id().unwrap());
+    tree.clear().unwrap();
+    assert!(tree.is_empty().unwrap());
+    assert!(!other.is_empty().unwrap());
+    storage.flush().unwrap();
+}
+
+#[test]
+fn memory_storage() {
+    check_storage(storage::open(&params(&["--storage", "memory"])).unwrap())
+}
+
+#[test]
+fn sled_storage() {
+    let dir = TempDir::new();
+    let db = dir.path("db");
+    check_storage(storage::open(&params(&["--storage", "sled", "--db", &db])).unwrap())
+}
+
+#[cfg(feature = "sqlite")]
+#[test]
+fn sqlite_storage() {
+    let dir = TempDir::new();
+    let db = dir.path("db.sqlite");
+    check_storage(storage::open(&params(&["--storage", "sqlite", "--db", &db])).unwrap())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn atomic_all_or_nothing() {
+    let db = memdb(&["--history"]);
+    let p = |s: &'static str| Path::from(s);
+    let later = || Utc::now() + chrono::Duration::seconds(11);
+    let history = |path| db.history(&p(path)).unwrap().len();
+    let mut txn = Txn::new();
+    let ttl = Ttl { duration: Duration::from_secs(10), expiry: Expiry::Delete };
+    txn.set_ttl(p("/t"), ttl, None);
+    let cols = vec![column("n", Some(Typ::I64), false, vec![], Value::I64(0))];
+    txn.set_table_schema(p("/s"), cols, None);
+    txn.set_data(true, p("/a"), Value::U64(1), None);
+    txn.set_formula(p("/f"), Value::from("sum(1, 2)"), None);
+    commit(&db, txn).await;
+    // the data, versions, expiry, and history are written together
+    let (r, res) = reply();
+    let mut txn = Txn::new();
+    let ops = vec![
+        AtomicOp::SetData(p("/a"), Value::U64(2)),
+        AtomicOp::SetData(p("/t/a"), Value::U64(1)),
+        AtomicOp::SetData(p("/b"), Value::U64(1)),
+        AtomicOp::Remove(p("/b")),
+    ];
+    txn.atomic(vec![(p("/a"), Value::U64(1)), (p("/b"), Value::Null)], ops, r);
+    commit(&db, txn).await;
+    assert_eq!(*res.lock(), Value::Ok);
+    assert_eq!(db.lookup_value("/a"), Some(Value::U64(2)));
+    assert_eq!(db.lookup_value("/t/a"), Some(Value::U64(1)));
+    assert!(matches!(db.lookup("/b").unwrap(), None | Some(Datum::Deleted)));
+    assert_eq!(db.version(&p("/a")).unwrap(), 2);
+    assert_eq!(db.version(&p("/t/a")).unwrap(), 1);
+    assert_eq!(paths(db.expired(later()).unwrap()), vec![p("/t/a")]);
+    assert_eq!((history("/a"), history("/t/a"), history("/b")), (2, 1, 1));
+    // a failed precondition, or a failed op, writes nothing
+    let failed = vec![
+        (vec![(p("/a"), Value::U64(1))], AtomicOp::SetData(p("/t/b"), Value::U64(1))),
+        (vec![(p("/f"), Value::I64(3))], AtomicOp::SetData(p("/t/b"), Value::U64(1))),
+        (vec![], AtomicOp::SetData(p("/s/r0/n"), Value::from("one"))),
+    ];
+    for (preconditions, op) in failed {
+        let (r, res) = reply();
+        let mut txn = Txn::new();
+        let ops = vec![AtomicOp::SetData(p("/t/c"), Value::U64(1)), op];
+        txn.atomic(preconditions, ops, r);
+        commit(&db, txn).await;
+        assert!(matches!(&*res.lock(), Value::Error(_)));
+    }
+    for path in ["/t/b", "/t/c", "/s/r0/n"] {
+        assert_eq!(db.lookup_value(path), None);
+        assert_eq!(db.version(&p(path)).unwrap(), 0);
+        assert_eq!(history(path), 0);
+    }
+    assert_eq!(paths(db.expired(later()).unwrap()), vec![p("/t/a")]);
+    // the formula of a formula path can be a precondition
+    let (r, res) = reply();
+    let mut txn = Txn::new();
+    let preconditions = vec![
+        (p("/f/.formula"), Value::from("sum(1, 2)")),
+        (p("/f/.on-write"), Value::Null),
+    ];
+    let ops = vec![AtomicOp::SetFormula(p("/f"), Value::from("sum(1, 3)"), Value::Null)];
+    txn.atomic(preconditions, ops, r);
+    commit(&db, txn).await;
+    assert_eq!(*res.lock(), Value::Ok);
+    assert_eq!(
+        db.lookup("/f").unwrap(),
+        Some(Datum::Formula(Value::from("sum(1, 3)"), Value::Null))
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn versions() {
+    let db = memdb(&[]);
+    let p = |s: &'static str| Path::from(s);
+    let version = |path| db.version(&p(path)).unwrap();
+    // a path that was never written is at version 0, and every
+    // change bumps it
+    assert_eq!(version("/v/a"), 0);
+    let mut txn = Txn::new();
+    txn.set_data(true, p("/v/a"), Value::U64(1), None);
+    txn.set_data(true, p("/v/b"), Value::U64(1), None);
+    txn.set_formula(p("/v/f"), Value::from("sum(1, 2)"), None);
+    commit(&db, txn).await;
+    let mut txn = Txn::new();
+    txn.set_data(true, p("/v/a"), Value::U64(2), None);
+    txn.set_on_write(p("/v/f"), Value::from("null"), None);
+    txn.remove(p("/v/b"), None);
+    commit(&db, txn).await;
+    assert_eq!((version("/v/a"), version("/v/b"), version("/v/f")), (2, 2, 2));
+    // a write at the current version succeeds and bumps it, a stale
+    // write is rejected and changes nothing
+    let (r0, res0) = reply();
+    let (r1, res1) = reply();
+    let (r2, res2) = reply();
+    let mut txn = Txn::new();
+    txn.set_data_if(p("/v/a"), Value::U64(3), 2, r0);
+    txn.set_data_if(p("/v/a"), Value::U64(4), 2, r1);
+    txn.set_data_if(p("/v/c"), Value::U64(1), 0, r2);
+    commit(&db, txn).await;
+    assert_eq!(*res0.lock(), Value::Ok);
+    assert!(matches!(&*res1.lock(), Value::Error(_)));
+    assert_eq!(*res2.lock(), Value::Ok);
+    assert_eq!(db.lookup_value("/v/a"), Some(Value::U64(3)));
+    assert_eq!((version("/v/a"), version("/v/c")), (3, 1));
+    // transactions bump the versions of the paths they change, so
+    // writes based on the state before them are stale
+    let mut txn = Txn::new();
+    let ops = vec![
+        AtomicOp::SetData(p("/v/a"), Value::U64(5)),
+        AtomicOp::SetData(p("/v/d"), Value::U64(1)),
+        AtomicOp::SetData(p("/v/d"), Value::U64(2)),
+    ];
+    txn.atomic(vec![], ops, None);
+    commit(&db, txn).await;
+    assert_eq!((version("/v/a"), version("/v/d")), (4, 1));
+    let (r0, res0) = reply();
+    let (r1, res1) = reply();
+    let mut txn = Txn::new();
+    txn.set_data_if(p("/v/a"), Value::U64(6), 3, r0);
+    txn.set_data_if(p("/v/d"), Value::U64(3), 1, r1);
+    commit(&db, txn).await;
+    assert!(matches!(&*res0.lock(), Value::Error(_)));
+    assert_eq!(*res1.lock(), Value::Ok);
+    assert_eq!(db.lookup_value("/v/a"), Some(Value::U64(5)));
+    assert_eq!(db.lookup_value("/v/d"), Some(Value::U64(3)));
+    // a transaction that fails bumps nothing
+    let mut txn = Txn::new();
+    let ops = vec![AtomicOp::SetData(p("/v/a"), Value::U64(7))];
+    txn.atomic(vec![(p("/v/a"), Value::U64(1))], ops, None);
+    commit(&db, txn).await;
+    assert_eq!(version("/v/a"), 4);
+}
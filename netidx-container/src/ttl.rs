@@ -0,0 +1,199 @@
+use crate::storage::{Batches, Tree};
+use anyhow::{bail, Error, Result};
+use arcstr::ArcStr;
+use chrono::prelude::*;
+use netidx::{pack::Pack, path::Path, subscriber::Value};
+use netidx_derive::Pack;
+use serde_derive::{Deserialize, Serialize};
+use std::{str, str::FromStr, time::Duration};
+
+/// What happens to a path when it expires
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Pack)]
+pub enum Expiry {
+    /// the path is deleted
+    Delete,
+    /// the value of the path is set to null
+    Null,
+}
+
+impl FromStr for Expiry {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "delete" => Ok(Expiry::Delete),
+            "null" => Ok(Expiry::Null),
+            s => bail!("invalid expiry {}, expected delete or null", s),
+        }
+    }
+}
+
+/// A time to live for the paths in a subtree. A path that isn't
+/// written for `duration` expires, writing it again, or changing it's
+/// formula, refreshes it. Null paths never expire. A path is governed
+/// by the ttl set on it's closest parent (or itself), paths with no
+/// ttl above them never expire.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Pack)]
+pub struct Ttl {
+    pub duration: Duration,
+    pub expiry: Expiry,
+}
+
+impl Ttl {
+    pub(super) fn to_value(&self, path: &Path) -> Value {
+        let expiry = match self.expiry {
+            Expiry::Delete => "delete",
+            Expiry::Null => "null",
+        };
+        Value::from(vec![
+            Value::from(path.clone()),
+            Value::from(self.duration),
+            Value::from(expiry),
+        ])
+    }
+}
+
+/// find the ttl governing `path`, and the path it is set on
+pub(super) fn lookup(ttls: &Tree, path: &Path) -> Result<Option<(Path, Ttl)>> {
+    let mut iter = ttls.range(..=path.as_bytes());
+    loop {
+        match iter.next_back() {
+            None => break Ok(None),
+            Some(r) => {
+                let (k, v) = r?;
+                let k = str::from_utf8(&k)?;
+                if Path::is_parent(k, &path) {
+                    let ttl = Ttl::decode(&mut &*v)?;
+                    break Ok(Some((Path::from(ArcStr::from(k)), ttl)));
+                }
+            }
+        }
+    }
+}
+
+// deadlines are encoded big endian with the sign bit flipped, so they
+// sort in time order
+fn deadline_key(deadline: &DateTime<Utc>) -> [u8; 8] {
+    ((deadline.timestamp_micros() as u64) ^ (1 << 63)).to_be_bytes()
+}
+
+fn queue_key(deadline: &DateTime<Utc>, path: &str) -> Vec<u8> {
+    let mut key = deadline_key(deadline).to_vec();
+    key.extend_from_slice(path.as_bytes());
+    key
+}
+
+/// The scheduled expiry of every path governed by a ttl. Deadlines
+/// are stored by path, so they can be changed, and by (deadline,
+/// path), so finding the paths that are due is a range scan.
+#[derive(Clone)]
+pub(super) struct Expires {
+    by_path: Tree,
+    by_deadline: Tree,
+}
+
+impl Expires {
+    pub(super) fn new(by_path: Tree, by_deadline: Tree) -> Result<Self> {
+        // dbs written before the deadline index existed only have
+        // the path index
+        if by_deadline.is_empty()? && !by_path.is_empty()? {
+            for r in by_path.iter() {
+                let (k, v) = r?;
+                let deadline = <DateTime<Utc> as Pack>::decode(&mut &*v)?;
+                by_deadline.insert(queue_key(&deadline, str::from_utf8(&k)?), b"")?;
+            }
+        }
+        Ok(Expires { by_path, by_deadline })
+    }
+
+    pub(super) fn is_empty(&self) -> Result<bool> {
+        self.by_path.is_empty()
+    }
+
+    /// schedule `path` to expire at `deadline`, replacing it's
+    /// current deadline, if any
+    pub(super) fn schedule(&self, path: &str, deadline: DateTime<Utc>) -> Result<()> {
+        let mut val = Vec::with_capacity(deadline.encoded_len());
+        deadline.encode(&mut val)?;
+        if let Some(prev) = self.by_path.insert(path.as_bytes(), &val)? {
+            let prev = <DateTime<Utc> as Pack>::decode(&mut &*prev)?;
+            self.by_deadline.remove(queue_key(&prev, path))?;
+        }
+        self.by_deadline.insert(queue_key(&deadline, path), b"")?;
+        Ok(())
+    }
+
+    /// remove the deadline of `path`, if any
+    pub(super) fn unschedule(&self, path: &str) -> Result<()> {
+        if let Some(prev) = self.by_path.remove(path.as_bytes())? {
+            let prev = <DateTime<Utc> as Pack>::decode(&mut &*prev)?;
+            self.by_deadline.remove(queue_key(&prev, path))?;
+        }
+        Ok(())
+    }
+
+    /// stage the writes that schedule `path` to expire at `deadline`
+    /// in `batches`
+    pub(super) fn stage_schedule(
+        &self,
+        batches: &mut Batches,
+        path: &str,
+        deadline: DateTime<Utc>,
+    ) -> Result<()> {
+        self.stage_unschedule(batches, path)?;
+        let mut val = Vec::with_capacity(deadline.encoded_len());
+        deadline.encode(&mut val)?;
+        batches.insert(&self.by_path, path.as_bytes(), &val);
+        batches.insert(&self.by_deadline, queue_key(&deadline, path), b"");
+        Ok(())
+    }
+
+    /// stage the writes that remove the deadline of `path` in `batches`
+    pub(super) fn stage_unschedule(
+        &self,
+        batches: &mut Batches,
+        path: &str,
+    ) -> Result<()> {
+        if let Some(prev) = batches.get(&self.by_path, path.as_bytes())? {
+            let prev = <DateTime<Utc> as Pack>::decode(&mut &*prev)?;
+            batches.remove(&self.by_path, path.as_bytes());
+            batches.remove(&self.by_deadline, queue_key(&prev, path));
+        }
+        Ok(())
+    }
+
+    /// the scheduled paths in the subtree rooted at `base`
+    pub(super) fn subtree(&self, base: &Path) -> Result<Vec<Path>> {
+        let mut paths = Vec::new();
+        for r in self.by_path.scan_prefix(base.as_bytes()).keys() {
+            let k = r?;
+            let k = str::from_utf8(&k)?;
+            if Path::is_parent(base, k) {
+                paths.push(Path::from(ArcStr::from(k)));
+            }
+        }
+        Ok(paths)
+    }
+
+    /// the paths due to expire at or before `now`, in deadline order
+    pub(super) fn due(
+        &self,
+        now: &DateTime<Utc>,
+    ) -> impl Iterator<Item = Result<Path>> + 'static {
+        let end = deadline_key(&(*now + chrono::Duration::microseconds(1)));
+        self.by_deadline.range(..&end[..]).keys().map(|r| {
+            let k = r?;
+            Ok(Path::from(ArcStr::from(str::from_utf8(&k[8..])?)))
+        })
+    }
+
+    pub(super) fn clear(&self) -> Result<()> {
+        self.by_path.clear()?;
+        self.by_deadline.clear()
+    }
+
+    pub(super) fn flush(&self) -> Result<()> {
+        self.by_path.flush()?;
+        self.by_deadline.flush()
+    }
+}
@@ -514,6 +514,40 @@ mod publisher {
         assert!(vequiv(&v, &v_))
     }
 
+    #[test]
+    fn typ_tags() {
+        use crate::value::Typ;
+        // the tags are part of the wire format, they must not change
+        let tags = [
+            (Typ::U32, 0),
+            (Typ::V32, 1),
+            (Typ::I32, 2),
+            (Typ::Z32, 3),
+            (Typ::U64, 4),
+            (Typ::V64, 5),
+            (Typ::I64, 6),
+            (Typ::Z64, 7),
+            (Typ::F32, 8),
+            (Typ::F64, 9),
+            (Typ::Decimal, 10),
+            (Typ::DateTime, 11),
+            (Typ::Duration, 12),
+            (Typ::Bool, 13),
+            (Typ::String, 14),
+            (Typ::Bytes, 15),
+            (Typ::Result, 16),
+            (Typ::Array, 17),
+            (Typ::Null, 18),
+        ];
+        assert_eq!(tags.len(), Typ::all().len());
+        for (typ, tag) in tags {
+            assert_eq!(&pack(&typ).unwrap()[..], &[tag]);
+            check(typ)
+        }
+        let r: Result<Typ, PackError> = Pack::decode(&mut &[19u8][..]);
+        assert!(r.is_err())
+    }
+
     proptest! {
         #[test]
         fn test_fuzz(b in bytes()) {
@@ -323,6 +323,67 @@ impl fmt::Display for Typ {
     }
 }
 
+impl Pack for Typ {
+    fn const_encoded_len() -> Option<usize> {
+        Some(1)
+    }
+
+    fn encoded_len(&self) -> usize {
+        1
+    }
+
+    // the tags are part of the wire format, never change or reuse one
+    fn encode(&self, buf: &mut impl BufMut) -> result::Result<(), PackError> {
+        let tag: u8 = match self {
+            Typ::U32 => 0,
+            Typ::V32 => 1,
+            Typ::I32 => 2,
+            Typ::Z32 => 3,
+            Typ::U64 => 4,
+            Typ::V64 => 5,
+            Typ::I64 => 6,
+            Typ::Z64 => 7,
+            Typ::F32 => 8,
+            Typ::F64 => 9,
+            Typ::Decimal => 10,
+            Typ::DateTime => 11,
+            Typ::Duration => 12,
+            Typ::Bool => 13,
+            Typ::String => 14,
+            Typ::Bytes => 15,
+            Typ::Result => 16,
+            Typ::Array => 17,
+            Typ::Null => 18,
+        };
+        <u8 as Pack>::encode(&tag, buf)
+    }
+
+    fn decode(buf: &mut impl Buf) -> result::Result<Self, PackError> {
+        match <u8 as Pack>::decode(buf)? {
+            0 => Ok(Typ::U32),
+            1 => Ok(Typ::V32),
+            2 => Ok(Typ::I32),
+            3 => Ok(Typ::Z32),
+            4 => Ok(Typ::U64),
+            5 => Ok(Typ::V64),
+            6 => Ok(Typ::I64),
+            7 => Ok(Typ::Z64),
+            8 => Ok(Typ::F32),
+            9 => Ok(Typ::F64),
+            10 => Ok(Typ::Decimal),
+            11 => Ok(Typ::DateTime),
+            12 => Ok(Typ::Duration),
+            13 => Ok(Typ::Bool),
+            14 => Ok(Typ::String),
+            15 => Ok(Typ::Bytes),
+            16 => Ok(Typ::Result),
+            17 => Ok(Typ::Array),
+            18 => Ok(Typ::Null),
+            _ => Err(PackError::UnknownTag),
+        }
+    }
+}
+
 // This enum is limited to 0x3F cases, because the high 2 bits of the
 // tag are reserved for zero cost wrapper types.
 #[derive(Debug, Clone, Serialize, Deserialize)]
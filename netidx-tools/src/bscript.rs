@@ -0,0 +1,156 @@
+mod rt;
+
+use crate::publisher;
+use anyhow::{bail, Context, Result};
+use futures::{channel::mpsc, prelude::*, stream};
+use netidx::{config::Config, publisher::DesiredAuth};
+use netidx_bscript::{check::Severity, expr::Expr};
+use netidx_tools_core::ClientParams;
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use structopt::StructOpt;
+use tokio::{
+    io::{stdin, AsyncBufReadExt, BufReader},
+    task,
+};
+
+#[derive(StructOpt, Debug)]
+pub(crate) enum Cmd {
+    #[structopt(name = "check", about = "check bscript files for errors")]
+    Check {
+        #[structopt(help = "the files to check")]
+        files: Vec<PathBuf>,
+    },
+    #[structopt(name = "run", about = "run a bscript program")]
+    Run {
+        #[structopt(flatten)]
+        common: ClientParams,
+        #[structopt(flatten)]
+        params: publisher::Params,
+        #[structopt(
+            short = "q",
+            long = "quiet",
+            help = "don't print the program's value"
+        )]
+        quiet: bool,
+        #[structopt(help = "the file to run")]
+        file: PathBuf,
+    },
+    #[structopt(
+        name = "repl",
+        about = "read bscript expressions from stdin and print their values"
+    )]
+    Repl {
+        #[structopt(flatten)]
+        common: ClientParams,
+        #[structopt(flatten)]
+        params: publisher::Params,
+    },
+}
+
+fn report(file: &str, expr: &Expr) -> usize {
+    let mut errors = 0;
+    for diag in rt::checker().check(expr) {
+        if diag.severity == Severity::Error {
+            errors += 1;
+        }
+        eprintln!("{}: {}", file, diag);
+    }
+    errors
+}
+
+async fn load(file: &Path) -> Result<Expr> {
+    let src = tokio::fs::read_to_string(file)
+        .await
+        .with_context(|| format!("reading {}", file.display()))?;
+    src.parse::<Expr>().with_context(|| format!("parsing {}", file.display()))
+}
+
+async fn check(files: Vec<PathBuf>) -> Result<()> {
+    let mut errors = 0;
+    for file in files {
+        match load(&file).await {
+            Ok(expr) => errors += report(&file.to_string_lossy(), &expr),
+            Err(e) => {
+                errors += 1;
+                eprintln!("error: {:#}", e);
+            }
+        }
+    }
+    if errors > 0 {
+        bail!("{} errors", errors)
+    }
+    Ok(())
+}
+
+async fn run_file(
+    cfg: Config,
+    auth: DesiredAuth,
+    params: publisher::Params,
+    quiet: bool,
+    file: PathBuf,
+) -> Result<()> {
+    let expr = load(&file).await?;
+    let errors = report(&file.to_string_lossy(), &expr);
+    if errors > 0 {
+        bail!("{} errors", errors)
+    }
+    let timeout = params.timeout.map(Duration::from_secs);
+    let rt = rt::Runtime::new(cfg, auth, params.bind, timeout).await?;
+    let input = stream::iter([(0, expr)]).chain(stream::pending()).fuse();
+    rt.run(input, |_, v| {
+        if !quiet {
+            println!("{}", v)
+        }
+    })
+    .await
+}
+
+async fn read_exprs(mut tx: mpsc::Sender<(usize, Expr)>) -> Result<()> {
+    let mut lines = BufReader::new(stdin()).lines();
+    let mut n = 0;
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        match line.parse::<Expr>() {
+            Err(e) => eprintln!("error: {}", e),
+            Ok(expr) => {
+                tx.send((n, expr)).await?;
+                n += 1;
+            }
+        }
+    }
+    Ok(())
+}
+
+// each expression is labeled with the order it was entered in, starting
+// from 0, so its updates can be told apart. When stdin ends we keep
+// printing updates until nothing is live, or we are interrupted.
+async fn repl(cfg: Config, auth: DesiredAuth, params: publisher::Params) -> Result<()> {
+    let timeout = params.timeout.map(Duration::from_secs);
+    let rt = rt::Runtime::new(cfg, auth, params.bind, timeout).await?;
+    let (tx, rx) = mpsc::channel(3);
+    task::spawn(async move {
+        if let Err(e) = read_exprs(tx).await {
+            eprintln!("error reading stdin: {}", e)
+        }
+    });
+    rt.run(rx.fuse(), |n, v| println!("[{}] {}", n, v)).await
+}
+
+pub(super) async fn run(cmd: Cmd) -> Result<()> {
+    match cmd {
+        Cmd::Check { files } => check(files).await,
+        Cmd::Run { common, params, quiet, file } => {
+            let (cfg, auth) = common.load();
+            run_file(cfg, auth, params, quiet, file).await
+        }
+        Cmd::Repl { common, params } => {
+            let (cfg, auth) = common.load();
+            repl(cfg, auth, params).await
+        }
+    }
+}
@@ -0,0 +1,674 @@
+use anyhow::{Context, Result};
+use arcstr::ArcStr;
+use futures::{channel::mpsc, prelude::*, select_biased, stream::FusedStream};
+use fxhash::{FxHashMap, FxHashSet};
+use netidx::{
+    chars::Chars,
+    config::Config,
+    path::Path,
+    pool::Pooled,
+    publisher::{
+        BindCfg, DesiredAuth, Id, Publisher, PublisherBuilder, UpdateBatch, Val,
+        WriteRequest,
+    },
+    subscriber::{Dval, Event, SubId, Subscriber, UpdatesFlags, Value},
+};
+use netidx_bscript::{
+    check::{Checker, Kind, Signature},
+    expr::{Expr, ExprId},
+    vm::{self, Apply, Ctx, ExecCtx, InitFn, Node, Register, RpcCallId, TimerId},
+};
+use netidx_protocols::rpc;
+use std::{
+    collections::{hash_map::Entry, HashMap, HashSet},
+    fs,
+    hash::Hash,
+    mem,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    signal, task,
+    time::{self, Instant},
+};
+
+#[derive(Debug, Clone)]
+pub(super) enum UserEv {
+    Write(Id, Value),
+}
+
+enum RtEvent {
+    Vars,
+    RpcReply { name: Path, id: RpcCallId, result: Value },
+    Timer(TimerId),
+}
+
+type RpcProc = mpsc::UnboundedSender<(Vec<(Chars, Value)>, RpcCallId)>;
+
+/// A `Ctx` that isn't attached to a gui or a container. Subscriptions,
+/// rpcs, and timers are real, and `publish` puts values in netidx
+/// using our own publisher.
+pub(super) struct Rt {
+    var: FxHashMap<Chars, FxHashMap<ExprId, usize>>,
+    sub: FxHashMap<SubId, FxHashMap<ExprId, usize>>,
+    rpc: FxHashMap<Path, FxHashSet<ExprId>>,
+    timer: FxHashMap<TimerId, FxHashMap<ExprId, usize>>,
+    published: FxHashMap<Id, ExprId>,
+    rpcs: FxHashMap<Path, (Instant, RpcProc)>,
+    rpcs_in_flight: usize,
+    subscriber: Subscriber,
+    publisher: Publisher,
+    batch: UpdateBatch,
+    sub_updates: mpsc::Sender<Pooled<Vec<(SubId, Event)>>>,
+    write_updates: mpsc::Sender<Pooled<Vec<WriteRequest>>>,
+    var_updates: Vec<(Path, Chars, Value)>,
+    events: mpsc::UnboundedSender<RtEvent>,
+}
+
+fn add_ref<K: Hash + Eq>(
+    tbl: &mut FxHashMap<K, FxHashMap<ExprId, usize>>,
+    key: K,
+    expr_id: ExprId,
+) {
+    *tbl.entry(key).or_insert_with(HashMap::default).entry(expr_id).or_insert(0) += 1;
+}
+
+fn remove_ref<K: Hash + Eq>(
+    tbl: &mut FxHashMap<K, FxHashMap<ExprId, usize>>,
+    key: K,
+    expr_id: ExprId,
+) {
+    if let Entry::Occupied(mut etbl) = tbl.entry(key) {
+        let set = etbl.get_mut();
+        if let Entry::Occupied(mut ecnt) = set.entry(expr_id) {
+            *ecnt.get_mut() -= 1;
+            if *ecnt.get() == 0 {
+                ecnt.remove();
+                if set.is_empty() {
+                    etbl.remove();
+                }
+            }
+        }
+    }
+}
+
+impl Rt {
+    fn get_rpc_proc(&mut self, name: &Path) -> RpcProc {
+        async fn rpc_task(
+            reply: mpsc::UnboundedSender<RtEvent>,
+            subscriber: Subscriber,
+            name: Path,
+            mut rx: mpsc::UnboundedReceiver<(Vec<(Chars, Value)>, RpcCallId)>,
+        ) -> Result<()> {
+            let proc = rpc::client::Proc::new(&subscriber, name.clone());
+            while let Some((args, id)) = rx.next().await {
+                let name = name.clone();
+                let result = match &proc {
+                    Ok(proc) => match proc.call(args).await {
+                        Ok(v) => v,
+                        Err(e) => Value::Error(Chars::from(e.to_string())),
+                    },
+                    Err(e) => Value::Error(Chars::from(e.to_string())),
+                };
+                reply.unbounded_send(RtEvent::RpcReply { name, id, result })?
+            }
+            Ok(())
+        }
+        match self.rpcs.get_mut(name) {
+            Some((ref mut last, ref proc)) => {
+                *last = Instant::now();
+                proc.clone()
+            }
+            None => {
+                let (tx, rx) = mpsc::unbounded();
+                task::spawn({
+                    let events = self.events.clone();
+                    let sub = self.subscriber.clone();
+                    let name = name.clone();
+                    async move {
+                        let _: Result<_> = rpc_task(events, sub, name, rx).await;
+                    }
+                });
+                self.rpcs.insert(name.clone(), (Instant::now(), tx.clone()));
+                tx
+            }
+        }
+    }
+
+    fn gc_rpcs(&mut self) {
+        static MAX_RPC_AGE: Duration = Duration::from_secs(120);
+        let now = Instant::now();
+        self.rpcs.retain(|_, (last, _)| now - *last < MAX_RPC_AGE);
+    }
+
+    fn publish(&mut self, path: Path, value: Value, ref_by: ExprId) -> Result<Val> {
+        let val = self.publisher.publish(path, value)?;
+        self.publisher.writes(val.id(), self.write_updates.clone());
+        self.published.insert(val.id(), ref_by);
+        Ok(val)
+    }
+
+    fn unpublish(&mut self, val: Val) {
+        self.published.remove(&val.id());
+    }
+}
+
+impl Ctx for Rt {
+    fn clear(&mut self) {}
+
+    fn durable_subscribe(
+        &mut self,
+        flags: UpdatesFlags,
+        path: Path,
+        ref_by: ExprId,
+    ) -> Dval {
+        let dv = self.subscriber.subscribe(path);
+        dv.updates(flags, self.sub_updates.clone());
+        add_ref(&mut self.sub, dv.id(), ref_by);
+        dv
+    }
+
+    fn unsubscribe(&mut self, _path: Path, dv: Dval, ref_by: ExprId) {
+        remove_ref(&mut self.sub, dv.id(), ref_by)
+    }
+
+    fn ref_var(&mut self, name: Chars, _scope: Path, ref_by: ExprId) {
+        add_ref(&mut self.var, name, ref_by)
+    }
+
+    fn unref_var(&mut self, name: Chars, _scope: Path, ref_by: ExprId) {
+        remove_ref(&mut self.var, name, ref_by)
+    }
+
+    fn register_fn(&mut self, _name: Chars, _scope: Path) {}
+
+    fn set_var(
+        &mut self,
+        variables: &mut FxHashMap<Path, FxHashMap<Chars, Value>>,
+        local: bool,
+        scope: Path,
+        name: Chars,
+        value: Value,
+    ) {
+        vm::store_var(variables, local, &scope, &name, value.clone());
+        self.var_updates.push((scope, name, value));
+    }
+
+    fn call_rpc(
+        &mut self,
+        name: Path,
+        mut args: Vec<(Chars, Value)>,
+        ref_by: ExprId,
+        id: RpcCallId,
+    ) {
+        self.rpc.entry(name.clone()).or_insert_with(HashSet::default).insert(ref_by);
+        self.rpcs_in_flight += 1;
+        for _ in 1..3 {
+            let proc = self.get_rpc_proc(&name);
+            match proc.unbounded_send((mem::replace(&mut args, vec![]), id)) {
+                Ok(()) => return,
+                Err(e) => {
+                    self.rpcs.remove(&name);
+                    args = e.into_inner().0;
+                }
+            }
+        }
+        let result = Value::Error(Chars::from("failed to call rpc"));
+        let _: Result<_, _> =
+            self.events.unbounded_send(RtEvent::RpcReply { name, id, result });
+    }
+
+    fn set_timer(&mut self, id: TimerId, timeout: Duration, ref_by: ExprId) {
+        add_ref(&mut self.timer, id, ref_by);
+        let events = self.events.clone();
+        task::spawn(async move {
+            time::sleep(timeout).await;
+            let _: Result<_, _> = events.unbounded_send(RtEvent::Timer(id));
+        });
+    }
+
+    fn load_file(&mut self, path: &str) -> Result<Chars> {
+        Ok(Chars::from(fs::read_to_string(path)?))
+    }
+}
+
+/// publish(path, val): publish val at path, and keep it updated. Values
+/// written to path by other netidx clients replace the published value,
+/// and are returned.
+struct Publish {
+    top_id: ExprId,
+    path: Option<Path>,
+    value: Option<Value>,
+    val: Option<Val>,
+    error: Option<Value>,
+}
+
+impl Register<Rt, UserEv> for Publish {
+    fn register(ctx: &mut ExecCtx<Rt, UserEv>) {
+        let f: InitFn<Rt, UserEv> = Arc::new(|ctx, from, _, top_id| {
+            let mut t =
+                Publish { top_id, path: None, value: None, val: None, error: None };
+            match from {
+                [path, value] => {
+                    let path = path.current(ctx);
+                    let value = value.current(ctx);
+                    t.set(ctx, path, value);
+                }
+                _ => t.invalid(),
+            }
+            Box::new(t)
+        });
+        ctx.functions.insert("publish".into(), f);
+        ctx.signatures.insert("publish".into(), Publish::signature());
+        ctx.user.register_fn("publish".into(), Path::root());
+    }
+}
+
+impl Apply<Rt, UserEv> for Publish {
+    fn current(&self, _ctx: &mut ExecCtx<Rt, UserEv>) -> Option<Value> {
+        self.error.clone()
+    }
+
+    fn update(
+        &mut self,
+        ctx: &mut ExecCtx<Rt, UserEv>,
+        from: &mut [Node<Rt, UserEv>],
+        event: &vm::Event<UserEv>,
+    ) -> Option<Value> {
+        match from {
+            [path, value] => {
+                let path = path.update(ctx, event);
+                let value = value.update(ctx, event);
+                let up = path.is_some() || value.is_some();
+                self.set(ctx, path, value);
+                match event {
+                    vm::Event::User(UserEv::Write(id, v))
+                        if self.val.as_ref().map(|val| val.id()) == Some(*id) =>
+                    {
+                        self.set(ctx, None, Some(v.clone()));
+                        Some(v.clone())
+                    }
+                    _ if up => self.error.clone(),
+                    _ => None,
+                }
+            }
+            exprs => {
+                let mut up = false;
+                for expr in exprs {
+                    up = expr.update(ctx, event).is_some() || up;
+                }
+                self.invalid();
+                if up {
+                    self.error.clone()
+                } else {
+                    None
+                }
+            }
+        }
+    }
+}
+
+impl Publish {
+    fn signature() -> Signature {
+        Signature::new(2, Some(2), &[Kind::String, Kind::Any], Kind::Any)
+    }
+
+    fn invalid(&mut self) {
+        self.error =
+            Some(Value::Error(Chars::from("publish(path, val): expected 2 arguments")))
+    }
+
+    fn unpublish(&mut self, ctx: &mut ExecCtx<Rt, UserEv>) {
+        if let Some(val) = self.val.take() {
+            ctx.user.unpublish(val)
+        }
+    }
+
+    fn set(
+        &mut self,
+        ctx: &mut ExecCtx<Rt, UserEv>,
+        path: Option<Value>,
+        value: Option<Value>,
+    ) {
+        if let Some(path) = path {
+            match path.cast_to::<Chars>() {
+                Ok(p) if Path::is_absolute(&*p) => {
+                    let p = Path::from(ArcStr::from(&*p));
+                    if self.path.as_ref() != Some(&p) {
+                        self.unpublish(ctx);
+                        self.path = Some(p);
+                    }
+                }
+                Ok(_) | Err(_) => {
+                    self.unpublish(ctx);
+                    self.path = None;
+                    self.error = Some(Value::Error(Chars::from(
+                        "publish(path, val): expected an absolute path",
+                    )));
+                    return;
+                }
+            }
+        }
+        let updated = value.is_some();
+        if let Some(v) = value {
+            self.value = Some(v);
+        }
+        let publish = match (&self.path, &self.val, &self.value) {
+            (Some(_), Some(val), Some(v)) if updated => {
+                val.update(&mut ctx.user.batch, v.clone());
+                None
+            }
+            (Some(path), None, Some(v)) => Some((path.clone(), v.clone())),
+            (_, _, _) => None,
+        };
+        if let Some((path, v)) = publish {
+            match ctx.user.publish(path, v, self.top_id) {
+                Ok(val) => {
+                    self.val = Some(val);
+                    self.error = None;
+                }
+                Err(e) => {
+                    let e = format!("publish(path, val): {}", e);
+                    self.error = Some(Value::Error(Chars::from(e)))
+                }
+            }
+        }
+    }
+}
+
+/// add the functions the runtime defines on top of the standard library
+pub(super) fn checker() -> Checker {
+    let mut checker = Checker::new();
+    checker.add_fn("publish".into(), Publish::signature());
+    checker
+}
+
+/// A set of compiled top level expressions, and the event loop that
+/// drives them.
+pub(super) struct Runtime {
+    ctx: ExecCtx<Rt, UserEv>,
+    nodes: FxHashMap<ExprId, (usize, Node<Rt, UserEv>)>,
+    sub_updates: mpsc::Receiver<Pooled<Vec<(SubId, Event)>>>,
+    write_updates: mpsc::Receiver<Pooled<Vec<WriteRequest>>>,
+    events: mpsc::UnboundedReceiver<RtEvent>,
+    output: Vec<(usize, Value)>,
+    timeout: Option<Duration>,
+}
+
+impl Runtime {
+    pub(super) async fn new(
+        cfg: Config,
+        auth: DesiredAuth,
+        bind: Option<BindCfg>,
+        timeout: Option<Duration>,
+    ) -> Result<Self> {
+        let subscriber =
+            Subscriber::new(cfg.clone(), auth.clone()).context("creating subscriber")?;
+        let publisher = PublisherBuilder::new(cfg)
+            .desired_auth(auth)
+            .bind_cfg(bind)
+            .build()
+            .await
+            .context("creating publisher")?;
+        let (sub_tx, sub_updates) = mpsc::channel(3);
+        let (write_tx, write_updates) = mpsc::channel(3);
+        let (events_tx, events) = mpsc::unbounded();
+        let rt = Rt {
+            var: HashMap::default(),
+            sub: HashMap::default(),
+            rpc: HashMap::default(),
+            timer: HashMap::default(),
+            published: HashMap::default(),
+            rpcs: HashMap::default(),
+            rpcs_in_flight: 0,
+            batch: publisher.start_batch(),
+            subscriber,
+            publisher,
+            sub_updates: sub_tx,
+            write_updates: write_tx,
+            var_updates: vec![],
+            events: events_tx,
+        };
+        let mut ctx = ExecCtx::new(rt);
+        Publish::register(&mut ctx);
+        Ok(Self {
+            ctx,
+            nodes: HashMap::default(),
+            sub_updates,
+            write_updates,
+            events,
+            output: vec![],
+            timeout,
+        })
+    }
+
+    fn add(&mut self, label: usize, expr: Expr) {
+        let id = expr.id;
+        let node = Node::compile(&mut self.ctx, Path::root(), expr);
+        if let Some(value) = node.current(&mut self.ctx) {
+            self.output.push((label, value))
+        }
+        self.nodes.insert(id, (label, node));
+    }
+
+    fn update_expr_ids(
+        &mut self,
+        ids: impl IntoIterator<Item = ExprId>,
+        event: &vm::Event<UserEv>,
+    ) {
+        for id in ids {
+            if let Some((label, node)) = self.nodes.get_mut(&id) {
+                if let Some(value) = node.update(&mut self.ctx, event) {
+                    self.output.push((*label, value))
+                }
+            }
+        }
+    }
+
+    fn refs<K: Hash + Eq>(
+        tbl: &FxHashMap<K, FxHashMap<ExprId, usize>>,
+        key: &K,
+    ) -> Vec<ExprId> {
+        tbl.get(key).map(|ids| ids.keys().copied().collect()).unwrap_or_default()
+    }
+
+    fn update_vars(&mut self) {
+        let mut n = 0;
+        while n < 10 && !self.ctx.user.var_updates.is_empty() {
+            for (scope, name, value) in mem::take(&mut self.ctx.user.var_updates) {
+                let ids = Self::refs(&self.ctx.user.var, &name);
+                self.update_expr_ids(ids, &vm::Event::Variable(scope, name, value));
+            }
+            n += 1;
+        }
+        if !self.ctx.user.var_updates.is_empty() {
+            let _: Result<_, _> = self.ctx.user.events.unbounded_send(RtEvent::Vars);
+        }
+    }
+
+    fn process_subscriptions(&mut self, mut updates: Pooled<Vec<(SubId, Event)>>) {
+        for (id, event) in updates.drain(..) {
+            if let Event::Update(value) = event {
+                let ids = Self::refs(&self.ctx.user.sub, &id);
+                self.update_expr_ids(ids, &vm::Event::Netidx(id, value));
+            }
+        }
+    }
+
+    fn process_writes(&mut self, mut writes: Pooled<Vec<WriteRequest>>) {
+        for req in writes.drain(..) {
+            if let Some(id) = self.ctx.user.published.get(&req.id).copied() {
+                let event = vm::Event::User(UserEv::Write(req.id, req.value));
+                self.update_expr_ids([id], &event);
+            }
+        }
+    }
+
+    fn process_event(&mut self, event: RtEvent) {
+        match event {
+            RtEvent::Vars => (),
+            RtEvent::RpcReply { name, id, result } => {
+                self.ctx.user.rpcs_in_flight -= 1;
+                let ids: Vec<ExprId> = match self.ctx.user.rpc.get(&name) {
+                    None => vec![],
+                    Some(ids) => ids.iter().copied().collect(),
+                };
+                self.update_expr_ids(ids, &vm::Event::Rpc(id, result));
+            }
+            RtEvent::Timer(id) => {
+                // a timer fires once, a node that wants another tick
+                // sets it again
+                let ids = match self.ctx.user.timer.remove(&id) {
+                    None => vec![],
+                    Some(ids) => ids.into_keys().collect(),
+                };
+                self.update_expr_ids(ids, &vm::Event::Timer(id));
+            }
+        }
+    }
+
+    async fn commit(&mut self) {
+        let batch = self.ctx.user.publisher.start_batch();
+        mem::replace(&mut self.ctx.user.batch, batch).commit(self.timeout).await
+    }
+
+    // true if nothing could produce another value without new input,
+    // no subscriptions, publications, timers, or rpc calls are live
+    fn idle(&self) -> bool {
+        let rt = &self.ctx.user;
+        rt.sub.is_empty()
+            && rt.published.is_empty()
+            && rt.timer.is_empty()
+            && rt.rpcs_in_flight == 0
+            && rt.var_updates.is_empty()
+    }
+
+    /// Compile and run the expressions from input until we are
+    /// interrupted, or input ends and nothing is left that could
+    /// produce a new value, passing every new value of a top level
+    /// expression to out along with the label it was added with.
+    pub(super) async fn run<S, F>(mut self, mut input: S, mut out: F) -> Result<()>
+    where
+        S: FusedStream<Item = (usize, Expr)> + Unpin,
+        F: FnMut(usize, &Value),
+    {
+        let mut gc = time::interval(Duration::from_secs(60));
+        let mut ctrl_c = Box::pin(signal::ctrl_c().fuse());
+        loop {
+            select_biased! {
+                r = ctrl_c => {
+                    r.context("failed to listen for ctrl-c")?;
+                    break
+                },
+                _ = gc.tick().fuse() => self.ctx.user.gc_rpcs(),
+                u = self.sub_updates.select_next_some() => self.process_subscriptions(u),
+                w = self.write_updates.select_next_some() => self.process_writes(w),
+                e = self.events.select_next_some() => self.process_event(e),
+                i = input.next() => match i {
+                    None => (),
+                    Some((label, expr)) => self.add(label, expr),
+                },
+            }
+            self.update_vars();
+            for (label, value) in self.output.drain(..) {
+                out(label, &value)
+            }
+            self.commit().await;
+            if input.is_terminated() && self.idle() {
+                break;
+            }
+        }
+        let publisher = self.ctx.user.publisher.clone();
+        drop(self);
+        publisher.shutdown().await;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::stream;
+    use netidx::resolver_server::{config::Config as ServerConfig, Server};
+    use netidx_protocols::rpc::server::{ArgSpec, RpcCall};
+
+    async fn wait_for(
+        rx: &mut mpsc::UnboundedReceiver<(usize, Value)>,
+        seen: &mut Vec<(usize, Value)>,
+        label: usize,
+        expected: Value,
+    ) {
+        while !seen.contains(&(label, expected.clone())) {
+            match rx.next().await {
+                Some(v) => seen.push(v),
+                None => panic!("the runtime stopped"),
+            }
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn run_against_published_values() {
+        let cfg = ServerConfig::load("../cfg/simple-server.json")
+            .expect("load simple server config");
+        let server = Server::new(cfg, false, 0).await.expect("start resolver server");
+        let mut cfg =
+            Config::load("../cfg/simple-client.json").expect("load simple client config");
+        cfg.addrs[0].0 = *server.local_addr();
+        let bind: BindCfg = "127.0.0.1/32".parse().unwrap();
+        let publisher = PublisherBuilder::new(cfg.clone())
+            .desired_auth(DesiredAuth::Anonymous)
+            .bind_cfg(Some(bind))
+            .build()
+            .await
+            .unwrap();
+        let val = publisher.publish(Path::from("/test/v"), Value::I64(1)).unwrap();
+        let _echo = rpc::server::Proc::new(
+            &publisher,
+            Path::from("/test/echo"),
+            Value::from("echo arg"),
+            [ArgSpec {
+                name: ArcStr::from("arg"),
+                doc: Value::from("the value to echo"),
+                default_value: Value::Null,
+            }],
+            |mut c: RpcCall| -> Option<()> {
+                let v = c.args.remove("arg").unwrap_or(Value::Null);
+                c.reply.send(v);
+                None
+            },
+            None,
+        )
+        .unwrap();
+        publisher.flushed().await;
+        let rt =
+            Runtime::new(cfg, DesiredAuth::Anonymous, Some(bind), None).await.unwrap();
+        let exprs = [
+            r#"load("/test/v") * 2"#,
+            r#"call(null, "/test/echo", "nope", 1)"#,
+            r#"call(null, "/test/echo", "arg", 5)"#,
+        ]
+        .map(|s| s.parse::<Expr>().unwrap());
+        let input = stream::iter(exprs.into_iter().enumerate()).chain(stream::pending());
+        let (tx, mut rx) = mpsc::unbounded();
+        let run = rt.run(input.fuse(), move |n, v| {
+            let _: Result<_, _> = tx.unbounded_send((n, v.clone()));
+        });
+        let check = async {
+            let mut seen = vec![];
+            wait_for(&mut rx, &mut seen, 0, Value::I64(2)).await;
+            let mut batch = publisher.start_batch();
+            val.update(&mut batch, Value::I64(21));
+            batch.commit(None).await;
+            wait_for(&mut rx, &mut seen, 0, Value::I64(42)).await;
+            // a failed call doesn't stop later calls to the same rpc
+            let e = Value::Error(Chars::from("no such argument nope"));
+            wait_for(&mut rx, &mut seen, 1, e).await;
+            wait_for(&mut rx, &mut seen, 2, Value::I64(5)).await;
+        };
+        tokio::select! {
+            r = run => panic!("the runtime stopped {:?}", r),
+            () = check => (),
+            () = time::sleep(Duration::from_secs(30)) => panic!("timed out"),
+        }
+    }
+}
@@ -1,12 +1,126 @@
-use anyhow::{Result, Context};
-use netidx::{config::Config, publisher::DesiredAuth};
-use netidx_container::Container;
+use anyhow::{Context, Result};
+use netidx::{config::Config, path::Path, publisher::DesiredAuth};
 pub(super) use netidx_container::Params;
+use netidx_container::{Container, Db, DumpFormat, ImportFormat};
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter, Read, Write},
+    path::PathBuf,
+};
+use structopt::StructOpt;
 use tokio::signal::ctrl_c;
 
-pub(crate) async fn run(cfg: Config, auth: DesiredAuth, params: Params) -> Result<()> {
-    let _c =
-        Container::start(cfg, auth, params).await.context("container init failed")?;
-    ctrl_c().await.context("ctrl-c handler failed")?;
+#[derive(StructOpt, Debug)]
+pub(crate) enum Cmd {
+    #[structopt(
+        name = "dump",
+        about = "dump the db to a portable file, the container must be stopped"
+    )]
+    Dump {
+        #[structopt(
+            long = "format",
+            help = "the dump format, json or packed",
+            default_value = "json"
+        )]
+        format: DumpFormat,
+        #[structopt(help = "the file to write, stdout if not specified")]
+        file: Option<PathBuf>,
+    },
+    #[structopt(
+        name = "load",
+        about = "load a dump into the db, the container must be stopped"
+    )]
+    Load {
+        #[structopt(
+            long = "format",
+            help = "the dump format, json or packed",
+            default_value = "json"
+        )]
+        format: DumpFormat,
+        #[structopt(help = "the file to read, stdin if not specified")]
+        file: Option<PathBuf>,
+    },
+    #[structopt(
+        name = "import",
+        about = "import csv or json into a table, the container must be stopped"
+    )]
+    Import {
+        #[structopt(
+            long = "format",
+            help = "the input format, csv or json",
+            default_value = "csv"
+        )]
+        format: ImportFormat,
+        #[structopt(long = "path", help = "the table to import into")]
+        path: Path,
+        #[structopt(help = "the file to read, stdin if not specified")]
+        file: Option<PathBuf>,
+    },
+    #[structopt(
+        name = "export",
+        about = "export a table or sheet as csv, the container must be stopped"
+    )]
+    Export {
+        #[structopt(long = "path", help = "the table or sheet to export")]
+        path: Path,
+        #[structopt(help = "the file to write, stdout if not specified")]
+        file: Option<PathBuf>,
+    },
+}
+
+pub(crate) async fn run(
+    cfg: Config,
+    auth: DesiredAuth,
+    params: Params,
+    cmd: Option<Cmd>,
+) -> Result<()> {
+    match cmd {
+        None => {
+            let _c = Container::start(cfg, auth, params)
+                .await
+                .context("container init failed")?;
+            ctrl_c().await.context("ctrl-c handler failed")?;
+        }
+        Some(Cmd::Dump { format, file }) => {
+            let db = Db::open(&params).context("opening db")?;
+            match file {
+                None => db.dump(format, io::stdout()).await?,
+                Some(file) => {
+                    let file = File::create(file).context("creating dump file")?;
+                    db.dump(format, BufWriter::new(file)).await?
+                }
+            }
+        }
+        Some(Cmd::Load { format, file }) => {
+            let db = Db::open(&params).context("opening db")?;
+            match file {
+                None => db.load(format, io::stdin()).await?,
+                Some(file) => {
+                    let file = File::open(file).context("opening dump file")?;
+                    db.load(format, file).await?
+                }
+            }
+        }
+        Some(Cmd::Import { format, path, file }) => {
+            let db = Db::open(&params).context("opening db")?;
+            let input = match file {
+                None => {
+                    let mut input = String::new();
+                    io::stdin().read_to_string(&mut input).context("reading stdin")?;
+                    input
+                }
+                Some(file) => fs::read_to_string(file).context("reading import file")?,
+            };
+            db.import(path, format, &input).await?
+        }
+        Some(Cmd::Export { path, file }) => {
+            let db = Db::open(&params).context("opening db")?;
+            let csv = db.export(&path)?;
+            match file {
+                None => io::stdout().write_all(csv.as_bytes())?,
+                Some(file) => fs::write(file, csv).context("writing export file")?,
+            }
+        }
+    }
     Ok(())
 }
@@ -11,6 +11,7 @@ mod wsproxy;
 
 #[cfg(unix)]
 mod activation;
+mod bscript;
 mod container;
 #[cfg(unix)]
 mod recorder;
@@ -89,6 +90,8 @@ enum Opt {
         common: ClientParams,
         #[structopt(flatten)]
         params: container::Params,
+        #[structopt(subcommand)]
+        cmd: Option<container::Cmd>,
     },
     #[cfg(unix)]
     #[structopt(name = "record", about = "record and republish archives")]
@@ -115,6 +118,11 @@ enum Opt {
         #[structopt(flatten)]
         params: activation::Params,
     },
+    #[structopt(name = "bscript", about = "check, run, or interactively evaluate bscript")]
+    Bscript {
+        #[structopt(subcommand)]
+        cmd: bscript::Cmd,
+    },
     #[structopt(name = "stress", about = "stress test")]
     Stress {
         #[structopt(subcommand)]
@@ -153,13 +161,14 @@ async fn tokio_main() -> Result<()> {
             let (cfg, auth) = common.load();
             subscriber::run(cfg, auth, params).await
         }
-        Opt::Container { common, params } => {
+        Opt::Container { common, params, cmd } => {
             let (cfg, auth) = common.load();
-            container::run(cfg, auth, params).await
+            container::run(cfg, auth, params, cmd).await
         }
         Opt::RecordClient { cmd } => record_client::run(cmd).await,
         #[cfg(unix)]
         Opt::Record { config, example } => recorder::run(config, example).await,
+        Opt::Bscript { cmd } => bscript::run(cmd).await,
         Opt::Stress { cmd } => match cmd {
             Stress::Subscriber { common, params } => {
                 let (cfg, auth) = common.load();
@@ -1,4 +1,4 @@
-use std::{path::PathBuf, collections::HashSet};
+use std::{collections::HashSet, ops::Bound, path::PathBuf};
 
 use anyhow::{Context, Result};
 use bytes::BytesMut;
@@ -10,8 +10,11 @@ use netidx::{
     subscriber::{Event, Subscriber, Value},
 };
 use netidx_archive::{
-    logfile::{self, ArchiveReader, BatchItem, Cursor, Seek},
+    import,
+    logfile::{self, ArchiveReader, ArchiveWriter, BatchItem, Cursor, Seek},
+    recorder,
     recorder_client::{Client, OneshotReplyShard},
+    reshard,
 };
 use netidx_tools_core::ClientParams;
 use structopt::StructOpt;
@@ -68,8 +71,76 @@ pub(crate) enum Cmd {
     },
     #[structopt(name = "verify", about = "verify that an archive can be read")]
     Verify { file: PathBuf },
+    #[structopt(
+        name = "repair",
+        about = "write an uncompressed copy of an archive skipping corrupted records"
+    )]
+    Repair {
+        #[structopt(long = "keep", help = "don't replace the input file")]
+        keep: bool,
+        file: PathBuf,
+    },
     #[structopt(name = "compressed", about = "if file compressed exit 0, 1 no")]
     Compressed { file: PathBuf },
+    #[structopt(name = "import", about = "import a csv or json lines time series")]
+    Import {
+        #[structopt(
+            long = "format",
+            help = "the input format, csv or json",
+            default_value = "csv"
+        )]
+        format: import::Format,
+        #[structopt(
+            long = "image-frequency",
+            help = "write an image every N bytes of deltas (default 64 MiB)"
+        )]
+        image_frequency: Option<usize>,
+        #[structopt(
+            long = "shard",
+            help = "import into this recorder shard directory (recording must be stopped)"
+        )]
+        shard: Option<PathBuf>,
+        #[structopt(long = "output", help = "write a standalone archive file")]
+        output: Option<PathBuf>,
+        #[structopt(help = "the file to import, stdin if not specified")]
+        input: Option<PathBuf>,
+    },
+    #[structopt(name = "merge", about = "merge several archives into one")]
+    Merge {
+        #[structopt(long = "output", help = "the merged archive file to write")]
+        output: PathBuf,
+        #[structopt(
+            long = "image-frequency",
+            help = "write an image every N bytes of deltas"
+        )]
+        image_frequency: Option<usize>,
+        #[structopt(help = "the archive files to merge")]
+        files: Vec<PathBuf>,
+    },
+    #[structopt(
+        name = "split",
+        about = "write the paths matching a filter to a new archive"
+    )]
+    Split {
+        #[structopt(long = "output", help = "the archive file to write")]
+        output: PathBuf,
+        #[structopt(long = "filter", help = "glob pattern(s) of paths to include")]
+        filter: Vec<String>,
+        file: PathBuf,
+    },
+    #[structopt(
+        name = "cut",
+        about = "write a time range of an archive to a new archive"
+    )]
+    Cut {
+        #[structopt(long = "output", help = "the archive file to write")]
+        output: PathBuf,
+        #[structopt(long = "start", help = "time to start at")]
+        start: Option<String>,
+        #[structopt(long = "end", help = "time to end at")]
+        end: Option<String>,
+        file: PathBuf,
+    },
 }
 
 fn parse_bound(s: Option<&str>) -> Result<Option<DateTime<Utc>>> {
@@ -162,6 +233,15 @@ async fn oneshot(subscriber: Subscriber, params: OneshotParams) -> Result<()> {
 }
 
 fn verify(file: impl AsRef<std::path::Path>) -> Result<()> {
+    let report = logfile::verify_file(file.as_ref())?;
+    if !report.is_ok() {
+        bail!(
+            "{} corrupted regions ({} bytes) starting at {:?}, try repair",
+            report.corrupted.len(),
+            report.lost_bytes,
+            report.corrupted
+        )
+    }
     let reader = ArchiveReader::open(file)?;
     let mut cursor = Cursor::new();
     loop {
@@ -212,6 +292,31 @@ async fn index(file: PathBuf, keep: bool) -> Result<()> {
     Ok(())
 }
 
+fn repair(file: PathBuf, keep: bool) -> Result<()> {
+    let mut repaired = file.to_string_lossy().into_owned();
+    repaired.push_str(".rp");
+    let report = logfile::repair_file(&file, &repaired)?;
+    println!("checksummed: {}", report.checksummed);
+    println!("intact records: {}", report.records);
+    println!("corrupted regions: {}", report.corrupted.len());
+    for pos in report.corrupted.iter() {
+        println!("    at {}", pos);
+    }
+    println!("bytes lost: {}", report.lost_bytes);
+    if let Some(pos) = report.unrecoverable {
+        println!("timestamp record lost at {}, nothing after it was kept", pos);
+    }
+    println!("ids without a path mapping: {}", report.unmapped.len());
+    if let Err(e) = verify(repaired.clone()) {
+        std::fs::remove_file(&repaired)?;
+        return Err(e).context("verifying contents");
+    }
+    if !keep {
+        std::fs::rename(repaired, file)?
+    }
+    Ok(())
+}
+
 fn dump(file: PathBuf, metadata: bool, check_index: bool) -> Result<()> {
     let reader = ArchiveReader::open(file)?;
     reader.check_remap_rescan()?;
@@ -226,6 +331,7 @@ fn dump(file: PathBuf, metadata: bool, check_index: bool) -> Result<()> {
     println!("delta batches: {}", reader.delta_batches());
     println!("compressed: {}", reader.is_compressed());
     println!("indexed: {}", reader.is_indexed());
+    println!("checksummed: {}", reader.is_checksummed());
     let filter = if check_index {
 	Some(HashSet::default())
     } else {
@@ -248,6 +354,95 @@ fn dump(file: PathBuf, metadata: bool, check_index: bool) -> Result<()> {
     Ok(())
 }
 
+fn import(
+    format: import::Format,
+    image_frequency: Option<usize>,
+    shard: Option<PathBuf>,
+    output: Option<PathBuf>,
+    input: Option<PathBuf>,
+) -> Result<()> {
+    use std::io::{self, BufRead, BufReader};
+    let image_frequency =
+        image_frequency.or_else(recorder::file::default_image_frequency);
+    let input: Box<dyn BufRead> = match input {
+        None => Box::new(io::stdin().lock()),
+        Some(file) => {
+            Box::new(BufReader::new(std::fs::File::open(file).context("open input")?))
+        }
+    };
+    let (file, stats) = match (shard, output) {
+        (Some(shard), None) => {
+            import::import_to_shard(input, format, image_frequency, shard)?
+        }
+        (None, Some(output)) => {
+            let mut dest = ArchiveWriter::open(&output)?;
+            let stats = import::import(input, format, image_frequency, &mut dest, None)?;
+            drop(dest);
+            (output, stats)
+        }
+        (_, _) => bail!("exactly one of --shard or --output must be specified"),
+    };
+    verify(&file).context("verifying contents")?;
+    println!("wrote: {}", file.display());
+    println!("rows: {}", stats.rows);
+    println!("paths: {}", stats.paths);
+    println!("delta batches: {}", stats.delta_batches);
+    println!("image batches: {}", stats.image_batches);
+    if let (Some(first), Some(last)) = (stats.first, stats.last) {
+        println!("range: {} - {}", first, last);
+    }
+    Ok(())
+}
+
+fn merge(
+    files: Vec<PathBuf>,
+    output: PathBuf,
+    image_frequency: Option<usize>,
+) -> Result<()> {
+    if files.is_empty() {
+        bail!("at least one file to merge must be specified")
+    }
+    let sources = files
+        .iter()
+        .map(|f| reshard::Source::open(f).with_context(|| format!("{}", f.display())))
+        .collect::<Result<Vec<_>>>()?;
+    reshard::merge(&sources, &output, image_frequency)?;
+    drop(sources);
+    verify(&output).context("verifying contents")
+}
+
+fn split(file: PathBuf, output: PathBuf, filter: Vec<String>) -> Result<()> {
+    let filter = GlobSet::new(
+        true,
+        filter
+            .into_iter()
+            .map(|g| Glob::new(Chars::from(g)))
+            .collect::<Result<Vec<Glob>>>()?,
+    )?;
+    let source = reshard::Source::open(&file)?;
+    reshard::split(&source, &[(filter, output.clone())])?;
+    drop(source);
+    verify(&output).context("verifying contents")
+}
+
+fn cut(
+    file: PathBuf,
+    output: PathBuf,
+    start: Option<String>,
+    end: Option<String>,
+) -> Result<()> {
+    let bound = |dt: Option<DateTime<Utc>>| match dt {
+        None => Bound::Unbounded,
+        Some(dt) => Bound::Included(dt),
+    };
+    let start = bound(parse_bound(start.as_ref().map(|s| s.as_str()))?);
+    let end = bound(parse_bound(end.as_ref().map(|s| s.as_str()))?);
+    let source = reshard::Source::open(&file)?;
+    reshard::cut(&source, &output, start, end)?;
+    drop(source);
+    verify(&output).context("verifying contents")
+}
+
 fn compressed(file: PathBuf) -> Result<()> {
     let hdr = logfile::read_file_header(file)?;
     if hdr.compressed {
@@ -267,7 +462,16 @@ pub(super) async fn run(cmd: Cmd) -> Result<()> {
         Cmd::Compress { file, window, keep } => compress(file, keep, window).await,
         Cmd::Dump { file, metadata, check_index } => dump(file, metadata, check_index),
         Cmd::Verify { file } => verify(file),
+        Cmd::Repair { file, keep } => repair(file, keep),
         Cmd::Compressed { file } => compressed(file),
         Cmd::Index { file, keep } => index(file, keep).await,
+        Cmd::Import { format, image_frequency, shard, output, input } => {
+            import(format, image_frequency, shard, output, input)
+        }
+        Cmd::Merge { output, image_frequency, files } => {
+            merge(files, output, image_frequency)
+        }
+        Cmd::Split { output, filter, file } => split(file, output, filter),
+        Cmd::Cut { output, start, end, file } => cut(file, output, start, end),
     }
 }
@@ -341,6 +341,23 @@ impl DvalWeak {
 pub struct Dval(Arc<Mutex<DvalInner>>);
 
 impl Dval {
+    /// Create a `Dval` that doesn't belong to any subscriber. It
+    /// will never be subscribed, and writes to it are queued
+    /// forever. This is useful for testing code that consumes
+    /// `Dval`s without a netidx cluster.
+    pub fn detached() -> Self {
+        Dval(Arc::new(Mutex::new(DvalInner {
+            sub_id: SubId::new(),
+            sub: DvState::Dead(Box::new(DvDead {
+                queued_writes: Vec::new(),
+                waiting: Vec::new(),
+                tries: 0,
+                next_try: Instant::now(),
+            })),
+            streams: SmallVec::new(),
+        })))
+    }
+
     pub fn downgrade(&self) -> DvalWeak {
         DvalWeak(Arc::downgrade(&self.0))
     }